@@ -0,0 +1,94 @@
+use super::HeapAllocator;
+#[cfg(feature = "poison")]
+use super::FREE_POISON;
+use core::ptr::{self, NonNull};
+
+/// Number of blocks moved between a cache and the heap at a time.
+pub const CACHE_BATCH: usize = 16;
+
+/// Number of slab segments a cache recognizes freed blocks in.
+pub const CACHE_RANGES: usize = 4;
+
+/// A stack of free blocks of one size class, linked through their first word. A `LockedHeap`
+/// keeps one per small size class behind its own lock, so small allocations don't wait for
+/// the heap lock.
+pub struct BlockCache {
+    block_size: usize,
+    head: usize,
+    len: usize,
+    enabled: bool,
+    /// The slab the blocks are taken from, and the `[start, end)` ranges of its first segments.
+    slab: HeapAllocator,
+    ranges: [(usize, usize); CACHE_RANGES],
+}
+
+impl BlockCache {
+    pub const fn new(block_size: usize) -> BlockCache {
+        BlockCache {
+            block_size,
+            head: 0,
+            len: 0,
+            enabled: false,
+            slab: HeapAllocator::LinkedListAllocator,
+            ranges: [(0, 0); CACHE_RANGES],
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if freed blocks may be kept in the cache. A cache is enabled by a refill
+    /// from a heap which can use it.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the slab the cache was last filled from.
+    pub fn slab(&self) -> HeapAllocator {
+        self.slab
+    }
+
+    /// Enables the cache for blocks of `slab`, whose segments span `ranges`. Freed blocks
+    /// outside of the first `CACHE_RANGES` ranges are left to the heap.
+    pub fn enable<I: Iterator<Item = (usize, usize)>>(&mut self, slab: HeapAllocator, ranges: I) {
+        self.enabled = true;
+        self.slab = slab;
+        self.ranges = [(0, 0); CACHE_RANGES];
+        for (range, (start, end)) in self.ranges.iter_mut().zip(ranges) {
+            *range = (start, end);
+        }
+    }
+
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    /// Returns true if `addr` lies in one of the recorded segments of the cache's slab.
+    pub fn owns(&self, addr: usize) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(start, end)| addr >= start && addr < end)
+    }
+
+    /// Safety: `ptr` must be a free block of the cache's size class, not used for anything else.
+    pub unsafe fn push(&mut self, ptr: NonNull<u8>) {
+        #[cfg(feature = "poison")]
+        ptr::write_bytes(ptr.as_ptr(), FREE_POISON, self.block_size);
+        #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+        ptr::write(ptr.as_ptr() as *mut usize, self.head);
+        self.head = ptr.as_ptr() as usize;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<NonNull<u8>> {
+        let block = NonNull::new(self.head as *mut u8)?;
+        self.head = unsafe { ptr::read(self.head as *const usize) };
+        self.len -= 1;
+        Some(block)
+    }
+}
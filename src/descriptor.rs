@@ -0,0 +1,105 @@
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::slab::{self, Segment, Slab};
+use super::{Heap, LockedHeap, NUM_OF_SLABS};
+
+/// Version of the `HeapDescriptor` layout, bumped whenever fields are added or change meaning.
+pub const HEAP_DESCRIPTOR_VERSION: u32 = 4;
+
+/// Layout of the allocator's data structures, so debuggers and crash dump scripts can read the
+/// state of a heap without knowing the struct layouts of this version. All offsets are in bytes.
+/// It is exported as `SLAB_ALLOCATOR_DESCRIPTOR`, and the heap registered with
+/// `Heap::register_for_debug` is found through `SLAB_ALLOCATOR_HEAP`.
+#[repr(C)]
+pub struct HeapDescriptor {
+    /// `HEAP_DESCRIPTOR_VERSION` of the build.
+    pub version: u32,
+    /// Size of this descriptor.
+    pub size: u32,
+    /// Offset of each slab, relative to the `Heap`. The slabs are ordered from the smallest
+    /// block size to the largest, but the sizes depend on how the heap was built, such as with
+    /// `Heap::new_from_raw_parts`, so read them from each slab's block size field.
+    pub slab_offsets: [usize; NUM_OF_SLABS - 1],
+    /// Offsets of the slab's block size, number of blocks, segments recorded in the slab itself
+    /// (unused ones have size 0), first block holding records of further segments (a block
+    /// address or 0), free list head (a block address or 0) and free list length, relative to
+    /// a slab.
+    pub slab_block_size_offset: usize,
+    pub slab_total_blocks_offset: usize,
+    pub slab_segments_offset: usize,
+    pub slab_segment_records_offset: usize,
+    pub slab_free_list_head_offset: usize,
+    pub slab_free_list_len_offset: usize,
+    /// Offsets of the start and end of the slab's uninitialized blocks, which are free but not
+    /// on the free list, relative to a slab.
+    pub slab_next_uninitialized_offset: usize,
+    pub slab_uninitialized_end_offset: usize,
+    /// Number of segments recorded in a slab itself.
+    pub inline_segments: usize,
+    /// Size of a segment and the offsets of its start address and size.
+    pub segment_size: usize,
+    pub segment_start_addr_offset: usize,
+    pub segment_size_offset: usize,
+    /// Offsets of the link to the next block holding segment records (or 0), of the number of
+    /// records in the block and of the first record, relative to a block holding records.
+    pub records_next_offset: usize,
+    pub records_len_offset: usize,
+    pub records_offset: usize,
+    /// Offset of the link to the next free block (or 0), relative to a free block.
+    pub free_block_next_offset: usize,
+}
+
+/// The descriptor of this build.
+pub const HEAP_DESCRIPTOR: HeapDescriptor = HeapDescriptor {
+    version: HEAP_DESCRIPTOR_VERSION,
+    size: size_of::<HeapDescriptor>() as u32,
+    // The slabs are the first fields of the `#[repr(C)]` `Heap`, from the smallest.
+    slab_offsets: [
+        0,
+        size_of::<Slab>(),
+        2 * size_of::<Slab>(),
+        3 * size_of::<Slab>(),
+        4 * size_of::<Slab>(),
+        5 * size_of::<Slab>(),
+        6 * size_of::<Slab>(),
+    ],
+    slab_block_size_offset: slab::BLOCK_SIZE_OFFSET,
+    slab_total_blocks_offset: slab::TOTAL_BLOCKS_OFFSET,
+    slab_segments_offset: slab::SEGMENTS_OFFSET,
+    slab_segment_records_offset: slab::SEGMENT_RECORDS_OFFSET,
+    slab_free_list_head_offset: slab::FREE_LIST_HEAD_OFFSET,
+    slab_free_list_len_offset: slab::FREE_LIST_LEN_OFFSET,
+    slab_next_uninitialized_offset: slab::NEXT_UNINITIALIZED_OFFSET,
+    slab_uninitialized_end_offset: slab::UNINITIALIZED_END_OFFSET,
+    inline_segments: slab::INLINE_SEGMENTS,
+    segment_size: size_of::<Segment>(),
+    segment_start_addr_offset: slab::SEGMENT_START_ADDR_OFFSET,
+    segment_size_offset: slab::SEGMENT_SIZE_OFFSET,
+    records_next_offset: slab::RECORDS_NEXT_OFFSET,
+    records_len_offset: slab::RECORDS_LEN_OFFSET,
+    records_offset: slab::RECORDS_OFFSET,
+    free_block_next_offset: slab::FREE_BLOCK_NEXT_OFFSET,
+};
+
+#[no_mangle]
+#[used]
+pub static SLAB_ALLOCATOR_DESCRIPTOR: HeapDescriptor = HEAP_DESCRIPTOR;
+
+/// Address of the `Heap` registered with `Heap::register_for_debug`, or 0.
+#[no_mangle]
+#[used]
+pub static SLAB_ALLOCATOR_HEAP: AtomicUsize = AtomicUsize::new(0);
+
+impl Heap {
+    /// Publishes the address of the heap inside `heap` in `SLAB_ALLOCATOR_HEAP`, so debuggers
+    /// can find it and read it with `SLAB_ALLOCATOR_DESCRIPTOR`. The heap must be initialized
+    /// and stay in place, that is not be taken out of or replaced in `heap`.
+    pub fn register_for_debug(heap: &'static LockedHeap) {
+        let addr = match *heap.lock() {
+            Some(ref heap) => heap as *const Heap as usize,
+            None => heap_panic!("Heap should be initialized before registering it for debugging"),
+        };
+        SLAB_ALLOCATOR_HEAP.store(addr, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,90 @@
+use core::fmt;
+
+use super::HeapAllocator;
+
+/// Whether a `HeapEvent` records an allocation or a deallocation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapEventKind {
+    Allocate,
+    Deallocate,
+}
+
+/// An allocation or deallocation recorded by the event ring of `Heap::enable_event_ring`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapEvent {
+    /// Number of events recorded before this one.
+    pub seq: usize,
+    pub kind: HeapEventKind,
+    pub addr: usize,
+    /// Size of the layout, not of the block it was served from.
+    pub size: usize,
+    pub class: HeapAllocator,
+}
+
+impl HeapEvent {
+    /// Event to initialize the buffer of an event ring with.
+    pub const EMPTY: HeapEvent = HeapEvent {
+        seq: 0,
+        kind: HeapEventKind::Allocate,
+        addr: 0,
+        size: 0,
+        class: HeapAllocator::Slab64Bytes,
+    };
+}
+
+/// The most recent events over a caller provided buffer, overwriting the oldest event once the
+/// buffer is full.
+pub struct EventRing {
+    events: &'static mut [HeapEvent],
+    recorded: usize,
+}
+
+impl EventRing {
+    pub fn new(events: &'static mut [HeapEvent]) -> EventRing {
+        EventRing {
+            events,
+            recorded: 0,
+        }
+    }
+
+    pub fn record(&mut self, kind: HeapEventKind, addr: usize, size: usize, class: HeapAllocator) {
+        let len = self.events.len();
+        if len == 0 {
+            return;
+        }
+        self.events[self.recorded % len] = HeapEvent {
+            seq: self.recorded,
+            kind,
+            addr,
+            size,
+            class,
+        };
+        self.recorded += 1;
+    }
+
+    /// Returns the events from the oldest to the newest, split in two where the ring wraps.
+    pub fn as_slices(&self) -> (&[HeapEvent], &[HeapEvent]) {
+        let len = self.events.len();
+        if self.recorded < len {
+            (&self.events[..self.recorded], &[])
+        } else {
+            let (newer, older) = self.events.split_at(self.recorded % len);
+            (older, newer)
+        }
+    }
+}
+
+/// Writes one line per event, from the oldest to the newest.
+impl fmt::Display for EventRing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (older, newer) = self.as_slices();
+        for event in older.iter().chain(newer.iter()) {
+            writeln!(
+                f,
+                "#{} {:?} {:#x}: {} bytes from {:?}",
+                event.seq, event.kind, event.addr, event.size, event.class
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use alloc::alloc::{Alloc, AllocErr, Layout};
+use core::alloc::GlobalAlloc;
+use core::ptr::NonNull;
+
+use spin::Mutex;
+
+use super::{Heap, HeapAllocator, Slab, MIN_HEAP_SIZE, NUM_OF_SLABS};
+#[cfg(feature = "poison")]
+use super::{FREE_POISON, INIT_POISON};
+
+/// A heap with one lock per slab plus one for the linked list allocator, so threads allocating
+/// from different slabs don't wait for each other. It is laid out like `Heap::new`, always uses
+/// `Heap::layout_to_allocator` for routing and has no telemetry, registry or reserve.
+/// `tests/fine_locked_throughput.rs` compares its multi-threaded throughput with `LockedHeap`.
+///
+/// Operations which hold more than one lock take them in this order: the region, then the slabs
+/// from the smallest block size to the largest, then the linked list allocator. `init` is the
+/// only one holding the region lock together with others.
+pub struct FineLockedHeap {
+    slabs: [Mutex<Option<Slab>>; NUM_OF_SLABS - 1],
+    linked_list_allocator: Mutex<linked_list_allocator::Heap>,
+    region: Mutex<Option<(usize, usize)>>,
+}
+
+impl FineLockedHeap {
+    pub const fn empty() -> FineLockedHeap {
+        FineLockedHeap {
+            slabs: [
+                Mutex::new(None),
+                Mutex::new(None),
+                Mutex::new(None),
+                Mutex::new(None),
+                Mutex::new(None),
+                Mutex::new(None),
+                Mutex::new(None),
+            ],
+            linked_list_allocator: Mutex::new(linked_list_allocator::Heap::empty()),
+            region: Mutex::new(None),
+        }
+    }
+
+    /// Initializes the heap over `[heap_start_addr, heap_start_addr + heap_size)` with the same
+    /// requirements as `Heap::new`. Panics if the heap is already initialized, as other threads
+    /// may be using its slabs.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn init(&self, heap_start_addr: usize, heap_size: usize) {
+        // Held until the slabs are in place, so concurrent calls can't both initialize the heap.
+        let mut region = self.region.lock();
+        heap_assert!(region.is_none(), "Heap should only be initialized once");
+        heap_assert!(
+            heap_start_addr % 4096 == 0,
+            "Start address should be page aligned"
+        );
+        heap_assert!(
+            heap_size >= MIN_HEAP_SIZE,
+            "Heap size should be greater or equal to minimum heap size"
+        );
+        heap_assert!(
+            heap_size % MIN_HEAP_SIZE == 0,
+            "Heap size should be a multiple of minimum heap size"
+        );
+        #[cfg(feature = "poison")]
+        core::ptr::write_bytes(heap_start_addr as *mut u8, INIT_POISON, heap_size);
+        let slab_size = heap_size / NUM_OF_SLABS;
+        for (i, slab) in self.slabs.iter().enumerate() {
+            *slab.lock() = Some(Slab::new(
+                heap_start_addr + i * slab_size,
+                slab_size,
+                64 << i,
+            ));
+        }
+        self.linked_list_allocator
+            .lock()
+            .init(heap_start_addr + (NUM_OF_SLABS - 1) * slab_size, slab_size);
+        *region = Some((heap_start_addr, heap_size));
+    }
+
+    /// Creates a new heap like `Heap::new`.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn new(heap_start_addr: usize, heap_size: usize) -> FineLockedHeap {
+        let heap = FineLockedHeap::empty();
+        heap.init(heap_start_addr, heap_size);
+        heap
+    }
+
+    /// Returns the `(start, size)` range the heap was initialized with.
+    pub fn region(&self) -> Option<(usize, usize)> {
+        *self.region.lock()
+    }
+
+    /// Allocates like `Heap::allocate`, only locking the slab or linked list allocator the
+    /// layout is routed to.
+    pub fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        match FineLockedHeap::slab_index(&layout) {
+            Some(index) => match *self.slabs[index].lock() {
+                Some(ref mut slab) => slab.allocate(layout),
+                None => heap_panic!("allocate: heap not initialized"),
+            },
+            None => self
+                .linked_list_allocator
+                .lock()
+                .allocate_first_fit(layout)
+                .map_err(|_| AllocErr),
+        }
+    }
+
+    /// Frees an allocation like `Heap::deallocate`, only locking the slab or linked list
+    /// allocator the layout is routed to.
+    /// This function is unsafe because undefined behavior may occur for invalid arguments.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match FineLockedHeap::slab_index(&layout) {
+            Some(index) => match *self.slabs[index].lock() {
+                Some(ref mut slab) => slab.deallocate(ptr),
+                None => heap_panic!("deallocate: heap not initialized"),
+            },
+            None => {
+                #[cfg(feature = "poison")]
+                core::ptr::write_bytes(ptr.as_ptr(), FREE_POISON, layout.size());
+                self.linked_list_allocator.lock().deallocate(ptr, layout)
+            }
+        }
+    }
+
+    /// Returns the number of free blocks in the given slab, or 0 for the linked list allocator.
+    pub fn free_blocks(&self, slab: HeapAllocator) -> usize {
+        let index = match slab.slab_index() {
+            Some(index) => index,
+            None => return 0,
+        };
+        self.slabs[index]
+            .lock()
+            .as_ref()
+            .map_or(0, |slab| slab.free_blocks())
+    }
+
+    /// Returns the number of free blocks summed over all slabs. The slabs are locked one after
+    /// the other, so the total may mix states from before and after concurrent allocations.
+    pub fn total_free_blocks(&self) -> usize {
+        self.slabs
+            .iter()
+            .map(|slab| slab.lock().as_ref().map_or(0, |slab| slab.free_blocks()))
+            .sum()
+    }
+
+    /// Returns the index of the slab serving `layout`, or `None` for the linked list allocator.
+    fn slab_index(layout: &Layout) -> Option<usize> {
+        let slab = Heap::layout_to_allocator(layout);
+        slab.slab_index()
+    }
+}
+
+unsafe impl<'a> Alloc for &'a FineLockedHeap {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        self.allocate(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.deallocate(ptr, layout)
+    }
+}
+
+unsafe impl GlobalAlloc for FineLockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.allocate(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => heap_panic!("allocate: failed"),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            self.deallocate(ptr, layout);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use core::mem::size_of;
+
+/// Maximum number of addresses whose generation is tracked with the `generations` feature.
+/// Must be a power of two.
+pub const GENERATION_CAPACITY: usize = 256;
+
+/// Hash table counting how often each address was handed out, kept with the `generations`
+/// feature. Addresses are placed by a multiplicative hash and probed linearly, so a lookup
+/// takes `O(1)` steps while the table is not close to full. Entries are never removed, as a
+/// freed address keeps its generation until it is handed out again, so once the table is full
+/// new addresses are not tracked.
+pub struct Generations {
+    // Address and generation; address 0 marks an empty slot.
+    entries: [(usize, u32); GENERATION_CAPACITY],
+}
+
+impl Generations {
+    pub fn new() -> Generations {
+        Generations {
+            entries: [(0, 0); GENERATION_CAPACITY],
+        }
+    }
+
+    /// Returns the slot holding `addr`, or the empty slot it would be placed in, or `None` if
+    /// it isn't in the table and the table is full.
+    fn slot(&self, addr: usize) -> Option<usize> {
+        const FIBONACCI: u64 = 0x9E37_79B9_7F4A_7C15;
+        let bits = size_of::<usize>() * 8;
+        let hash = addr.wrapping_mul(FIBONACCI as usize)
+            >> (bits - GENERATION_CAPACITY.trailing_zeros() as usize);
+        (0..GENERATION_CAPACITY)
+            .map(|probe| (hash + probe) % GENERATION_CAPACITY)
+            .find(|&slot| self.entries[slot].0 == addr || self.entries[slot].0 == 0)
+    }
+
+    /// Counts another allocation at `addr` and returns its generation, or `None` if the
+    /// address is not tracked. Generations start at 1 and wrap around after `u32::MAX`.
+    pub fn bump(&mut self, addr: usize) -> Option<u32> {
+        let slot = self.slot(addr)?;
+        let entry = &mut self.entries[slot];
+        if entry.0 == 0 {
+            *entry = (addr, 0);
+        }
+        entry.1 = entry.1.wrapping_add(1);
+        Some(entry.1)
+    }
+
+    /// Returns the generation of `addr`, `Some(0)` if it was never handed out while the table
+    /// had room, or `None` if it is not tracked.
+    pub fn get(&self, addr: usize) -> Option<u32> {
+        self.slot(addr).map(|slot| self.entries[slot].1)
+    }
+}
@@ -0,0 +1,36 @@
+use core::ptr::NonNull;
+
+use alloc::alloc::Layout;
+
+use super::{DeallocError, LockedHeap};
+
+/// Several heaps used side by side, such as per-CPU, NUMA node or DMA heaps. Chunks can be
+/// freed through the group without knowing which heap they came from.
+pub struct HeapGroup<'a> {
+    heaps: &'a [&'a LockedHeap],
+}
+
+impl<'a> HeapGroup<'a> {
+    /// Creates a group of `heaps`, whose memory must not overlap.
+    pub fn new(heaps: &'a [&'a LockedHeap]) -> HeapGroup<'a> {
+        HeapGroup { heaps }
+    }
+
+    /// Returns the heap whose memory contains `addr`, if any.
+    pub fn owner(&self, addr: usize) -> Option<&'a LockedHeap> {
+        self.heaps.iter().cloned().find(|heap| heap.owns(addr))
+    }
+
+    /// Frees the given allocation to the heap owning it, validating it like
+    /// `Heap::try_deallocate`. Memory moved between the heaps, such as with
+    /// `Heap::transfer_segment`, leaves the heap it came from, so the chunk is freed to the heap
+    /// holding it now. Returns `DeallocError::NotOwned` if no heap of the group owns `ptr`.
+    /// This function is unsafe for the same reasons as `Heap::try_deallocate`: `ptr` must have
+    /// been allocated from one of the heaps with `layout` and not been freed since.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), DeallocError> {
+        match self.owner(ptr.as_ptr() as usize) {
+            Some(heap) => heap.try_deallocate(ptr, layout),
+            None => Err(DeallocError::NotOwned),
+        }
+    }
+}
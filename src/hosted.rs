@@ -0,0 +1,105 @@
+use core::ops::Deref;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::alloc::{handle_alloc_error, GlobalAlloc, System};
+
+use alloc::alloc::{Alloc, Layout};
+
+use super::{Heap, LockedHeap, MIN_SLAB_SIZE};
+
+/// A `LockedHeap` for running the allocator as the global allocator of a host process, such as
+/// to profile the allocation patterns of a kernel in userspace. Its region is allocated from
+/// the system allocator on first use, and allocations the heap can't serve are forwarded to
+/// the system allocator instead of failing, so the process keeps running once the heap is full.
+pub struct HostedSlabAlloc {
+    heap: LockedHeap,
+    heap_size: usize,
+    region_start: AtomicUsize,
+    system_allocations: AtomicUsize,
+}
+
+impl HostedSlabAlloc {
+    /// Creates an allocator with a region of `heap_size` bytes, which must be valid for
+    /// `Heap::new` and is only allocated on first use.
+    pub const fn new(heap_size: usize) -> HostedSlabAlloc {
+        HostedSlabAlloc {
+            heap: LockedHeap::empty(),
+            heap_size,
+            region_start: AtomicUsize::new(0),
+            system_allocations: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the `(start, size)` range of the heap's region, or `None` before first use.
+    pub fn region(&self) -> Option<(usize, usize)> {
+        match self.region_start.load(Ordering::Acquire) {
+            0 => None,
+            start => Some((start, self.heap_size)),
+        }
+    }
+
+    /// Returns the number of allocations forwarded to the system allocator.
+    pub fn system_allocations(&self) -> usize {
+        self.system_allocations.load(Ordering::SeqCst)
+    }
+
+    fn owns(&self, addr: usize) -> bool {
+        self.region()
+            .map_or(false, |(start, size)| addr >= start && addr - start < size)
+    }
+
+    fn ensure_initialized(&self) {
+        if self.region_start.load(Ordering::Acquire) != 0 {
+            return;
+        }
+        {
+            let mut heap = self.heap.lock();
+            if heap.is_none() {
+                // Taken from `System`, as the global allocator may be this allocator.
+                let layout = match Layout::from_size_align(self.heap_size, MIN_SLAB_SIZE) {
+                    Ok(layout) => layout,
+                    Err(_) => heap_panic!("Heap size is too big for the system allocator"),
+                };
+                let addr = unsafe { System.alloc(layout.clone()) };
+                if addr.is_null() {
+                    handle_alloc_error(layout);
+                }
+                *heap = Some(unsafe { Heap::new(addr as usize, self.heap_size) });
+                self.region_start.store(addr as usize, Ordering::Release);
+            }
+        }
+        #[cfg(not(feature = "tiny"))]
+        self.heap.refresh_counters();
+    }
+}
+
+/// Gives access to the inner `LockedHeap`, such as for its counters, initializing it first.
+impl Deref for HostedSlabAlloc {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        self.ensure_initialized();
+        &self.heap
+    }
+}
+
+unsafe impl GlobalAlloc for HostedSlabAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.ensure_initialized();
+        match Alloc::alloc(&mut &self.heap, layout.clone()) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => {
+                self.system_allocations.fetch_add(1, Ordering::SeqCst);
+                System.alloc(layout)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.owns(ptr as usize) {
+            Alloc::dealloc(&mut &self.heap, NonNull::new_unchecked(ptr), layout)
+        } else {
+            System.dealloc(ptr, layout)
+        }
+    }
+}
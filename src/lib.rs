@@ -1,32 +1,136 @@
 #![feature(alloc, allocator_api)]
 #![feature(const_fn)]
+#![cfg_attr(feature = "valgrind", feature(asm))]
 #![no_std]
 
 extern crate alloc;
 
+#[cfg(feature = "std")]
+extern crate std;
+
 extern crate spin;
 
 extern crate linked_list_allocator;
 
+/// Panics with the given message. With the `tiny` feature the message is dropped, so the strings
+/// and formatting machinery behind it are left out of the binary.
+macro_rules! heap_panic {
+    ($($arg:tt)+) => {{
+        #[cfg(not(feature = "tiny"))]
+        panic!($($arg)+);
+        #[cfg(feature = "tiny")]
+        panic!();
+    }};
+}
+
+/// Captures the current source location as a `CallSite`, to pass to `Heap::allocate_at`.
+#[cfg(feature = "track-allocations")]
+#[macro_export]
+macro_rules! call_site {
+    () => {
+        $crate::CallSite {
+            file: file!(),
+            line: line!(),
+        }
+    };
+}
+
+/// `assert!` counterpart of `heap_panic!`.
+macro_rules! heap_assert {
+    ($cond:expr, $msg:expr) => {
+        if !$cond {
+            heap_panic!($msg);
+        }
+    };
+}
+
+mod cache;
+#[cfg(not(feature = "tiny"))]
+mod descriptor;
+#[cfg(feature = "stats")]
+mod events;
+mod fine_locked;
+#[cfg(feature = "generations")]
+mod generations;
+mod group;
+#[cfg(feature = "std")]
+mod hosted;
+#[cfg(feature = "track-allocations")]
+mod registry;
+mod rt;
 mod slab;
+mod static_heap;
+mod tuning;
+#[cfg(feature = "valgrind")]
+mod valgrind;
 
-use core::ops::Deref;
+use cache::{BlockCache, CACHE_BATCH};
+use core::cmp;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
 
 use alloc::alloc::{Alloc, AllocErr, Layout};
 use core::alloc::GlobalAlloc;
+use core::mem::{align_of, size_of};
 use core::ptr::NonNull;
-use slab::Slab;
+#[cfg(any(not(feature = "tiny"), all(feature = "std", debug_assertions)))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+pub use slab::{Segment, Segments, Slab};
 
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 
+#[cfg(kani)]
+mod proofs;
 #[cfg(test)]
 mod test;
 
 pub const NUM_OF_SLABS: usize = 8;
 pub const MIN_SLAB_SIZE: usize = 4096;
+#[cfg(not(target_pointer_width = "16"))]
 pub const MIN_HEAP_SIZE: usize = NUM_OF_SLABS * MIN_SLAB_SIZE;
+/// On 16-bit targets `Heap::new` builds the reduced class set of `Heap::new_reduced`.
+#[cfg(target_pointer_width = "16")]
+pub const MIN_HEAP_SIZE: usize = NUM_OF_REDUCED_CLASSES * REDUCED_SLAB_SIZE;
+pub const NUM_OF_REDUCED_CLASSES: usize = 5;
+/// Block sizes of the slabs of `Heap::new_reduced`, for targets with little RAM.
+pub const REDUCED_CLASSES: [usize; NUM_OF_REDUCED_CLASSES] = [16, 32, 64, 128, 256];
+/// Granularity of the slabs of `Heap::new_reduced`.
+pub const REDUCED_SLAB_SIZE: usize = 1024;
+/// Lowest address bit and number of bits selecting the cache set of a typical physically
+/// indexed cache, the colors of `Heap::allocate_color`.
+pub const CACHE_COLOR_SHIFT: usize = 12;
+pub const CACHE_COLOR_BITS: usize = 4;
+
+#[cfg(not(feature = "tiny"))]
+pub use descriptor::{
+    HeapDescriptor, HEAP_DESCRIPTOR, HEAP_DESCRIPTOR_VERSION, SLAB_ALLOCATOR_DESCRIPTOR,
+    SLAB_ALLOCATOR_HEAP,
+};
+#[cfg(feature = "stats")]
+pub use events::{HeapEvent, HeapEventKind};
+pub use fine_locked::FineLockedHeap;
+#[cfg(feature = "generations")]
+pub use generations::GENERATION_CAPACITY;
+pub use group::HeapGroup;
+#[cfg(feature = "std")]
+pub use hosted::HostedSlabAlloc;
+#[cfg(feature = "track-allocations")]
+pub use registry::{CallSite, REGISTRY_CAPACITY};
+pub use rt::RtHeap;
+pub use static_heap::StaticSlabHeap;
+pub use tuning::{suggest_slab_sizes, validate_slab_config, ConfigError};
 
-#[derive(Copy, Clone)]
+/// Byte pattern written over all memory handed to the heap before it is carved into blocks.
+#[cfg(feature = "poison")]
+pub const INIT_POISON: u8 = 0xA5;
+/// Byte pattern written over a block when it is freed.
+#[cfg(feature = "poison")]
+pub const FREE_POISON: u8 = 0xDD;
+/// Byte pattern written over a chunk when it is allocated.
+#[cfg(feature = "alloc-fill")]
+pub const ALLOC_FILL: u8 = 0xCD;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum HeapAllocator {
     Slab64Bytes,
     Slab128Bytes,
@@ -38,8 +142,301 @@ pub enum HeapAllocator {
     LinkedListAllocator,
 }
 
+impl HeapAllocator {
+    /// Returns the index of the allocator in arrays such as `Heap::fallback_counts`, from 0 for
+    /// the 64 byte slab to `NUM_OF_SLABS - 1` for the linked list allocator.
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Returns the index of the slab in `SLAB_ALLOCATORS`, or `None` for the linked list
+    /// allocator.
+    fn slab_index(self) -> Option<usize> {
+        match self {
+            HeapAllocator::LinkedListAllocator => None,
+            slab => Some(slab.index()),
+        }
+    }
+}
+
+/// All slabs of the heap, from the smallest block size to the largest.
+const SLAB_ALLOCATORS: [HeapAllocator; NUM_OF_SLABS - 1] = [
+    HeapAllocator::Slab64Bytes,
+    HeapAllocator::Slab128Bytes,
+    HeapAllocator::Slab256Bytes,
+    HeapAllocator::Slab512Bytes,
+    HeapAllocator::Slab1024Bytes,
+    HeapAllocator::Slab2048Bytes,
+    HeapAllocator::Slab4096Bytes,
+];
+
+/// Reasons why memory could not be added to the heap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GrowError {
+    /// The memory does not start at a multiple of the block size (or page size).
+    Misaligned,
+    /// The memory is too small to hold a single block (or page).
+    TooSmall,
+    /// The linked list allocator can only be extended by memory directly following its region.
+    NotAdjacent,
+}
+
+/// Error returned by `Slab::move_all_to`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MoveError {
+    /// The slabs have different block sizes.
+    IncompatibleBlockSize,
+}
+
+/// Error returned by `Heap::donate_class_to_large`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DonateError {
+    /// The linked list allocator can't be donated to itself.
+    NotASlab,
+    /// The slab still has allocated blocks.
+    LiveAllocations,
+    /// None of the slab's segments adjoins the linked list region where it can be extended.
+    NotAdjacent,
+}
+
+/// Summary of the memory `Heap::grow_from_map` added to the heap.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GrowSummary {
+    /// Bytes added to the heap.
+    pub bytes_used: usize,
+    /// Bytes of the map which were not added, because they were cut off by page alignment or
+    /// belonged to regions smaller than a page or overlapping memory the heap already owns.
+    pub bytes_skipped: usize,
+    /// Bytes added to each slab, from the smallest block size to the largest.
+    pub slab_bytes: [usize; NUM_OF_SLABS - 1],
+}
+
+/// Reasons why a heap could not be created over a memory range.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapInitError {
+    /// The end of the range lies before its start.
+    InvalidRange,
+    /// After rounding to page boundaries the range is smaller than `MIN_HEAP_SIZE`, or it can't
+    /// hold the capacities passed to `Heap::with_capacities`.
+    TooSmall,
+    /// The range does not start at a page boundary.
+    Misaligned,
+    /// A capacity is given for a block size no slab has.
+    InvalidBlockSize,
+    /// Memory past the part the heap was created over could not be added to its slabs, as
+    /// returned by `Heap::grow_from_map`.
+    GrowFailed(GrowError),
+}
+
+/// Reasons why `Heap::new_verifying` rejected a memory range.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VerificationError {
+    /// The word at `addr` did not read back as it was written.
+    WriteError { addr: usize },
+}
+
+/// Reasons why `try_deallocate` rejected a pointer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeallocError {
+    /// The pointer does not lie in memory managed by the heap.
+    NotOwned,
+    /// The pointer lies inside the heap but not at the start of a block, or it does not
+    /// have the layout's alignment.
+    Misaligned,
+    /// The pointer belongs to a different slab (or to the linked list allocator) than
+    /// the layout is routed to.
+    ClassMismatch,
+}
+
+/// Reasons why `Heap::allocate_bounded` failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BoundedAllocError {
+    /// Searching the linked list allocator could take longer than `set_large_search_limit`
+    /// allows. The heap may still have the memory.
+    SearchLimit,
+    /// The heap has no memory for the layout.
+    OutOfMemory,
+}
+
+/// Returned by `LockedHeap::try_with_locked` if the heap is not initialized.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapNotInitialized;
+
+/// Hooks called by a heap created with `Heap::new_with_telemetry`, to feed allocator events
+/// into a monitoring backend. All methods do nothing by default. They are called while the
+/// heap is borrowed (and locked, for `LockedHeap`), so they must not allocate from it.
+#[cfg(feature = "stats")]
+pub trait HeapTelemetry: Sync {
+    /// Called after `ptr` was allocated for `layout` from `slab`.
+    fn on_allocate(&self, _layout: &Layout, _slab: HeapAllocator, _ptr: NonNull<u8>) {}
+    /// Called before `ptr`, allocated for `layout` from `slab`, is freed.
+    fn on_deallocate(&self, _layout: &Layout, _slab: HeapAllocator, _ptr: NonNull<u8>) {}
+    /// Called when an allocation for `layout` failed.
+    fn on_oom(&self, _layout: &Layout) {}
+    /// Called after `added` bytes were added to `slab`.
+    fn on_grow(&self, _slab: HeapAllocator, _added: usize) {}
+}
+
+/// Maximum number of chunks in a `ScatterList`.
+pub const MAX_SCATTER_CHUNKS: usize = 16;
+
+/// A buffer made of up to `MAX_SCATTER_CHUNKS` separate 4096 byte blocks, allocated by
+/// `Heap::allocate_scattered`. The chunks are not contiguous, so the buffer is meant for
+/// scatter-gather hardware, such as DMA engines, taking a list of chunks.
+pub struct ScatterList {
+    chunks: [(usize, usize); MAX_SCATTER_CHUNKS],
+    num_of_chunks: usize,
+}
+
+impl ScatterList {
+    /// Returns the `(start address, length)` of every chunk, in buffer order. All chunks but
+    /// the last one are 4096 bytes long.
+    pub fn chunks(&self) -> &[(usize, usize)] {
+        &self.chunks[..self.num_of_chunks]
+    }
+
+    /// Returns the total length of the buffer.
+    pub fn len(&self) -> usize {
+        self.chunks().iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Returns true if the buffer has no chunks.
+    pub fn is_empty(&self) -> bool {
+        self.num_of_chunks == 0
+    }
+}
+
+/// How close a heap is to running out of memory, as estimated by `Heap::estimate_oom_risk`.
+/// Levels are ordered from the least to the most risky.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OomRisk {
+    /// Every slab has more than half of its blocks free.
+    Low,
+    /// A slab has less than half of its blocks free.
+    Medium,
+    /// A slab has less than a tenth of its blocks free.
+    High,
+    /// A slab has no free blocks left.
+    Critical,
+}
+
+/// Free memory of the linked list allocator, as reported by `Heap::large_alloc_health`. A
+/// `largest_free` well below `total_free` means the free memory is fragmented.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LargeAllocHealth {
+    /// Free bytes summed over all free extents.
+    pub total_free: usize,
+    /// Size of the largest free extent, which is the largest allocation that would succeed.
+    pub largest_free: usize,
+    /// Number of separate free extents.
+    pub free_block_count: usize,
+}
+
+/// How contiguous the free blocks of a slab are, as reported by `Heap::contiguity`. Free pages
+/// can only be taken back from a slab if runs span whole pages.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassContiguity {
+    /// Number of blocks in the longest run of free blocks lying next to each other in memory.
+    pub longest_run: usize,
+    /// Number of maximal runs of free blocks, 0 if no block is free.
+    pub runs: usize,
+}
+
+/// A memory range managed by one of the heap's allocators, as reported by
+/// `Heap::as_region_descriptor` and `Heap::for_each_region`, such as for a kernel's page table builder to pick the caching
+/// mode of its pages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegionDescriptor {
+    pub start: usize,
+    /// End of the range, exclusive. Equal to `start` if the allocator has no memory.
+    pub end: usize,
+    /// `"slab_64_bytes"` to `"slab_4096_bytes"`, `"linked_list"` or `"reserved_linked_list"`.
+    pub allocator_type: &'static str,
+}
+
+/// `RegionDescriptor::allocator_type` of each slab, from the 64 byte slab to the 4096 byte slab.
+const SLAB_NAMES: [&str; NUM_OF_SLABS - 1] = [
+    "slab_64_bytes",
+    "slab_128_bytes",
+    "slab_256_bytes",
+    "slab_512_bytes",
+    "slab_1024_bytes",
+    "slab_2048_bytes",
+    "slab_4096_bytes",
+];
+
+/// How long an allocation is expected to live, as hinted to `Heap::allocate_with_hint`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Lifetime {
+    /// Freed soon, such as a buffer for one request. The default of `Heap::allocate`.
+    Short,
+    /// Kept for a long time, such as a table built at startup.
+    Long,
+}
+
+/// Iterator over the parts of a region which are not covered by any of the given holes.
+struct UsableRanges<'a> {
+    cursor: usize,
+    end: usize,
+    holes: &'a [(usize, usize)],
+}
+
+impl<'a> UsableRanges<'a> {
+    fn new(start: usize, size: usize, holes: &'a [(usize, usize)]) -> UsableRanges<'a> {
+        UsableRanges {
+            cursor: start,
+            end: start.saturating_add(size),
+            holes,
+        }
+    }
+}
+
+impl<'a> Iterator for UsableRanges<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        while self.cursor < self.end {
+            let cursor = self.cursor;
+            let end = self.end;
+            // The first hole which is not entirely behind the cursor.
+            let next_hole = self
+                .holes
+                .iter()
+                .map(|&(start, size)| (start, start.saturating_add(size)))
+                .filter(|&(start, hole_end)| hole_end > cursor && start < end)
+                .min_by_key(|&(start, _)| start);
+            match next_hole {
+                Some((hole_start, hole_end)) => {
+                    self.cursor = hole_end;
+                    if hole_start > cursor {
+                        return Some((cursor, hole_start - cursor));
+                    }
+                }
+                None => {
+                    self.cursor = end;
+                    return Some((cursor, end - cursor));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Number of blocks per slab whose free list link `Heap::new_verifying` reads back.
+const VERIFICATION_SAMPLES: usize = 64;
+
+/// Header written in front of every allocation made by `Heap::allocate_prefixed`.
+struct PrefixHeader {
+    size: usize,
+    align: usize,
+}
+
+const PREFIX_HEADER_SIZE: usize = size_of::<PrefixHeader>();
+
 /// A fixed size heap backed by multiple slabs with blocks of different sizes.
 /// Allocations over 4096 bytes are served by linked list allocator.
+// `#[repr(C)]` so the slab offsets of the `HeapDescriptor` can be computed.
+#[repr(C)]
 pub struct Heap {
     slab_64_bytes: Slab,
     slab_128_bytes: Slab,
@@ -49,40 +446,543 @@ pub struct Heap {
     slab_2048_bytes: Slab,
     slab_4096_bytes: Slab,
     linked_list_allocator: linked_list_allocator::Heap,
+    reserved_linked_list_allocator: linked_list_allocator::Heap,
+    static_linked_list_bytes: usize,
+    linked_list_used_bytes: usize,
+    linked_list_live_chunks: usize,
+    large_search_limit: usize,
+    /// Set if every slab has the block size of its `HeapAllocator`, so the class
+    /// `layout_to_allocator` picks is the smallest slab fitting a layout, unless offset segments
+    /// lower its block alignment.
+    default_classes: bool,
+    /// Set by `set_routing`, or `None` to pick the smallest slab fitting a layout.
+    #[cfg(feature = "custom-routing")]
+    routing: Option<fn(&Layout) -> HeapAllocator>,
+    #[cfg(feature = "custom-routing")]
+    bypass_slabs: bool,
+    /// Set once `bypass_slabs` was enabled, as chunks of the linked list allocator may be freed
+    /// with layouts routed to slabs from then on.
+    #[cfg(feature = "custom-routing")]
+    slabs_bypassed: bool,
+    oom_handler: Option<fn(Layout) -> !>,
+    #[cfg(feature = "track-allocations")]
+    registry: registry::Registry,
+    #[cfg(feature = "generations")]
+    generations: generations::Generations,
+    // Bytes requested and blocks handed out per slab, for `total_wasted_bytes`.
+    #[cfg(feature = "waste-tracking")]
+    requested_bytes: [usize; NUM_OF_SLABS - 1],
+    #[cfg(feature = "waste-tracking")]
+    live_blocks: [usize; NUM_OF_SLABS - 1],
+    // Allocations served elsewhere than the class of their layout, for `fallback_counts`.
+    #[cfg(feature = "stats")]
+    fallback_counts: [usize; NUM_OF_SLABS],
+    // Allocations and frees of each allocator since the last `rate_since_last`.
+    #[cfg(feature = "stats")]
+    rates: [(usize, usize); NUM_OF_SLABS],
+    #[cfg(feature = "stats")]
+    telemetry: Option<&'static dyn HeapTelemetry>,
+    #[cfg(feature = "stats")]
+    event_ring: Option<events::EventRing>,
+    // Declared last so the backing memory is freed only after the slabs are dropped.
+    #[cfg(feature = "std")]
+    owned_region: Option<OwnedRegion>,
+}
+
+/// Backing memory allocated from the system allocator by `Heap::new_boxed`.
+#[cfg(feature = "std")]
+struct OwnedRegion {
+    addr: usize,
+    layout: Layout,
+}
+
+#[cfg(feature = "std")]
+impl Drop for OwnedRegion {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.addr as *mut u8, self.layout) }
+    }
 }
 
 impl Heap {
     /// Creates a new heap with the given `heap_start_addr` and `heap_size`. The start address must be valid
     /// and the memory in the `[heap_start_addr, heap_start_addr + heap_size)` range must not be used for
-    /// anything else. This function is unsafe because it can cause undefined behavior if the
+    /// anything else. Each slab hands out its blocks in ascending address order, until blocks
+    /// are freed or the slab is grown. On 16-bit targets the heap is built like `new_reduced`.
+    /// This function is unsafe because it can cause undefined behavior if the
     /// given address is invalid.
     pub unsafe fn new(heap_start_addr: usize, heap_size: usize) -> Heap {
-        assert!(
+        if cfg!(target_pointer_width = "16") {
+            return Heap::new_reduced(heap_start_addr, heap_size);
+        }
+        heap_assert!(
+            heap_start_addr % 4096 == 0,
+            "Start address should be page aligned"
+        );
+        heap_assert!(
+            heap_size >= MIN_HEAP_SIZE,
+            "Heap size should be greater or equal to minimum heap size"
+        );
+        heap_assert!(
+            heap_size % MIN_HEAP_SIZE == 0,
+            "Heap size should be a multiple of minimum heap size"
+        );
+        #[cfg(feature = "poison")]
+        core::ptr::write_bytes(heap_start_addr as *mut u8, INIT_POISON, heap_size);
+        let slab_size = heap_size / NUM_OF_SLABS;
+        Heap::new_from_raw_parts(
+            [
+                Slab::new(heap_start_addr, slab_size, 64),
+                Slab::new(heap_start_addr + slab_size, slab_size, 128),
+                Slab::new(heap_start_addr + 2 * slab_size, slab_size, 256),
+                Slab::new(heap_start_addr + 3 * slab_size, slab_size, 512),
+                Slab::new(heap_start_addr + 4 * slab_size, slab_size, 1024),
+                Slab::new(heap_start_addr + 5 * slab_size, slab_size, 2048),
+                Slab::new(heap_start_addr + 6 * slab_size, slab_size, 4096),
+            ],
+            linked_list_allocator::Heap::new(heap_start_addr + 7 * slab_size, slab_size),
+        )
+    }
+
+    /// Creates a new heap with the reduced class set for targets with little RAM, such as 16-bit
+    /// MSP430 or AVR microcontrollers: slabs of the `REDUCED_CLASSES` block sizes, from 16 to
+    /// 256 bytes, and no linked list region, so larger allocations fail. The memory is split
+    /// into `REDUCED_SLAB_SIZE` parts spread evenly over the slabs, with left over parts going to
+    /// the smallest classes. The slabs take the places of the 64 to 1024 byte slabs of
+    /// `HeapAllocator`, and the 2048 and 4096 byte slabs stay empty. The start address must be
+    /// aligned to `REDUCED_SLAB_SIZE` and the size a multiple of it, holding at least one part
+    /// per class.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn new_reduced(heap_start_addr: usize, heap_size: usize) -> Heap {
+        heap_assert!(
+            heap_start_addr % REDUCED_SLAB_SIZE == 0,
+            "Start address should be aligned to the reduced slab size"
+        );
+        heap_assert!(
+            heap_size >= NUM_OF_REDUCED_CLASSES * REDUCED_SLAB_SIZE,
+            "Heap size should hold one reduced slab per class"
+        );
+        heap_assert!(
+            heap_size % REDUCED_SLAB_SIZE == 0,
+            "Heap size should be a multiple of the reduced slab size"
+        );
+        #[cfg(feature = "poison")]
+        core::ptr::write_bytes(heap_start_addr as *mut u8, INIT_POISON, heap_size);
+        let num_of_parts = heap_size / REDUCED_SLAB_SIZE;
+        let mut slab_starts = [heap_start_addr + heap_size; NUM_OF_SLABS - 1];
+        let mut slab_sizes = [0; NUM_OF_SLABS - 1];
+        let mut addr = heap_start_addr;
+        for i in 0..NUM_OF_REDUCED_CLASSES {
+            let mut parts = num_of_parts / NUM_OF_REDUCED_CLASSES;
+            if i < num_of_parts % NUM_OF_REDUCED_CLASSES {
+                parts += 1;
+            }
+            slab_starts[i] = addr;
+            slab_sizes[i] = parts * REDUCED_SLAB_SIZE;
+            addr += slab_sizes[i];
+        }
+        let largest = REDUCED_CLASSES[NUM_OF_REDUCED_CLASSES - 1];
+        let slab = |i: usize| {
+            let block_size = REDUCED_CLASSES.get(i).cloned().unwrap_or(largest);
+            Slab::new(slab_starts[i], slab_sizes[i], block_size)
+        };
+        Heap::new_from_raw_parts(
+            [
+                slab(0),
+                slab(1),
+                slab(2),
+                slab(3),
+                slab(4),
+                slab(5),
+                slab(6),
+            ],
+            linked_list_allocator::Heap::empty(),
+        )
+    }
+
+    /// Creates a new heap like `new`, but with slabs created by `Slab::new_lazy`, so their
+    /// memory is only written to when blocks are first handed out. This keeps the start-up
+    /// cost of a large heap low and leaves its cold pages untouched for demand paging.
+    /// `prefault` initializes the remaining blocks up front.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn new_lazy(heap_start_addr: usize, heap_size: usize) -> Heap {
+        Heap::new_lazy_with(heap_start_addr, heap_size, Slab::new_lazy)
+    }
+
+    /// Creates a new heap like `new_lazy` over memory which is known to be zero, such as a
+    /// `.bss` section, with slabs created by `Slab::new_lazy_zeroed`. `allocate_zeroed_batch`
+    /// doesn't zero the blocks it hands out before they were ever used. With the `poison`
+    /// feature the memory is poisoned instead, so this is the same as `new_lazy`.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid or the memory is not zero.
+    pub unsafe fn new_lazy_zeroed(heap_start_addr: usize, heap_size: usize) -> Heap {
+        Heap::new_lazy_with(heap_start_addr, heap_size, Slab::new_lazy_zeroed)
+    }
+
+    unsafe fn new_lazy_with(
+        heap_start_addr: usize,
+        heap_size: usize,
+        new_slab: unsafe fn(usize, usize, usize) -> Slab,
+    ) -> Heap {
+        heap_assert!(
             heap_start_addr % 4096 == 0,
             "Start address should be page aligned"
         );
-        assert!(
+        heap_assert!(
             heap_size >= MIN_HEAP_SIZE,
             "Heap size should be greater or equal to minimum heap size"
         );
-        assert!(
+        heap_assert!(
             heap_size % MIN_HEAP_SIZE == 0,
             "Heap size should be a multiple of minimum heap size"
         );
         let slab_size = heap_size / NUM_OF_SLABS;
+        // The slabs poison their blocks as they are initialized.
+        #[cfg(feature = "poison")]
+        core::ptr::write_bytes(
+            (heap_start_addr + 7 * slab_size) as *mut u8,
+            INIT_POISON,
+            slab_size,
+        );
+        Heap::new_from_raw_parts(
+            [
+                new_slab(heap_start_addr, slab_size, 64),
+                new_slab(heap_start_addr + slab_size, slab_size, 128),
+                new_slab(heap_start_addr + 2 * slab_size, slab_size, 256),
+                new_slab(heap_start_addr + 3 * slab_size, slab_size, 512),
+                new_slab(heap_start_addr + 4 * slab_size, slab_size, 1024),
+                new_slab(heap_start_addr + 5 * slab_size, slab_size, 2048),
+                new_slab(heap_start_addr + 6 * slab_size, slab_size, 4096),
+            ],
+            linked_list_allocator::Heap::new(heap_start_addr + 7 * slab_size, slab_size),
+        )
+    }
+
+    /// Creates a new heap like `new`, but with cache coloring: the blocks of the slab at index
+    /// `i`, from the 64 byte slab to the 4096 byte slab, start `(i << color_bits) % block_size`
+    /// bytes after the start of its region, rounded up to the pointer alignment, so blocks of
+    /// different slabs map to different cache sets. `color_bits` is typically 4 to 8. A slab
+    /// with an offset loses its last block, and its blocks are only aligned to the offset, so
+    /// layouts with a larger alignment are served by a larger slab or the linked list
+    /// allocator. The full class set is built on every target, also where `new` builds the
+    /// reduced one, so the size must be a multiple of `NUM_OF_SLABS * MIN_SLAB_SIZE`.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn new_with_color_offset(
+        heap_start_addr: usize,
+        heap_size: usize,
+        color_bits: usize,
+    ) -> Heap {
+        heap_assert!(
+            heap_start_addr % 4096 == 0,
+            "Start address should be page aligned"
+        );
+        heap_assert!(
+            heap_size >= NUM_OF_SLABS * MIN_SLAB_SIZE,
+            "Heap size should be greater or equal to minimum heap size"
+        );
+        heap_assert!(
+            heap_size % (NUM_OF_SLABS * MIN_SLAB_SIZE) == 0,
+            "Heap size should be a multiple of minimum heap size"
+        );
+        #[cfg(feature = "poison")]
+        core::ptr::write_bytes(heap_start_addr as *mut u8, INIT_POISON, heap_size);
+        let slab_size = heap_size / NUM_OF_SLABS;
+        // Offsets wrap around at the block size, which is 4096 bytes at most.
+        let color_bits = cmp::min(color_bits, 12);
+        let colored = |i: usize| {
+            let block_size = 64 << i;
+            // Free blocks hold a link, which must be aligned.
+            let align = align_of::<usize>();
+            let offset = ((i << color_bits) % block_size + align - 1) / align * align % block_size;
+            Slab::new(
+                heap_start_addr + i * slab_size + offset,
+                slab_size - offset,
+                block_size,
+            )
+        };
+        Heap::new_from_raw_parts(
+            [
+                colored(0),
+                colored(1),
+                colored(2),
+                colored(3),
+                colored(4),
+                colored(5),
+                colored(6),
+            ],
+            linked_list_allocator::Heap::new(heap_start_addr + 7 * slab_size, slab_size),
+        )
+    }
+
+    /// Creates a new heap from already built slabs, ordered by block size, and a linked list
+    /// allocator for allocations over 4096 bytes. This allows building each part with its own
+    /// parameters, such as slab regions of different sizes, regions which are not adjacent or
+    /// classes which are not powers of two, like 96 byte blocks built with
+    /// `Slab::new_with_align`. The slab at index `i` takes the place of the `i`-th slab of
+    /// `HeapAllocator`, such as `Slab128Bytes` for a class of 96 byte blocks at index 1, and
+    /// layouts are routed to the smallest slab whose block size and block alignment fit them.
+    /// Slabs may share a block size, such as over two memory banks, see `balance`, in which
+    /// case layouts go to the first of them unless routed otherwise. Panics if the block sizes
+    /// decrease or exceed 4096 bytes.
+    /// This function is unsafe because the slabs and the linked list allocator must manage
+    /// memory which is valid and not used by each other or for anything else.
+    pub unsafe fn new_from_raw_parts(
+        slabs: [Slab; NUM_OF_SLABS - 1],
+        linked_list_allocator: linked_list_allocator::Heap,
+    ) -> Heap {
+        let mut previous = 0;
+        let mut default_classes = true;
+        for (i, slab) in slabs.iter().enumerate() {
+            heap_assert!(
+                slab.block_size() >= previous && slab.block_size() <= MIN_SLAB_SIZE,
+                "Slabs should be ordered by block size of at most 4096 bytes"
+            );
+            previous = slab.block_size();
+            default_classes &= slab.block_size() == 64 << i;
+        }
+        #[cfg(feature = "valgrind")]
+        valgrind::create_pool();
+        let [s64, s128, s256, s512, s1024, s2048, s4096] = slabs;
         Heap {
-            slab_64_bytes: Slab::new(heap_start_addr, slab_size, 64),
-            slab_128_bytes: Slab::new(heap_start_addr + slab_size, slab_size, 128),
-            slab_256_bytes: Slab::new(heap_start_addr + 2 * slab_size, slab_size, 256),
-            slab_512_bytes: Slab::new(heap_start_addr + 3 * slab_size, slab_size, 512),
-            slab_1024_bytes: Slab::new(heap_start_addr + 4 * slab_size, slab_size, 1024),
-            slab_2048_bytes: Slab::new(heap_start_addr + 5 * slab_size, slab_size, 2048),
-            slab_4096_bytes: Slab::new(heap_start_addr + 6 * slab_size, slab_size, 4096),
-            linked_list_allocator: linked_list_allocator::Heap::new(
-                heap_start_addr + 7 * slab_size,
-                slab_size,
-            ),
+            slab_64_bytes: s64,
+            slab_128_bytes: s128,
+            slab_256_bytes: s256,
+            slab_512_bytes: s512,
+            slab_1024_bytes: s1024,
+            slab_2048_bytes: s2048,
+            slab_4096_bytes: s4096,
+            linked_list_allocator,
+            reserved_linked_list_allocator: linked_list_allocator::Heap::empty(),
+            static_linked_list_bytes: 0,
+            linked_list_used_bytes: 0,
+            linked_list_live_chunks: 0,
+            large_search_limit: usize::max_value(),
+            default_classes,
+            #[cfg(feature = "custom-routing")]
+            routing: None,
+            #[cfg(feature = "custom-routing")]
+            bypass_slabs: false,
+            #[cfg(feature = "custom-routing")]
+            slabs_bypassed: false,
+            oom_handler: None,
+            #[cfg(feature = "track-allocations")]
+            registry: registry::Registry::new(),
+            #[cfg(feature = "generations")]
+            generations: generations::Generations::new(),
+            #[cfg(feature = "waste-tracking")]
+            requested_bytes: [0; NUM_OF_SLABS - 1],
+            #[cfg(feature = "waste-tracking")]
+            live_blocks: [0; NUM_OF_SLABS - 1],
+            #[cfg(feature = "stats")]
+            fallback_counts: [0; NUM_OF_SLABS],
+            #[cfg(feature = "stats")]
+            rates: [(0, 0); NUM_OF_SLABS],
+            #[cfg(feature = "stats")]
+            telemetry: None,
+            #[cfg(feature = "stats")]
+            event_ring: None,
+            #[cfg(feature = "std")]
+            owned_region: None,
+        }
+    }
+
+    /// Creates a new heap like `new`, but each slab hands out its blocks in descending address
+    /// order, starting with the highest block.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn new_high_first(heap_start_addr: usize, heap_size: usize) -> Heap {
+        let mut heap = Heap::new(heap_start_addr, heap_size);
+        for slab in heap.slabs_mut().iter_mut() {
+            slab.reverse_free_list();
+        }
+        heap
+    }
+
+    /// Creates a new heap like `new` which reports every allocation, deallocation, failed
+    /// allocation and growth to `telemetry`.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    #[cfg(feature = "stats")]
+    pub unsafe fn new_with_telemetry(
+        heap_start_addr: usize,
+        heap_size: usize,
+        telemetry: &'static dyn HeapTelemetry,
+    ) -> Heap {
+        let mut heap = Heap::new(heap_start_addr, heap_size);
+        heap.telemetry = Some(telemetry);
+        heap
+    }
+
+    /// Creates a new heap over the `[start, end)` range, typically given by linker symbols such as
+    /// `__heap_start` and `__heap_end`. The start is rounded up to a page boundary and the size
+    /// rounded down to a multiple of `MIN_HEAP_SIZE`, so the heap may not use the whole range.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given range is invalid.
+    pub unsafe fn from_symbols(start: *const u8, end: *const u8) -> Result<Heap, HeapInitError> {
+        let (heap_start_addr, heap_size) = Heap::heap_range(start as usize, end as usize)?;
+        Ok(Heap::new(heap_start_addr, heap_size))
+    }
+
+    /// Creates a new heap over the `[heap_start_addr, heap_start_addr + heap_size)` region,
+    /// leaving out every `(start, size)` hole like `grow_with_holes`, such as firmware data in
+    /// the middle of physical memory. The heap is created like `new` over the first part between
+    /// the holes which holds `MIN_HEAP_SIZE` bytes of whole pages, and the other parts are added
+    /// to its slabs like `grow_with_holes` does. Returns the heap along with the summary of the
+    /// other parts, `Err(HeapInitError::TooSmall)` if no part is large enough or
+    /// `Err(HeapInitError::GrowFailed)` if the other parts could not be added.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given region is invalid.
+    pub unsafe fn new_with_holes(
+        heap_start_addr: usize,
+        heap_size: usize,
+        holes: &[(usize, usize)],
+    ) -> Result<(Heap, GrowSummary), HeapInitError> {
+        let (first_start, first_size) = UsableRanges::new(heap_start_addr, heap_size, holes)
+            .filter_map(|(start, size)| Heap::heap_range(start, start + size).ok())
+            .next()
+            .ok_or(HeapInitError::TooSmall)?;
+        let mut heap = Heap::new(first_start, first_size);
+        let first = [(first_start, first_size)];
+        let summary = heap
+            .grow_from_map(
+                UsableRanges::new(heap_start_addr, heap_size, holes)
+                    .flat_map(|(start, size)| UsableRanges::new(start, size, &first)),
+            )
+            .map_err(HeapInitError::GrowFailed)?;
+        Ok((heap, summary))
+    }
+
+    /// Creates a new heap like `new`, then reads back the free list links written into a sample
+    /// of each slab's blocks, to catch memory which is not writable or is mapped wrongly before
+    /// the heap is used. The linked list region is not checked.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn new_verifying(
+        heap_start_addr: usize,
+        heap_size: usize,
+    ) -> Result<Heap, VerificationError> {
+        let heap = Heap::new(heap_start_addr, heap_size);
+        for slab in heap.slabs().iter() {
+            slab.verify_new_free_list(VERIFICATION_SAMPLES)
+                .map_err(|addr| VerificationError::WriteError { addr })?;
+        }
+        Ok(heap)
+    }
+
+    /// Rounds `[start, end)` to the largest range `new` accepts.
+    fn heap_range(start: usize, end: usize) -> Result<(usize, usize), HeapInitError> {
+        if end < start {
+            return Err(HeapInitError::InvalidRange);
+        }
+        let heap_start_addr = match start.checked_add(MIN_SLAB_SIZE - 1) {
+            Some(addr) => addr & !(MIN_SLAB_SIZE - 1),
+            None => return Err(HeapInitError::TooSmall),
+        };
+        if heap_start_addr >= end {
+            return Err(HeapInitError::TooSmall);
+        }
+        let heap_size = (end - heap_start_addr) / MIN_HEAP_SIZE * MIN_HEAP_SIZE;
+        if heap_size == 0 {
+            return Err(HeapInitError::TooSmall);
+        }
+        Ok((heap_start_addr, heap_size))
+    }
+
+    /// Creates a heap over `[heap_start_addr, heap_start_addr + heap_size)` from the number of
+    /// blocks each slab should have, given as `(block_size, block_count)` pairs, instead of
+    /// from a total size. Slabs without a pair get no blocks, and the linked list allocator
+    /// gets the memory left after the slabs. The slabs are laid out from the 4096 byte slab
+    /// down, so each starts aligned to its block size.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn with_capacities(
+        heap_start_addr: usize,
+        heap_size: usize,
+        capacities: &[(usize, usize)],
+    ) -> Result<Heap, HeapInitError> {
+        if heap_start_addr % MIN_SLAB_SIZE != 0 {
+            return Err(HeapInitError::Misaligned);
+        }
+        let mut slab_sizes = [0usize; NUM_OF_SLABS - 1];
+        for &(block_size, block_count) in capacities {
+            let index = (0..NUM_OF_SLABS - 1)
+                .position(|i| 64 << i == block_size)
+                .ok_or(HeapInitError::InvalidBlockSize)?;
+            slab_sizes[index] = block_size
+                .checked_mul(block_count)
+                .and_then(|size| size.checked_add(slab_sizes[index]))
+                .ok_or(HeapInitError::TooSmall)?;
+        }
+        let slabs_size = slab_sizes
+            .iter()
+            .try_fold(0usize, |total, &size| total.checked_add(size))
+            .ok_or(HeapInitError::TooSmall)?;
+        if slabs_size > heap_size {
+            return Err(HeapInitError::TooSmall);
+        }
+        #[cfg(feature = "poison")]
+        core::ptr::write_bytes(heap_start_addr as *mut u8, INIT_POISON, heap_size);
+        let mut slab_starts = [0usize; NUM_OF_SLABS - 1];
+        let mut addr = heap_start_addr;
+        for i in (0..NUM_OF_SLABS - 1).rev() {
+            slab_starts[i] = addr;
+            addr += slab_sizes[i];
         }
+        let slab = |i: usize| Slab::new(slab_starts[i], slab_sizes[i], 64 << i);
+        let linked_list_size = heap_size - slabs_size;
+        let linked_list_allocator = if linked_list_size >= 2 * size_of::<usize>() {
+            linked_list_allocator::Heap::new(addr, linked_list_size)
+        } else {
+            linked_list_allocator::Heap::empty()
+        };
+        Ok(Heap::new_from_raw_parts(
+            [
+                slab(0),
+                slab(1),
+                slab(2),
+                slab(3),
+                slab(4),
+                slab(5),
+                slab(6),
+            ],
+            linked_list_allocator,
+        ))
+    }
+
+    /// Creates a new heap like `new`, but keeps the first `ll_reserve_bytes` of the linked list
+    /// region in reserve. The reserve is only used for allocations over 4096 bytes which can't
+    /// be served by the rest of the linked list region, so large allocations still have room
+    /// after ordinary ones have used up the region. A reserve of 0 bytes gives the same heap as
+    /// `new`, any other reserve holds at least two `usize`s.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn new_with_reserved_ll_region(
+        heap_start_addr: usize,
+        heap_size: usize,
+        ll_reserve_bytes: usize,
+    ) -> Heap {
+        let mut heap = Heap::new(heap_start_addr, heap_size);
+        if ll_reserve_bytes == 0 {
+            return heap;
+        }
+        let ll_reserve_bytes = cmp::max(ll_reserve_bytes, 2 * size_of::<usize>());
+        let ll_start = heap.linked_list_allocator.bottom();
+        let ll_size = heap.linked_list_allocator.size();
+        let ll_reserve_bytes =
+            (ll_reserve_bytes + align_of::<usize>() - 1) & !(align_of::<usize>() - 1);
+        heap_assert!(
+            ll_reserve_bytes < ll_size,
+            "Reserve should be smaller than the linked list region"
+        );
+        heap.reserved_linked_list_allocator =
+            linked_list_allocator::Heap::new(ll_start, ll_reserve_bytes);
+        heap.linked_list_allocator = linked_list_allocator::Heap::new(
+            ll_start + ll_reserve_bytes,
+            ll_size - ll_reserve_bytes,
+        );
+        heap
     }
 
     /// Adds memory to the heap. The start address must be valid
@@ -92,6 +992,14 @@ impl Heap {
     /// This function is unsafe because it can cause undefined behavior if the
     /// given address is invalid.
     pub unsafe fn grow(&mut self, mem_start_addr: usize, mem_size: usize, slab: HeapAllocator) {
+        #[cfg(feature = "poison")]
+        {
+            let poison_start = match slab {
+                HeapAllocator::LinkedListAllocator => self.linked_list_allocator.top(),
+                _ => mem_start_addr,
+            };
+            core::ptr::write_bytes(poison_start as *mut u8, INIT_POISON, mem_size);
+        }
         match slab {
             HeapAllocator::Slab64Bytes => self.slab_64_bytes.grow(mem_start_addr, mem_size),
             HeapAllocator::Slab128Bytes => self.slab_128_bytes.grow(mem_start_addr, mem_size),
@@ -102,109 +1010,2360 @@ impl Heap {
             HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.grow(mem_start_addr, mem_size),
             HeapAllocator::LinkedListAllocator => self.linked_list_allocator.extend(mem_size),
         }
+        #[cfg(feature = "stats")]
+        {
+            if let Some(telemetry) = self.telemetry {
+                telemetry.on_grow(slab, mem_size);
+            }
+        }
     }
 
-    /// Allocates a chunk of the given size with the given alignment. Returns a pointer to the
-    /// beginning of that chunk if it was successful. Else it returns `Err`.
-    /// This function finds the slab of lowest size which can still accomodate the given chunk.
-    /// The runtime is in `O(1)` for chunks of size <= 4096, and `O(n)` when chunk size is > 4096,
-    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        match Heap::layout_to_allocator(&layout) {
-            HeapAllocator::Slab64Bytes => self.slab_64_bytes.allocate(layout),
-            HeapAllocator::Slab128Bytes => self.slab_128_bytes.allocate(layout),
-            HeapAllocator::Slab256Bytes => self.slab_256_bytes.allocate(layout),
-            HeapAllocator::Slab512Bytes => self.slab_512_bytes.allocate(layout),
-            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.allocate(layout),
-            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.allocate(layout),
-            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.allocate(layout),
-            HeapAllocator::LinkedListAllocator => {
-                self.linked_list_allocator.allocate_first_fit(layout)
+    /// Adds the memory of `mem` to the given slab, or to the linked list allocator, which requires
+    /// `mem` to be page aligned and directly follow its region. The slice is consumed, so the memory can't be used for
+    /// anything else. For slabs `mem` must start at a multiple of the block size; bytes past the
+    /// last whole block are left unused. Returns the number of bytes added to the heap.
+    pub fn grow_from_static(
+        &mut self,
+        mem: &'static mut [u8],
+        slab: HeapAllocator,
+    ) -> Result<usize, GrowError> {
+        unsafe { self.checked_grow(mem.as_mut_ptr() as usize, mem.len(), slab) }
+    }
+
+    /// Grows the given slab like `grow`, but first checks that the memory starts at a multiple
+    /// of the block size, or is page aligned and directly follows the linked list allocator's
+    /// region. Bytes past the last whole block are left unused. Returns the number of bytes
+    /// added to the heap.
+    unsafe fn checked_grow(
+        &mut self,
+        mem_start_addr: usize,
+        mem_size: usize,
+        slab: HeapAllocator,
+    ) -> Result<usize, GrowError> {
+        let mem_size = match self.slab(slab) {
+            Some(slab) => {
+                if mem_start_addr % slab.block_size() != 0 {
+                    return Err(GrowError::Misaligned);
+                }
+                mem_size - mem_size % slab.block_size()
             }
+            None => {
+                if mem_start_addr % MIN_SLAB_SIZE != 0 {
+                    return Err(GrowError::Misaligned);
+                }
+                if mem_start_addr != self.linked_list_allocator.top() {
+                    return Err(GrowError::NotAdjacent);
+                }
+                mem_size
+            }
+        };
+        if mem_size == 0 {
+            return Err(GrowError::TooSmall);
         }
+        self.grow(mem_start_addr, mem_size, slab);
+        Ok(mem_size)
     }
 
-    /// Frees the given allocation. `ptr` must be a pointer returned
-    /// by a call to the `allocate` function with identical size and alignment. Undefined
-    /// behavior may occur for invalid arguments, thus this function is unsafe.
-    ///
-    /// This function finds the slab which contains address of `ptr` and adds the blocks beginning
-    /// with `ptr` address to the list of free blocks.
-    /// This operation is in `O(1)` for blocks <= 4096 bytes and `O(n)` for blocks > 4096 bytes.
-    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        match Heap::layout_to_allocator(&layout) {
-            HeapAllocator::Slab64Bytes => self.slab_64_bytes.deallocate(ptr),
-            HeapAllocator::Slab128Bytes => self.slab_128_bytes.deallocate(ptr),
-            HeapAllocator::Slab256Bytes => self.slab_256_bytes.deallocate(ptr),
-            HeapAllocator::Slab512Bytes => self.slab_512_bytes.deallocate(ptr),
-            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.deallocate(ptr),
-            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.deallocate(ptr),
-            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.deallocate(ptr),
-            HeapAllocator::LinkedListAllocator => {
-                self.linked_list_allocator.deallocate(ptr, layout)
+    /// Adds `[mem_start_addr, mem_start_addr + mem_size)` to the slab `layout` is routed to, like
+    /// `grow_from_static` does, and allocates a chunk for `layout` from it right away, so no other
+    /// allocation can take the new memory first. Chunks over 4096 bytes are allocated from the
+    /// grown linked list allocator, which may place them in memory it had before. Returns `Err`
+    /// and leaves the heap unchanged if the memory can't be added. The OOM handler is not
+    /// called.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn grow_and_allocate(
+        &mut self,
+        layout: Layout,
+        mem_start_addr: usize,
+        mem_size: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        let slab = self.route(&layout);
+        self.checked_grow(mem_start_addr, mem_size, slab)
+            .map_err(|_| AllocErr)?;
+        let result = match self.slab_mut(slab) {
+            Some(slab) => slab.allocate_if_address(mem_start_addr),
+            None => return self.try_allocate(layout, Lifetime::Short),
+        };
+        self.finish_slab_allocation(&layout, slab, result)
+    }
+
+    /// Adds the memory of `mem` to the heap like `grow_auto` does. The slice is consumed, so the
+    /// memory can't be used for anything else. Returns the number of bytes added to the heap.
+    pub fn grow_from_static_auto(&mut self, mem: &'static mut [u8]) -> Result<usize, GrowError> {
+        unsafe { self.grow_auto(mem.as_mut_ptr() as usize, mem.len()) }
+    }
+
+    /// Adds memory to the heap, spreading it evenly over all slabs the way `new` does.
+    /// The start address must be page aligned; bytes past the last whole page are left unused.
+    /// Returns the number of bytes added to the heap.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn grow_auto(
+        &mut self,
+        mem_start_addr: usize,
+        mem_size: usize,
+    ) -> Result<usize, GrowError> {
+        if mem_start_addr % MIN_SLAB_SIZE != 0 {
+            return Err(GrowError::Misaligned);
+        }
+        let num_of_pages = mem_size / MIN_SLAB_SIZE;
+        if num_of_pages == 0 {
+            return Err(GrowError::TooSmall);
+        }
+        let mut slab_bytes = [0; NUM_OF_SLABS - 1];
+        self.grow_pages(mem_start_addr, num_of_pages, 0, &mut slab_bytes)
+    }
+
+    /// Adds the usable memory of every `(start, size)` region of a memory map, such as the one
+    /// passed by a UEFI or Multiboot bootloader. Each region is trimmed to whole pages and spread
+    /// over all slabs like `grow_auto` does. Regions with less than a page left and regions
+    /// overlapping memory the heap already owns are skipped. Returns `Err` at the first region
+    /// a slab can't add its pages of, which is left out entirely; the regions before it stay in
+    /// the heap.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given regions are invalid.
+    pub unsafe fn grow_from_map(
+        &mut self,
+        regions: impl Iterator<Item = (usize, usize)>,
+    ) -> Result<GrowSummary, GrowError> {
+        let mut summary = GrowSummary::default();
+        let mut next_slab = 0;
+        for (start, size) in regions {
+            let end = start.saturating_add(size);
+            let page_start = match start.checked_add(MIN_SLAB_SIZE - 1) {
+                Some(addr) => addr & !(MIN_SLAB_SIZE - 1),
+                None => end,
+            };
+            let page_end = end & !(MIN_SLAB_SIZE - 1);
+            if page_end <= page_start || self.overlaps(page_start, page_end - page_start) {
+                summary.bytes_skipped += size;
+                continue;
             }
+            let num_of_pages = (page_end - page_start) / MIN_SLAB_SIZE;
+            let added =
+                self.grow_pages(page_start, num_of_pages, next_slab, &mut summary.slab_bytes)?;
+            next_slab = (next_slab + num_of_pages) % SLAB_ALLOCATORS.len();
+            summary.bytes_used += added;
+            summary.bytes_skipped += size - added;
         }
+        Ok(summary)
     }
 
-    /// Returns bounds on the guaranteed usable size of a successful
-    /// allocation created with the specified `layout`.
-    pub fn usable_size(&self, layout: &Layout) -> (usize, usize) {
-        match Heap::layout_to_allocator(&layout) {
-            HeapAllocator::Slab64Bytes => (layout.size(), 64),
-            HeapAllocator::Slab128Bytes => (layout.size(), 128),
-            HeapAllocator::Slab256Bytes => (layout.size(), 256),
-            HeapAllocator::Slab512Bytes => (layout.size(), 512),
-            HeapAllocator::Slab1024Bytes => (layout.size(), 1024),
-            HeapAllocator::Slab2048Bytes => (layout.size(), 2048),
-            HeapAllocator::Slab4096Bytes => (layout.size(), 4096),
-            HeapAllocator::LinkedListAllocator => (layout.size(), layout.size()),
+    /// Adds the `[mem_start_addr, mem_start_addr + mem_size)` region to the heap like
+    /// `grow_from_map`, leaving out every `(start, size)` hole, such as memory reserved by
+    /// firmware. No block overlaps a hole and `owns` returns false inside of them. Pages
+    /// partially covered by a hole are dropped and counted in `bytes_skipped` of the summary,
+    /// the holes themselves are not. Returns `Err` like `grow_from_map`.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given region is invalid.
+    pub unsafe fn grow_with_holes(
+        &mut self,
+        mem_start_addr: usize,
+        mem_size: usize,
+        holes: &[(usize, usize)],
+    ) -> Result<GrowSummary, GrowError> {
+        self.grow_from_map(UsableRanges::new(mem_start_addr, mem_size, holes))
+    }
+
+    /// Adds `num_of_pages` pages starting at `mem_start_addr`, spreading them evenly over all
+    /// slabs. Left over pages go to the slabs from index `first_slab` on. The bytes added to each
+    /// slab are accumulated in `slab_bytes`. Returns the number of bytes added to the heap, or
+    /// `Err` without adding any of the pages if a slab can't add its share.
+    unsafe fn grow_pages(
+        &mut self,
+        mem_start_addr: usize,
+        num_of_pages: usize,
+        first_slab: usize,
+        slab_bytes: &mut [usize; NUM_OF_SLABS - 1],
+    ) -> Result<usize, GrowError> {
+        let share = |i: usize| {
+            let index = (first_slab + i) % SLAB_ALLOCATORS.len();
+            let mut slab_pages = num_of_pages / SLAB_ALLOCATORS.len();
+            if i < num_of_pages % SLAB_ALLOCATORS.len() {
+                slab_pages += 1;
+            }
+            (index, slab_pages * MIN_SLAB_SIZE)
+        };
+        let mut addr = mem_start_addr;
+        for i in 0..SLAB_ALLOCATORS.len() {
+            let (index, size) = share(i);
+            if size > 0 {
+                self.slabs()[index].check_grow(addr, size)?;
+                addr += size;
+            }
         }
+        let mut addr = mem_start_addr;
+        for i in 0..SLAB_ALLOCATORS.len() {
+            let (index, size) = share(i);
+            if size > 0 {
+                self.grow(addr, size, SLAB_ALLOCATORS[index]);
+                slab_bytes[index] += size;
+                addr += size;
+            }
+        }
+        Ok(addr - mem_start_addr)
     }
 
-    ///Finds allocator to use based on layout size and alignment
-    pub fn layout_to_allocator(layout: &Layout) -> HeapAllocator {
-        if layout.size() > 4096 {
-            HeapAllocator::LinkedListAllocator
-        } else if layout.size() <= 64 && layout.align() <= 64 {
-            HeapAllocator::Slab64Bytes
-        } else if layout.size() <= 128 && layout.align() <= 128 {
-            HeapAllocator::Slab128Bytes
-        } else if layout.size() <= 256 && layout.align() <= 256 {
-            HeapAllocator::Slab256Bytes
-        } else if layout.size() <= 512 && layout.align() <= 512 {
-            HeapAllocator::Slab512Bytes
-        } else if layout.size() <= 1024 && layout.align() <= 1024 {
-            HeapAllocator::Slab1024Bytes
-        } else if layout.size() <= 2048 && layout.align() <= 2048 {
-            HeapAllocator::Slab2048Bytes
-        } else {
-            HeapAllocator::Slab4096Bytes
+    /// Returns true if any part of `[start, start + size)` is owned by the heap.
+    fn overlaps(&self, start: usize, size: usize) -> bool {
+        let end = start + size;
+        let ll_start = self.linked_list_region_start();
+        if start < ll_start + self.linked_list_region_size() && ll_start < end {
+            return true;
         }
+        self.slabs().iter().any(|slab| {
+            slab.segments()
+                .any(|segment| start < segment.end_addr() && segment.start_addr < end)
+        })
     }
-}
 
-unsafe impl Alloc for Heap {
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        self.allocate(layout)
+    /// Allocates a chunk of the given size with the given alignment. Returns a pointer to the
+    /// beginning of that chunk if it was successful. Else it returns `Err`.
+    /// This function finds the slab of lowest size which can still accomodate the given chunk.
+    /// The runtime is in `O(1)` for chunks of size <= 4096, and `O(n)` when chunk size is > 4096,
+    /// If a handler was set with `set_oom_handler`, it is called instead of returning `Err`.
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        self.allocate_with_hint(layout, Lifetime::Short)
     }
 
-    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        self.deallocate(ptr, layout)
+    /// Allocates a chunk like `allocate`, placing chunks over 4096 bytes by how long they are
+    /// expected to live, so short-lived chunks freed in between don't leave holes around
+    /// long-lived ones. Short-lived chunks are allocated first fit from the bottom of the linked
+    /// list region. Long-lived chunks are placed at the highest address first fit can reach,
+    /// which is found by allocating first fit until that fails and freeing all chunks but the
+    /// last, so it takes `O(n * k)` for `n` holes and `k` chunks which would fit. The hint is
+    /// ignored for chunks served by the slabs, and while a limit is set with
+    /// `set_large_search_limit`, as every search counts against it.
+    pub fn allocate_with_hint(
+        &mut self,
+        layout: Layout,
+        lifetime: Lifetime,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        let result = self.try_allocate(layout.clone(), lifetime);
+        self.handle_oom(result, layout)
     }
 
-    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
-        self.usable_size(layout)
+    /// Allocates a chunk like `allocate`, but tells an allocation over 4096 bytes refused by the
+    /// limit set with `set_large_search_limit` apart from running out of memory, so the caller
+    /// can retry from a context which may wait longer. The OOM handler is not called.
+    pub fn allocate_bounded(&mut self, layout: Layout) -> Result<NonNull<u8>, BoundedAllocError> {
+        if self.route(&layout) == HeapAllocator::LinkedListAllocator
+            && !self.large_search_within_limit()
+        {
+            return Err(BoundedAllocError::SearchLimit);
+        }
+        self.try_allocate(layout, Lifetime::Short)
+            .map_err(|_| BoundedAllocError::OutOfMemory)
     }
-}
 
-pub struct LockedHeap(Mutex<Option<Heap>>);
+    /// Limits the time allocations over 4096 bytes hold the heap, such as the lock of a
+    /// `LockedHeap` in a system with tight interrupt latency: they fail instead of searching the
+    /// linked list allocator when the search could visit more than `max_holes` free holes.
+    /// The linked list allocator can't stop a search midway, so the holes are bounded by the
+    /// live chunks, which split each of the linked list region and its reserve into at most one
+    /// hole more than they hold chunks. A heap with many live chunks but few holes is thus
+    /// refused too. The limit applies to every search, so while it is set long-lived chunks of
+    /// `allocate_with_hint` are placed like short-lived ones. There is no limit by default.
+    pub fn set_large_search_limit(&mut self, max_holes: usize) {
+        self.large_search_limit = max_holes;
+    }
 
-impl LockedHeap {
-    pub const fn empty() -> LockedHeap {
-        LockedHeap(Mutex::new(None))
+    /// Returns true if searching the linked list allocator stays within the limit set with
+    /// `set_large_search_limit`.
+    fn large_search_within_limit(&self) -> bool {
+        self.linked_list_max_holes()
+            .map_or(false, |holes| holes <= self.large_search_limit)
+            || self.large_search_limit == usize::max_value()
     }
 
-    pub unsafe fn init(&self, heap_start_addr: usize, size: usize) {
-        *self.0.lock() = Some(Heap::new(heap_start_addr, size));
+    /// Returns the most free holes the linked list allocator can have, which is one more than
+    /// the live chunks in each of the linked list region and its reserve, or `None` if that
+    /// doesn't fit a `usize`.
+    fn linked_list_max_holes(&self) -> Option<usize> {
+        let regions = if self.reserved_linked_list_allocator.size() > 0 {
+            2
+        } else {
+            1
+        };
+        self.linked_list_live_chunks.checked_add(regions)
+    }
+
+    /// Returns the most internal steps `allocate` takes for `layout`, such as to decide whether
+    /// an allocation is admissible in an interrupt handler: 1 for chunks served by a slab, and
+    /// for larger chunks the number of free holes the linked list allocator may search, bounded
+    /// as for `set_large_search_limit`. Searches refused by that limit take 1 step. The same
+    /// bound holds for `allocate_with_hint` while a limit is set. Returns `None` if the number
+    /// of steps has no bound. The OOM handler, the router of `set_routing` and the checks of
+    /// the debugging features are not counted.
+    pub fn max_alloc_ops(&self, layout: &Layout) -> Option<usize> {
+        match self.route(layout) {
+            HeapAllocator::LinkedListAllocator if self.large_search_within_limit() => {
+                self.linked_list_max_holes()
+            }
+            _ => Some(1),
+        }
+    }
+
+    fn try_allocate(
+        &mut self,
+        layout: Layout,
+        lifetime: Lifetime,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        let ptr = self.allocate_block(layout.clone(), lifetime)?;
+        #[cfg(feature = "track-allocations")]
+        self.registry
+            .insert(ptr.as_ptr() as usize, &layout, None, None);
+        Ok(ptr)
+    }
+
+    /// Sets a function which `allocate` and `allocate_traced` call instead of returning `Err`,
+    /// for systems where running out of memory is unrecoverable, such as to write a crash dump
+    /// or reset the CPU. Allocations which fall back on failure, such as `allocate_up_to`,
+    /// `allocate_scattered`, `allocate_zeroed_batch`, `allocate_bounded` and
+    /// `try_grow_then_allocate`, still return `Err`, as do allocations refused by the limit of
+    /// `set_large_search_limit`. The handler must not use the heap, which is still borrowed, or
+    /// for a `LockedHeap` locked, when it runs.
+    pub fn set_oom_handler(&mut self, handler: fn(Layout) -> !) {
+        self.oom_handler = Some(handler);
+    }
+
+    fn handle_oom(
+        &self,
+        result: Result<NonNull<u8>, AllocErr>,
+        layout: Layout,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        match (result, self.oom_handler) {
+            // The heap may still have the memory, it just wasn't searched.
+            (Err(_), Some(_))
+                if self.route(&layout) == HeapAllocator::LinkedListAllocator
+                    && !self.large_search_within_limit() =>
+            {
+                Err(AllocErr)
+            }
+            (Err(_), Some(handler)) => handler(layout),
+            (result, _) => result,
+        }
+    }
+
+    /// Allocates a chunk like `allocate`. If that fails, `mem_provider` is asked once for more
+    /// memory with the size of the layout. The memory it returns as `(start, size)` is added to
+    /// the slab the layout is routed to, and the allocation is retried. Returns `Err` if the
+    /// provider returns `None`, the memory can't be added (see `grow_from_static`) or the retry
+    /// fails too.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// memory returned by `mem_provider` is invalid.
+    pub unsafe fn try_grow_then_allocate(
+        &mut self,
+        layout: Layout,
+        mut mem_provider: impl FnMut(usize) -> Option<(usize, usize)>,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        if let Ok(ptr) = self.try_allocate(layout.clone(), Lifetime::Short) {
+            return Ok(ptr);
+        }
+        let (mem_start_addr, mem_size) = mem_provider(layout.size()).ok_or(AllocErr)?;
+        self.checked_grow(mem_start_addr, mem_size, self.route(&layout))
+            .map_err(|_| AllocErr)?;
+        self.try_allocate(layout, Lifetime::Short)
+    }
+
+    /// Allocates a chunk of `preferred_size` bytes, or a smaller one if that fails, for users
+    /// which can make do with less memory, such as by chaining buffers. After the size for
+    /// `preferred_size` the block size of each smaller slab is tried in turn, skipping slabs
+    /// whose blocks are not aligned to `min.align()`, and lastly `min.size()`. Returns the chunk
+    /// and its size, which never is below `min.size()`, or `Err` if even `min` can't be
+    /// allocated. The chunk must be freed with a layout of the returned size and `min.align()`.
+    pub fn allocate_up_to(
+        &mut self,
+        min: Layout,
+        preferred_size: usize,
+    ) -> Result<(NonNull<u8>, usize), AllocErr> {
+        let mut size = cmp::max(preferred_size, min.size());
+        loop {
+            let layout = Layout::from_size_align(size, min.align()).map_err(|_| AllocErr)?;
+            let usable_size = self.usable_size(&layout).1;
+            if let Ok(ptr) = self.try_allocate(layout, Lifetime::Short) {
+                return Ok((ptr, size));
+            }
+            if size == min.size() {
+                return Err(AllocErr);
+            }
+            let smaller_block_size = self
+                .slabs()
+                .iter()
+                .map(|slab| slab.block_size())
+                .filter(|&block_size| block_size < usable_size)
+                .max();
+            size = match smaller_block_size {
+                Some(block_size) if block_size > min.size() && block_size >= min.align() => {
+                    block_size
+                }
+                _ => min.size(),
+            };
+        }
+    }
+
+    /// Experimental: allocates a buffer of `total` bytes as separate 4096 byte blocks, for
+    /// scatter-gather hardware which doesn't need the buffer to be contiguous. This succeeds
+    /// from the slabs even if the linked list allocator has no room for `total` bytes.
+    /// Returns `None` if the buffer needs more than `MAX_SCATTER_CHUNKS` chunks or not enough
+    /// blocks are free, in which case nothing is allocated.
+    pub fn allocate_scattered(&mut self, total: usize) -> Option<ScatterList> {
+        let num_of_chunks = total / MIN_SLAB_SIZE + (total % MIN_SLAB_SIZE != 0) as usize;
+        if num_of_chunks > MAX_SCATTER_CHUNKS {
+            return None;
+        }
+        let mut list = ScatterList {
+            chunks: [(0, 0); MAX_SCATTER_CHUNKS],
+            num_of_chunks: 0,
+        };
+        let mut remaining = total;
+        while remaining > 0 {
+            match self.try_allocate(Heap::scatter_chunk_layout(), Lifetime::Short) {
+                Ok(ptr) => {
+                    let len = cmp::min(remaining, MIN_SLAB_SIZE);
+                    list.chunks[list.num_of_chunks] = (ptr.as_ptr() as usize, len);
+                    list.num_of_chunks += 1;
+                    remaining -= len;
+                }
+                Err(_) => {
+                    unsafe { self.deallocate_scattered(list) };
+                    return None;
+                }
+            }
+        }
+        Some(list)
+    }
+
+    /// Frees a buffer allocated by `allocate_scattered`. `list` must have been allocated by
+    /// this heap, thus this function is unsafe.
+    pub unsafe fn deallocate_scattered(&mut self, list: ScatterList) {
+        for &(addr, _) in list.chunks() {
+            self.deallocate(
+                NonNull::new_unchecked(addr as *mut u8),
+                Heap::scatter_chunk_layout(),
+            );
+        }
+    }
+
+    fn scatter_chunk_layout() -> Layout {
+        Layout::from_size_align(MIN_SLAB_SIZE, MIN_SLAB_SIZE).unwrap()
+    }
+
+    /// Allocates a chunk like `allocate`. With the `track-allocations` feature the allocation is
+    /// recorded, without a call site, so it shows up in leak reports and double free panics.
+    pub fn allocate_traced(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let result = self.allocate_block(layout.clone(), Lifetime::Short);
+        let ptr = self.handle_oom(result, layout.clone())?;
+        #[cfg(feature = "track-allocations")]
+        self.registry
+            .insert(ptr.as_ptr() as usize, &layout, None, None);
+        Ok(ptr)
+    }
+
+    /// Allocates a chunk like `allocate_traced` and records `site`, usually `call_site!()`, as
+    /// the location it was allocated at.
+    #[cfg(feature = "track-allocations")]
+    pub fn allocate_at(&mut self, layout: Layout, site: CallSite) -> Result<NonNull<u8>, AllocErr> {
+        let result = self.allocate_block(layout.clone(), Lifetime::Short);
+        let ptr = self.handle_oom(result, layout.clone())?;
+        self.registry
+            .insert(ptr.as_ptr() as usize, &layout, Some(site), None);
+        Ok(ptr)
+    }
+
+    /// Allocates a chunk like `allocate` and records it with `tag`, such as the id of the
+    /// subsystem it belongs to, so everything the subsystem still holds can be freed at once
+    /// with `deallocate_all_tagged`.
+    #[cfg(feature = "track-allocations")]
+    pub fn allocate_tagged(&mut self, layout: Layout, tag: u16) -> Result<NonNull<u8>, AllocErr> {
+        let result = self.allocate_block(layout.clone(), Lifetime::Short);
+        let ptr = self.handle_oom(result, layout.clone())?;
+        self.registry
+            .insert(ptr.as_ptr() as usize, &layout, None, Some(tag));
+        Ok(ptr)
+    }
+
+    /// Frees every live allocation made by `allocate_tagged` with `tag`, like `deallocate`
+    /// would, and returns how many were freed. Only allocations recorded in the registry are
+    /// found: chunks allocated while the registry was full are counted but not recorded, so
+    /// they must still be freed one by one. Takes `O(n * m)` for `n` freed chunks and a
+    /// registry of `m` entries.
+    #[cfg(feature = "track-allocations")]
+    pub fn deallocate_all_tagged(&mut self, tag: u16) -> usize {
+        let mut freed = 0;
+        while let Some((addr, layout)) = self.registry.find_tagged(tag) {
+            unsafe { self.deallocate(NonNull::new_unchecked(addr as *mut u8), layout) };
+            freed += 1;
+        }
+        freed
+    }
+
+    /// Writes the allocations which have not been freed yet, with the location they were
+    /// allocated at if they were allocated through `allocate_at`. At most
+    /// `REGISTRY_CAPACITY` allocations are tracked at the same time.
+    #[cfg(feature = "track-allocations")]
+    pub fn write_leak_report<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        self.registry.write_leaks(w)
+    }
+
+    fn allocate_block(
+        &mut self,
+        layout: Layout,
+        lifetime: Lifetime,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        let slab = self.route(&layout);
+        // Placing a long-lived chunk takes one search per chunk which fits, so it is only done
+        // without a search limit.
+        let result = match (slab, lifetime) {
+            (HeapAllocator::LinkedListAllocator, Lifetime::Long)
+                if self.large_search_limit == usize::max_value() =>
+            {
+                self.linked_list_allocate_high(layout.clone())
+                    .or_else(|_| self.allocate_from(slab, layout.clone()))
+            }
+            _ => self.allocate_from(slab, layout.clone()),
+        };
+        self.record_allocation(&layout, slab, &result);
+        result
+    }
+
+    /// Reports an allocation, or its failure, to the telemetry and the event ring, counts the
+    /// generation of its address and fills it with `ALLOC_FILL`.
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    fn record_allocation(
+        &mut self,
+        layout: &Layout,
+        slab: HeapAllocator,
+        result: &Result<NonNull<u8>, AllocErr>,
+    ) {
+        #[cfg(feature = "alloc-fill")]
+        {
+            if let Ok(ptr) = *result {
+                self.fill_allocation(ptr, slab, layout);
+            }
+        }
+        #[cfg(feature = "generations")]
+        {
+            if let Ok(ptr) = *result {
+                let _ = self.generations.bump(ptr.as_ptr() as usize);
+            }
+        }
+        #[cfg(feature = "waste-tracking")]
+        {
+            let index = slab.slab_index();
+            if let (Some(index), true) = (index, result.is_ok()) {
+                self.requested_bytes[index] += layout.size();
+                self.live_blocks[index] += 1;
+            }
+        }
+        #[cfg(feature = "stats")]
+        {
+            let index = slab.index();
+            if result.is_ok() {
+                self.rates[index].0 += 1;
+                if slab != self.class_of(layout) {
+                    self.fallback_counts[index] += 1;
+                }
+            }
+            if let Some(telemetry) = self.telemetry {
+                match *result {
+                    Ok(ptr) => telemetry.on_allocate(layout, slab, ptr),
+                    Err(_) => telemetry.on_oom(layout),
+                }
+            }
+            if let (Some(ring), &Ok(ptr)) = (self.event_ring.as_mut(), result) {
+                ring.record(
+                    HeapEventKind::Allocate,
+                    ptr.as_ptr() as usize,
+                    layout.size(),
+                    slab,
+                );
+            }
+        }
+    }
+
+    /// Fills a new chunk with `ALLOC_FILL`, a slab block up to its block size and a linked list
+    /// chunk up to the size the linked list allocator rounds it up to.
+    #[cfg(feature = "alloc-fill")]
+    fn fill_allocation(&self, ptr: NonNull<u8>, slab: HeapAllocator, layout: &Layout) {
+        let size = match self.slab(slab) {
+            Some(slab) => slab.block_size(),
+            None => Heap::linked_list_chunk_size(layout.size()),
+        };
+        unsafe { core::ptr::write_bytes(ptr.as_ptr(), ALLOC_FILL, size) };
+    }
+
+    /// Returns the size the linked list allocator rounds a chunk of `size` bytes up to: room
+    /// for its hole header, in whole `usize`s.
+    #[cfg(feature = "alloc-fill")]
+    fn linked_list_chunk_size(size: usize) -> usize {
+        let size = cmp::max(size, 2 * size_of::<usize>());
+        (size + align_of::<usize>() - 1) & !(align_of::<usize>() - 1)
+    }
+
+    fn allocate_from(
+        &mut self,
+        slab: HeapAllocator,
+        layout: Layout,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        #[cfg(feature = "valgrind")]
+        let size = layout.size();
+        let result = match slab {
+            HeapAllocator::Slab64Bytes => self.slab_64_bytes.allocate(layout),
+            HeapAllocator::Slab128Bytes => self.slab_128_bytes.allocate(layout),
+            HeapAllocator::Slab256Bytes => self.slab_256_bytes.allocate(layout),
+            HeapAllocator::Slab512Bytes => self.slab_512_bytes.allocate(layout),
+            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.allocate(layout),
+            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.allocate(layout),
+            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.allocate(layout),
+            HeapAllocator::LinkedListAllocator => {
+                if !self.large_search_within_limit() {
+                    return Err(AllocErr);
+                }
+                let size = layout.size();
+                let result = match self
+                    .linked_list_allocator
+                    .allocate_first_fit(layout.clone())
+                {
+                    Ok(ptr) => Ok(ptr),
+                    Err(_) => self
+                        .reserved_linked_list_allocator
+                        .allocate_first_fit(layout),
+                };
+                if let Ok(ptr) = result {
+                    if cfg!(debug_assertions) {
+                        self.check_linked_list_bounds(ptr.as_ptr() as usize, size);
+                    }
+                    self.linked_list_used_bytes += size;
+                    self.linked_list_live_chunks += 1;
+                }
+                // The linked list allocator keeps its own headers in freed memory, so its
+                // allocations are not reported to Valgrind.
+                return result;
+            }
+        };
+        #[cfg(feature = "valgrind")]
+        {
+            if let Ok(ptr) = result {
+                valgrind::mempool_alloc(ptr.as_ptr() as usize, size);
+            }
+        }
+        result
+    }
+
+    /// Allocates from the linked list allocator at the highest address first fit reaches. Chunks
+    /// are allocated first fit until that fails, each linked to the one before through its first
+    /// word, and all but the last are freed again.
+    #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+    fn linked_list_allocate_high(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        if !self.large_search_within_limit() {
+            return Err(AllocErr);
+        }
+        let mut highest: Option<NonNull<u8>> = None;
+        while let Ok(ptr) = self
+            .linked_list_allocator
+            .allocate_first_fit(layout.clone())
+        {
+            let previous = highest.map_or(0, |previous| previous.as_ptr() as usize);
+            unsafe { core::ptr::write(ptr.as_ptr() as *mut usize, previous) };
+            highest = Some(ptr);
+        }
+        let highest = highest.ok_or(AllocErr)?;
+        if cfg!(debug_assertions) {
+            self.check_linked_list_bounds(highest.as_ptr() as usize, layout.size());
+        }
+        let mut next = unsafe { core::ptr::read(highest.as_ptr() as *const usize) };
+        while let Some(ptr) = NonNull::new(next as *mut u8) {
+            unsafe {
+                next = core::ptr::read(next as *const usize);
+                self.linked_list_allocator.deallocate(ptr, layout.clone());
+            }
+        }
+        self.linked_list_used_bytes += layout.size();
+        self.linked_list_live_chunks += 1;
+        Ok(highest)
+    }
+
+    /// Panics if the chunk `[addr, addr + size)` does not lie completely inside the linked list
+    /// region or its reserve.
+    fn check_linked_list_bounds(&self, addr: usize, size: usize) {
+        let inside = |allocator: &linked_list_allocator::Heap| {
+            addr >= allocator.bottom() && addr + size <= allocator.top()
+        };
+        if !inside(&self.linked_list_allocator) && !inside(&self.reserved_linked_list_allocator) {
+            heap_panic!(
+                "chunk {:#x}..{:#x} lies outside of the linked list region",
+                addr,
+                addr + size
+            );
+        }
+    }
+
+    /// Frees the given allocation. `ptr` must be a pointer returned
+    /// by a call to the `allocate` function with identical size and alignment. Undefined
+    /// behavior may occur for invalid arguments, thus this function is unsafe.
+    ///
+    /// This function finds the slab which contains address of `ptr` and adds the blocks beginning
+    /// with `ptr` address to the list of free blocks.
+    /// This operation is in `O(1)` for blocks <= 4096 bytes and `O(n)` for blocks > 4096 bytes.
+    /// In debug builds it panics if `ptr` doesn't lie in memory owned by the heap, such as a
+    /// chunk of another heap, see `HeapGroup`.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if cfg!(debug_assertions) {
+            heap_assert!(
+                self.owns(ptr.as_ptr() as usize),
+                "Freed pointer should lie in memory owned by the heap"
+            );
+        }
+        let slab = self.route_free(ptr.as_ptr() as usize, &layout);
+        #[cfg(feature = "waste-tracking")]
+        {
+            if let Some(index) = slab.slab_index() {
+                self.requested_bytes[index] =
+                    self.requested_bytes[index].saturating_sub(layout.size());
+                self.live_blocks[index] = self.live_blocks[index].saturating_sub(1);
+            }
+        }
+        #[cfg(feature = "stats")]
+        {
+            let index = slab.index();
+            self.rates[index].1 += 1;
+            if let Some(ref mut ring) = self.event_ring {
+                ring.record(
+                    HeapEventKind::Deallocate,
+                    ptr.as_ptr() as usize,
+                    layout.size(),
+                    slab,
+                );
+            }
+        }
+        #[cfg(feature = "track-allocations")]
+        {
+            if let Err(site) = self.registry.remove(ptr.as_ptr() as usize) {
+                match site {
+                    Some(site) => self.corruption_panic(format_args!(
+                        "double free of {:#x} allocated at {}:{}",
+                        ptr.as_ptr() as usize,
+                        site.file,
+                        site.line
+                    )),
+                    None => self.corruption_panic(format_args!(
+                        "double free of {:#x}",
+                        ptr.as_ptr() as usize
+                    )),
+                }
+            }
+        }
+        #[cfg(feature = "stats")]
+        {
+            if let Some(telemetry) = self.telemetry {
+                telemetry.on_deallocate(&layout, slab, ptr);
+            }
+        }
+        match slab {
+            HeapAllocator::LinkedListAllocator => {
+                self.linked_list_deallocate(ptr, layout.size(), layout.align())
+            }
+            _ => self.deallocate_to(slab, ptr),
+        }
+    }
+
+    /// Allocates `size` bytes aligned to `align` from the linked list allocator, even if the
+    /// layout would be served by a slab, such as for 4096 byte aligned page table buffers.
+    /// Every hole is searched for room at an aligned address, not only the first one, so this
+    /// only fails if no hole can fit the chunk. The chunk must be freed with
+    /// `linked_list_deallocate`.
+    pub fn linked_list_allocate_aligned(
+        &mut self,
+        size: usize,
+        align: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        let layout = Layout::from_size_align(size, align).map_err(|_| AllocErr)?;
+        let ptr = self.allocate_from(HeapAllocator::LinkedListAllocator, layout.clone())?;
+        #[cfg(feature = "alloc-fill")]
+        self.fill_allocation(ptr, HeapAllocator::LinkedListAllocator, &layout);
+        Ok(ptr)
+    }
+
+    /// Frees a chunk of the linked list allocator, allocated by `linked_list_allocate_aligned`
+    /// or by `allocate` with a layout of `size` and `align`.
+    /// This function is unsafe because undefined behavior may occur for invalid arguments.
+    pub unsafe fn linked_list_deallocate(&mut self, ptr: NonNull<u8>, size: usize, align: usize) {
+        let layout = Layout::from_size_align_unchecked(size, align);
+        #[cfg(feature = "poison")]
+        core::ptr::write_bytes(ptr.as_ptr(), FREE_POISON, layout.size());
+        self.linked_list_used_bytes -= size;
+        self.linked_list_live_chunks -= 1;
+        let addr = ptr.as_ptr() as usize;
+        let reserved = &mut self.reserved_linked_list_allocator;
+        if addr >= reserved.bottom() && addr < reserved.top() {
+            reserved.deallocate(ptr, layout)
+        } else {
+            self.linked_list_allocator.deallocate(ptr, layout)
+        }
+    }
+
+    /// Frees a block to the given slab, bypassing routing, telemetry and the registry.
+    /// Does nothing for the linked list allocator, which needs the layout.
+    unsafe fn deallocate_to(&mut self, slab: HeapAllocator, ptr: NonNull<u8>) {
+        match slab {
+            HeapAllocator::Slab64Bytes => self.slab_64_bytes.deallocate(ptr),
+            HeapAllocator::Slab128Bytes => self.slab_128_bytes.deallocate(ptr),
+            HeapAllocator::Slab256Bytes => self.slab_256_bytes.deallocate(ptr),
+            HeapAllocator::Slab512Bytes => self.slab_512_bytes.deallocate(ptr),
+            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.deallocate(ptr),
+            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.deallocate(ptr),
+            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.deallocate(ptr),
+            HeapAllocator::LinkedListAllocator => return,
+        }
+        #[cfg(feature = "valgrind")]
+        valgrind::mempool_free(ptr.as_ptr() as usize);
+    }
+
+    /// Frees the given allocation like `deallocate`, but first checks that `ptr` lies in memory
+    /// owned by the heap, starts a block (or is aligned to `layout`) and belongs to the slab
+    /// `layout` is routed to. If any check fails an error is returned and the heap is left
+    /// untouched. Frees of pointers which are valid but not currently allocated (such as
+    /// double frees) are not detected, and neither are aligned pointers into the middle of a
+    /// linked list chunk, thus this function is unsafe like `deallocate`.
+    pub unsafe fn try_deallocate(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(), DeallocError> {
+        let addr = ptr.as_ptr() as usize;
+        let owner = self.owner_of(addr).ok_or(DeallocError::NotOwned)?;
+        match self.slab(owner) {
+            Some(slab) if !slab.is_block_start(addr) => return Err(DeallocError::Misaligned),
+            None if addr % layout.align() != 0 => return Err(DeallocError::Misaligned),
+            _ => {}
+        }
+        let routed = if self.slabs_bypassed() && owner == HeapAllocator::LinkedListAllocator {
+            owner
+        } else {
+            self.route_layout(&layout)
+        };
+        if owner != routed {
+            return Err(DeallocError::ClassMismatch);
+        }
+        self.deallocate(ptr, layout);
+        Ok(())
+    }
+
+    /// Returns the slab, or the linked list allocator, whose memory contains `addr`.
+    fn owner_of(&self, addr: usize) -> Option<HeapAllocator> {
+        let ll_start = self.linked_list_region_start();
+        if addr >= ll_start && addr < ll_start + self.linked_list_region_size() {
+            return Some(HeapAllocator::LinkedListAllocator);
+        }
+        SLAB_ALLOCATORS
+            .iter()
+            .zip(self.slabs().iter())
+            .find(|&(_, slab)| slab.owns(addr))
+            .map(|(&class, _)| class)
+    }
+
+    /// Returns true if `ptr` is the start of a free slab block, for debug assertions such as
+    /// `assert!(!heap.is_block_free(ptr), "use after free")`. Always returns false for memory
+    /// of the linked list allocator. This operation is in `O(n)` in the number of free blocks.
+    #[cfg(feature = "debug-checks")]
+    pub fn is_block_free(&self, ptr: NonNull<u8>) -> bool {
+        match self
+            .owner_of(ptr.as_ptr() as usize)
+            .and_then(|class| self.slab(class))
+        {
+            Some(slab) => slab.is_block_free(ptr),
+            None => false,
+        }
+    }
+
+    /// Starts keeping a bitfield of the given slab's blocks in `map`, indexed by address as
+    /// described at `fragmentation_map`. `map` needs `fragmentation_map_words` words. Keeping the map costs a
+    /// bit update on every allocation and deallocation of the slab. Returns false if `map` is
+    /// too short or `slab` is the linked list allocator.
+    #[cfg(not(feature = "tiny"))]
+    pub fn enable_fragmentation_map(
+        &mut self,
+        slab: HeapAllocator,
+        map: &'static mut [u64],
+    ) -> bool {
+        match self.slab_mut(slab) {
+            Some(slab) => slab.enable_block_map(map),
+            None => false,
+        }
+    }
+
+    /// Returns the number of `u64` words the fragmentation map of the given slab needs,
+    /// or 0 for the linked list allocator.
+    #[cfg(not(feature = "tiny"))]
+    pub fn fragmentation_map_words(&self, slab: HeapAllocator) -> usize {
+        self.slab(slab).map_or(0, |slab| slab.block_map_words())
+    }
+
+    /// Returns the bitfield enabled by `enable_fragmentation_map`, where bit `i` is set if the
+    /// block at `i` block sizes past the start of the slab's lowest segment is free. Bits of
+    /// addresses between segments are clear. Returns an empty slice if no map is kept.
+    #[cfg(not(feature = "tiny"))]
+    pub fn fragmentation_map(&self, slab: HeapAllocator) -> &[u64] {
+        self.slab(slab)
+            .and_then(|slab| slab.block_map())
+            .unwrap_or(&[])
+    }
+
+    /// Returns the number of bytes `block_usage_heatmap` writes for the given slab, one per page
+    /// of its memory, or 0 for the linked list allocator.
+    #[cfg(not(feature = "tiny"))]
+    pub fn block_usage_heatmap_len(&self, slab: HeapAllocator) -> usize {
+        self.slab(slab).map_or(0, |slab| slab.heatmap_pages())
+    }
+
+    /// Writes a heatmap of where in the given slab allocations cluster: for each page of the
+    /// slab's memory the share of allocated blocks touching it, from 0 for none to 255 for all.
+    /// Pages are ordered like the slab's segments. Walks the free list once for every 128
+    /// pages. Returns the number of pages written, which is 0 for the linked list allocator.
+    /// Panics if `out` is shorter than `block_usage_heatmap_len`.
+    #[cfg(not(feature = "tiny"))]
+    pub fn block_usage_heatmap(&self, slab: HeapAllocator, out: &mut [u8]) -> usize {
+        self.slab(slab)
+            .map_or(0, |slab| slab.block_usage_heatmap(out))
+    }
+
+    /// Calls `f` with the start and size of each maximal page aligned range of slab memory
+    /// which holds no live allocation, such as for leaving free memory out of a hibernation
+    /// image. Only slabs with a fragmentation map are checked, since free blocks are tracked
+    /// page by page through it. The linked list allocator is never reported, as its free
+    /// chunks can't be listed.
+    ///
+    /// Free blocks hold the links of their slab's free list, so once a reported range has
+    /// lost its content, `rebuild_free_lists` must be called before the heap is used again.
+    #[cfg(not(feature = "tiny"))]
+    pub fn for_each_free_page_range<F: FnMut(usize, usize)>(&self, mut f: F) {
+        let mut pending: Option<(usize, usize)> = None;
+        for slab in self.slabs().iter() {
+            slab.for_each_free_page_range(|start, size| match pending {
+                Some((pending_start, pending_size)) if pending_start + pending_size == start => {
+                    pending = Some((pending_start, pending_size + size));
+                }
+                _ => {
+                    if let Some((pending_start, pending_size)) = pending {
+                        f(pending_start, pending_size);
+                    }
+                    pending = Some((start, size));
+                }
+            });
+        }
+        if let Some((start, size)) = pending {
+            f(start, size);
+        }
+    }
+
+    /// Relinks the free list of every slab with a fragmentation map from its map, which
+    /// recovers the heap after the ranges reported by `for_each_free_page_range` lost their
+    /// content. The maps must be up to date, thus this function is unsafe.
+    #[cfg(not(feature = "tiny"))]
+    pub unsafe fn rebuild_free_lists(&mut self) {
+        for slab in self.slabs_mut().iter_mut() {
+            slab.rebuild_free_list();
+        }
+    }
+
+    /// Starts recording every allocation and deallocation in `events`, overwriting the oldest
+    /// event once it is full, so the last `events.len()` events, such as 64, can be inspected
+    /// with `recent_events` and are printed by double free and corruption panics. Recording
+    /// costs a few stores per allocation and deallocation. A `LockedHeap` stops caching small
+    /// blocks once it sees the ring, which must be enabled before the first small allocation
+    /// for every event to be recorded.
+    #[cfg(feature = "stats")]
+    pub fn enable_event_ring(&mut self, events: &'static mut [HeapEvent]) {
+        self.event_ring = Some(events::EventRing::new(events));
+    }
+
+    /// Returns the events recorded since `enable_event_ring` from the oldest to the newest,
+    /// split in two slices where the ring wraps. Both are empty if no ring is enabled.
+    #[cfg(feature = "stats")]
+    pub fn recent_events(&self) -> (&[HeapEvent], &[HeapEvent]) {
+        match self.event_ring {
+            Some(ref ring) => ring.as_slices(),
+            None => (&[], &[]),
+        }
+    }
+
+    /// Panics with `message`, followed by the recent events if an event ring is enabled.
+    #[cfg_attr(feature = "tiny", allow(unused_variables))]
+    fn corruption_panic(&self, message: fmt::Arguments) -> ! {
+        #[cfg(feature = "stats")]
+        {
+            if let Some(ref ring) = self.event_ring {
+                panic!("{}\nrecent heap events, oldest first:\n{}", message, ring);
+            }
+        }
+        heap_panic!("{}", message)
+    }
+
+    /// Returns true if `ptr1` and `ptr2` start neighbouring blocks of the same slab, which is
+    /// useful for emulating a buddy allocator on top of the slabs. Slab blocks are never
+    /// coalesced, so this is only informational.
+    pub fn coalesce_check(&self, ptr1: NonNull<u8>, ptr2: NonNull<u8>) -> bool {
+        let (addr1, addr2) = (ptr1.as_ptr() as usize, ptr2.as_ptr() as usize);
+        let class = match self.owner_of(addr1) {
+            Some(class) if self.owner_of(addr2) == Some(class) => class,
+            _ => return false,
+        };
+        match self.slab(class) {
+            Some(slab) => {
+                slab.is_block_start(addr1)
+                    && slab.is_block_start(addr2)
+                    && cmp::max(addr1, addr2) - cmp::min(addr1, addr2) == slab.block_size()
+            }
+            None => false,
+        }
+    }
+
+    /// Returns true if `addr` lies in memory managed by the heap.
+    pub fn owns(&self, addr: usize) -> bool {
+        self.owner_of(addr).is_some()
+    }
+
+    /// Allocates a chunk of the given size and alignment which is never freed, such as an
+    /// interrupt stack or a descriptor ring. Unlike with `allocate`, the chunk is removed from
+    /// the heap's capacity, so it is excluded from all availability accounting.
+    pub fn allocate_static(&mut self, size: usize, align: usize) -> Result<NonNull<u8>, AllocErr> {
+        let layout = Layout::from_size_align(size, align).map_err(|_| AllocErr)?;
+        match self.route(&layout) {
+            HeapAllocator::Slab64Bytes => self.slab_64_bytes.allocate_static(layout),
+            HeapAllocator::Slab128Bytes => self.slab_128_bytes.allocate_static(layout),
+            HeapAllocator::Slab256Bytes => self.slab_256_bytes.allocate_static(layout),
+            HeapAllocator::Slab512Bytes => self.slab_512_bytes.allocate_static(layout),
+            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.allocate_static(layout),
+            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.allocate_static(layout),
+            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.allocate_static(layout),
+            HeapAllocator::LinkedListAllocator => {
+                let ptr = self.allocate(layout)?;
+                self.static_linked_list_bytes += size;
+                Ok(ptr)
+            }
+        }
+    }
+
+    /// Returns the number of bytes the heap can hand out: the blocks of all slabs plus the
+    /// linked list region, minus chunks taken by `allocate_static`.
+    pub fn capacity(&self) -> usize {
+        let slab_bytes: usize = self
+            .slabs()
+            .iter()
+            .map(|slab| slab.total_blocks() * slab.block_size())
+            .sum();
+        slab_bytes + self.linked_list_region_size() - self.static_linked_list_bytes
+    }
+
+    /// Returns the share of `capacity` which is allocated, in percent from 0 to 100. Slab blocks
+    /// count with their whole block size and chunks of the linked list allocator with their
+    /// requested size, so slabs are weighted by bytes rather than by blocks.
+    pub fn load_factor(&self) -> u8 {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return 0;
+        }
+        let slab_bytes: usize = self
+            .slabs()
+            .iter()
+            .map(|slab| (slab.total_blocks() - slab.free_blocks()) * slab.block_size())
+            .sum();
+        let used_bytes = slab_bytes + self.linked_list_used_bytes - self.static_linked_list_bytes;
+        (used_bytes as u64 * 100 / capacity as u64) as u8
+    }
+
+    /// Allocates a chunk of exactly `size` bytes with the given alignment. Unlike `allocate`, this
+    /// never hands out a larger block: it only succeeds if the request is served by a slab whose
+    /// block size equals `size`, or by the linked list allocator which sizes chunks exactly.
+    /// The chunk can be freed with `deallocate` using the same size and alignment.
+    pub fn allocate_exact(&mut self, size: usize, align: usize) -> Result<NonNull<u8>, AllocErr> {
+        let layout = Layout::from_size_align(size, align).map_err(|_| AllocErr)?;
+        match self.slab(self.route(&layout)) {
+            Some(slab) if slab.block_size() != size => Err(AllocErr),
+            _ => self.allocate(layout),
+        }
+    }
+
+    /// Allocates a chunk like `allocate` and returns it with the generation of its address,
+    /// which counts how often the address was handed out by the heap, so lock-free structures
+    /// can detect that a freed address was reused (the ABA problem). Generations start at 1.
+    /// Up to `GENERATION_CAPACITY` addresses are tracked; the generation of further addresses
+    /// is `None`, as reuse of them can't be detected.
+    #[cfg(feature = "generations")]
+    pub fn allocate_with_generation(
+        &mut self,
+        layout: Layout,
+    ) -> Result<(NonNull<u8>, Option<u32>), AllocErr> {
+        let ptr = self.allocate(layout)?;
+        Ok((ptr, self.generation(ptr)))
+    }
+
+    /// Returns the generation of the address `ptr`, as described at `allocate_with_generation`,
+    /// `Some(0)` if it was not handed out yet, or `None` if it is not tracked.
+    #[cfg(feature = "generations")]
+    pub fn generation(&self, ptr: NonNull<u8>) -> Option<u32> {
+        self.generations.get(ptr.as_ptr() as usize)
+    }
+
+    /// Allocates a chunk like `allocate` and fills its first `layout.size()` bytes with `fill`,
+    /// such as `0xFF` for buffers which hardware expects to look like erased flash.
+    pub fn allocate_filled(&mut self, layout: Layout, fill: u8) -> Result<NonNull<u8>, AllocErr> {
+        let size = layout.size();
+        let ptr = self.allocate(layout)?;
+        unsafe { core::ptr::write_bytes(ptr.as_ptr(), fill, size) };
+        Ok(ptr)
+    }
+
+    /// Allocates up to `count` zeroed chunks of `layout`, writing them to the start of `out`,
+    /// such as for a batch of descriptors. Stops when `out` is full or the heap runs out of
+    /// memory for `layout`, without calling the OOM handler. Returns the number of chunks
+    /// allocated, which are freed one by one with `deallocate`. Blocks which are known to be
+    /// zero, see `Slab::known_zero_blocks`, are handed out after the others and are not
+    /// zeroed again, which is decided once for the whole batch. Everything else is zeroed,
+    /// and so is everything with the `alloc-fill` or `valgrind` features, which mark fresh
+    /// allocations.
+    pub fn allocate_zeroed_batch(
+        &mut self,
+        layout: Layout,
+        count: usize,
+        out: &mut [NonNull<u8>],
+    ) -> usize {
+        let count = cmp::min(count, out.len());
+        let mut dirty = match self.slab(self.route(&layout)) {
+            Some(slab) if !cfg!(any(feature = "alloc-fill", feature = "valgrind")) => {
+                slab.free_blocks() - slab.known_zero_blocks()
+            }
+            _ => usize::max_value(),
+        };
+        for (allocated, slot) in out[..count].iter_mut().enumerate() {
+            match self.try_allocate(layout.clone(), Lifetime::Short) {
+                Ok(ptr) => {
+                    if dirty > 0 {
+                        unsafe { core::ptr::write_bytes(ptr.as_ptr(), 0, layout.size()) };
+                        dirty -= 1;
+                    }
+                    *slot = ptr;
+                }
+                Err(_) => return allocated,
+            }
+        }
+        count
+    }
+
+    /// Allocates a chunk from the memory region starting at `start`, such as the bank local to
+    /// the calling core on a NUMA system. `start` must be the start of a segment of the slab
+    /// `layout` is routed to, as added by `new` or `grow`, or the start of the linked list region
+    /// for chunks over 4096 bytes. Slab blocks are searched for in the free list, so this takes
+    /// `O(n)` in the number of free blocks. Returns `Err` if no region of the slab starts at
+    /// `start` or the region is full. The chunk is freed with `deallocate`.
+    pub fn allocate_from_specific_region(
+        &mut self,
+        start: usize,
+        layout: Layout,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        let slab = self.route(&layout);
+        if slab == HeapAllocator::LinkedListAllocator {
+            if start != self.linked_list_region_start() {
+                return Err(AllocErr);
+            }
+            return self.try_allocate(layout, Lifetime::Short);
+        }
+        let result = match self.slab_mut(slab) {
+            Some(slab) => slab.allocate_from_segment(start),
+            None => Err(AllocErr),
+        };
+        self.finish_slab_allocation(&layout, slab, result)
+    }
+
+    /// Reports a block allocated from `slab` without `allocate_from`, like `try_allocate` does
+    /// for the blocks it allocates.
+    fn finish_slab_allocation(
+        &mut self,
+        layout: &Layout,
+        slab: HeapAllocator,
+        result: Result<NonNull<u8>, AllocErr>,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        #[cfg(feature = "valgrind")]
+        {
+            if let Ok(ptr) = result {
+                valgrind::mempool_alloc(ptr.as_ptr() as usize, layout.size());
+            }
+        }
+        self.record_allocation(layout, slab, &result);
+        let ptr = result?;
+        #[cfg(feature = "track-allocations")]
+        self.registry
+            .insert(ptr.as_ptr() as usize, layout, None, None);
+        Ok(ptr)
+    }
+
+    /// Allocates a chunk like `allocate`, preferring a slab block whose address has `color` in
+    /// the bits `CACHE_COLOR_SHIFT` to `CACHE_COLOR_SHIFT + CACHE_COLOR_BITS`, so it maps to a
+    /// given set of the cache, such as to keep hot kernel caches from evicting each other.
+    /// Higher bits of `color` are ignored. If no free block has the color, or the chunk is
+    /// served by the linked list allocator, any chunk is returned. The free list is searched,
+    /// so this takes `O(n)` in the number of free blocks. The chunk is freed with `deallocate`.
+    pub fn allocate_color(&mut self, layout: Layout, color: u8) -> Result<NonNull<u8>, AllocErr> {
+        let slab = self.route(&layout);
+        let mask = ((1 << CACHE_COLOR_BITS) - 1) << CACHE_COLOR_SHIFT;
+        let color = ((color as usize) << CACHE_COLOR_SHIFT) & mask;
+        let result = match self.slab_mut(slab) {
+            Some(slab) => slab.allocate_with_color(color, mask),
+            None => Err(AllocErr),
+        };
+        if result.is_err() {
+            let result = self.try_allocate(layout.clone(), Lifetime::Short);
+            return self.handle_oom(result, layout);
+        }
+        self.finish_slab_allocation(&layout, slab, result)
+    }
+
+    /// Allocates a chunk for the given layout preceded by a small header recording that layout,
+    /// so it can later be freed with `free_prefixed` without knowing its size. The returned pointer
+    /// is aligned to `layout.align()`. The request is enlarged by the header, which may route it
+    /// to a bigger slab (or to the linked list allocator) than `layout` alone would.
+    pub fn allocate_prefixed(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let (prefixed_layout, offset) = Heap::prefixed_layout(&layout)?;
+        let block = self.allocate(prefixed_layout)?;
+        unsafe {
+            let payload = block.as_ptr().add(offset);
+            let header = payload.sub(PREFIX_HEADER_SIZE) as *mut PrefixHeader;
+            header.write(PrefixHeader {
+                size: layout.size(),
+                align: layout.align(),
+            });
+            Ok(NonNull::new_unchecked(payload))
+        }
+    }
+
+    /// Frees an allocation made by `allocate_prefixed`, reading its layout from the header in
+    /// front of `ptr`. Undefined behavior may occur if `ptr` was not returned by
+    /// `allocate_prefixed`, thus this function is unsafe.
+    pub unsafe fn free_prefixed(&mut self, ptr: NonNull<u8>) {
+        #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+        let header = ptr.as_ptr().sub(PREFIX_HEADER_SIZE) as *const PrefixHeader;
+        let layout = Layout::from_size_align_unchecked((*header).size, (*header).align);
+        let (prefixed_layout, offset) = match Heap::prefixed_layout(&layout) {
+            Ok(prefixed) => prefixed,
+            Err(_) => self.corruption_panic(format_args!("free_prefixed: corrupted header")),
+        };
+        let block = NonNull::new_unchecked(ptr.as_ptr().sub(offset));
+        self.deallocate(block, prefixed_layout)
+    }
+
+    /// Returns the layout actually allocated for a prefixed request of `layout`, together with
+    /// the offset of the payload from the start of the allocated chunk.
+    fn prefixed_layout(layout: &Layout) -> Result<(Layout, usize), AllocErr> {
+        let align = cmp::max(layout.align(), align_of::<PrefixHeader>());
+        let offset = (PREFIX_HEADER_SIZE + align - 1) & !(align - 1);
+        let size = offset.checked_add(layout.size()).ok_or(AllocErr)?;
+        let prefixed_layout = Layout::from_size_align(size, align).map_err(|_| AllocErr)?;
+        Ok((prefixed_layout, offset))
+    }
+
+    /// Returns the number of bytes lost to internal fragmentation, that is the block bytes of
+    /// live slab allocations minus the bytes their layouts requested. Only allocations made
+    /// and freed through the heap's allocation functions are counted, not blocks moved with
+    /// `balance` or allocated with `allocate_static`.
+    #[cfg(feature = "waste-tracking")]
+    pub fn total_wasted_bytes(&self) -> usize {
+        self.slabs()
+            .iter()
+            .enumerate()
+            .map(|(i, slab)| {
+                (self.live_blocks[i] * slab.block_size()).saturating_sub(self.requested_bytes[i])
+            })
+            .sum()
+    }
+
+    /// Returns the number of allocations served by another slab than the class of their layout,
+    /// the smallest slab whose block size fits it, which `layout_to_allocator` picks for the
+    /// default classes, indexed by the slab serving them from the 64 byte slab to the 4096 byte
+    /// slab, followed by the linked list allocator. Allocations end up elsewhere through a
+    /// router set with `set_routing`, or when the picked slab can't deliver the layout's
+    /// alignment, such as with `new_with_color_offset`. High counts hint that the slabs meant
+    /// for these layouts are too small.
+    #[cfg(feature = "stats")]
+    pub fn fallback_counts(&self) -> [usize; NUM_OF_SLABS] {
+        self.fallback_counts
+    }
+
+    /// Returns the number of allocations and frees of each slab since the last call, such as to
+    /// poll allocation rates for rebalancing heaps, indexed like `fallback_counts`. The counts
+    /// start over with every call. Blocks handed out by the small object caches of a
+    /// `LockedHeap` don't pass through the heap, use `LockedHeap::rate_since_last` to include
+    /// them.
+    #[cfg(feature = "stats")]
+    pub fn rate_since_last(&mut self) -> [(usize, usize); NUM_OF_SLABS] {
+        core::mem::replace(&mut self.rates, [(0, 0); NUM_OF_SLABS])
+    }
+
+    /// Zeroes the cumulative statistics, `fallback_counts` and the counts of `rate_since_last`,
+    /// such as at the start of a profiling phase. Live state, such as the free blocks, the
+    /// allocated bytes and the events already recorded, is left alone.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&mut self) {
+        self.fallback_counts = [0; NUM_OF_SLABS];
+        self.rates = [(0, 0); NUM_OF_SLABS];
+    }
+
+    /// Returns the smallest slab whose blocks are all aligned to `align`, independent of the
+    /// allocation size, such as to pick a class for aligned hardware buffers. Slabs without
+    /// memory are skipped. Returns `None` if no slab delivers that alignment.
+    pub fn slab_for_align(&self, align: usize) -> Option<HeapAllocator> {
+        SLAB_ALLOCATORS
+            .iter()
+            .zip(self.slabs().iter())
+            .find(|&(_, slab)| slab.total_blocks() > 0 && slab.block_align() >= align)
+            .map(|(&class, _)| class)
+    }
+
+    /// Returns the number of free blocks in the given slab.
+    /// The linked list allocator has no fixed blocks, so 0 is returned for it.
+    pub fn free_blocks(&self, slab: HeapAllocator) -> usize {
+        match self.slab(slab) {
+            Some(slab) => slab.free_blocks(),
+            None => 0,
+        }
+    }
+
+    /// Returns the number of free blocks summed over all slabs.
+    /// Free memory of the linked list allocator is not included.
+    pub fn total_free_blocks(&self) -> usize {
+        self.slabs().iter().map(|slab| slab.free_blocks()).sum()
+    }
+
+    /// Returns the number of blocks summed over all slabs, allocated or not. This is the maximum
+    /// number of allocations that can be served by the slabs at the same time.
+    pub fn total_blocks_across_all_slabs(&self) -> usize {
+        self.slabs().iter().map(|slab| slab.total_blocks()).sum()
+    }
+
+    /// Splits the bytes wasted by serving `layout` from a slab block into `(size_waste,
+    /// align_waste)`. `size_waste` is what the block the size alone would be routed to wastes,
+    /// `align_waste` the extra bytes of a larger block chosen because of the alignment.
+    /// Allocations of the linked list allocator are sized exactly and waste nothing.
+    pub fn classify_waste(&self, layout: &Layout) -> (usize, usize) {
+        let size_layout = match Layout::from_size_align(layout.size(), 1) {
+            Ok(size_layout) => size_layout,
+            Err(_) => return (0, 0),
+        };
+        let block_size = self.slab(self.route(layout)).map(|slab| slab.block_size());
+        let size_block_size = self
+            .slab(self.route(&size_layout))
+            .map(|slab| slab.block_size());
+        match (block_size, size_block_size) {
+            (Some(block_size), Some(size_block_size)) => (
+                size_block_size - layout.size(),
+                block_size.saturating_sub(size_block_size),
+            ),
+            _ => (0, 0),
+        }
+    }
+
+    /// Returns the share of allocated blocks of each slab in percent, from the smallest block
+    /// size to the largest. Slabs without any blocks read 0.
+    pub fn occupancy(&self) -> [u8; NUM_OF_SLABS - 1] {
+        let mut occupancy = [0; NUM_OF_SLABS - 1];
+        for (percent, slab) in occupancy.iter_mut().zip(self.slabs().iter()) {
+            let total = slab.total_blocks();
+            if total > 0 {
+                *percent = ((total - slab.free_blocks()) * 100 / total) as u8;
+            }
+        }
+        occupancy
+    }
+
+    /// Classifies the heap's health by the share of free blocks of its least free slab, so
+    /// memory can be reclaimed before allocations start to fail. Slabs without any blocks and
+    /// the linked list allocator are not taken into account.
+    pub fn estimate_oom_risk(&self) -> OomRisk {
+        self.slabs()
+            .iter()
+            .filter(|slab| slab.total_blocks() > 0)
+            .map(|slab| {
+                let (free, total) = (slab.free_blocks(), slab.total_blocks());
+                if free == 0 {
+                    OomRisk::Critical
+                } else if free * 10 < total {
+                    OomRisk::High
+                } else if free * 2 < total {
+                    OomRisk::Medium
+                } else {
+                    OomRisk::Low
+                }
+            })
+            .max()
+            .unwrap_or(OomRisk::Low)
+    }
+
+    /// Returns true if all slabs and the linked list region together form one contiguous
+    /// address range with no gaps, as laid out by `new`. Growing the heap with memory that
+    /// does not directly follow the heap makes it non-contiguous.
+    pub fn is_contiguous(&self) -> bool {
+        let mut start = self.linked_list_region_start();
+        let mut end = self.linked_list_allocator.top();
+        let mut size = self.linked_list_region_size();
+        for slab in self.slabs().iter() {
+            for segment in slab.segments() {
+                start = cmp::min(start, segment.start_addr);
+                end = cmp::max(end, segment.end_addr());
+                size += segment.size;
+            }
+        }
+        end - start == size
+    }
+
+    /// Returns the start address of the region managed by the linked list allocator,
+    /// including the reserve set up by `new_with_reserved_ll_region`.
+    pub fn linked_list_region_start(&self) -> usize {
+        if self.reserved_linked_list_allocator.size() > 0 {
+            self.reserved_linked_list_allocator.bottom()
+        } else {
+            self.linked_list_allocator.bottom()
+        }
+    }
+
+    /// Returns the size of the region managed by the linked list allocator, including the
+    /// reserve and any memory it was grown by.
+    pub fn linked_list_region_size(&self) -> usize {
+        self.linked_list_allocator.top() - self.linked_list_region_start()
+    }
+
+    /// Returns the memory range of each slab, from the 64 byte slab to the 4096 byte slab,
+    /// followed by the linked list region and its reserve set up by
+    /// `new_with_reserved_ll_region`. A slab made of several segments, such as after growing it
+    /// with memory which doesn't adjoin its own, is reported by its first segment, so use
+    /// `for_each_region` to cover all of the heap's memory.
+    pub fn as_region_descriptor(&self) -> [RegionDescriptor; NUM_OF_SLABS + 1] {
+        let mut regions = [RegionDescriptor {
+            start: 0,
+            end: 0,
+            allocator_type: "",
+        }; NUM_OF_SLABS + 1];
+        for (i, slab) in self.slabs().iter().enumerate() {
+            let segment = slab.segments().next().unwrap_or(Segment {
+                start_addr: 0,
+                size: 0,
+            });
+            regions[i] = RegionDescriptor {
+                start: segment.start_addr,
+                end: segment.end_addr(),
+                allocator_type: SLAB_NAMES[i],
+            };
+        }
+        regions[NUM_OF_SLABS - 1] = self.linked_list_region_descriptor();
+        regions[NUM_OF_SLABS] = self.reserved_region_descriptor();
+        regions
+    }
+
+    /// Calls `f` with the memory range of every segment of each slab, from the 64 byte slab to
+    /// the 4096 byte slab, followed by the linked list region and its reserve like
+    /// `as_region_descriptor`. Every range holds memory of the reported allocator only.
+    pub fn for_each_region<F: FnMut(RegionDescriptor)>(&self, mut f: F) {
+        for (i, slab) in self.slabs().iter().enumerate() {
+            for segment in slab.segments() {
+                f(RegionDescriptor {
+                    start: segment.start_addr,
+                    end: segment.end_addr(),
+                    allocator_type: SLAB_NAMES[i],
+                });
+            }
+        }
+        f(self.linked_list_region_descriptor());
+        f(self.reserved_region_descriptor());
+    }
+
+    fn linked_list_region_descriptor(&self) -> RegionDescriptor {
+        RegionDescriptor {
+            start: self.linked_list_allocator.bottom(),
+            end: self.linked_list_allocator.top(),
+            allocator_type: "linked_list",
+        }
+    }
+
+    fn reserved_region_descriptor(&self) -> RegionDescriptor {
+        RegionDescriptor {
+            start: self.reserved_linked_list_allocator.bottom(),
+            end: self.reserved_linked_list_allocator.top(),
+            allocator_type: "reserved_linked_list",
+        }
+    }
+
+    /// Returns `(max_size, max_align)` for allocations served by the linked list allocator.
+    /// `max_size` is the capacity of its region, ignoring the reserve, and `max_align` is the
+    /// largest power of two dividing the region's start address.
+    pub fn linked_list_limits(&self) -> (usize, usize) {
+        (
+            self.linked_list_allocator.size(),
+            slab::addr_align(self.linked_list_allocator.bottom()),
+        )
+    }
+
+    /// Returns the size of the largest allocation with the given alignment which would succeed
+    /// right now. That is the block size of the largest slab with a free block whose block
+    /// alignment satisfies `align` and which a layout of that size and alignment is routed to,
+    /// or the largest chunk the linked list allocator could hand out if such a chunk is routed
+    /// to it, whichever is larger. So slabs with offset segments, such as those of
+    /// `new_with_color_offset`, or classes with a smaller alignment than their block size only
+    /// count for the alignment they deliver. The linked list allocator is probed like
+    /// `largest_first_fit` does, so this takes `O(n log m)` for `n` holes and a region of `m`
+    /// bytes, and the linked list part is approximate in the same way. Takes `&mut self`, as
+    /// the probing allocates and frees.
+    pub fn max_allocation_size(&mut self, align: usize) -> usize {
+        let slab_size = SLAB_ALLOCATORS
+            .iter()
+            .zip(self.slabs().iter())
+            .filter(|&(&class, slab)| {
+                slab.free_blocks() > 0
+                    && slab.block_align() >= align
+                    && Layout::from_size_align(slab.block_size(), align)
+                        .map_or(false, |layout| self.route(&layout) == class)
+            })
+            .map(|(_, slab)| slab.block_size())
+            .max()
+            .unwrap_or(0);
+
+        let low = cmp::max(
+            Heap::largest_first_fit(&mut self.linked_list_allocator, align),
+            Heap::largest_first_fit(&mut self.reserved_linked_list_allocator, align),
+        );
+        // Chunks the slabs could hold are routed to them instead.
+        let linked_list_size = match Layout::from_size_align(low, align) {
+            Ok(ref layout)
+                if low > 0 && self.route(layout) == HeapAllocator::LinkedListAllocator =>
+            {
+                low
+            }
+            _ => 0,
+        };
+        cmp::max(slab_size, linked_list_size)
+    }
+
+    /// Returns the runs of free blocks of `slab`, or no runs for the linked list allocator. See
+    /// `Slab::contiguity` for its cost.
+    pub fn contiguity(&self, slab: HeapAllocator) -> ClassContiguity {
+        self.slab(slab)
+            .map_or(ClassContiguity::default(), |slab| slab.contiguity())
+    }
+
+    /// Returns the free memory of the linked list allocator and its reserve, as the total, the
+    /// largest extent and the number of extents. The linked list allocator does not expose its
+    /// free list, so every extent is found by allocating the largest chunk which fits, like
+    /// `max_allocation_size`, until none is left, after which all chunks are freed again. This
+    /// takes `O(n² log m)` for `n` extents and a region of `m` bytes. Extents are sized with
+    /// `largest_first_fit`, so they are approximate: an extent may be reported a few bytes
+    /// short, or split in two. Takes `&mut self` like `max_allocation_size`, as the probing
+    /// allocates and frees.
+    pub fn large_alloc_health(&mut self) -> LargeAllocHealth {
+        let mut health = LargeAllocHealth::default();
+        Heap::probe_extents(&mut self.linked_list_allocator, &mut health);
+        Heap::probe_extents(&mut self.reserved_linked_list_allocator, &mut health);
+        health
+    }
+
+    /// Adds the free extents of `allocator` to `health`, see `large_alloc_health`.
+    fn probe_extents(allocator: &mut linked_list_allocator::Heap, health: &mut LargeAllocHealth) {
+        // The chunks are linked through their first word, followed by their size.
+        let mut chunks = 0;
+        loop {
+            let size = Heap::largest_first_fit(allocator, align_of::<usize>());
+            if size < 2 * size_of::<usize>() {
+                break;
+            }
+            let layout = Layout::from_size_align(size, align_of::<usize>()).unwrap();
+            let chunk = match allocator.allocate_first_fit(layout) {
+                Ok(ptr) => ptr.as_ptr() as *mut usize,
+                Err(_) => break,
+            };
+            unsafe {
+                *chunk = chunks;
+                *chunk.offset(1) = size;
+            }
+            chunks = chunk as usize;
+            health.total_free += size;
+            health.largest_free = cmp::max(health.largest_free, size);
+            health.free_block_count += 1;
+        }
+        while chunks != 0 {
+            let chunk = chunks as *mut usize;
+            unsafe {
+                chunks = *chunk;
+                let layout =
+                    Layout::from_size_align_unchecked(*chunk.offset(1), align_of::<usize>());
+                allocator.deallocate(NonNull::new_unchecked(chunk as *mut u8), layout);
+            }
+        }
+    }
+
+    /// Returns the size of the largest chunk with the given alignment `allocator` can hand out,
+    /// found with a binary search of allocations which are freed right away. Whether a chunk
+    /// fits is not monotonic in its size: a hole may fit a chunk exactly, but not one a few
+    /// bytes smaller whose rest is too small to hold a hole. So the result is approximate. A
+    /// chunk of the returned size always fits, but a larger one may fit too.
+    fn largest_first_fit(allocator: &mut linked_list_allocator::Heap, align: usize) -> usize {
+        let (mut low, mut high) = (0, allocator.size());
+        while low < high {
+            let size = high - (high - low) / 2;
+            let fits = match Layout::from_size_align(size, align) {
+                Ok(layout) => match allocator.allocate_first_fit(layout.clone()) {
+                    Ok(ptr) => {
+                        unsafe { allocator.deallocate(ptr, layout) };
+                        true
+                    }
+                    Err(_) => false,
+                },
+                Err(_) => false,
+            };
+            if fits {
+                low = size;
+            } else {
+                high = size - 1;
+            }
+        }
+        low
+    }
+
+    /// Moves up to `blocks` free blocks from the slab `from` to the slab `to`, which must have
+    /// the same block size, such as two 64 byte slabs over different memory banks given to
+    /// `new_from_raw_parts` and picked per core by a router set with `set_routing`. Blocks are
+    /// moved like `Slab::move_free_blocks` does, so the moved blocks belong to `to` alone and
+    /// are freed to it. Returns the number of blocks moved, which is 0 if the block sizes
+    /// differ, `from` and `to` are the same or either is the linked list allocator.
+    pub fn balance(&mut self, from: HeapAllocator, to: HeapAllocator, blocks: usize) -> usize {
+        let (from, to) = match (from.slab_index(), to.slab_index()) {
+            (Some(from), Some(to)) if from != to => (from, to),
+            _ => return 0,
+        };
+        let mut slabs = self.slabs_mut();
+        let (low, high) = slabs.split_at_mut(cmp::max(from, to));
+        if from < to {
+            low[from].move_free_blocks(&mut *high[0], blocks)
+        } else {
+            high[0].move_free_blocks(&mut *low[to], blocks)
+        }
+    }
+
+    /// Moves a segment of the given slab whose blocks are all free to the same slab of `to`, such
+    /// as from a heap with an idle slab to a starved one. Returns false if there is no such
+    /// segment or `class` is the linked list allocator. Blocks of the segment must be freed to
+    /// `to` afterwards.
+    pub fn transfer_segment(&mut self, to: &mut Heap, class: HeapAllocator) -> bool {
+        if to.slab(class).is_none() {
+            return false;
+        }
+        let segment = match self
+            .slab_mut(class)
+            .and_then(|slab| slab.take_free_segment())
+        {
+            Some(segment) => segment,
+            None => return false,
+        };
+        unsafe { to.grow(segment.start_addr, segment.size, class) };
+        true
+    }
+
+    /// Hands the memory of `class` to the linked list allocator once none of its blocks is
+    /// allocated, such as when a workload moves from many small buffers to a few large ones.
+    /// The linked list allocator can only be extended by memory directly following its region,
+    /// or directly preceding it while no chunk is allocated from it and there is no reserve. So
+    /// segments are donated as long as one adjoins the region that way, which for a heap made
+    /// by `new` means the 4096 byte slab first, then the 2048 byte slab and so on. The other
+    /// segments stay with the slab. Returns the number of bytes donated.
+    pub fn donate_class_to_large(&mut self, class: HeapAllocator) -> Result<usize, DonateError> {
+        match self.slab(class) {
+            Some(slab) if slab.free_blocks() != slab.total_blocks() => {
+                return Err(DonateError::LiveAllocations)
+            }
+            Some(_) => {}
+            None => return Err(DonateError::NotASlab),
+        }
+        let mut donated = 0;
+        loop {
+            let bottom = self.linked_list_allocator.bottom();
+            let top = self.linked_list_allocator.top();
+            let movable_bottom = self.reserved_linked_list_allocator.size() == 0
+                && self.linked_list_live_chunks == 0
+                && self.static_linked_list_bytes == 0;
+            let slab = match self.slab_mut(class) {
+                Some(slab) => slab,
+                None => break,
+            };
+            let segment = slab.segments().find(|segment| {
+                segment.start_addr == top || (movable_bottom && segment.end_addr() == bottom)
+            });
+            let segment = match segment {
+                Some(segment) if slab.remove_segment(segment.start_addr) => segment,
+                _ => break,
+            };
+            #[cfg(feature = "valgrind")]
+            valgrind::make_mem_defined(segment.start_addr, segment.size);
+            unsafe {
+                if segment.start_addr == top {
+                    self.linked_list_allocator.extend(segment.size);
+                } else {
+                    let size = segment.size + self.linked_list_allocator.size();
+                    self.linked_list_allocator =
+                        linked_list_allocator::Heap::new(segment.start_addr, size);
+                }
+            }
+            donated += segment.size;
+        }
+        if donated == 0 {
+            return Err(DonateError::NotAdjacent);
+        }
+        Ok(donated)
+    }
+
+    /// Touches every page of memory managed by the heap, including grown slab segments and the
+    /// linked list region with its reserve, with a volatile read and write back of one byte, so
+    /// all pages are faulted in before a real-time loop starts. Slabs of a heap created with
+    /// `new_lazy` also link their uninitialized blocks into their free lists, so no allocation
+    /// writes to a fresh block afterwards. Returns the number of pages touched.
+    pub fn prefault(&mut self) -> usize {
+        let mut pages = 0;
+        for slab in self.slabs_mut().iter_mut() {
+            slab.initialize_remaining();
+            for segment in slab.segments() {
+                pages += Heap::touch_pages(segment.start_addr, segment.size);
+            }
+        }
+        pages
+            + Heap::touch_pages(
+                self.linked_list_region_start(),
+                self.linked_list_region_size(),
+            )
+    }
+
+    /// Touches one byte of every page overlapping `[start, start + size)`, returning the number
+    /// of pages.
+    fn touch_pages(start: usize, size: usize) -> usize {
+        let end = start + size;
+        let mut addr = start;
+        let mut pages = 0;
+        while addr < end {
+            let byte = addr as *mut u8;
+            unsafe { core::ptr::write_volatile(byte, core::ptr::read_volatile(byte)) };
+            pages += 1;
+            addr = (addr & !(MIN_SLAB_SIZE - 1)) + MIN_SLAB_SIZE;
+        }
+        pages
+    }
+
+    /// Writes zeros over the free blocks of all slabs, so no data from earlier allocations is
+    /// left behind in them. The free lists themselves are left intact.
+    pub fn fill_free_blocks_with_zeros(&mut self) {
+        for slab in self.slabs_mut().iter_mut() {
+            slab.fill_with_zeros();
+        }
+    }
+
+    fn slabs(&self) -> [&Slab; NUM_OF_SLABS - 1] {
+        [
+            &self.slab_64_bytes,
+            &self.slab_128_bytes,
+            &self.slab_256_bytes,
+            &self.slab_512_bytes,
+            &self.slab_1024_bytes,
+            &self.slab_2048_bytes,
+            &self.slab_4096_bytes,
+        ]
+    }
+
+    fn slabs_mut(&mut self) -> [&mut Slab; NUM_OF_SLABS - 1] {
+        [
+            &mut self.slab_64_bytes,
+            &mut self.slab_128_bytes,
+            &mut self.slab_256_bytes,
+            &mut self.slab_512_bytes,
+            &mut self.slab_1024_bytes,
+            &mut self.slab_2048_bytes,
+            &mut self.slab_4096_bytes,
+        ]
+    }
+
+    fn slab_mut(&mut self, slab: HeapAllocator) -> Option<&mut Slab> {
+        match slab {
+            HeapAllocator::Slab64Bytes => Some(&mut self.slab_64_bytes),
+            HeapAllocator::Slab128Bytes => Some(&mut self.slab_128_bytes),
+            HeapAllocator::Slab256Bytes => Some(&mut self.slab_256_bytes),
+            HeapAllocator::Slab512Bytes => Some(&mut self.slab_512_bytes),
+            HeapAllocator::Slab1024Bytes => Some(&mut self.slab_1024_bytes),
+            HeapAllocator::Slab2048Bytes => Some(&mut self.slab_2048_bytes),
+            HeapAllocator::Slab4096Bytes => Some(&mut self.slab_4096_bytes),
+            HeapAllocator::LinkedListAllocator => None,
+        }
+    }
+
+    fn slab(&self, slab: HeapAllocator) -> Option<&Slab> {
+        match slab {
+            HeapAllocator::Slab64Bytes => Some(&self.slab_64_bytes),
+            HeapAllocator::Slab128Bytes => Some(&self.slab_128_bytes),
+            HeapAllocator::Slab256Bytes => Some(&self.slab_256_bytes),
+            HeapAllocator::Slab512Bytes => Some(&self.slab_512_bytes),
+            HeapAllocator::Slab1024Bytes => Some(&self.slab_1024_bytes),
+            HeapAllocator::Slab2048Bytes => Some(&self.slab_2048_bytes),
+            HeapAllocator::Slab4096Bytes => Some(&self.slab_4096_bytes),
+            HeapAllocator::LinkedListAllocator => None,
+        }
+    }
+
+    /// Returns bounds on the guaranteed usable size of a successful
+    /// allocation created with the specified `layout`.
+    pub fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        match self.slab(self.route(&layout)) {
+            Some(slab) => (layout.size(), slab.block_size()),
+            None => (layout.size(), layout.size()),
+        }
+    }
+
+    /// Replaces the function choosing the slab for a layout, which defaults to the smallest slab
+    /// whose block size and block alignment fit the layout, the one `layout_to_allocator` picks
+    /// for the default classes. It is used by `allocate` and `deallocate` alike, so it must not
+    /// be changed while allocations are live. If it picks a slab whose blocks are smaller than
+    /// the layout's size or alignment, the default routing is used instead. Needs the
+    /// `custom-routing` feature.
+    #[cfg(feature = "custom-routing")]
+    pub fn set_routing(&mut self, routing: fn(&Layout) -> HeapAllocator) {
+        self.routing = Some(routing);
+    }
+
+    /// Routes every allocation to the linked list allocator while `enabled`, regardless of its
+    /// size, such as to measure what the slabs gain over the linked list allocator alone. The
+    /// slabs keep their memory, so the linked list region alone has to hold all chunks and the
+    /// heap runs out of memory sooner; `donate_class_to_large` can hand it idle slabs.
+    /// Chunks are freed to the allocator whose memory holds them, so blocks allocated before a
+    /// switch are freed correctly after it. On a `LockedHeap`, use `LockedHeap::set_bypass`,
+    /// which also turns off its small object caches. Needs the `custom-routing` feature.
+    #[cfg(feature = "custom-routing")]
+    pub fn set_bypass(&mut self, enabled: bool) {
+        self.bypass_slabs = enabled;
+        self.slabs_bypassed |= enabled;
+    }
+
+    /// Finds the allocator for `layout` with the function set by `set_routing`, or the linked
+    /// list allocator while `set_bypass` is enabled.
+    fn route(&self, layout: &Layout) -> HeapAllocator {
+        #[cfg(feature = "custom-routing")]
+        {
+            if self.bypass_slabs {
+                return HeapAllocator::LinkedListAllocator;
+            }
+        }
+        self.route_layout(layout)
+    }
+
+    /// Returns true once `set_bypass` was enabled.
+    #[cfg(feature = "custom-routing")]
+    fn slabs_bypassed(&self) -> bool {
+        self.slabs_bypassed
+    }
+
+    #[cfg(not(feature = "custom-routing"))]
+    fn slabs_bypassed(&self) -> bool {
+        false
+    }
+
+    /// Returns the slab the small object cache of a `LockedHeap` for `block_size` byte blocks
+    /// can be filled from, the one every layout of that class of `layout_to_allocator` is
+    /// routed to. Returns `None` if the layouts may be routed elsewhere, such as by a router of
+    /// `set_routing`, or if cached blocks would be missed by the telemetry or the event ring.
+    fn cacheable_slab(&self, block_size: usize) -> Option<HeapAllocator> {
+        #[cfg(feature = "custom-routing")]
+        {
+            if self.routing.is_some() || self.slabs_bypassed {
+                return None;
+            }
+        }
+        #[cfg(feature = "stats")]
+        {
+            if self.telemetry.is_some() || self.event_ring.is_some() {
+                return None;
+            }
+        }
+        // With the default classes, every layout of the class goes to its slab if the layout
+        // with the largest size and alignment of the class does.
+        let layout = Layout::from_size_align(block_size, block_size).ok()?;
+        let slab = self.route(&layout);
+        if self.default_classes && slab == Heap::layout_to_allocator(&layout) {
+            Some(slab)
+        } else {
+            None
+        }
+    }
+
+    /// Finds the allocator the chunk at `addr` is freed to, which is the one whose memory holds
+    /// it, so a free doesn't depend on how the layout would be routed now. The allocator
+    /// `route_layout` picks is checked first, as it usually holds the chunk.
+    fn route_free(&self, addr: usize, layout: &Layout) -> HeapAllocator {
+        let routed = self.route_layout(layout);
+        let holds = match self.slab(routed) {
+            Some(slab) => slab.owns(addr),
+            None => {
+                let ll_start = self.linked_list_region_start();
+                addr >= ll_start && addr - ll_start < self.linked_list_region_size()
+            }
+        };
+        if holds {
+            return routed;
+        }
+        self.owner_of(addr).unwrap_or(routed)
+    }
+
+    /// Finds the allocator for `layout` with the function set by `set_routing`.
+    fn route_layout(&self, layout: &Layout) -> HeapAllocator {
+        #[cfg(feature = "custom-routing")]
+        {
+            if let Some(routing) = self.routing {
+                let slab = routing(layout);
+                match self.slab(slab) {
+                    Some(block_slab) if !Heap::fits(block_slab, layout) => {}
+                    _ => return slab,
+                }
+            }
+        }
+        self.smallest_fit(layout)
+    }
+
+    /// Finds the smallest slab whose block size and block alignment fit `layout`, checked
+    /// independently, as blocks are only aligned to the largest power of two dividing their
+    /// size and to the start of offset segments, such as those of `new_with_color_offset`.
+    /// Returns the linked list allocator if no slab fits. With the default classes this is the
+    /// class `layout_to_allocator` picks, unless offset segments keep it from fitting.
+    fn smallest_fit(&self, layout: &Layout) -> HeapAllocator {
+        if self.default_classes {
+            let class = Heap::layout_to_allocator(layout);
+            match self.slab(class) {
+                Some(slab) if !Heap::fits(slab, layout) => {}
+                _ => return class,
+            }
+        }
+        self.find_slab(|slab| Heap::fits(slab, layout))
+    }
+
+    /// Finds the smallest slab whose block size is large enough for `layout` and a multiple of
+    /// its alignment, like `layout_to_allocator` does for the default classes. Unlike
+    /// `smallest_fit`, offset segments are not taken into account.
+    #[cfg(feature = "stats")]
+    fn class_of(&self, layout: &Layout) -> HeapAllocator {
+        self.find_slab(|slab| {
+            let block_size = slab.block_size();
+            block_size >= layout.size() && block_size & block_size.wrapping_neg() >= layout.align()
+        })
+    }
+
+    /// Returns the first slab, from the smallest block size on, for which `f` returns true, or
+    /// the linked list allocator if there is none.
+    fn find_slab<F: Fn(&Slab) -> bool>(&self, f: F) -> HeapAllocator {
+        SLAB_ALLOCATORS
+            .iter()
+            .zip(self.slabs().iter())
+            .find(|&(_, slab)| f(slab))
+            .map_or(HeapAllocator::LinkedListAllocator, |(&class, _)| class)
+    }
+
+    /// Returns true if the blocks of `slab` can hold a chunk of `layout`.
+    fn fits(slab: &Slab, layout: &Layout) -> bool {
+        slab.block_size() >= layout.size() && slab.block_align() >= layout.align()
+    }
+
+    ///Finds allocator to use based on layout size and alignment
+    pub fn layout_to_allocator(layout: &Layout) -> HeapAllocator {
+        Heap::slab_for_size_and_align(layout.size(), layout.align())
+    }
+
+    /// Finds the allocator to use for a chunk of `size` bytes aligned to `align` like
+    /// `layout_to_allocator`, without building a `Layout` first.
+    pub fn slab_for_size_and_align(size: usize, align: usize) -> HeapAllocator {
+        if size > 4096 || align > 4096 {
+            HeapAllocator::LinkedListAllocator
+        } else if size <= 64 && align <= 64 {
+            HeapAllocator::Slab64Bytes
+        } else if size <= 128 && align <= 128 {
+            HeapAllocator::Slab128Bytes
+        } else if size <= 256 && align <= 256 {
+            HeapAllocator::Slab256Bytes
+        } else if size <= 512 && align <= 512 {
+            HeapAllocator::Slab512Bytes
+        } else if size <= 1024 && align <= 1024 {
+            HeapAllocator::Slab1024Bytes
+        } else if size <= 2048 && align <= 2048 {
+            HeapAllocator::Slab2048Bytes
+        } else {
+            HeapAllocator::Slab4096Bytes
+        }
+    }
+
+    /// Returns the number of heap bytes an array of `capacity` values of `T` takes with the
+    /// default routing, such as the buffer of a `Vec<T>` with that capacity. That is the block
+    /// size of the slab it is routed to, or its size for the linked list allocator. Returns 0
+    /// if the array is empty, as nothing is allocated then, or if its size overflows.
+    pub fn footprint_of<T>(capacity: usize) -> usize {
+        let layout = match Layout::array::<T>(capacity) {
+            Ok(layout) => layout,
+            Err(_) => return 0,
+        };
+        if layout.size() == 0 {
+            return 0;
+        }
+        let slab = Heap::layout_to_allocator(&layout);
+        match slab.slab_index() {
+            Some(index) => 64 << index,
+            None => layout.size(),
+        }
+    }
+
+    /// Returns how many blocks of each slab, from the 64 byte slab to the 4096 byte slab, fit in
+    /// `[start, start + size)`, for planning how to split memory between slabs such as with
+    /// `new_from_raw_parts` or `grow`. Blocks start at a multiple of their size, so a misaligned
+    /// `start` loses the memory up to the next block boundary.
+    pub fn block_count_for_region(start: usize, size: usize) -> [usize; NUM_OF_SLABS - 1] {
+        let mut counts = [0; NUM_OF_SLABS - 1];
+        let end = start.saturating_add(size);
+        for (i, count) in counts.iter_mut().enumerate() {
+            let block_size = 64 << i;
+            let first_block = match start.checked_add(block_size - 1) {
+                Some(addr) => addr & !(block_size - 1),
+                None => continue,
+            };
+            *count = end.saturating_sub(first_block) / block_size;
+        }
+        counts
+    }
+}
+
+#[cfg(feature = "std")]
+impl Heap {
+    /// Creates a heap sized by the `SLAB_HEAP_SIZE` environment variable, returning `None` if it
+    /// is missing or not a number. If `SLAB_HEAP_ADDR` is also set, the heap is placed at that
+    /// address, otherwise a page aligned buffer is allocated and leaked to back the heap. Both
+    /// values may be given in decimal or as `0x` prefixed hex.
+    /// This function is unsafe because `SLAB_HEAP_ADDR` may point to memory which is invalid
+    /// or used for something else.
+    pub unsafe fn new_from_env() -> Option<Heap> {
+        let heap_size = Heap::env_usize("SLAB_HEAP_SIZE")?;
+        let heap_start_addr = match Heap::env_usize("SLAB_HEAP_ADDR") {
+            Some(addr) => addr,
+            None => {
+                let buffer = std::vec![0u8; heap_size + MIN_SLAB_SIZE];
+                let addr = buffer.as_ptr() as usize;
+                core::mem::forget(buffer);
+                (addr + MIN_SLAB_SIZE - 1) & !(MIN_SLAB_SIZE - 1)
+            }
+        };
+        Some(Heap::new(heap_start_addr, heap_size))
+    }
+
+    /// Creates a heap of `heap_size` bytes backed by page aligned memory from the system
+    /// allocator. The memory is returned to the system allocator when the heap is dropped.
+    pub fn new_boxed(heap_size: usize) -> Heap {
+        let layout = Layout::from_size_align(heap_size, MIN_SLAB_SIZE)
+            .expect("Heap size is too big for the system allocator");
+        let addr = unsafe { std::alloc::alloc(layout) };
+        if addr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        let owned_region = OwnedRegion {
+            addr: addr as usize,
+            layout,
+        };
+        let mut heap = unsafe { Heap::new(addr as usize, heap_size) };
+        heap.owned_region = Some(owned_region);
+        heap
+    }
+
+    fn env_usize(name: &str) -> Option<usize> {
+        let value = std::env::var(name).ok()?;
+        let value = value.trim();
+        if value.starts_with("0x") {
+            usize::from_str_radix(&value[2..], 16).ok()
+        } else {
+            value.parse().ok()
+        }
+    }
+}
+
+unsafe impl Alloc for Heap {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        self.allocate_traced(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.deallocate(ptr, layout)
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        self.usable_size(layout)
+    }
+}
+
+/// A `Heap` behind a lock, for use as the global allocator.
+///
+/// Blocks of the 64 and 128 byte slabs are additionally kept in small caches with their own
+/// locks, which are refilled from and drained to the heap in batches. Small allocations made
+/// through the `Alloc` and `GlobalAlloc` implementations thus mostly don't wait for the heap
+/// lock, even while another thread makes large allocations. A cache is only used while the heap
+/// routes every layout of its class to a slab of its block size, so not with a router of
+/// `Heap::set_routing`, a slab bypass, other classes, offset segments, telemetry, an event ring
+/// or the `alloc-fill`, `generations`, `track-allocations`, `valgrind` and `waste-tracking`
+/// features. Freed blocks are only cached if they lie in the first segments of that slab.
+/// `lock()` returns the cached blocks to the heap first, see `flush_caches`.
+pub struct LockedHeap {
+    heap: Mutex<Option<Heap>>,
+    small_caches: [Mutex<BlockCache>; 2],
+    #[cfg(not(feature = "tiny"))]
+    counters: HeapCounters,
+    // Token of the thread holding the heap lock, or 0, to detect re-entry.
+    #[cfg(all(feature = "std", debug_assertions))]
+    holder: AtomicUsize,
+}
+
+/// The locked heap of a `LockedHeap`, returned by `LockedHeap::lock`. The heap is unlocked when
+/// the guard is dropped.
+pub struct LockedHeapGuard<'a> {
+    guard: MutexGuard<'a, Option<Heap>>,
+    #[cfg(all(feature = "std", debug_assertions))]
+    holder: &'a AtomicUsize,
+}
+
+impl<'a> Deref for LockedHeapGuard<'a> {
+    type Target = Option<Heap>;
+
+    fn deref(&self) -> &Option<Heap> {
+        &self.guard
+    }
+}
+
+impl<'a> DerefMut for LockedHeapGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Option<Heap> {
+        &mut self.guard
+    }
+}
+
+#[cfg(all(feature = "std", debug_assertions))]
+impl<'a> Drop for LockedHeapGuard<'a> {
+    fn drop(&mut self) {
+        self.holder.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Returns a token unique to the calling thread, or 0 while its thread locals are destroyed.
+#[cfg(all(feature = "std", debug_assertions))]
+fn thread_token() -> usize {
+    std::thread_local!(static TOKEN: u8 = 0);
+    TOKEN
+        .try_with(|token| token as *const u8 as usize)
+        .unwrap_or(0)
+}
+
+/// Statistics of a `LockedHeap` which can be read without taking its lock, so a monitoring
+/// thread does not contend with allocations. They are updated by every allocation and
+/// deallocation made through the `LockedHeap`. Changes made directly to the heap behind
+/// `lock()` are only picked up by `LockedHeap::refresh_counters` or the next use of the
+/// affected slab.
+#[cfg(not(feature = "tiny"))]
+pub struct HeapCounters {
+    alloc_count: AtomicUsize,
+    dealloc_count: AtomicUsize,
+    free_blocks: [AtomicUsize; NUM_OF_SLABS - 1],
+    // Allocations and frees served by each small object cache since the last
+    // `LockedHeap::rate_since_last`, which the heap doesn't see.
+    #[cfg(feature = "stats")]
+    cached_allocs: [AtomicUsize; 2],
+    #[cfg(feature = "stats")]
+    cached_frees: [AtomicUsize; 2],
+}
+
+#[cfg(not(feature = "tiny"))]
+impl HeapCounters {
+    const fn new() -> HeapCounters {
+        HeapCounters {
+            alloc_count: AtomicUsize::new(0),
+            dealloc_count: AtomicUsize::new(0),
+            free_blocks: [
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+            ],
+            #[cfg(feature = "stats")]
+            cached_allocs: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            #[cfg(feature = "stats")]
+            cached_frees: [AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    /// Returns the number of successful allocations.
+    pub fn alloc_count(&self) -> usize {
+        self.alloc_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of deallocations.
+    pub fn dealloc_count(&self) -> usize {
+        self.dealloc_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of free blocks of the given slab, or 0 for the linked list allocator.
+    pub fn free_blocks(&self, slab: HeapAllocator) -> usize {
+        match slab.slab_index() {
+            Some(index) => self.free_blocks[index].load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
+    fn reset(&self) {
+        self.alloc_count.store(0, Ordering::Relaxed);
+        self.dealloc_count.store(0, Ordering::Relaxed);
+        #[cfg(feature = "stats")]
+        for count in self.cached_allocs.iter().chain(self.cached_frees.iter()) {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn update_free_blocks(&self, heap: &Heap, slab: HeapAllocator) {
+        if let Some(index) = slab.slab_index() {
+            self.free_blocks[index].store(heap.free_blocks(slab), Ordering::Relaxed);
+        }
+    }
+
+    fn update_all(&self, heap: Option<&Heap>) {
+        for (index, &slab) in SLAB_ALLOCATORS.iter().enumerate() {
+            let free_blocks = heap.map_or(0, |heap| heap.free_blocks(slab));
+            self.free_blocks[index].store(free_blocks, Ordering::Relaxed);
+        }
+    }
+
+    fn record_allocation(&self, heap: &Heap, slab: HeapAllocator, succeeded: bool) {
+        if succeeded {
+            self.alloc_count.fetch_add(1, Ordering::Relaxed);
+            self.update_free_blocks(heap, slab);
+        }
+    }
+
+    fn record_deallocation(&self, heap: &Heap, slab: HeapAllocator) {
+        self.dealloc_count.fetch_add(1, Ordering::Relaxed);
+        self.update_free_blocks(heap, slab);
+    }
+}
+
+impl LockedHeap {
+    pub const fn empty() -> LockedHeap {
+        LockedHeap {
+            heap: Mutex::new(None),
+            small_caches: [
+                Mutex::new(BlockCache::new(64)),
+                Mutex::new(BlockCache::new(128)),
+            ],
+            #[cfg(not(feature = "tiny"))]
+            counters: HeapCounters::new(),
+            #[cfg(all(feature = "std", debug_assertions))]
+            holder: AtomicUsize::new(0),
+        }
+    }
+
+    /// Locks the heap. In debug builds with the `std` feature, panics with "allocator
+    /// re-entered" if the calling thread already holds the lock, such as when a telemetry hook
+    /// or OOM handler allocates, instead of spinning forever.
+    fn lock_heap<'a>(&'a self) -> LockedHeapGuard<'a> {
+        #[cfg(all(feature = "std", debug_assertions))]
+        {
+            let token = thread_token();
+            if token != 0 && self.holder.load(Ordering::SeqCst) == token {
+                heap_panic!("allocator re-entered");
+            }
+        }
+        self.guard(self.heap.lock())
+    }
+
+    /// Wraps a guard of the heap lock, recording the calling thread as its holder.
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, Option<Heap>>) -> LockedHeapGuard<'a> {
+        #[cfg(all(feature = "std", debug_assertions))]
+        {
+            self.holder.store(thread_token(), Ordering::SeqCst);
+            LockedHeapGuard {
+                guard,
+                holder: &self.holder,
+            }
+        }
+        #[cfg(not(all(feature = "std", debug_assertions)))]
+        LockedHeapGuard { guard }
+    }
+
+    /// Returns the blocks of the small object caches to the locked heap and turns the caches
+    /// off, see `lock`.
+    fn drain_caches(&self, caches: &mut [MutexGuard<BlockCache>; 2], guard: &mut LockedHeapGuard) {
+        for cache in caches.iter_mut() {
+            if let Some(ref mut heap) = **guard {
+                self.drain_to(heap, cache, usize::max_value());
+            }
+            cache.disable();
+        }
+    }
+
+    /// Locks the heap, such as to inspect it, use methods `LockedHeap` doesn't forward or replace
+    /// it. The blocks of the small object caches are returned to the heap first and the caches
+    /// are turned off, so the heap sees them as free and a replaced heap never gets blocks of the
+    /// old one. The caches are turned on again by their next refill, if the heap in place can
+    /// use them. The heap must not be used through `self` while the guard is alive; in debug
+    /// builds with the `std` feature that panics like `lock_heap` instead of deadlocking.
+    pub fn lock<'a>(&'a self) -> LockedHeapGuard<'a> {
+        let mut caches = [self.small_caches[0].lock(), self.small_caches[1].lock()];
+        let mut guard = self.lock_heap();
+        self.drain_caches(&mut caches, &mut guard);
+        guard
+    }
+
+    /// Locks the heap like `lock` if that doesn't need to wait, otherwise returns `None`. The
+    /// lock is not available while a small object cache is in use either, as their blocks are
+    /// returned to the heap first.
+    pub fn try_lock<'a>(&'a self) -> Option<LockedHeapGuard<'a>> {
+        let mut caches = [
+            self.small_caches[0].try_lock()?,
+            self.small_caches[1].try_lock()?,
+        ];
+        let mut guard = self.guard(self.heap.try_lock()?);
+        self.drain_caches(&mut caches, &mut guard);
+        Some(guard)
+    }
+
+    /// Returns whether the heap is locked at the moment, such as by `lock` or an allocation.
+    /// The answer may be outdated as soon as it is returned.
+    pub fn is_locked(&self) -> bool {
+        self.heap.try_lock().is_none()
+    }
+
+    /// Unlocks the heap, such as from a panic handler when the code which panicked held the
+    /// lock. Any guard still alive must not be used anymore, and the heap may be in the middle
+    /// of a change, thus this function is unsafe.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(all(feature = "std", debug_assertions))]
+        self.holder.store(0, Ordering::SeqCst);
+        self.heap.force_unlock();
+    }
+
+    pub unsafe fn init(&self, heap_start_addr: usize, size: usize) {
+        *self.lock() = Some(Heap::new(heap_start_addr, size));
+        #[cfg(not(feature = "tiny"))]
+        self.refresh_counters();
+    }
+
+    /// Initializes the heap over the `[start, end)` range, see `Heap::from_symbols`.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given range is invalid.
+    pub unsafe fn init_from_symbols(
+        &self,
+        start: *const u8,
+        end: *const u8,
+    ) -> Result<(), HeapInitError> {
+        *self.lock() = Some(Heap::from_symbols(start, end)?);
+        #[cfg(not(feature = "tiny"))]
+        self.refresh_counters();
+        Ok(())
     }
 
     /// Creates a new heap with the given `heap_start_addr` and `heap_size`. The start address must be valid
@@ -212,64 +3371,423 @@ impl LockedHeap {
     /// anything else. This function is unsafe because it can cause undefined behavior if the
     /// given address is invalid.
     pub unsafe fn new(heap_start_addr: usize, heap_size: usize) -> LockedHeap {
-        LockedHeap(Mutex::new(Some(Heap::new(heap_start_addr, heap_size))))
+        let locked_heap = LockedHeap::empty();
+        locked_heap.init(heap_start_addr, heap_size);
+        locked_heap
+    }
+
+    /// Frees the given allocation after validating it, see `Heap::try_deallocate`.
+    /// Returns `DeallocError::NotOwned` if the heap is not initialized. The checks don't catch
+    /// every invalid pointer, thus this function is unsafe.
+    pub unsafe fn try_deallocate(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(), DeallocError> {
+        match *self.lock_heap() {
+            Some(ref mut heap) => {
+                #[cfg(not(feature = "tiny"))]
+                let slab = heap.route_free(ptr.as_ptr() as usize, &layout);
+                heap.try_deallocate(ptr, layout)?;
+                #[cfg(not(feature = "tiny"))]
+                self.counters.record_deallocation(heap, slab);
+                Ok(())
+            }
+            None => Err(DeallocError::NotOwned),
+        }
+    }
+
+    /// Grows the heap and allocates from the new memory under one lock, see
+    /// `Heap::grow_and_allocate`. Panics if the heap is not initialized.
+    /// This function is unsafe because it can cause undefined behavior if the
+    /// given address is invalid.
+    pub unsafe fn grow_and_allocate(
+        &self,
+        layout: Layout,
+        mem_start_addr: usize,
+        mem_size: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        match *self.lock_heap() {
+            Some(ref mut heap) => {
+                let result = heap.grow_and_allocate(layout, mem_start_addr, mem_size);
+                #[cfg(not(feature = "tiny"))]
+                {
+                    self.counters.update_all(Some(heap));
+                    if result.is_ok() {
+                        self.counters.alloc_count.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                result
+            }
+            None => heap_panic!("grow_and_allocate: heap not initialized"),
+        }
+    }
+
+    /// Locks the heap once like `lock` and runs `f` with it, such as to make a batch of
+    /// allocations without taking the lock for each of them. The free block counts of `counters` are updated
+    /// afterwards, while the allocation and deallocation counts don't include the batch. `f`
+    /// must not use `self`, which is locked while it runs. Panics if the heap is not
+    /// initialized.
+    pub fn with_locked<R>(&self, f: impl FnOnce(&mut Heap) -> R) -> R {
+        match self.try_with_locked(f) {
+            Ok(result) => result,
+            Err(HeapNotInitialized) => heap_panic!("with_locked: heap not initialized"),
+        }
+    }
+
+    /// Like `with_locked`, but returns `Err` if the heap is not initialized.
+    pub fn try_with_locked<R>(
+        &self,
+        f: impl FnOnce(&mut Heap) -> R,
+    ) -> Result<R, HeapNotInitialized> {
+        let mut guard = self.lock();
+        let heap = guard.as_mut().ok_or(HeapNotInitialized)?;
+        let result = f(heap);
+        #[cfg(not(feature = "tiny"))]
+        self.counters.update_all(Some(heap));
+        Ok(result)
+    }
+
+    /// Returns true if `addr` lies in memory managed by the heap, see `Heap::owns`. Returns
+    /// false if the heap is not initialized.
+    pub fn owns(&self, addr: usize) -> bool {
+        self.lock_heap()
+            .as_ref()
+            .map_or(false, |heap| heap.owns(addr))
+    }
+
+    /// Returns the statistics which can be read without taking the lock.
+    #[cfg(not(feature = "tiny"))]
+    pub fn counters(&self) -> &HeapCounters {
+        &self.counters
+    }
+
+    /// Updates the free block counts of all slabs, after the heap was changed through `lock()`.
+    #[cfg(not(feature = "tiny"))]
+    pub fn refresh_counters(&self) {
+        self.counters.update_all(self.lock_heap().as_ref());
+    }
+
+    /// Returns the number of allocations and frees of each slab since the last call like
+    /// `Heap::rate_since_last`, including the ones served by the small object caches.
+    #[cfg(feature = "stats")]
+    pub fn rate_since_last(&self) -> [(usize, usize); NUM_OF_SLABS] {
+        let mut rates = match *self.lock_heap() {
+            Some(ref mut heap) => heap.rate_since_last(),
+            None => [(0, 0); NUM_OF_SLABS],
+        };
+        for (index, rate) in rates.iter_mut().take(self.small_caches.len()).enumerate() {
+            rate.0 += self.counters.cached_allocs[index].swap(0, Ordering::Relaxed);
+            rate.1 += self.counters.cached_frees[index].swap(0, Ordering::Relaxed);
+        }
+        rates
+    }
+
+    /// Zeroes the allocation and deallocation counts and, with the `stats` feature, the heap's
+    /// cumulative statistics like `Heap::reset_stats`, such as at the start of a profiling
+    /// phase. Live state, such as the free block counts and the heap itself, is left alone.
+    #[cfg(not(feature = "tiny"))]
+    pub fn reset_stats(&self) {
+        #[cfg(feature = "stats")]
+        {
+            if let Some(ref mut heap) = *self.lock_heap() {
+                heap.reset_stats();
+            }
+        }
+        self.counters.reset();
+    }
+
+    /// Writes heap statistics from a panic handler. It never waits for the lock, allocates or
+    /// forces the lock open: if the lock is held, such as by the code which panicked, only the
+    /// lock-free counters are written, marked as approximate since they may lag behind
+    /// changes made through `lock()`. Otherwise the heap is locked with `try_lock`, which
+    /// returns the blocks of the small block caches to it first.
+    #[cfg(not(feature = "tiny"))]
+    pub fn panic_dump<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        writeln!(
+            w,
+            "allocations: {}, deallocations: {}",
+            self.counters.alloc_count(),
+            self.counters.dealloc_count()
+        )?;
+        let heap = match self.try_lock() {
+            Some(heap) => heap,
+            None => {
+                writeln!(w, "heap is locked, free block counts are approximate")?;
+                for &slab in SLAB_ALLOCATORS.iter() {
+                    writeln!(
+                        w,
+                        "{:?}: ~{} blocks free",
+                        slab,
+                        self.counters.free_blocks(slab)
+                    )?;
+                }
+                return Ok(());
+            }
+        };
+        match *heap {
+            Some(ref heap) => {
+                for (&class, slab) in SLAB_ALLOCATORS.iter().zip(heap.slabs().iter()) {
+                    writeln!(
+                        w,
+                        "{:?}: {} of {} blocks free",
+                        class,
+                        slab.free_blocks(),
+                        slab.total_blocks()
+                    )?;
+                }
+                writeln!(
+                    w,
+                    "LinkedListAllocator: {} of {} bytes used",
+                    heap.linked_list_used_bytes,
+                    heap.linked_list_region_size()
+                )
+            }
+            None => writeln!(w, "heap is not initialized"),
+        }
+    }
+
+    /// Consumes the locked heap, returning the inner heap if it was initialized.
+    /// A `LockedHeap` stored in a `static` can't be moved out of; take its heap with
+    /// `lock().take()` instead.
+    pub fn into_inner(self) -> Option<Heap> {
+        self.flush_caches();
+        self.heap.into_inner()
+    }
+
+    /// Switches the heap's slab bypass like `Heap::set_bypass`. The small object caches are
+    /// emptied and stay off from then on, as they would take chunks of the linked list
+    /// allocator freed with small layouts for slab blocks. Needs the `custom-routing` feature.
+    #[cfg(feature = "custom-routing")]
+    pub fn set_bypass(&self, enabled: bool) {
+        match *self.lock() {
+            Some(ref mut heap) => heap.set_bypass(enabled),
+            None => heap_panic!("set_bypass: heap not initialized"),
+        }
+    }
+
+    /// Returns the blocks held by the small object caches to the heap, such as before reading
+    /// the free block counts of `counters`.
+    pub fn flush_caches(&self) {
+        for cache in self.small_caches.iter() {
+            let mut cache = cache.lock();
+            self.drain_cache(&mut cache, usize::max_value());
+        }
+    }
+
+    /// Returns the index of the small object cache for the class of `layout`, if any. Whether
+    /// the heap routes the class to the cache's slab is checked by `refill_cache`.
+    fn cache_index(layout: &Layout) -> Option<usize> {
+        if cfg!(any(
+            feature = "alloc-fill",
+            feature = "generations",
+            feature = "track-allocations",
+            feature = "valgrind",
+            feature = "waste-tracking"
+        )) {
+            return None;
+        }
+        match Heap::layout_to_allocator(layout) {
+            HeapAllocator::Slab64Bytes => Some(0),
+            HeapAllocator::Slab128Bytes => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Allocates a block from the small object cache with the given index, refilling it from
+    /// the heap if it is empty. Returns `None` if the cache can't be used or the slab is out of
+    /// blocks, in which case the allocation goes through the heap.
+    fn allocate_cached(&self, index: usize) -> Option<NonNull<u8>> {
+        let mut cache = self.small_caches[index].lock();
+        if cache.len() == 0 {
+            self.refill_cache(&mut cache);
+        }
+        let block = cache.pop();
+        #[cfg(not(feature = "tiny"))]
+        {
+            if block.is_some() {
+                self.counters.alloc_count.fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "stats")]
+                self.counters.cached_allocs[index].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        block
+    }
+
+    /// Frees a block to the small object cache with the given index, draining half of the
+    /// cache to the heap if it is full. Returns false if the cache is not enabled or the block
+    /// doesn't lie in its slab, in which case the block must be freed to the heap.
+    unsafe fn deallocate_cached(&self, index: usize, ptr: NonNull<u8>) -> bool {
+        let mut cache = self.small_caches[index].lock();
+        if !cache.is_enabled() || !cache.owns(ptr.as_ptr() as usize) {
+            return false;
+        }
+        cache.push(ptr);
+        #[cfg(not(feature = "tiny"))]
+        {
+            self.counters.dealloc_count.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "stats")]
+            self.counters.cached_frees[index].fetch_add(1, Ordering::Relaxed);
+        }
+        if cache.len() >= 2 * CACHE_BATCH {
+            self.drain_cache(&mut cache, CACHE_BATCH);
+        }
+        true
+    }
+
+    /// Fills an empty cache from the heap, enabling it if the heap can use it and disabling it
+    /// otherwise.
+    fn refill_cache(&self, cache: &mut BlockCache) {
+        if let Some(ref mut heap) = *self.lock_heap() {
+            let block_size = cache.block_size();
+            let slab = match heap.cacheable_slab(block_size) {
+                Some(slab) => slab,
+                None => {
+                    cache.disable();
+                    return;
+                }
+            };
+            if let Some(block_slab) = heap.slab(slab) {
+                let segments = block_slab.segments();
+                cache.enable(slab, segments.map(|seg| (seg.start_addr, seg.end_addr())));
+            }
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            // Pushed in reverse, so the cache hands out blocks in the same order as the slab.
+            let mut blocks = [NonNull::dangling(); CACHE_BATCH];
+            let mut num_of_blocks = 0;
+            while num_of_blocks < CACHE_BATCH {
+                match heap.allocate_from(slab, layout.clone()) {
+                    Ok(ptr) => blocks[num_of_blocks] = ptr,
+                    Err(_) => break,
+                }
+                num_of_blocks += 1;
+            }
+            for &ptr in blocks[..num_of_blocks].iter().rev() {
+                unsafe { cache.push(ptr) };
+            }
+            #[cfg(not(feature = "tiny"))]
+            self.counters.update_free_blocks(heap, slab);
+        } else {
+            heap_panic!("allocate: heap not initialized");
+        }
     }
-}
 
-impl Deref for LockedHeap {
-    type Target = Mutex<Option<Heap>>;
+    /// Returns up to `max_blocks` blocks of the cache to the heap. A cache never holds more
+    /// than `2 * CACHE_BATCH` blocks.
+    fn drain_cache(&self, cache: &mut BlockCache, max_blocks: usize) {
+        if cache.len() == 0 {
+            return;
+        }
+        if let Some(ref mut heap) = *self.lock_heap() {
+            self.drain_to(heap, cache, max_blocks);
+        }
+    }
 
-    fn deref(&self) -> &Mutex<Option<Heap>> {
-        &self.0
+    /// Returns up to `max_blocks` blocks of the cache to the slab it was filled from.
+    fn drain_to(&self, heap: &mut Heap, cache: &mut BlockCache, max_blocks: usize) {
+        let slab = cache.slab();
+        // Freed in reverse, so the slab hands out blocks in the same order as the cache.
+        let mut blocks = [NonNull::dangling(); 2 * CACHE_BATCH];
+        let mut num_of_blocks = 0;
+        while num_of_blocks < cmp::min(max_blocks, blocks.len()) {
+            match cache.pop() {
+                Some(ptr) => blocks[num_of_blocks] = ptr,
+                None => break,
+            }
+            num_of_blocks += 1;
+        }
+        for &ptr in blocks[..num_of_blocks].iter().rev() {
+            unsafe { heap.deallocate_to(slab, ptr) };
+        }
+        #[cfg(not(feature = "tiny"))]
+        self.counters.update_free_blocks(heap, slab);
     }
 }
 
 unsafe impl<'a> Alloc for &'a LockedHeap {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        if let Some(ref mut heap) = *self.0.lock() {
-            heap.allocate(layout)
+        if let Some(index) = LockedHeap::cache_index(&layout) {
+            if let Some(ptr) = self.allocate_cached(index) {
+                return Ok(ptr);
+            }
+        }
+        if let Some(ref mut heap) = *self.lock_heap() {
+            #[cfg(not(feature = "tiny"))]
+            let slab = heap.route(&layout);
+            let result = heap.allocate_traced(layout);
+            #[cfg(not(feature = "tiny"))]
+            self.counters.record_allocation(heap, slab, result.is_ok());
+            result
         } else {
-            panic!("allocate: heap not initialized");
+            heap_panic!("allocate: heap not initialized");
         }
     }
 
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        if let Some(ref mut heap) = *self.0.lock() {
-            heap.deallocate(ptr, layout)
+        if let Some(index) = LockedHeap::cache_index(&layout) {
+            if self.deallocate_cached(index, ptr) {
+                return;
+            }
+        }
+        if let Some(ref mut heap) = *self.lock_heap() {
+            #[cfg(not(feature = "tiny"))]
+            let slab = heap.route_free(ptr.as_ptr() as usize, &layout);
+            heap.deallocate(ptr, layout);
+            #[cfg(not(feature = "tiny"))]
+            self.counters.record_deallocation(heap, slab);
         } else {
-            panic!("deallocate: heap not initialized");
+            heap_panic!("deallocate: heap not initialized");
         }
     }
 
     fn usable_size(&self, layout: &Layout) -> (usize, usize) {
-        if let Some(ref mut heap) = *self.0.lock() {
+        if let Some(ref mut heap) = *self.lock_heap() {
             heap.usable_size(layout)
         } else {
-            panic!("usable_size: heap not initialized");
+            heap_panic!("usable_size: heap not initialized");
         }
     }
 }
 
 unsafe impl GlobalAlloc for LockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Some(ref mut heap) = *self.0.lock() {
-            if let Ok(ref mut nnptr) = heap.allocate(layout) {
+        if let Some(index) = LockedHeap::cache_index(&layout) {
+            if let Some(ptr) = self.allocate_cached(index) {
+                return ptr.as_ptr();
+            }
+        }
+        if let Some(ref mut heap) = *self.lock_heap() {
+            #[cfg(not(feature = "tiny"))]
+            let slab = heap.route(&layout);
+            let result = heap.allocate_traced(layout);
+            #[cfg(not(feature = "tiny"))]
+            self.counters.record_allocation(heap, slab, result.is_ok());
+            if let Ok(nnptr) = result {
                 return nnptr.as_ptr();
             } else {
-                panic!("allocate: failed");
+                heap_panic!("allocate: failed");
             }
         } else {
-            panic!("allocate: heap not initialzied");
+            heap_panic!("allocate: heap not initialzied");
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if let Some(ref mut heap) = *self.0.lock() {
+        if let (Some(index), Some(p)) = (LockedHeap::cache_index(&layout), NonNull::new(ptr)) {
+            if self.deallocate_cached(index, p) {
+                return;
+            }
+        }
+        if let Some(ref mut heap) = *self.lock_heap() {
             if let Some(p) = NonNull::new(ptr) {
-                heap.deallocate(p, layout)
+                #[cfg(not(feature = "tiny"))]
+                let slab = heap.route_free(p.as_ptr() as usize, &layout);
+                heap.deallocate(p, layout);
+                #[cfg(not(feature = "tiny"))]
+                self.counters.record_deallocation(heap, slab);
             }
         } else {
-            panic!("deallocate: heap not initialized");
+            heap_panic!("deallocate: heap not initialized");
         }
     }
 }
@@ -0,0 +1,138 @@
+//! Kani proof harnesses, run with `cargo kani`.
+
+use super::*;
+use slab::{blocks_end, FreeBlock, FreeBlockList};
+
+/// Most blocks the free list harnesses build a list of, bounding the loops Kani unwinds.
+const MAX_LIST_BLOCKS: usize = 4;
+const LIST_BLOCK_SIZE: usize = 64;
+
+/// Checks that `list` links exactly `len` blocks, the `i`-th of them at
+/// `start_addr + (first + i) * LIST_BLOCK_SIZE`.
+fn assert_list(list: &FreeBlockList, start_addr: usize, first: usize, len: usize) {
+    assert_eq!(list.len(), len);
+    let mut walked = 0;
+    list.for_each(|addr| {
+        assert!(walked < len);
+        assert_eq!(addr, start_addr + (first + walked) * LIST_BLOCK_SIZE);
+        walked += 1;
+    });
+    assert_eq!(walked, len);
+}
+
+/// Block size of the given slab, `None` for the linked list allocator.
+fn block_size(slab: HeapAllocator) -> Option<usize> {
+    SLAB_ALLOCATORS
+        .iter()
+        .position(|&class| class == slab)
+        .map(|index| 64 << index)
+}
+
+#[kani::proof]
+fn layout_to_allocator_fits_layout() {
+    let size: usize = kani::any();
+    let align_shift: u32 = kani::any();
+    kani::assume(align_shift < usize::max_value().count_ones());
+    let layout = match Layout::from_size_align(size, 1 << align_shift) {
+        Ok(layout) => layout,
+        Err(_) => return,
+    };
+    if let Some(block_size) = block_size(Heap::layout_to_allocator(&layout)) {
+        assert!(block_size >= layout.size());
+        assert!(block_size >= layout.align());
+    }
+}
+
+#[kani::proof]
+fn block_addresses_do_not_overflow() {
+    let start_addr: usize = kani::any();
+    let block_size: usize = kani::any();
+    let num_of_blocks: usize = kani::any();
+    let index: usize = kani::any();
+    kani::assume(index < num_of_blocks);
+    if let Some(end) = blocks_end(start_addr, block_size, num_of_blocks) {
+        let offset = index.checked_mul(block_size).unwrap();
+        let addr = start_addr.checked_add(offset).unwrap();
+        assert!(addr.checked_add(block_size).unwrap() <= end);
+    }
+}
+
+#[kani::proof]
+#[kani::unwind(5)]
+fn free_list_keeps_every_block() {
+    const NUM_OF_BLOCKS: usize = 4;
+    let mut memory = [0u64; NUM_OF_BLOCKS * 8];
+    let start_addr = memory.as_mut_ptr() as usize;
+    let mut slab = unsafe { Slab::new(start_addr, NUM_OF_BLOCKS * 64, 64) };
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let taken: usize = kani::any();
+    kani::assume(taken <= NUM_OF_BLOCKS);
+    let mut blocks = [0usize; NUM_OF_BLOCKS];
+    for i in 0..taken {
+        blocks[i] = slab.allocate(layout.clone()).unwrap().as_ptr() as usize;
+        assert_eq!(slab.free_blocks(), NUM_OF_BLOCKS - i - 1);
+        assert!(blocks[i] >= start_addr && blocks[i] < start_addr + NUM_OF_BLOCKS * 64);
+        assert_eq!((blocks[i] - start_addr) % 64, 0);
+        for j in 0..i {
+            assert!(blocks[i] != blocks[j]);
+        }
+    }
+    for i in 0..taken {
+        unsafe { slab.deallocate(NonNull::new_unchecked(blocks[i] as *mut u8)) };
+        assert_eq!(slab.free_blocks(), NUM_OF_BLOCKS - taken + i + 1);
+    }
+
+    for _ in 0..NUM_OF_BLOCKS {
+        assert!(slab.allocate(layout.clone()).is_ok());
+    }
+    assert!(slab.allocate(layout).is_err());
+}
+
+#[kani::proof]
+#[kani::unwind(5)]
+fn free_list_new_links_every_block() {
+    let mut memory = [0u64; MAX_LIST_BLOCKS * LIST_BLOCK_SIZE / 8];
+    let start_addr = memory.as_mut_ptr() as usize;
+    let num_of_blocks: usize = kani::any();
+    kani::assume(num_of_blocks <= MAX_LIST_BLOCKS);
+
+    let list = unsafe { FreeBlockList::new(start_addr, LIST_BLOCK_SIZE, num_of_blocks) };
+    assert_list(&list, start_addr, 0, num_of_blocks);
+}
+
+#[kani::proof]
+#[kani::unwind(5)]
+fn free_list_push_then_pop_returns_the_block() {
+    let mut memory = [0u64; MAX_LIST_BLOCKS * LIST_BLOCK_SIZE / 8];
+    let start_addr = memory.as_mut_ptr() as usize;
+    let num_of_blocks: usize = kani::any();
+    kani::assume(num_of_blocks < MAX_LIST_BLOCKS);
+    // The list covers blocks 1 to `num_of_blocks`, block 0 is pushed onto it.
+    let mut list =
+        unsafe { FreeBlockList::new(start_addr + LIST_BLOCK_SIZE, LIST_BLOCK_SIZE, num_of_blocks) };
+
+    list.push(unsafe { &mut *(start_addr as *mut FreeBlock) });
+    assert_list(&list, start_addr, 0, num_of_blocks + 1);
+
+    assert_eq!(list.pop().map(|block| block.addr()), Some(start_addr));
+    assert_list(&list, start_addr, 1, num_of_blocks);
+}
+
+#[kani::proof]
+#[kani::unwind(6)]
+fn free_list_pop_drains_in_address_order() {
+    let mut memory = [0u64; MAX_LIST_BLOCKS * LIST_BLOCK_SIZE / 8];
+    let start_addr = memory.as_mut_ptr() as usize;
+    let num_of_blocks: usize = kani::any();
+    kani::assume(num_of_blocks <= MAX_LIST_BLOCKS);
+    let mut list = unsafe { FreeBlockList::new(start_addr, LIST_BLOCK_SIZE, num_of_blocks) };
+
+    for i in 0..num_of_blocks {
+        let block = list.pop().unwrap();
+        assert_eq!(block.addr(), start_addr + i * LIST_BLOCK_SIZE);
+        assert_list(&list, start_addr, i + 1, num_of_blocks - i - 1);
+    }
+    assert!(list.pop().is_none());
+    assert_eq!(list.len(), 0);
+}
@@ -0,0 +1,155 @@
+use alloc::alloc::Layout;
+use core::fmt;
+use core::mem::size_of;
+
+/// Maximum number of allocations the registry keeps track of at the same time.
+/// Must be a power of two.
+pub const REGISTRY_CAPACITY: usize = 256;
+
+/// Number of slots looked at for an address before it is given up on, which bounds the work
+/// done by every allocation and free.
+const MAX_PROBES: usize = 16;
+
+/// Source location an allocation was made at, as captured by `call_site!`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CallSite {
+    pub file: &'static str,
+    pub line: u32,
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    // 0 marks a slot which never held an allocation.
+    addr: usize,
+    size: usize,
+    align: usize,
+    site: Option<CallSite>,
+    tag: Option<u16>,
+    live: bool,
+}
+
+/// Hash table of allocations recorded with the `track-allocations` feature. Addresses are
+/// placed by a multiplicative hash and probed linearly for at most `MAX_PROBES` slots, so
+/// inserting and removing an allocation takes `O(1)` steps. Entries of freed allocations are
+/// kept until their slot is reused, so a second free of the same address can be reported
+/// together with where it was allocated. Slots are never emptied again, which keeps every
+/// probe sequence intact.
+pub struct Registry {
+    entries: [Entry; REGISTRY_CAPACITY],
+    untracked: usize,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            entries: [Entry {
+                addr: 0,
+                size: 0,
+                align: 0,
+                site: None,
+                tag: None,
+                live: false,
+            }; REGISTRY_CAPACITY],
+            untracked: 0,
+        }
+    }
+
+    fn probes(addr: usize) -> impl Iterator<Item = usize> {
+        const FIBONACCI: u64 = 0x9E37_79B9_7F4A_7C15;
+        let bits = size_of::<usize>() * 8;
+        let hash = addr.wrapping_mul(FIBONACCI as usize)
+            >> (bits - REGISTRY_CAPACITY.trailing_zeros() as usize);
+        (0..MAX_PROBES).map(move |probe| (hash + probe) % REGISTRY_CAPACITY)
+    }
+
+    /// Returns the slot holding `addr`, if any.
+    fn find(&self, addr: usize) -> Option<usize> {
+        for slot in Registry::probes(addr) {
+            match self.entries[slot].addr {
+                a if a == addr => return Some(slot),
+                0 => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Records a new allocation. If no slot is free near the address the allocation is only
+    /// counted.
+    pub fn insert(
+        &mut self,
+        addr: usize,
+        layout: &Layout,
+        site: Option<CallSite>,
+        tag: Option<u16>,
+    ) {
+        let entry = Entry {
+            addr,
+            size: layout.size(),
+            align: layout.align(),
+            site,
+            tag,
+            live: true,
+        };
+        // Reuse the slot of an earlier allocation at the same address first, so no stale
+        // entry for the address is left behind, then the first freed or unused slot.
+        let mut free = None;
+        for slot in Registry::probes(addr) {
+            let e = &self.entries[slot];
+            if e.addr == addr {
+                free = Some(slot);
+                break;
+            }
+            if !e.live && free.is_none() {
+                free = Some(slot);
+            }
+            if e.addr == 0 {
+                break;
+            }
+        }
+        match free {
+            Some(slot) => self.entries[slot] = entry,
+            None => self.untracked += 1,
+        }
+    }
+
+    /// Marks the allocation at `addr` as freed. Returns `Err` with the call site it was
+    /// allocated at, if known, if it was already freed.
+    pub fn remove(&mut self, addr: usize) -> Result<(), Option<CallSite>> {
+        if let Some(slot) = self.find(addr) {
+            let entry = &mut self.entries[slot];
+            if !entry.live {
+                return Err(entry.site);
+            }
+            entry.live = false;
+        }
+        Ok(())
+    }
+
+    /// Returns the address and layout of a live allocation with the given tag.
+    pub fn find_tagged(&self, tag: u16) -> Option<(usize, Layout)> {
+        self.entries
+            .iter()
+            .find(|e| e.live && e.tag == Some(tag))
+            .map(|e| unsafe { (e.addr, Layout::from_size_align_unchecked(e.size, e.align)) })
+    }
+
+    /// Writes one line for every allocation which has not been freed yet.
+    pub fn write_leaks<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        for entry in self.entries.iter().filter(|e| e.live) {
+            write!(w, "{:#x}: {} bytes allocated at ", entry.addr, entry.size)?;
+            match entry.site {
+                Some(site) => writeln!(w, "{}:{}", site.file, site.line)?,
+                None => writeln!(w, "unknown location")?,
+            }
+        }
+        if self.untracked > 0 {
+            writeln!(
+                w,
+                "{} allocations were not tracked because the registry was full",
+                self.untracked
+            )?;
+        }
+        Ok(())
+    }
+}
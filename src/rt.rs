@@ -0,0 +1,75 @@
+use alloc::alloc::{AllocErr, Layout};
+use core::ptr::NonNull;
+
+use super::{HeapAllocator, LockedHeap, MIN_SLAB_SIZE};
+
+/// A view of a `LockedHeap` for real-time code, which only serves allocations of at most
+/// `max` bytes and `max` alignment from the slabs. Larger layouts, and layouts the heap routes
+/// to the linked list allocator, such as with a router of `Heap::set_routing` or while
+/// `Heap::set_bypass` is enabled, are rejected instead of searching the linked list allocator,
+/// so the work done under the heap lock is `O(1)`. The limit is checked when `allocate` is
+/// called, not at compile time. The view shares the heap's spin lock with all other users of
+/// the heap, so its calls still wait for whoever holds the lock, such as a large allocation of
+/// another thread; only the time spent once the lock is taken is bounded. The small object
+/// caches of the `LockedHeap` are not used.
+pub struct RtHeap<'a> {
+    heap: &'a LockedHeap,
+    max: usize,
+}
+
+impl<'a> RtHeap<'a> {
+    /// Creates a view of `heap` serving layouts of at most `max` bytes and alignment.
+    /// Panics if `max` is larger than the largest slab block size.
+    pub fn new(heap: &'a LockedHeap, max: usize) -> RtHeap<'a> {
+        heap_assert!(
+            max <= MIN_SLAB_SIZE,
+            "Maximum should not exceed the largest block size"
+        );
+        RtHeap { heap, max }
+    }
+
+    /// Allocates like `Heap::allocate`, but returns `Err` for layouts over the maximum and for
+    /// layouts the heap would serve from the linked list allocator. Panics if the heap is not
+    /// initialized.
+    pub fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        if layout.size() > self.max || layout.align() > self.max {
+            return Err(AllocErr);
+        }
+        match *self.heap.lock_heap() {
+            Some(ref mut heap) => {
+                let slab = heap.route(&layout);
+                if slab == HeapAllocator::LinkedListAllocator {
+                    return Err(AllocErr);
+                }
+                let result = heap.allocate_traced(layout);
+                #[cfg(not(feature = "tiny"))]
+                self.heap
+                    .counters
+                    .record_allocation(heap, slab, result.is_ok());
+                result
+            }
+            None => heap_panic!("allocate: heap not initialized"),
+        }
+    }
+
+    /// Allocates memory for a `T`, returning `Err` if `T` is over the maximum.
+    pub fn allocate_sized<T>(&self) -> Result<NonNull<T>, AllocErr> {
+        self.allocate(Layout::new::<T>()).map(|ptr| ptr.cast())
+    }
+
+    /// Frees the given allocation like `Heap::deallocate`. `ptr` must have been allocated by
+    /// this view, or by the underlying heap from a slab with a layout within the maximum, thus
+    /// this function is unsafe.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match *self.heap.lock_heap() {
+            Some(ref mut heap) => {
+                #[cfg(not(feature = "tiny"))]
+                let slab = heap.route_free(ptr.as_ptr() as usize, &layout);
+                heap.deallocate(ptr, layout);
+                #[cfg(not(feature = "tiny"))]
+                self.heap.counters.record_deallocation(heap, slab);
+            }
+            None => heap_panic!("deallocate: heap not initialized"),
+        }
+    }
+}
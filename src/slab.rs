@@ -1,33 +1,1606 @@
+use super::{ClassContiguity, GrowError, MoveError};
+#[cfg(feature = "poison")]
+use super::{FREE_POISON, INIT_POISON};
 use alloc::alloc::{AllocErr, Layout};
-use core::ptr::NonNull;
+use core::cmp;
+use core::mem::{self, align_of, size_of};
+use core::ptr::{self, NonNull};
+use core::slice;
 
+/// Number of segments a slab records in itself. Further segments are recorded in its blocks.
+pub const INLINE_SEGMENTS: usize = 2;
+
+/// Size of the pages `Slab::block_usage_heatmap` and `Slab::blocks_in_page` report on.
+const PAGE_SIZE: usize = 4096;
+
+/// Number of pages `Slab::block_usage_heatmap` counts free blocks for in one walk of the free
+/// list.
+#[cfg(not(feature = "tiny"))]
+const HEATMAP_WINDOW: usize = 128;
+
+/// Returns the offset of a field of a `#[repr(C)]` struct with alignment `align`, which follows
+/// a field of `size` bytes at `offset`.
+#[cfg(not(feature = "tiny"))]
+const fn next_field(offset: usize, size: usize, align: usize) -> usize {
+    (offset + size + align - 1) / align * align
+}
+
+/// Offsets of the `Slab` fields a `HeapDescriptor` describes, relative to the slab. They follow
+/// the field order of the `#[repr(C)]` structs, which must be kept in sync.
+#[cfg(not(feature = "tiny"))]
+pub const BLOCK_SIZE_OFFSET: usize = 0;
+#[cfg(not(feature = "tiny"))]
+pub const TOTAL_BLOCKS_OFFSET: usize =
+    next_field(BLOCK_SIZE_OFFSET, size_of::<usize>(), align_of::<usize>());
+#[cfg(not(feature = "tiny"))]
+pub const SEGMENTS_OFFSET: usize = next_field(
+    TOTAL_BLOCKS_OFFSET,
+    size_of::<usize>(),
+    align_of::<Segment>(),
+);
+#[cfg(not(feature = "tiny"))]
+pub const SEGMENT_RECORDS_OFFSET: usize = next_field(
+    SEGMENTS_OFFSET,
+    size_of::<[Segment; INLINE_SEGMENTS]>(),
+    align_of::<usize>(),
+);
+#[cfg(not(feature = "tiny"))]
+const BLOCK_ALIGN_OFFSET: usize = next_field(
+    SEGMENT_RECORDS_OFFSET,
+    size_of::<usize>(),
+    align_of::<usize>(),
+);
+#[cfg(not(feature = "tiny"))]
+const FREE_BLOCK_LIST_OFFSET: usize = next_field(
+    BLOCK_ALIGN_OFFSET,
+    size_of::<usize>(),
+    align_of::<FreeBlockList>(),
+);
+#[cfg(not(feature = "tiny"))]
+pub const FREE_LIST_LEN_OFFSET: usize = FREE_BLOCK_LIST_OFFSET;
+#[cfg(not(feature = "tiny"))]
+pub const FREE_LIST_HEAD_OFFSET: usize = FREE_BLOCK_LIST_OFFSET
+    + next_field(
+        0,
+        size_of::<usize>(),
+        align_of::<Option<&'static mut FreeBlock>>(),
+    );
+#[cfg(not(feature = "tiny"))]
+pub const NEXT_UNINITIALIZED_OFFSET: usize = next_field(
+    FREE_BLOCK_LIST_OFFSET,
+    size_of::<FreeBlockList>(),
+    align_of::<usize>(),
+);
+#[cfg(not(feature = "tiny"))]
+pub const UNINITIALIZED_END_OFFSET: usize = next_field(
+    NEXT_UNINITIALIZED_OFFSET,
+    size_of::<usize>(),
+    align_of::<usize>(),
+);
+/// Offsets of the start address and size of a segment.
+#[cfg(not(feature = "tiny"))]
+pub const SEGMENT_START_ADDR_OFFSET: usize = 0;
+#[cfg(not(feature = "tiny"))]
+pub const SEGMENT_SIZE_OFFSET: usize = next_field(
+    SEGMENT_START_ADDR_OFFSET,
+    size_of::<usize>(),
+    align_of::<usize>(),
+);
+/// Offset of the link to the next free block, relative to a free block.
+#[cfg(not(feature = "tiny"))]
+pub const FREE_BLOCK_NEXT_OFFSET: usize = 0;
+/// Offsets of the link to the next block holding segment records and of the number of records,
+/// relative to a block holding segment records. The records follow at `RECORDS_OFFSET`.
+#[cfg(not(feature = "tiny"))]
+pub const RECORDS_NEXT_OFFSET: usize = 0;
+#[cfg(not(feature = "tiny"))]
+pub const RECORDS_LEN_OFFSET: usize =
+    next_field(RECORDS_NEXT_OFFSET, size_of::<usize>(), align_of::<usize>());
+pub const RECORDS_OFFSET: usize = size_of::<SegmentRecords>();
+
+/// Returns the offsets a `HeapDescriptor` exports, in its field order, taken from the addresses
+/// of the fields rather than computed, so tests can check the constants above against them.
+#[cfg(all(test, not(feature = "tiny")))]
+pub fn field_offsets() -> [(&'static str, usize); 14] {
+    /// A block holding records, as `SegmentRecords::records` reads it.
+    #[repr(C)]
+    struct RecordsBlock {
+        header: SegmentRecords,
+        first: Segment,
+    }
+
+    let offset = |field: usize, base: usize| field - base;
+    let slab = Slab::empty(64);
+    let base = &slab as *const Slab as usize;
+    let segment = slab.segments[0];
+    let segment_base = &segment as *const Segment as usize;
+    let block = RecordsBlock {
+        header: SegmentRecords { next: 0, len: 0 },
+        first: segment,
+    };
+    let block_base = &block as *const RecordsBlock as usize;
+    let free_block = FreeBlock { next: None };
+    [
+        (
+            "block_size",
+            offset(&slab.block_size as *const _ as usize, base),
+        ),
+        (
+            "total_blocks",
+            offset(&slab.total_blocks as *const _ as usize, base),
+        ),
+        (
+            "segments",
+            offset(&slab.segments as *const _ as usize, base),
+        ),
+        (
+            "segment_records",
+            offset(&slab.segment_records as *const _ as usize, base),
+        ),
+        (
+            "free_list_head",
+            offset(&slab.free_block_list.head as *const _ as usize, base),
+        ),
+        (
+            "free_list_len",
+            offset(&slab.free_block_list.len as *const _ as usize, base),
+        ),
+        (
+            "next_uninitialized",
+            offset(&slab.next_uninitialized as *const _ as usize, base),
+        ),
+        (
+            "uninitialized_end",
+            offset(&slab.uninitialized_end as *const _ as usize, base),
+        ),
+        (
+            "segment_start_addr",
+            offset(&segment.start_addr as *const _ as usize, segment_base),
+        ),
+        (
+            "segment_size",
+            offset(&segment.size as *const _ as usize, segment_base),
+        ),
+        (
+            "records_next",
+            offset(&block.header.next as *const _ as usize, block_base),
+        ),
+        (
+            "records_len",
+            offset(&block.header.len as *const _ as usize, block_base),
+        ),
+        (
+            "records",
+            offset(&block.first as *const _ as usize, block_base),
+        ),
+        (
+            "free_block_next",
+            offset(
+                &free_block.next as *const _ as usize,
+                &free_block as *const _ as usize,
+            ),
+        ),
+    ]
+}
+
+/// A contiguous memory range owned by a slab.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Segment {
+    pub start_addr: usize,
+    pub size: usize,
+}
+
+impl Segment {
+    pub fn end_addr(&self) -> usize {
+        self.start_addr + self.size
+    }
+
+    /// Extends the segment by `[start_addr, start_addr + size)` if the memory directly follows
+    /// or precedes it without shifting the blocks of `block_size` bytes it is split into.
+    /// Returns true if the memory is part of the segment afterwards.
+    fn merge(&mut self, start_addr: usize, size: usize, block_size: usize) -> bool {
+        if start_addr >= self.start_addr && start_addr + size <= self.end_addr() {
+            return true;
+        }
+        if self.end_addr() == start_addr && self.size % block_size == 0 {
+            self.size += size;
+            return true;
+        }
+        if start_addr + size == self.start_addr && size % block_size == 0 {
+            self.start_addr = start_addr;
+            self.size += size;
+            return true;
+        }
+        false
+    }
+}
+
+/// Iterator over the segments of a slab, returned by `Slab::segments`.
+#[derive(Clone)]
+pub struct Segments<'a> {
+    inline: [Segment; INLINE_SEGMENTS],
+    inline_index: usize,
+    records: Option<&'a SegmentRecords>,
+    index: usize,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Segment> {
+        while let Some(&segment) = self.inline.get(self.inline_index) {
+            self.inline_index += 1;
+            if segment.size > 0 {
+                return Some(segment);
+            }
+        }
+        while let Some(records) = self.records {
+            if let Some(segment) = records.records().get(self.index) {
+                self.index += 1;
+                return Some(*segment);
+            }
+            self.records = unsafe { SegmentRecords::at(records.next) }.map(|records| &*records);
+            self.index = 0;
+        }
+        None
+    }
+}
+
+/// Header of a block holding the records of the segments a slab can't record in itself, which
+/// follow it in the block. Such blocks are taken from the slab's free blocks, so a slab can be
+/// made of any number of segments without reserving room for them up front.
+#[repr(C)]
+struct SegmentRecords {
+    /// Address of the next block holding records, or 0.
+    next: usize,
+    len: usize,
+}
+
+impl SegmentRecords {
+    /// Returns the block holding records at `addr`, or `None` for address 0.
+    /// Safety: `addr` must be 0 or the address of a block holding records.
+    unsafe fn at(addr: usize) -> Option<&'static mut SegmentRecords> {
+        (addr as *mut SegmentRecords).as_mut()
+    }
+
+    fn records(&self) -> &[Segment] {
+        let start = self as *const SegmentRecords as usize + RECORDS_OFFSET;
+        unsafe { slice::from_raw_parts(start as *const Segment, self.len) }
+    }
+
+    fn records_mut(&mut self) -> &mut [Segment] {
+        let start = self as *mut SegmentRecords as usize + RECORDS_OFFSET;
+        unsafe { slice::from_raw_parts_mut(start as *mut Segment, self.len) }
+    }
+}
+
+/// A pool of equally sized blocks, handed out from a free list in `O(1)`. A `Heap` is made of
+/// one slab per block size, but slabs can also be used on their own. `#[repr(C)]`, like the
+/// structs it is made of, so the offsets of the `HeapDescriptor` can be computed.
+#[repr(C)]
 pub struct Slab {
     block_size: usize,
+    total_blocks: usize,
+    /// Segments of size 0 are unused. Further segments are recorded in blocks of the slab,
+    /// starting with the one at `segment_records` (or 0), which don't count towards
+    /// `total_blocks`.
+    segments: [Segment; INLINE_SEGMENTS],
+    segment_records: usize,
+    /// The alignment every block is guaranteed to have, see `block_align`. Kept up to date by
+    /// `segments_changed`, as it is looked up on every allocation and deallocation.
+    block_align: usize,
     free_block_list: FreeBlockList,
+    /// Blocks in `[next_uninitialized, uninitialized_end)` are free but not yet linked into the
+    /// free list, see `new_lazy`.
+    next_uninitialized: usize,
+    uninitialized_end: usize,
+    /// Whether the uninitialized blocks are known to be zero, see `new_lazy_zeroed`.
+    uninitialized_zeroed: bool,
+    #[cfg(not(feature = "tiny"))]
+    block_map: Option<&'static mut [u64]>,
+    /// Address bit 0 of the block map stands for, the start of the lowest segment.
+    #[cfg(not(feature = "tiny"))]
+    block_map_base: usize,
 }
 
 impl Slab {
+    /// Creates a slab of `block_size` byte blocks over `[start_addr, start_addr + slab_size)`.
+    /// The memory must be valid, aligned to `block_size` and not used for anything else,
+    /// thus this function is unsafe.
     pub unsafe fn new(start_addr: usize, slab_size: usize, block_size: usize) -> Slab {
         let num_of_blocks = slab_size / block_size;
-        Slab {
+        let mut slab = Slab {
             block_size,
+            total_blocks: num_of_blocks,
+            segments: [
+                Segment {
+                    start_addr,
+                    size: slab_size,
+                },
+                Segment {
+                    start_addr: 0,
+                    size: 0,
+                },
+            ],
+            segment_records: 0,
+            block_align: 0,
             free_block_list: FreeBlockList::new(start_addr, block_size, num_of_blocks),
+            next_uninitialized: 0,
+            uninitialized_end: 0,
+            uninitialized_zeroed: false,
+            #[cfg(not(feature = "tiny"))]
+            block_map: None,
+            #[cfg(not(feature = "tiny"))]
+            block_map_base: 0,
+        };
+        slab.segments_changed();
+        slab
+    }
+
+    /// Creates a slab like `new`, for a block size which doesn't need to be a power of two, such
+    /// as 96 byte blocks for 96 byte objects, which would waste a quarter of 128 byte blocks.
+    /// Every block is aligned to `block_align`. Panics if `block_align` is not a power of two of
+    /// at least the pointer alignment, `block_size` is not a multiple of it or `start_addr` is
+    /// not aligned to it. The memory has the same requirements as for `new`, thus this function
+    /// is unsafe.
+    pub unsafe fn new_with_align(
+        start_addr: usize,
+        slab_size: usize,
+        block_size: usize,
+        block_align: usize,
+    ) -> Slab {
+        heap_assert!(
+            block_align.is_power_of_two() && block_align >= align_of::<FreeBlock>(),
+            "Block alignment should be a power of two of at least the pointer alignment"
+        );
+        heap_assert!(
+            block_size % block_align == 0,
+            "Block size should be a multiple of the block alignment"
+        );
+        heap_assert!(
+            start_addr % block_align == 0,
+            "Slab should start aligned to its block alignment"
+        );
+        Slab::new(start_addr, slab_size, block_size)
+    }
+
+    /// Like `new`, but without writing to the blocks: they are handed out by bumping a cursor
+    /// once the free list runs empty, so pages of a large slab are only touched when their
+    /// blocks are first used. Operations which search or reorder the free list link all
+    /// remaining blocks first, see `initialize_remaining`.
+    pub unsafe fn new_lazy(start_addr: usize, slab_size: usize, block_size: usize) -> Slab {
+        let num_of_blocks = slab_size / block_size;
+        if cfg!(debug_assertions) {
+            heap_assert!(
+                blocks_end(start_addr, block_size, num_of_blocks).is_some(),
+                "Blocks should not extend past the end of the address space"
+            );
+        }
+        let mut slab = Slab::empty(block_size);
+        slab.add_segment(start_addr, slab_size);
+        slab.segments_changed();
+        slab.total_blocks = num_of_blocks;
+        slab.next_uninitialized = start_addr;
+        slab.uninitialized_end = start_addr + num_of_blocks * block_size;
+        slab
+    }
+
+    /// Creates a slab like `new_lazy` over memory which is known to be zero, such as a `.bss`
+    /// section or pages fresh from the OS, so blocks handed out before they were ever linked
+    /// into the free list need not be zeroed, see `known_zero_blocks`. With the `poison`
+    /// feature blocks are poisoned as they are first handed out, so none is known to be zero.
+    /// The memory must be zero and meet the requirements of `new`, thus this function is
+    /// unsafe.
+    pub unsafe fn new_lazy_zeroed(start_addr: usize, slab_size: usize, block_size: usize) -> Slab {
+        let mut slab = Slab::new_lazy(start_addr, slab_size, block_size);
+        slab.uninitialized_zeroed = !cfg!(feature = "poison");
+        slab
+    }
+
+    /// Returns the number of free blocks which are known to be zero. These are handed out
+    /// after the other free blocks, as the blocks of a slab created with `new_lazy_zeroed`
+    /// which were never handed out or linked into the free list.
+    pub fn known_zero_blocks(&self) -> usize {
+        if self.uninitialized_zeroed {
+            self.uninitialized_blocks()
+        } else {
+            0
         }
     }
 
+    /// Creates a slab without any memory.
+    fn empty(block_size: usize) -> Slab {
+        let mut slab = Slab {
+            block_size,
+            total_blocks: 0,
+            segments: [Segment {
+                start_addr: 0,
+                size: 0,
+            }; INLINE_SEGMENTS],
+            segment_records: 0,
+            block_align: 0,
+            free_block_list: FreeBlockList { len: 0, head: None },
+            next_uninitialized: 0,
+            uninitialized_end: 0,
+            uninitialized_zeroed: false,
+            #[cfg(not(feature = "tiny"))]
+            block_map: None,
+            #[cfg(not(feature = "tiny"))]
+            block_map_base: 0,
+        };
+        slab.segments_changed();
+        slab
+    }
+
+    /// Returns the number of blocks of a slab created with `new_lazy` which were never handed
+    /// out or linked into the free list.
+    pub fn uninitialized_blocks(&self) -> usize {
+        (self.uninitialized_end - self.next_uninitialized) / self.block_size
+    }
+
+    /// Links the blocks `new_lazy` left uninitialized into the free list, so that they are
+    /// handed out in ascending address order after the blocks already on it.
+    pub fn initialize_remaining(&mut self) {
+        let mut list = FreeBlockList { len: 0, head: None };
+        while self.uninitialized_end > self.next_uninitialized {
+            self.uninitialized_end -= self.block_size;
+            let block = unsafe { self.take_uninitialized(self.uninitialized_end) };
+            list.push(block);
+        }
+        // Blocks already on the free list stay in front, so they are pushed back in reverse.
+        let mut free = FreeBlockList { len: 0, head: None };
+        while let Some(block) = self.free_block_list.pop() {
+            free.push(block);
+        }
+        while let Some(block) = free.pop() {
+            list.push(block);
+        }
+        self.free_block_list = list;
+    }
+
+    /// Prepares the uninitialized block at `addr` to be used, poisoning it like `Heap::new`
+    /// poisons its memory.
+    unsafe fn take_uninitialized(&self, addr: usize) -> &'static mut FreeBlock {
+        #[cfg(feature = "poison")]
+        ptr::write_bytes(addr as *mut u8, INIT_POISON, self.block_size);
+        &mut *(addr as *mut FreeBlock)
+    }
+
+    /// Calls `f` with the address of every free block, linked or uninitialized.
+    fn for_each_free_block<F: FnMut(usize)>(&self, mut f: F) {
+        self.free_block_list.for_each(&mut f);
+        let mut addr = self.next_uninitialized;
+        while addr < self.uninitialized_end {
+            f(addr);
+            addr += self.block_size;
+        }
+    }
+
+    /// Adds the blocks of `[start_addr, start_addr + slab_size)` to the slab, with the same
+    /// requirements on the memory as `new`. Memory which doesn't adjoin the slab's segments is
+    /// recorded in a block of the slab, which takes one of the new blocks if no block holding
+    /// records has room left. Panics if `start_addr` is not aligned to the block size, or for a
+    /// block size which is not a power of two to the largest power of two dividing it, which
+    /// would hand out misaligned blocks, or if the blocks are too small to hold a record.
     pub unsafe fn grow(&mut self, start_addr: usize, slab_size: usize) {
+        heap_assert!(
+            start_addr % self.natural_align() == 0,
+            "Slab should be grown with memory aligned to its block size"
+        );
         let num_of_blocks = slab_size / self.block_size;
+        if num_of_blocks == 0 {
+            return;
+        }
         let mut block_list = FreeBlockList::new(start_addr, self.block_size, num_of_blocks);
+        self.total_blocks += num_of_blocks;
         while let Some(block) = block_list.pop() {
             self.free_block_list.push(block);
         }
+        self.add_segment(start_addr, slab_size);
+        self.segments_changed();
+    }
+
+    /// Like `grow`, but returns an error instead of panicking if `start_addr` is misaligned, the
+    /// memory can't hold a single block or the blocks are too small to record it.
+    pub unsafe fn try_grow(
+        &mut self,
+        start_addr: usize,
+        slab_size: usize,
+    ) -> Result<(), GrowError> {
+        self.check_grow(start_addr, slab_size)?;
+        self.grow(start_addr, slab_size);
+        Ok(())
+    }
+
+    /// Returns the error `try_grow` would return for the given memory, without growing the slab.
+    pub fn check_grow(&self, start_addr: usize, slab_size: usize) -> Result<(), GrowError> {
+        if start_addr % self.natural_align() != 0 {
+            return Err(GrowError::Misaligned);
+        }
+        if slab_size < self.block_size || !self.can_add_segment(start_addr, slab_size) {
+            return Err(GrowError::TooSmall);
+        }
+        Ok(())
+    }
+
+    /// Records a new segment, merging it with an adjacent one.
+    fn add_segment(&mut self, start_addr: usize, size: usize) {
+        heap_assert!(
+            self.try_add_segment(start_addr, size),
+            "Slab should have a free block to record a segment in"
+        );
+    }
+
+    /// Like `add_segment`, but returns false instead of panicking if the segment needs a record
+    /// and there is no room for it.
+    fn try_add_segment(&mut self, start_addr: usize, size: usize) -> bool {
+        let block_size = self.block_size;
+        if self.any_segment_mut(|segment| segment.merge(start_addr, size, block_size)) {
+            return true;
+        }
+        if let Some(unused) = self.segments.iter_mut().find(|segment| segment.size == 0) {
+            *unused = Segment { start_addr, size };
+            return true;
+        }
+        self.push_record(Segment { start_addr, size })
+    }
+
+    /// Returns true if `[start_addr, start_addr + size)` merges with a segment or the slab can
+    /// record it, given a free block to take.
+    fn can_add_segment(&self, start_addr: usize, size: usize) -> bool {
+        self.segments.iter().any(|segment| segment.size == 0)
+            || self.records_per_block() > 0
+            || self
+                .segments()
+                .any(|mut segment| segment.merge(start_addr, size, self.block_size))
+    }
+
+    /// Calls `f` with each segment until it returns true. Returns true if it did.
+    fn any_segment_mut<F: FnMut(&mut Segment) -> bool>(&mut self, mut f: F) -> bool {
+        if self
+            .segments
+            .iter_mut()
+            .any(|segment| segment.size > 0 && f(segment))
+        {
+            return true;
+        }
+        let mut addr = self.segment_records;
+        while let Some(records) = unsafe { SegmentRecords::at(addr) } {
+            if records.records_mut().iter_mut().any(|segment| f(segment)) {
+                return true;
+            }
+            addr = records.next;
+        }
+        false
+    }
+
+    /// Returns the number of segment records a block holds.
+    fn records_per_block(&self) -> usize {
+        self.block_size.saturating_sub(RECORDS_OFFSET) / size_of::<Segment>()
+    }
+
+    /// Calls `f` with the address of every block holding segment records.
+    fn for_each_record_block<F: FnMut(usize)>(&self, mut f: F) {
+        let mut addr = self.segment_records;
+        while let Some(records) = unsafe { SegmentRecords::at(addr) } {
+            f(addr);
+            addr = records.next;
+        }
+    }
+
+    /// Records `segment` in a block holding records which has room left, or else in a free
+    /// block taken for it. Returns false if there is neither.
+    fn push_record(&mut self, segment: Segment) -> bool {
+        let capacity = self.records_per_block();
+        let mut addr = self.segment_records;
+        while let Some(records) = unsafe { SegmentRecords::at(addr) } {
+            if records.len < capacity {
+                records.len += 1;
+                *records.records_mut().last_mut().unwrap() = segment;
+                return true;
+            }
+            addr = records.next;
+        }
+        if capacity == 0 {
+            return false;
+        }
+        let addr = match self.take_free_block() {
+            Some(addr) => addr,
+            None => return false,
+        };
+        let records = unsafe { &mut *(addr as *mut SegmentRecords) };
+        records.next = self.segment_records;
+        records.len = 1;
+        records.records_mut()[0] = segment;
+        self.segment_records = addr;
+        true
+    }
+
+    /// Removes the segment starting at `start_addr` from the segments and returns it, keeping
+    /// the order of the others. A block left without records becomes free.
+    fn remove_record(&mut self, start_addr: usize) -> Option<Segment> {
+        if let Some(inline) = self
+            .segments
+            .iter_mut()
+            .find(|segment| segment.size > 0 && segment.start_addr == start_addr)
+        {
+            let segment = *inline;
+            inline.size = 0;
+            return Some(segment);
+        }
+        let mut link: *mut usize = &mut self.segment_records;
+        unsafe {
+            while let Some(records) = SegmentRecords::at(*link) {
+                if let Some(index) = records
+                    .records()
+                    .iter()
+                    .position(|segment| segment.start_addr == start_addr)
+                {
+                    let segment = records.records()[index];
+                    for i in index + 1..records.len {
+                        let next = records.records()[i];
+                        records.records_mut()[i - 1] = next;
+                    }
+                    records.len -= 1;
+                    if records.len == 0 {
+                        let addr = *link;
+                        *link = records.next;
+                        self.release_block(addr);
+                    }
+                    return Some(segment);
+                }
+                link = &mut records.next;
+            }
+        }
+        None
+    }
+
+    /// Moves the blocks holding segment records for which `inside` returns true to free blocks
+    /// it returns false for, such as to hand over the memory they lie in. Returns false if
+    /// there are not enough such free blocks, leaving the records already moved in place.
+    fn relocate_records<F: Fn(&Slab, usize) -> bool>(&mut self, inside: F) -> bool {
+        let mut link: *mut usize = &mut self.segment_records;
+        unsafe {
+            while *link != 0 {
+                let addr = *link;
+                if inside(self, addr) {
+                    self.initialize_remaining();
+                    let slab: *const Slab = self;
+                    let new_addr = match self
+                        .free_block_list
+                        .remove_first(|block| !inside(&*slab, block))
+                    {
+                        Some(block) => block.addr(),
+                        None => return false,
+                    };
+                    ptr::copy_nonoverlapping(
+                        addr as *const u8,
+                        new_addr as *mut u8,
+                        self.block_size,
+                    );
+                    *link = new_addr;
+                    self.total_blocks -= 1;
+                    self.release_block(addr);
+                }
+                link = &mut (*(*link as *mut SegmentRecords)).next;
+            }
+        }
+        true
+    }
+
+    /// Takes a free block to hold segment records, removing it from the slab's capacity. The
+    /// block map must be refreshed afterwards.
+    fn take_free_block(&mut self) -> Option<usize> {
+        let addr = match self.free_block_list.pop() {
+            Some(block) => block.addr(),
+            None if self.next_uninitialized < self.uninitialized_end => {
+                let addr = self.next_uninitialized;
+                self.next_uninitialized += self.block_size;
+                addr
+            }
+            None => return None,
+        };
+        self.total_blocks -= 1;
+        Some(addr)
+    }
+
+    /// Returns a block which held segment records to the free blocks. The block map must be
+    /// refreshed afterwards.
+    fn release_block(&mut self, addr: usize) {
+        #[cfg(feature = "poison")]
+        unsafe {
+            ptr::write_bytes(addr as *mut u8, FREE_POISON, self.block_size)
+        };
+        self.free_block_list
+            .push(unsafe { &mut *(addr as *mut FreeBlock) });
+        self.total_blocks += 1;
+    }
+
+    /// Moves up to `max_blocks` free blocks to `dest`, which must have the same block size, such
+    /// as between two slabs of one block size backed by different memory banks. Blocks are
+    /// moved in runs of free blocks ending a segment: the segment shrinks by the run, which
+    /// becomes a segment of `dest`, so every block stays owned by a single slab. Free blocks
+    /// followed by an allocated block in their segment are not moved, so fewer blocks than
+    /// requested may move even though the slab has enough free blocks. Returns the number of
+    /// blocks moved, which is 0 if the block sizes differ.
+    pub fn move_free_blocks(&mut self, dest: &mut Slab, max_blocks: usize) -> usize {
+        if self.block_size != dest.block_size {
+            return 0;
+        }
+        self.initialize_remaining();
+        let mut moved = 0;
+        while moved < max_blocks {
+            let run = match self.take_free_run(dest, max_blocks - moved) {
+                Some(run) => run,
+                None => break,
+            };
+            unsafe { dest.grow(run.start_addr, run.size) };
+            moved += run.size / self.block_size;
+        }
+        moved
+    }
+
+    /// Removes the run of up to `max_blocks` free blocks ending the first segment which ends
+    /// with a free block `dest` can be grown with, and returns it. The slab must not have
+    /// uninitialized blocks.
+    fn take_free_run(&mut self, dest: &Slab, max_blocks: usize) -> Option<Segment> {
+        let block_size = self.block_size;
+        let mut index = 0;
+        while let Some(segment) = self.segments().nth(index) {
+            index += 1;
+            let num_of_blocks = segment.size / block_size;
+            let end = segment.start_addr + num_of_blocks * block_size;
+            let mut len = 0;
+            while len < cmp::min(max_blocks, num_of_blocks)
+                && self.block_free(end - (len + 1) * block_size)
+            {
+                len += 1;
+            }
+            let run = Segment {
+                start_addr: end - len * block_size,
+                size: len * block_size,
+            };
+            if len == 0 || dest.check_grow(run.start_addr, run.size).is_err() {
+                continue;
+            }
+            if len == num_of_blocks {
+                self.remove_record(segment.start_addr);
+            } else {
+                self.any_segment_mut(|shrunk| {
+                    if shrunk.start_addr != segment.start_addr {
+                        return false;
+                    }
+                    shrunk.size = run.start_addr - segment.start_addr;
+                    true
+                });
+            }
+            // Blocks of other segments are pushed back in their order.
+            let mut kept = FreeBlockList { len: 0, head: None };
+            while let Some(block) = self.free_block_list.pop() {
+                if block.addr() < run.start_addr || block.addr() >= run.end_addr() {
+                    kept.push(block);
+                }
+            }
+            while let Some(block) = kept.pop() {
+                self.free_block_list.push(block);
+            }
+            self.total_blocks -= len;
+            self.segments_changed();
+            return Some(run);
+        }
+        None
+    }
+
+    /// Moves all free blocks to `dest`, which must have the same block size, such as to donate
+    /// the memory of a slab which is shut down. `dest` takes over the slab's segments along with
+    /// its free blocks, so blocks still allocated from the slab must be freed to `dest`
+    /// afterwards. Returns the number of blocks moved, after which the slab has no free blocks
+    /// and no memory left. A slab without free blocks is left as it is.
+    pub fn move_all_to(&mut self, dest: &mut Slab) -> Result<usize, MoveError> {
+        if self.block_size != dest.block_size {
+            return Err(MoveError::IncompatibleBlockSize);
+        }
+        let moved = self.free_blocks();
+        if moved > 0 {
+            dest.merge(mem::replace(self, Slab::empty(self.block_size)));
+        }
+        Ok(moved)
+    }
+
+    /// Splits the slab into one with blocks `[0, block_index)` and one with the remaining
+    /// blocks, numbering blocks in the order of the slab's segments. Allocated blocks past
+    /// `block_index` stay allocated in the second slab, but all blocks of the first slab must be
+    /// free. Otherwise, if `block_index` lies past the last block, or if the blocks holding
+    /// segment records in the first slab's memory can't be moved to free blocks of the second,
+    /// `self` is returned. The new slabs don't keep the block map.
+    pub fn split_at(mut self, block_index: usize) -> Result<(Slab, Slab), Slab> {
+        self.initialize_remaining();
+        let block_size = self.block_size;
+        let mut remaining = block_index;
+        let mut split = None;
+        for (index, segment) in self.segments().enumerate() {
+            let num_of_blocks = segment.size / block_size;
+            if remaining <= num_of_blocks {
+                split = Some((index, segment.start_addr + remaining * block_size));
+                break;
+            }
+            remaining -= num_of_blocks;
+        }
+        let (split_segment, split_addr) = match split {
+            Some(split) => split,
+            None => return Err(self),
+        };
+        let in_first = |slab: &Slab, addr: usize| {
+            slab.segments()
+                .take(split_segment + 1)
+                .enumerate()
+                .any(|(index, segment)| {
+                    let end = if index < split_segment {
+                        segment.end_addr()
+                    } else {
+                        split_addr
+                    };
+                    addr >= segment.start_addr && addr < end
+                })
+        };
+        let mut free_in_first = 0;
+        self.free_block_list.for_each(|addr| {
+            if in_first(&self, addr) {
+                free_in_first += 1;
+            }
+        });
+        self.for_each_record_block(|addr| {
+            if in_first(&self, addr) {
+                free_in_first += 1;
+            }
+        });
+        if free_in_first != block_index || !self.relocate_records(&in_first) {
+            return Err(self);
+        }
+
+        // The second slab is what remains of `self` once the first slab's blocks and segments
+        // are taken out.
+        let mut first = Slab::empty(block_size);
+        first.total_blocks = block_index;
+        let mut kept = FreeBlockList { len: 0, head: None };
+        while let Some(block) = self.free_block_list.pop() {
+            if in_first(&self, block.addr()) {
+                first.free_block_list.push(block);
+            } else {
+                kept.push(block);
+            }
+        }
+        // Popping and pushing reversed the order in which blocks are handed out.
+        first.free_block_list.reverse();
+        for (index, segment) in self.segments().take(split_segment + 1).enumerate() {
+            if index < split_segment {
+                first.add_segment(segment.start_addr, segment.size);
+            } else if split_addr > segment.start_addr {
+                first.add_segment(segment.start_addr, split_addr - segment.start_addr);
+            }
+        }
+        for _ in 0..split_segment {
+            let start_addr = self.segments().next().unwrap().start_addr;
+            self.remove_record(start_addr);
+        }
+        let segment = self.segments().next().unwrap();
+        if split_addr < segment.end_addr() {
+            self.any_segment_mut(|split| {
+                split.start_addr = split_addr;
+                split.size = segment.end_addr() - split_addr;
+                true
+            });
+        } else {
+            self.remove_record(segment.start_addr);
+        }
+        while let Some(block) = kept.pop() {
+            self.free_block_list.push(block);
+        }
+        self.total_blocks -= block_index;
+        #[cfg(not(feature = "tiny"))]
+        {
+            self.block_map = None;
+        }
+        first.segments_changed();
+        self.segments_changed();
+        Ok((first, self))
+    }
+
+    /// Absorbs `other`, which must have the same block size, taking over its free blocks and
+    /// segments. Blocks allocated from `other` can be freed to `self` afterwards.
+    /// Panics if the block sizes differ or neither slab has a free block left to record the
+    /// segments `other` recorded in itself in.
+    pub fn merge(&mut self, mut other: Slab) {
+        heap_assert!(
+            self.block_size == other.block_size,
+            "Merged slabs should have the same block size"
+        );
+        other.initialize_remaining();
+        while let Some(block) = other.free_block_list.pop() {
+            self.free_block_list.push(block);
+        }
+        self.total_blocks += other.total_blocks;
+        // The blocks holding `other`'s records stay where they are.
+        let mut last = other.segment_records;
+        while let Some(records) = unsafe { SegmentRecords::at(last) } {
+            if records.next == 0 {
+                records.next = self.segment_records;
+                self.segment_records = other.segment_records;
+                break;
+            }
+            last = records.next;
+        }
+        for segment in other.segments.iter().filter(|segment| segment.size > 0) {
+            self.add_segment(segment.start_addr, segment.size);
+        }
+        self.segments_changed();
+    }
+
+    /// Removes the segment starting at `start_addr` from the slab, such as to hand its memory to
+    /// another allocator. Blocks in it which hold the records of other segments are moved to
+    /// free blocks outside of it. Returns false and keeps the segment if no segment starts
+    /// there, not all of its blocks are free or there are not enough free blocks outside of it
+    /// to move the records to.
+    pub fn remove_segment(&mut self, start_addr: usize) -> bool {
+        let segment = match self
+            .segments()
+            .find(|segment| segment.start_addr == start_addr)
+        {
+            Some(segment) => segment,
+            None => return false,
+        };
+        let num_of_blocks = segment.size / self.block_size;
+        let inside =
+            move |_: &Slab, addr: usize| addr >= segment.start_addr && addr < segment.end_addr();
+        let mut free = 0;
+        self.for_each_free_block(|addr| {
+            if inside(self, addr) {
+                free += 1;
+            }
+        });
+        self.for_each_record_block(|addr| {
+            if inside(self, addr) {
+                free += 1;
+            }
+        });
+        if free < num_of_blocks {
+            return false;
+        }
+        let inline = self
+            .segments
+            .iter()
+            .position(|segment| segment.size > 0 && segment.start_addr == start_addr);
+        self.remove_record(start_addr);
+        if !self.relocate_records(&inside) {
+            // A block was freed or has room left since the record was removed.
+            if let Some(index) = inline {
+                self.segments[index] = segment;
+            } else {
+                self.push_record(segment);
+            }
+            return false;
+        }
+        self.initialize_remaining();
+        // Blocks of other segments are pushed back in their order.
+        let mut kept = FreeBlockList { len: 0, head: None };
+        while let Some(block) = self.free_block_list.pop() {
+            if !inside(self, block.addr()) {
+                kept.push(block);
+            }
+        }
+        while let Some(block) = kept.pop() {
+            self.free_block_list.push(block);
+        }
+        self.total_blocks -= num_of_blocks;
+        self.segments_changed();
+        true
+    }
+
+    /// Removes the first segment whose blocks are all free like `remove_segment` and returns it.
+    pub fn take_free_segment(&mut self) -> Option<Segment> {
+        let mut index = 0;
+        while let Some(segment) = self.segments().nth(index) {
+            if self.remove_segment(segment.start_addr) {
+                return Some(segment);
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// Returns true if `addr` lies in one of the slab's segments.
+    pub fn owns(&self, addr: usize) -> bool {
+        self.segments()
+            .any(|segment| addr >= segment.start_addr && addr < segment.end_addr())
+    }
+
+    /// Returns true if `addr` is the start of a block of this slab.
+    pub fn is_block_start(&self, addr: usize) -> bool {
+        self.segments().any(|segment| {
+            addr >= segment.start_addr
+                && addr < segment.end_addr()
+                && (addr - segment.start_addr) % self.block_size == 0
+        })
+    }
+
+    /// Returns true if the block at `ptr` is on the free list. Walks the whole list, so it
+    /// is `O(n)` in the number of free blocks.
+    /// If the slab has a block map, it is used instead, which takes `O(1)`.
+    #[cfg(feature = "debug-checks")]
+    pub fn is_block_free(&self, ptr: NonNull<u8>) -> bool {
+        self.block_free(ptr.as_ptr() as usize)
+    }
+
+    /// Returns true if a free block starts at `addr`, see `is_block_free`.
+    fn block_free(&self, addr: usize) -> bool {
+        #[cfg(not(feature = "tiny"))]
+        {
+            if let Some(map) = self.block_map() {
+                return self.is_block_start(addr) && map_bit(map, self.block_index(addr));
+            }
+        }
+        let mut free = false;
+        self.for_each_free_block(|block| free |= block == addr);
+        free
+    }
+
+    /// Returns the segments of memory the slab's blocks lie in, starting with the ones recorded
+    /// in the slab itself, which include the one it was created with, followed by the ones
+    /// recorded in its blocks.
+    pub fn segments<'a>(&'a self) -> Segments<'a> {
+        Segments {
+            inline: self.segments,
+            inline_index: 0,
+            records: unsafe { SegmentRecords::at(self.segment_records) }.map(|records| &*records),
+            index: 0,
+        }
+    }
+
+    /// Returns the bit of the block starting at `addr`, one of the slab's blocks, in the block
+    /// map.
+    #[cfg(not(feature = "tiny"))]
+    fn block_index(&self, addr: usize) -> usize {
+        (addr - self.block_map_base) / self.block_size
+    }
+
+    /// Returns the number of pairs of free blocks lying next to each other in memory, such as to
+    /// tell whether free memory is clustered or scattered between allocated blocks. Every run
+    /// of `n` free blocks holds `n - 1` pairs, so this is derived from `contiguity` and has the
+    /// same cost.
+    pub fn free_adjacent_pairs(&self) -> usize {
+        self.free_blocks() - self.contiguity().runs
+    }
+
+    /// Returns the longest run of free blocks lying next to each other in memory and the number
+    /// of runs, which may span adjacent segments. Looks up the neighbours of each free block
+    /// on the free list, so it is `O(n²)` in the number of free blocks. If the slab has a block
+    /// map, it is used instead, which takes `O(n * s)` for `n` free blocks and `s` segments.
+    pub fn contiguity(&self) -> ClassContiguity {
+        let mut contiguity = ClassContiguity::default();
+        self.for_each_free_block(|addr| {
+            // Runs are measured from their first block.
+            if addr >= self.block_size && self.block_free(addr - self.block_size) {
+                return;
+            }
+            let mut run = 1;
+            while self.block_free(addr + run * self.block_size) {
+                run += 1;
+            }
+            contiguity.runs += 1;
+            contiguity.longest_run = cmp::max(contiguity.longest_run, run);
+        });
+        contiguity
+    }
+
+    /// Returns the number of `u64` words a block map of this slab needs, one bit for every
+    /// block size step from the start of the lowest segment to the end of the highest one.
+    #[cfg(not(feature = "tiny"))]
+    pub fn block_map_words(&self) -> usize {
+        let start = self.segments().map(|segment| segment.start_addr).min();
+        let end = self.segments().map(|segment| segment.end_addr()).max();
+        match (start, end) {
+            (Some(start), Some(end)) => ((end - start) / self.block_size + 63) / 64,
+            _ => 0,
+        }
+    }
+
+    /// Starts keeping a bitfield in `map` where bit `i` is set if the block `i` block sizes past
+    /// the start of the lowest segment is free, so a block's bit is found in `O(1)`. Bits of
+    /// addresses between segments stay clear. It is updated on every allocation and
+    /// deallocation and rebuilt when the slab's segments change. If the slab grows past what
+    /// `map` can hold, the map is dropped. Returns false if `map` is shorter than
+    /// `block_map_words`.
+    #[cfg(not(feature = "tiny"))]
+    pub fn enable_block_map(&mut self, map: &'static mut [u64]) -> bool {
+        if map.len() < self.block_map_words() {
+            return false;
+        }
+        self.block_map = Some(map);
+        self.refresh_block_map();
+        true
+    }
+
+    /// Returns the block map enabled by `enable_block_map`.
+    #[cfg(not(feature = "tiny"))]
+    pub fn block_map(&self) -> Option<&[u64]> {
+        self.block_map.as_ref().map(|map| &map[..])
+    }
+
+    /// Calls `f` with the start and size of each run of whole pages in the slab's segments
+    /// which only holds free blocks according to the block map, merging neighbouring pages.
+    /// Reports nothing if no block map is kept.
+    #[cfg(not(feature = "tiny"))]
+    pub fn for_each_free_page_range<F: FnMut(usize, usize)>(&self, mut f: F) {
+        let map = match self.block_map {
+            Some(ref map) => map,
+            None => return,
+        };
+        for segment in self.segments() {
+            let segment_blocks = segment.size / self.block_size;
+            let first_block = self.block_index(segment.start_addr);
+            let mut run_start = None;
+            let mut page_start = (segment.start_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            while page_start + PAGE_SIZE <= segment.end_addr() {
+                let offset = page_start - segment.start_addr;
+                let start = first_block + offset / self.block_size;
+                let end = first_block
+                    + cmp::min(
+                        (offset + PAGE_SIZE + self.block_size - 1) / self.block_size,
+                        segment_blocks,
+                    );
+                let free = (start..end).all(|index| map_bit(map, index));
+                match run_start {
+                    None if free => run_start = Some(page_start),
+                    Some(run) if !free => {
+                        f(run, page_start - run);
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+                page_start += PAGE_SIZE;
+            }
+            if let Some(run) = run_start {
+                f(run, page_start - run);
+            }
+        }
+    }
+
+    /// Relinks the free list from the block map, handing out blocks in the order of the
+    /// slab's segments afterwards. This recovers a slab whose free blocks lost their content,
+    /// such as the pages reported by `for_each_free_page_range` which were left out of a
+    /// hibernation image. Returns false and keeps the free list if no block map is kept.
+    /// The block map must be up to date and the blocks it marks as free must not be used for
+    /// anything else, thus this function is unsafe.
+    #[cfg(not(feature = "tiny"))]
+    pub unsafe fn rebuild_free_list(&mut self) -> bool {
+        let map = match self.block_map.take() {
+            Some(map) => map,
+            None => return false,
+        };
+        let mut list = FreeBlockList { len: 0, head: None };
+        for segment in self.segments() {
+            for i in 0..segment.size / self.block_size {
+                let addr = segment.start_addr + i * self.block_size;
+                if map_bit(&map, self.block_index(addr)) {
+                    #[cfg(feature = "poison")]
+                    ptr::write_bytes(addr as *mut u8, FREE_POISON, self.block_size);
+                    list.push(&mut *(addr as *mut FreeBlock));
+                }
+            }
+        }
+        list.reverse();
+        // The old list runs through memory which may have been overwritten, so it must not
+        // be walked.
+        core::mem::forget(core::mem::replace(&mut self.free_block_list, list));
+        self.next_uninitialized = self.uninitialized_end;
+        self.block_map = Some(map);
+        true
+    }
+
+    /// Returns the number of pages `block_usage_heatmap` writes, counting each segment's pages
+    /// from its start.
+    #[cfg(not(feature = "tiny"))]
+    pub fn heatmap_pages(&self) -> usize {
+        self.segments()
+            .map(|segment| (segment.size + PAGE_SIZE - 1) / PAGE_SIZE)
+            .sum()
+    }
+
+    /// Writes the share of allocated blocks of each page of the slab's segments to `out`, from
+    /// 0 for none to 255 for all, in the order of the segments. A block crossing a page boundary
+    /// counts towards every page it touches. Walks the whole free list once for every
+    /// `HEATMAP_WINDOW` pages, so it is `O(n * p / HEATMAP_WINDOW)` for `n` free blocks and `p`
+    /// pages. Returns the number of pages written.
+    /// Panics if `out` is shorter than `heatmap_pages`.
+    #[cfg(not(feature = "tiny"))]
+    pub fn block_usage_heatmap(&self, out: &mut [u8]) -> usize {
+        let num_of_pages = self.heatmap_pages();
+        heap_assert!(
+            out.len() >= num_of_pages,
+            "Heatmap should have room for every page of the slab"
+        );
+        // A page touches at most `PAGE_SIZE / block_size + 1` blocks, which doesn't fit in a
+        // byte for small blocks, so free blocks are counted separately.
+        let mut free = [0u16; HEATMAP_WINDOW];
+        let mut first_page = 0;
+        while first_page < num_of_pages {
+            let window = first_page..cmp::min(first_page + HEATMAP_WINDOW, num_of_pages);
+            for count in free.iter_mut() {
+                *count = 0;
+            }
+            self.for_each_free_block(|addr| {
+                if let (Some(first), Some(last)) = (
+                    self.page_index(addr),
+                    self.page_index(addr + self.block_size - 1),
+                ) {
+                    for page in cmp::max(first, window.start)..cmp::min(last + 1, window.end) {
+                        free[page - window.start] += 1;
+                    }
+                }
+            });
+            let mut page = 0;
+            for segment in self.segments() {
+                let segment_blocks = segment.size / self.block_size;
+                let mut offset = 0;
+                while offset < segment.size {
+                    if page >= window.start && page < window.end {
+                        let page_end = cmp::min(offset + PAGE_SIZE, segment.size);
+                        let first_block = offset / self.block_size;
+                        let end_block = cmp::min(
+                            (page_end + self.block_size - 1) / self.block_size,
+                            segment_blocks,
+                        );
+                        let blocks = end_block.saturating_sub(first_block);
+                        let used = blocks.saturating_sub(free[page - window.start] as usize);
+                        out[page] = if blocks == 0 {
+                            0
+                        } else {
+                            (used * 255 / blocks) as u8
+                        };
+                    }
+                    offset += PAGE_SIZE;
+                    page += 1;
+                }
+            }
+            first_page = window.end;
+        }
+        num_of_pages
+    }
+
+    /// Returns the index of the page containing `addr`, counting each segment's pages from its
+    /// start.
+    #[cfg(not(feature = "tiny"))]
+    fn page_index(&self, addr: usize) -> Option<usize> {
+        let mut index = 0;
+        for segment in self.segments() {
+            if addr >= segment.start_addr && addr < segment.end_addr() {
+                return Some(index + (addr - segment.start_addr) / PAGE_SIZE);
+            }
+            index += (segment.size + PAGE_SIZE - 1) / PAGE_SIZE;
+        }
+        None
+    }
+
+    /// Rebuilds the block map from the free list.
+    #[cfg(not(feature = "tiny"))]
+    fn refresh_block_map(&mut self) {
+        let map = match self.block_map.take() {
+            Some(map) => map,
+            None => return,
+        };
+        if map.len() < self.block_map_words() {
+            return;
+        }
+        self.block_map_base = self
+            .segments()
+            .map(|segment| segment.start_addr)
+            .min()
+            .unwrap_or(0);
+        for word in map.iter_mut() {
+            *word = 0;
+        }
+        {
+            let slab = &*self;
+            slab.for_each_free_block(|addr| {
+                let index = slab.block_index(addr);
+                map[index / 64] |= 1 << (index % 64);
+            });
+        }
+        self.block_map = Some(map);
+    }
+
+    /// Updates the bit of the block at `addr` in the block map.
+    #[cfg(not(feature = "tiny"))]
+    fn mark_block(&mut self, addr: usize, free: bool) {
+        let index = self.block_index(addr);
+        if let Some(ref mut map) = self.block_map {
+            if free {
+                map[index / 64] |= 1 << (index % 64);
+            } else {
+                map[index / 64] &= !(1 << (index % 64));
+            }
+        }
+    }
+
+    /// Creates a slab which claims `num_of_blocks` blocks regardless of `slab_size`, so tests can
+    /// hand out blocks lying outside of the slab's segment.
+    #[cfg(all(test, debug_assertions))]
+    pub unsafe fn new_with_num_of_blocks(
+        start_addr: usize,
+        slab_size: usize,
+        block_size: usize,
+        num_of_blocks: usize,
+    ) -> Slab {
+        let mut slab = Slab::new(start_addr, slab_size, block_size);
+        let new_list = FreeBlockList::new(start_addr, block_size, num_of_blocks);
+        // The old list's blocks were overwritten by the new list, so it must not be walked.
+        core::mem::forget(core::mem::replace(&mut slab.free_block_list, new_list));
+        slab.total_blocks = num_of_blocks;
+        slab
     }
 
     pub fn allocate(&mut self, _layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        match self.free_block_list.pop() {
-            Some(block) => Ok(unsafe { NonNull::new_unchecked(block.addr() as *mut u8) }),
-            None => Err(AllocErr),
+        let block = match self.free_block_list.pop() {
+            Some(block) => block,
+            None if self.next_uninitialized < self.uninitialized_end => {
+                let block = unsafe { self.take_uninitialized(self.next_uninitialized) };
+                self.next_uninitialized += self.block_size;
+                block
+            }
+            None => return Err(AllocErr),
+        };
+        let addr = block.addr();
+        if cfg!(debug_assertions) {
+            self.check_bounds(addr);
         }
+        #[cfg(not(feature = "tiny"))]
+        self.mark_block(addr, false);
+        Ok(unsafe { NonNull::new_unchecked(addr as *mut u8) })
+    }
+
+    /// Allocates a block from the segment starting at `start`, such as the memory bank local to
+    /// the calling core. Searches the free list for the first block inside the segment, so it
+    /// is `O(n)` in the number of free blocks. Returns `Err` if no segment starts at `start` or
+    /// the segment has no free block.
+    pub fn allocate_from_segment(&mut self, start: usize) -> Result<NonNull<u8>, AllocErr> {
+        let segment = self
+            .segments()
+            .find(|segment| segment.start_addr == start)
+            .ok_or(AllocErr)?;
+        self.initialize_remaining();
+        let block = self
+            .free_block_list
+            .remove_first(|addr| addr >= segment.start_addr && addr < segment.end_addr())
+            .ok_or(AllocErr)?;
+        let addr = block.addr();
+        #[cfg(not(feature = "tiny"))]
+        self.mark_block(addr, false);
+        Ok(unsafe { NonNull::new_unchecked(addr as *mut u8) })
+    }
+
+    /// Allocates the block starting at `addr` if it is free, such as to reserve a block at a
+    /// fixed address. Searches the free list, so it is `O(n)` in the number of free blocks.
+    /// Returns `Err` if `addr` does not start a block of the slab or the block is allocated.
+    pub fn allocate_if_address(&mut self, addr: usize) -> Result<NonNull<u8>, AllocErr> {
+        if !self.is_block_start(addr) {
+            return Err(AllocErr);
+        }
+        self.initialize_remaining();
+        self.free_block_list
+            .remove_first(|block| block == addr)
+            .ok_or(AllocErr)?;
+        #[cfg(not(feature = "tiny"))]
+        self.mark_block(addr, false);
+        Ok(unsafe { NonNull::new_unchecked(addr as *mut u8) })
+    }
+
+    /// Allocates a free block whose address has the bits selected by `mask` equal to `color`,
+    /// such as the cache set bits of a physically indexed cache. Searches the free list for the
+    /// first such block, so it is `O(n)` in the number of free blocks. Blocks `new_lazy` left
+    /// uninitialized are searched by address without touching them, and only the ones below
+    /// the block found are linked into the free list. Returns `Err` if no free block has the
+    /// color.
+    pub fn allocate_with_color(
+        &mut self,
+        color: usize,
+        mask: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        let addr = match self
+            .free_block_list
+            .remove_first(|addr| addr & mask == color)
+        {
+            Some(block) => block.addr(),
+            None => {
+                let addr = self
+                    .first_uninitialized_with_color(color, mask)
+                    .ok_or(AllocErr)?;
+                while self.next_uninitialized < addr {
+                    let block = unsafe { self.take_uninitialized(self.next_uninitialized) };
+                    self.free_block_list.push(block);
+                    self.next_uninitialized += self.block_size;
+                }
+                unsafe { self.take_uninitialized(addr) };
+                self.next_uninitialized += self.block_size;
+                addr
+            }
+        };
+        if cfg!(debug_assertions) {
+            self.check_bounds(addr);
+        }
+        #[cfg(not(feature = "tiny"))]
+        self.mark_block(addr, false);
+        Ok(unsafe { NonNull::new_unchecked(addr as *mut u8) })
+    }
+
+    /// Returns the lowest uninitialized block whose address has the bits selected by `mask`
+    /// equal to `color`. Addresses in the same window of the lowest bit of `mask` share these
+    /// bits, so one block of every window is checked.
+    fn first_uninitialized_with_color(&self, color: usize, mask: usize) -> Option<usize> {
+        let window = mask & mask.wrapping_neg();
+        let mut addr = self.next_uninitialized;
+        while addr < self.uninitialized_end {
+            if addr & mask == color {
+                return Some(addr);
+            }
+            if window == 0 {
+                return None;
+            }
+            let skipped = (addr | (window - 1)).checked_add(1)? - addr;
+            addr = addr
+                .checked_add((skipped + self.block_size - 1) / self.block_size * self.block_size)?;
+        }
+        None
+    }
+
+    /// Panics if the block at `addr` does not lie completely inside one of the slab's segments.
+    fn check_bounds(&self, addr: usize) {
+        let in_bounds = self.segments().any(|segment| {
+            addr >= segment.start_addr && addr + self.block_size <= segment.end_addr()
+        });
+        if !in_bounds {
+            heap_panic!(
+                "block {:#x}..{:#x} lies outside of the slab's segments",
+                addr,
+                addr + self.block_size
+            );
+        }
+    }
+
+    /// Allocates a block which is never freed, removing it from the slab's capacity.
+    pub fn allocate_static(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let block = self.allocate(layout)?;
+        self.total_blocks -= 1;
+        Ok(block)
+    }
+
+    /// Reverses the order in which free blocks are handed out. A new slab hands out its blocks
+    /// in ascending address order.
+    pub fn reverse_free_list(&mut self) {
+        self.initialize_remaining();
+        self.free_block_list.reverse();
+    }
+
+    /// Returns the address of the first free block, or 0 if there is none, and the number of
+    /// free blocks. Together with the slab's memory they describe the free list, such as for
+    /// saving it before hibernation and restoring it with `restore_free_list`. Panics if the
+    /// slab has uninitialized blocks, which `initialize_remaining` links into the free list.
+    pub fn free_list_raw_parts(&self) -> (usize, usize) {
+        heap_assert!(
+            self.uninitialized_blocks() == 0,
+            "Free list should hold every free block"
+        );
+        let head = self
+            .free_block_list
+            .head
+            .as_ref()
+            .map_or(0, |block| block.addr());
+        (head, self.free_block_list.len())
+    }
+
+    /// Replaces the free list with the one described by `free_list_raw_parts`, without
+    /// rebuilding it block by block. The blocks' memory, including the links at their start,
+    /// must be unchanged since the parts were taken, thus this function is unsafe.
+    pub unsafe fn restore_free_list(&mut self, head: usize, len: usize) {
+        let list = FreeBlockList::from_raw_parts(head as *mut FreeBlock, len);
+        // The current list's blocks may be linked into the restored list, so it must not be
+        // walked.
+        core::mem::forget(core::mem::replace(&mut self.free_block_list, list));
+        self.next_uninitialized = self.uninitialized_end;
+        #[cfg(not(feature = "tiny"))]
+        self.refresh_block_map();
+    }
+
+    /// Reads back the free list links `new` wrote into up to `samples` blocks, spread evenly
+    /// over the slab, to check that the memory holds what was written, such as to catch bad RAM
+    /// or wrong mappings at boot. Only meaningful before any block is allocated or freed.
+    /// Returns the address of the first sampled block whose link does not point to the block
+    /// after it. Uninitialized blocks of a slab created with `new_lazy` hold no links, so
+    /// there is nothing to check.
+    pub fn verify_new_free_list(&self, samples: usize) -> Result<(), usize> {
+        let start_addr = match self.segments().next() {
+            Some(segment) => segment.start_addr,
+            None => return Ok(()),
+        };
+        let num_of_blocks = self.total_blocks - self.uninitialized_blocks();
+        let step = cmp::max(num_of_blocks / cmp::max(samples, 1), 1);
+        for i in (0..num_of_blocks).step_by(step) {
+            let addr = start_addr + i * self.block_size;
+            let expected = if i + 1 < num_of_blocks {
+                addr + self.block_size
+            } else {
+                0
+            };
+            if unsafe { ptr::read_volatile(addr as *const usize) } != expected {
+                return Err(addr);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the alignment every block of the slab is guaranteed to have. That is the block
+    /// size, unless a segment starts at an address which is not a multiple of it. For a block
+    /// size which is not a power of two, it is at most the largest power of two dividing it.
+    pub fn block_align(&self) -> usize {
+        self.block_align
+    }
+
+    /// Updates what is derived from the slab's segments, its block alignment and block map.
+    fn segments_changed(&mut self) {
+        self.block_align = self
+            .segments()
+            .map(|segment| addr_align(segment.start_addr))
+            .fold(self.natural_align(), cmp::min);
+        #[cfg(not(feature = "tiny"))]
+        self.refresh_block_map();
+    }
+
+    /// Returns the largest power of two dividing the block size, the alignment successive
+    /// blocks keep.
+    fn natural_align(&self) -> usize {
+        self.block_size & self.block_size.wrapping_neg()
+    }
+
+    /// Returns the number of blocks starting in `[page_addr, page_addr + 4096)`, such as to
+    /// track pages being made read-only or paged in. Blocks straddling the start of the page
+    /// belong to the page before.
+    pub fn blocks_in_page(&self, page_addr: usize) -> usize {
+        let page_end = page_addr.saturating_add(PAGE_SIZE);
+        self.segments()
+            .map(|segment| {
+                let num_of_blocks = segment.size / self.block_size;
+                // Index of the first block starting at or after the given address.
+                let block_index = |addr: usize| {
+                    let offset = addr.saturating_sub(segment.start_addr);
+                    cmp::min(
+                        (offset + self.block_size - 1) / self.block_size,
+                        num_of_blocks,
+                    )
+                };
+                block_index(page_end) - block_index(page_addr)
+            })
+            .sum()
+    }
+
+    pub fn total_blocks(&self) -> usize {
+        self.total_blocks
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.free_block_list.len() + self.uninitialized_blocks()
+    }
+
+    /// Writes zeros over every free block, except for the free list pointer at its start.
+    pub fn fill_with_zeros(&mut self) {
+        let block_size = self.block_size;
+        self.for_each_free_block(|addr| unsafe {
+            let header_size = size_of::<FreeBlock>();
+            ptr::write_bytes((addr + header_size) as *mut u8, 0, block_size - header_size);
+        });
     }
 
     /// Safety: ptr must have been previously allocated by self.
@@ -37,17 +1610,53 @@ impl Slab {
         // &mut FreeBlock would be undefined behavior.
         #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
         let ptr = ptr.as_ptr() as *mut FreeBlock;
+        #[cfg(feature = "poison")]
+        ptr::write_bytes(ptr as *mut u8, FREE_POISON, self.block_size);
+        #[cfg(not(feature = "tiny"))]
+        self.mark_block(ptr as usize, true);
         self.free_block_list.push(&mut *ptr);
     }
 }
 
-struct FreeBlockList {
+/// Returns true if bit `index` of the block map `map` is set.
+#[cfg(not(feature = "tiny"))]
+fn map_bit(map: &[u64], index: usize) -> bool {
+    map[index / 64] & (1 << (index % 64)) != 0
+}
+
+/// Returns the largest power of two dividing `addr`. Address 0 counts as aligned to the largest
+/// power of two a `usize` holds.
+pub fn addr_align(addr: usize) -> usize {
+    match addr & addr.wrapping_neg() {
+        0 => !(usize::max_value() >> 1),
+        align => align,
+    }
+}
+
+/// Returns the end address of `num_of_blocks` blocks starting at `start_addr`, or `None` if
+/// they would extend past the end of the address space.
+pub fn blocks_end(start_addr: usize, block_size: usize, num_of_blocks: usize) -> Option<usize> {
+    num_of_blocks
+        .checked_mul(block_size)
+        .and_then(|size| size.checked_add(start_addr))
+}
+
+#[repr(C)]
+pub struct FreeBlockList {
     len: usize,
     head: Option<&'static mut FreeBlock>,
 }
 
 impl FreeBlockList {
-    unsafe fn new(start_addr: usize, block_size: usize, num_of_blocks: usize) -> FreeBlockList {
+    /// Links the blocks from the highest address down, so `pop` returns them in ascending
+    /// address order.
+    pub unsafe fn new(start_addr: usize, block_size: usize, num_of_blocks: usize) -> FreeBlockList {
+        if cfg!(debug_assertions) {
+            heap_assert!(
+                blocks_end(start_addr, block_size, num_of_blocks).is_some(),
+                "Blocks should not extend past the end of the address space"
+            );
+        }
         let mut new_list = FreeBlockList { len: 0, head: None };
         for i in (0..num_of_blocks).rev() {
             let new_block = (start_addr + i * block_size) as *mut FreeBlock;
@@ -56,7 +1665,48 @@ impl FreeBlockList {
         new_list
     }
 
-    fn pop(&mut self) -> Option<&'static mut FreeBlock> {
+    /// Rebuilds a list from its first block and length. In debug builds the list is checked to
+    /// end after exactly `len` blocks.
+    /// Safety: `head` must be null or point to a valid list of free blocks.
+    unsafe fn from_raw_parts(head: *mut FreeBlock, len: usize) -> FreeBlockList {
+        if cfg!(debug_assertions) {
+            let mut current = head as *const FreeBlock;
+            for _ in 0..len {
+                heap_assert!(!current.is_null(), "Free list ends before its length");
+                current = match (*current).next {
+                    Some(ref next) => *next as *const FreeBlock,
+                    None => ptr::null(),
+                };
+            }
+            heap_assert!(current.is_null(), "Free list is longer than its length");
+        }
+        FreeBlockList {
+            len,
+            head: head.as_mut(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn reverse(&mut self) {
+        let mut reversed = FreeBlockList { len: 0, head: None };
+        while let Some(block) = self.pop() {
+            reversed.push(block);
+        }
+        *self = reversed;
+    }
+
+    pub fn for_each<F: FnMut(usize)>(&self, mut f: F) {
+        let mut current = self.head.as_ref();
+        while let Some(block) = current {
+            f(block.addr());
+            current = block.next.as_ref();
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<&'static mut FreeBlock> {
         self.head.take().map(|node| {
             self.head = node.next.take();
             self.len -= 1;
@@ -64,7 +1714,30 @@ impl FreeBlockList {
         })
     }
 
-    fn push(&mut self, free_block: &'static mut FreeBlock) {
+    /// Unlinks and returns the first block whose address `f` returns true for.
+    fn remove_first<F: FnMut(usize) -> bool>(
+        &mut self,
+        mut f: F,
+    ) -> Option<&'static mut FreeBlock> {
+        let mut link: *mut Option<&'static mut FreeBlock> = &mut self.head;
+        unsafe {
+            loop {
+                let found = match *link {
+                    Some(ref block) => f(block.addr()),
+                    None => return None,
+                };
+                if found {
+                    let block = (*link).take().unwrap();
+                    *link = block.next.take();
+                    self.len -= 1;
+                    return Some(block);
+                }
+                link = &mut (*link).as_mut().unwrap().next;
+            }
+        }
+    }
+
+    pub fn push(&mut self, free_block: &'static mut FreeBlock) {
         free_block.next = self.head.take();
         self.len += 1;
         self.head = Some(free_block);
@@ -77,12 +1750,13 @@ impl Drop for FreeBlockList {
     }
 }
 
-struct FreeBlock {
+#[repr(C)]
+pub struct FreeBlock {
     next: Option<&'static mut FreeBlock>,
 }
 
 impl FreeBlock {
-    fn addr(&self) -> usize {
+    pub fn addr(&self) -> usize {
         self as *const _ as usize
     }
 }
@@ -0,0 +1,97 @@
+use core::alloc::GlobalAlloc;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::alloc::Layout;
+
+use super::{Heap, LockedHeap};
+
+/// A `LockedHeap` over its own backing storage, which is initialized on first use, so it can
+/// be the global allocator without any init call. Declare one with `static_slab_heap!`.
+pub struct StaticSlabHeap {
+    heap: LockedHeap,
+    storage: fn() -> (usize, usize),
+    initialized: AtomicBool,
+}
+
+impl StaticSlabHeap {
+    /// Creates a heap over the `(start, size)` range returned by `storage`, which is only called
+    /// on first use. The range must be valid for `Heap::new` and not used for anything else,
+    /// thus this function is unsafe. `static_slab_heap!` takes care of that.
+    pub const unsafe fn new(storage: fn() -> (usize, usize)) -> StaticSlabHeap {
+        StaticSlabHeap {
+            heap: LockedHeap::empty(),
+            storage,
+            initialized: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the `(start, size)` range of the backing storage.
+    pub fn region(&self) -> (usize, usize) {
+        (self.storage)()
+    }
+
+    fn ensure_initialized(&self) {
+        if self.initialized.load(Ordering::Acquire) {
+            return;
+        }
+        {
+            let mut heap = self.heap.lock();
+            if heap.is_none() {
+                let (start, size) = self.region();
+                *heap = Some(unsafe { Heap::new(start, size) });
+            }
+        }
+        #[cfg(not(feature = "tiny"))]
+        self.heap.refresh_counters();
+        self.initialized.store(true, Ordering::Release);
+    }
+}
+
+/// Gives access to the inner `LockedHeap`, initializing it first.
+impl Deref for StaticSlabHeap {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        self.ensure_initialized();
+        &self.heap
+    }
+}
+
+unsafe impl GlobalAlloc for StaticSlabHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.ensure_initialized();
+        self.heap.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
+
+/// Declares a `StaticSlabHeap` with `size` bytes of page aligned storage, such as
+/// `static_slab_heap!(static HEAP: 128 * 1024);`, which can be the `#[global_allocator]`.
+/// Compilation fails unless `size` is a multiple of `MIN_HEAP_SIZE`.
+#[macro_export]
+macro_rules! static_slab_heap {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $size:expr $(;)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::StaticSlabHeap = {
+            #[repr(align(4096))]
+            struct Storage([u8; $size]);
+
+            static mut STORAGE: Storage = Storage([0; $size]);
+
+            // The array lengths only match if the size is a multiple of `MIN_HEAP_SIZE`.
+            #[allow(dead_code)]
+            const SIZE_CHECK: [(); 0] = [(); ($size % $crate::MIN_HEAP_SIZE)
+                + ($size < $crate::MIN_HEAP_SIZE) as usize];
+
+            fn storage() -> (usize, usize) {
+                unsafe { (&STORAGE as *const Storage as usize, $size) }
+            }
+
+            unsafe { $crate::StaticSlabHeap::new(storage) }
+        };
+    };
+}
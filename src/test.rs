@@ -1,6 +1,12 @@
 use super::*;
 use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::mem::{align_of, size_of};
+#[cfg(feature = "stats")]
+use core::sync::atomic::AtomicUsize;
+#[cfg(not(feature = "tiny"))]
+use core::sync::atomic::Ordering;
 
 const HEAP_SIZE: usize = 8 * 4096;
 const BIG_HEAP_SIZE: usize = HEAP_SIZE * 10;
@@ -47,6 +53,37 @@ fn new_big_heap() -> Heap {
     heap
 }
 
+// Like the helpers above, but the memory is leaked, so it outlives the heap built on it.
+fn leaked_heap() -> Heap {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    unsafe { Heap::new(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE) }
+}
+
+fn leaked_locked_heap() -> LockedHeap {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let locked_heap = LockedHeap::empty();
+    unsafe {
+        locked_heap.init(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE);
+    }
+    locked_heap
+}
+
+fn leaked_big_heap() -> Heap {
+    let test_heap = Box::leak(Box::new(TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    }));
+    unsafe {
+        Heap::new(
+            &test_heap.heap_space[0] as *const u8 as usize,
+            BIG_HEAP_SIZE,
+        )
+    }
+}
+
 #[test]
 fn oom() {
     let mut heap = new_heap();
@@ -227,4 +264,3954 @@ fn allocate_one_8192_block() {
     unsafe {
         heap.deallocate(x, layout.clone());
     }
-}
\ No newline at end of file
+}
+#[test]
+fn allocate_and_free_prefixed() {
+    let mut heap = leaked_big_heap();
+    let sizes = [
+        1, 8, 48, 49, 64, 100, 1000, 2040, 4080, 4081, 4096, 5000, 8192,
+    ];
+
+    for &size in sizes.iter() {
+        for &align in [1, 8, 64].iter() {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let (prefixed_layout, _) = Heap::prefixed_layout(&layout).unwrap();
+            let class = Heap::layout_to_allocator(&prefixed_layout);
+            let free_before = heap.free_blocks(class);
+
+            let ptr = heap.allocate_prefixed(layout).unwrap();
+            assert_eq!(ptr.as_ptr() as usize % align, 0);
+            match class {
+                HeapAllocator::LinkedListAllocator => {}
+                _ => assert_eq!(heap.free_blocks(class), free_before - 1),
+            }
+            unsafe {
+                core::ptr::write_bytes(ptr.as_ptr(), 0xab, size);
+                heap.free_prefixed(ptr);
+            }
+            assert_eq!(heap.free_blocks(class), free_before);
+        }
+    }
+}
+
+#[test]
+fn prefixed_allocation_is_promoted() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let (prefixed_layout, offset) = Heap::prefixed_layout(&layout).unwrap();
+    assert_eq!(offset, PREFIX_HEADER_SIZE);
+    match Heap::layout_to_allocator(&prefixed_layout) {
+        HeapAllocator::Slab128Bytes => {}
+        _ => panic!("prefixed 64 byte allocation should be served by the 128 byte slab"),
+    }
+
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    let (prefixed_layout, _) = Heap::prefixed_layout(&layout).unwrap();
+    match Heap::layout_to_allocator(&prefixed_layout) {
+        HeapAllocator::LinkedListAllocator => {}
+        _ => panic!("prefixed 4096 byte allocation should be served by the linked list"),
+    }
+}
+
+#[test]
+fn total_free_blocks() {
+    let mut heap = leaked_heap();
+    let slab_size = HEAP_SIZE / NUM_OF_SLABS;
+    let expected: usize = [64, 128, 256, 512, 1024, 2048, 4096]
+        .iter()
+        .map(|block_size| slab_size / block_size)
+        .sum();
+    assert_eq!(heap.total_free_blocks(), expected);
+
+    let small = Layout::from_size_align(32, 8).unwrap();
+    let big = Layout::from_size_align(3000, 8).unwrap();
+    let x = heap.allocate(small.clone()).unwrap();
+    assert_eq!(heap.total_free_blocks(), expected - 1);
+    let y = heap.allocate(big.clone()).unwrap();
+    assert_eq!(heap.total_free_blocks(), expected - 2);
+
+    unsafe {
+        heap.deallocate(x, small);
+        heap.deallocate(y, big);
+    }
+    assert_eq!(heap.total_free_blocks(), expected);
+}
+
+#[test]
+fn total_blocks_across_all_slabs() {
+    let mut heap = leaked_heap();
+    let total = heap.total_blocks_across_all_slabs();
+    assert_eq!(total, heap.total_free_blocks());
+
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(heap.total_blocks_across_all_slabs(), total);
+
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    unsafe {
+        heap.grow(
+            &test_heap.heap_space[0] as *const u8 as usize,
+            4096,
+            HeapAllocator::Slab512Bytes,
+        );
+        heap.deallocate(x, layout);
+    }
+    assert_eq!(heap.total_blocks_across_all_slabs(), total + 8);
+}
+
+#[cfg(feature = "poison")]
+#[test]
+fn poison_never_allocated_and_freed_blocks() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let mut heap = unsafe { Heap::new(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE) };
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let header = size_of::<usize>();
+
+    let x = heap.allocate(layout.clone()).unwrap();
+    let untouched = unsafe { x.as_ptr().add(5 * 64) };
+    for i in header..64 {
+        assert_eq!(unsafe { *untouched.add(i) }, INIT_POISON);
+    }
+
+    unsafe {
+        core::ptr::write_bytes(x.as_ptr(), 0, 64);
+        heap.deallocate(x, layout);
+    }
+    for i in header..64 {
+        assert_eq!(unsafe { *x.as_ptr().add(i) }, FREE_POISON);
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn new_from_env() {
+    std::env::remove_var("SLAB_HEAP_ADDR");
+    std::env::remove_var("SLAB_HEAP_SIZE");
+    assert!(unsafe { Heap::new_from_env() }.is_none());
+
+    std::env::set_var("SLAB_HEAP_SIZE", "0x8000");
+    let mut heap = unsafe { Heap::new_from_env() }.unwrap();
+    assert_eq!(heap.total_blocks_across_all_slabs(), 127);
+
+    let layout = Layout::from_size_align(4000, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    unsafe {
+        heap.deallocate(x, layout);
+    }
+    std::env::remove_var("SLAB_HEAP_SIZE");
+}
+
+#[test]
+fn allocate_exact() {
+    let mut heap = leaked_big_heap();
+    let x = heap.allocate_exact(256, 8).unwrap();
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab256Bytes), 159);
+    assert!(heap.allocate_exact(200, 8).is_err());
+    assert!(heap.allocate_exact(64, 128).is_err());
+    let y = heap.allocate_exact(5000, 8).unwrap();
+
+    unsafe {
+        heap.deallocate(x, Layout::from_size_align(256, 8).unwrap());
+        heap.deallocate(y, Layout::from_size_align(5000, 8).unwrap());
+    }
+}
+
+#[test]
+#[should_panic]
+fn new_rejects_unaligned_start() {
+    unsafe {
+        Heap::new(4096 + 8, HEAP_SIZE);
+    }
+}
+
+#[test]
+fn locked_heap_into_inner() {
+    assert!(LockedHeap::empty().into_inner().is_none());
+
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let locked_heap = LockedHeap::empty();
+    unsafe {
+        locked_heap.init(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE);
+    }
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let x = unsafe { locked_heap.alloc(layout.clone()) };
+
+    let mut heap = locked_heap.into_inner().unwrap();
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 63);
+    unsafe {
+        heap.deallocate(NonNull::new(x).unwrap(), layout);
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 64);
+}
+
+#[test]
+fn is_contiguous() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    assert!(heap.is_contiguous());
+
+    unsafe {
+        heap.grow(start + HEAP_SIZE, 4096, HeapAllocator::Slab512Bytes);
+    }
+    assert!(heap.is_contiguous());
+
+    unsafe {
+        heap.grow(
+            start + HEAP_SIZE + 2 * 4096,
+            4096,
+            HeapAllocator::Slab64Bytes,
+        );
+    }
+    assert!(!heap.is_contiguous());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn new_boxed() {
+    let mut heap = Heap::new_boxed(BIG_HEAP_SIZE);
+    let small = Layout::from_size_align(100, 8).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+
+    let x = heap.allocate(small.clone()).unwrap();
+    let y = heap.allocate(large.clone()).unwrap();
+    unsafe {
+        core::ptr::write_bytes(y.as_ptr(), 0xab, 5000);
+        heap.deallocate(x, small);
+        heap.deallocate(y, large);
+    }
+    drop(heap);
+}
+
+#[test]
+fn reserved_linked_list_region() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let ll_start = start + 7 * (BIG_HEAP_SIZE / NUM_OF_SLABS);
+    let reserve = 8192 + 64;
+    let mut heap = unsafe { Heap::new_with_reserved_ll_region(start, BIG_HEAP_SIZE, reserve) };
+    assert!(heap.is_contiguous());
+
+    let layout = Layout::from_size_align(8000, 8).unwrap();
+    let mut ptrs = [None; 4];
+    for ptr in ptrs.iter_mut() {
+        let x = heap.allocate(layout.clone()).unwrap();
+        assert!(x.as_ptr() as usize >= ll_start + reserve);
+        *ptr = Some(x);
+    }
+
+    let reserved = heap.allocate(layout.clone()).unwrap();
+    assert!((reserved.as_ptr() as usize) < ll_start + reserve);
+    assert!(heap.allocate(layout.clone()).is_err());
+
+    unsafe {
+        heap.deallocate(reserved, layout.clone());
+    }
+    let again = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(again, reserved);
+
+    unsafe {
+        heap.deallocate(ptrs[0].unwrap(), layout.clone());
+    }
+    let normal = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(normal, ptrs[0].unwrap());
+}
+
+#[test]
+fn zero_linked_list_reserve() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let heap = unsafe { Heap::new_with_reserved_ll_region(start, HEAP_SIZE, 0) };
+    assert_eq!(heap.reserved_linked_list_allocator.size(), 0);
+    assert_eq!(
+        heap.linked_list_allocator.bottom(),
+        start + 7 * (HEAP_SIZE / NUM_OF_SLABS)
+    );
+    assert_eq!(heap.linked_list_allocator.size(), HEAP_SIZE / NUM_OF_SLABS);
+}
+
+#[test]
+fn grow_from_static() {
+    const SPACE: usize = HEAP_SIZE + 17 * 4096;
+    #[repr(align(4096))]
+    struct GrowSpace([u8; SPACE]);
+    static mut GROW_SPACE: GrowSpace = GrowSpace([0; SPACE]);
+
+    let space: &'static mut [u8] = unsafe { &mut GROW_SPACE.0 };
+    let (heap_space, rest) = space.split_at_mut(HEAP_SIZE);
+    let mut heap = unsafe { Heap::new(heap_space.as_mut_ptr() as usize, HEAP_SIZE) };
+
+    let (ll_space, rest) = rest.split_at_mut(5000);
+    assert_eq!(
+        heap.grow_from_static(ll_space, HeapAllocator::LinkedListAllocator),
+        Ok(5000)
+    );
+    let (misaligned, mut rest) = rest.split_at_mut(2 * 4096 - 5000);
+    assert_eq!(
+        heap.grow_from_static(misaligned, HeapAllocator::Slab64Bytes),
+        Err(GrowError::Misaligned)
+    );
+
+    let slabs = [
+        (HeapAllocator::Slab64Bytes, 64),
+        (HeapAllocator::Slab128Bytes, 128),
+        (HeapAllocator::Slab256Bytes, 256),
+        (HeapAllocator::Slab512Bytes, 512),
+        (HeapAllocator::Slab1024Bytes, 1024),
+        (HeapAllocator::Slab2048Bytes, 2048),
+    ];
+    for &(slab, block_size) in slabs.iter() {
+        let (chunk, tail) = rest.split_at_mut(2 * 4096);
+        rest = tail;
+        let free_before = heap.free_blocks(slab);
+        let (mem, _) = chunk.split_at_mut(3 * block_size + 50);
+        assert_eq!(heap.grow_from_static(mem, slab), Ok(3 * block_size));
+        assert_eq!(heap.free_blocks(slab), free_before + 3);
+    }
+
+    let (too_small, rest) = rest.split_at_mut(4096);
+    let (too_small, _) = too_small.split_at_mut(4000);
+    assert_eq!(
+        heap.grow_from_static(too_small, HeapAllocator::Slab4096Bytes),
+        Err(GrowError::TooSmall)
+    );
+    assert_eq!(heap.grow_from_static_auto(rest), Ok(2 * 4096));
+    // The third segment of these slabs is recorded in one of their blocks.
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 64 + 3 + 63);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 32 + 3 + 31);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab256Bytes), 16 + 3);
+}
+
+#[test]
+fn linked_list_region() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let slab_size = HEAP_SIZE / NUM_OF_SLABS;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    assert_eq!(heap.linked_list_region_start(), start + 7 * slab_size);
+    assert_eq!(heap.linked_list_region_size(), slab_size);
+
+    unsafe {
+        heap.grow(start + HEAP_SIZE, 4096, HeapAllocator::LinkedListAllocator);
+    }
+    assert_eq!(heap.linked_list_region_start(), start + 7 * slab_size);
+    assert_eq!(heap.linked_list_region_size(), slab_size + 4096);
+
+    let heap = unsafe { Heap::new_with_reserved_ll_region(start, HEAP_SIZE, 1024) };
+    assert_eq!(heap.linked_list_region_start(), start + 7 * slab_size);
+    assert_eq!(heap.linked_list_region_size(), slab_size);
+}
+
+#[test]
+fn linked_list_limits() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let heap = unsafe { Heap::new(start, BIG_HEAP_SIZE) };
+    let ll_start = heap.linked_list_region_start();
+    let (max_size, max_align) = heap.linked_list_limits();
+    assert_eq!(max_size, BIG_HEAP_SIZE / NUM_OF_SLABS);
+    assert!(max_align >= 4096);
+    assert_eq!(ll_start % max_align, 0);
+    assert_ne!(ll_start % (2 * max_align), 0);
+}
+
+#[test]
+fn addr_align() {
+    assert_eq!(slab::addr_align(4096 + 64), 64);
+    assert_eq!(slab::addr_align(1), 1);
+    assert_eq!(slab::addr_align(0), !(usize::max_value() >> 1));
+}
+
+#[test]
+fn heap_from_symbols() {
+    static mut HEAP_SYMBOLS: TestBigHeap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let base = unsafe { HEAP_SYMBOLS.heap_space.as_ptr() };
+    let start = unsafe { base.add(100) };
+    let end = unsafe { base.add(2 * MIN_HEAP_SIZE + 4096 + 100) };
+
+    let heap = unsafe { Heap::from_symbols(start, end) }.unwrap();
+    let slab_size = 2 * MIN_HEAP_SIZE / NUM_OF_SLABS;
+    assert_eq!(
+        heap.linked_list_region_start(),
+        base as usize + 4096 + 7 * slab_size
+    );
+    assert_eq!(heap.linked_list_region_size(), slab_size);
+    assert!(heap.is_contiguous());
+
+    let exact_end = unsafe { base.add(4096 + MIN_HEAP_SIZE) };
+    assert_eq!(
+        Heap::heap_range(start as usize, exact_end as usize),
+        Ok((base as usize + 4096, MIN_HEAP_SIZE))
+    );
+    let short_end = unsafe { base.add(4096 + MIN_HEAP_SIZE - 1) };
+    assert!(unsafe { Heap::from_symbols(start, short_end) }.err() == Some(HeapInitError::TooSmall));
+    assert!(unsafe { Heap::from_symbols(end, start) }.err() == Some(HeapInitError::InvalidRange));
+
+    let locked_heap = LockedHeap::empty();
+    assert_eq!(
+        unsafe { locked_heap.init_from_symbols(base, base) },
+        Err(HeapInitError::TooSmall)
+    );
+    assert!(locked_heap.lock().is_none());
+}
+
+#[test]
+fn fill_free_blocks_with_zeros() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let mut heap = unsafe { Heap::new(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE) };
+    let layout = Layout::from_size_align(512, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    let y = heap.allocate(layout.clone()).unwrap();
+    unsafe {
+        core::ptr::write_bytes(x.as_ptr(), 0xff, 512);
+        core::ptr::write_bytes(y.as_ptr(), 0xff, 512);
+        heap.deallocate(x, layout.clone());
+    }
+
+    heap.fill_free_blocks_with_zeros();
+    for i in size_of::<usize>()..512 {
+        assert_eq!(unsafe { *x.as_ptr().add(i) }, 0);
+        assert_eq!(unsafe { *y.as_ptr().add(i) }, 0xff);
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab512Bytes), 7);
+    assert_eq!(heap.allocate(layout.clone()).unwrap(), x);
+    for _ in 0..6 {
+        heap.allocate(layout.clone()).unwrap();
+    }
+    assert!(heap.allocate(layout).is_err());
+}
+
+#[test]
+fn allocate_static() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let mut heap = unsafe {
+        Heap::new(
+            &test_heap.heap_space[0] as *const u8 as usize,
+            BIG_HEAP_SIZE,
+        )
+    };
+    let capacity = heap.capacity();
+    assert_eq!(capacity, BIG_HEAP_SIZE);
+
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(heap.capacity(), capacity);
+    unsafe {
+        heap.deallocate(x, layout);
+    }
+
+    let ring = heap.allocate_static(1000, 512).unwrap();
+    assert_eq!(ring.as_ptr() as usize % 512, 0);
+    assert_eq!(heap.capacity(), capacity - 1024);
+    assert_eq!(
+        heap.total_blocks_across_all_slabs(),
+        heap.total_free_blocks()
+    );
+
+    heap.allocate_static(6000, 4096).unwrap();
+    assert_eq!(heap.capacity(), capacity - 1024 - 6000);
+}
+
+#[test]
+fn try_deallocate() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let mut heap = unsafe {
+        Heap::new(
+            &test_heap.heap_space[0] as *const u8 as usize,
+            BIG_HEAP_SIZE,
+        )
+    };
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let medium = Layout::from_size_align(128, 8).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    let x = heap.allocate(small.clone()).unwrap();
+    let y = heap.allocate(large.clone()).unwrap();
+    let free = heap.total_free_blocks();
+
+    let on_stack = 0u64;
+    let stack_ptr = NonNull::new(&on_stack as *const u64 as *mut u8).unwrap();
+    assert_eq!(
+        unsafe { heap.try_deallocate(stack_ptr, small.clone()) },
+        Err(DeallocError::NotOwned)
+    );
+    let interior = unsafe { NonNull::new_unchecked(x.as_ptr().add(8)) };
+    assert_eq!(
+        unsafe { heap.try_deallocate(interior, small.clone()) },
+        Err(DeallocError::Misaligned)
+    );
+    assert_eq!(
+        unsafe { heap.try_deallocate(x, medium) },
+        Err(DeallocError::ClassMismatch)
+    );
+    assert_eq!(
+        unsafe { heap.try_deallocate(y, small.clone()) },
+        Err(DeallocError::ClassMismatch)
+    );
+    let unaligned = unsafe { NonNull::new_unchecked(y.as_ptr().add(1)) };
+    assert_eq!(
+        unsafe { heap.try_deallocate(unaligned, large.clone()) },
+        Err(DeallocError::Misaligned)
+    );
+    assert_eq!(heap.total_free_blocks(), free);
+
+    assert_eq!(unsafe { heap.try_deallocate(x, small) }, Ok(()));
+    assert_eq!(unsafe { heap.try_deallocate(y, large.clone()) }, Ok(()));
+    assert_eq!(heap.total_free_blocks(), free + 1);
+    assert_eq!(heap.allocate(large).unwrap(), y);
+
+    let locked_heap = LockedHeap::empty();
+    assert_eq!(
+        unsafe { locked_heap.try_deallocate(stack_ptr, Layout::from_size_align(8, 8).unwrap()) },
+        Err(DeallocError::NotOwned)
+    );
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[cfg_attr(
+    not(feature = "tiny"),
+    should_panic(expected = "outside of the slab's segments")
+)]
+#[cfg_attr(feature = "tiny", should_panic)]
+fn slab_allocation_out_of_bounds() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { slab::Slab::new_with_num_of_blocks(start, 4096, 64, 65) };
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    for _ in 0..64 {
+        slab.allocate(layout.clone()).unwrap();
+    }
+    let _ = slab.allocate(layout);
+}
+
+#[test]
+fn balance_between_tiers() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    // Two 64 byte slabs, as if over two memory banks.
+    let bank_b = start + 8 * 4096;
+    let mut heap = unsafe {
+        Heap::new_from_raw_parts(
+            [
+                Slab::new(start, 4096, 64),
+                Slab::new(bank_b, 4096, 64),
+                Slab::new(start + 2 * 4096, 4096, 256),
+                Slab::new(start + 3 * 4096, 4096, 512),
+                Slab::new(start + 4 * 4096, 4096, 1024),
+                Slab::new(start + 5 * 4096, 4096, 2048),
+                Slab::new(start + 6 * 4096, 4096, 4096),
+            ],
+            linked_list_allocator::Heap::new(start + 7 * 4096, 4096),
+        )
+    };
+    let (a, b) = (HeapAllocator::Slab64Bytes, HeapAllocator::Slab128Bytes);
+
+    assert_eq!(heap.balance(a, b, 10), 10);
+    assert_eq!(heap.free_blocks(a), 54);
+    assert_eq!(heap.free_blocks(b), 74);
+    // The last blocks of the first slab moved and are owned by the second slab alone.
+    assert_eq!(heap.owner_of(start + 53 * 64), Some(a));
+    assert_eq!(heap.owner_of(start + 54 * 64), Some(b));
+    assert_eq!(heap.owner_of(start + 63 * 64), Some(b));
+    assert_eq!(heap.slab(a).unwrap().segments().count(), 1);
+    assert_eq!(heap.slab(b).unwrap().segments().count(), 2);
+    assert_eq!(heap.balance(a, HeapAllocator::LinkedListAllocator, 10), 0);
+    assert_eq!(heap.balance(a, HeapAllocator::Slab256Bytes, 10), 0);
+    assert_eq!(heap.balance(a, a, 10), 0);
+
+    // Blocks allocated from the second slab are freed to it, whatever the layout routes to.
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let moved = heap.allocate_from(b, layout.clone()).unwrap();
+    assert!(heap.slab(b).unwrap().owns(moved.as_ptr() as usize));
+    unsafe { heap.deallocate(moved, layout.clone()) };
+    assert_eq!(heap.free_blocks(a), 54);
+    assert_eq!(heap.free_blocks(b), 74);
+
+    // Only free blocks ending a segment move.
+    let ptrs: Vec<_> = (0..54)
+        .map(|_| heap.allocate(layout.clone()).unwrap())
+        .collect();
+    for ptr in ptrs.iter().skip(1).step_by(2) {
+        unsafe { heap.deallocate(*ptr, layout.clone()) };
+    }
+    assert_eq!(heap.free_blocks(a), 27);
+    assert_eq!(heap.balance(a, b, 100), 1);
+    assert_eq!(heap.free_blocks(a), 26);
+    assert_eq!(heap.free_blocks(b), 75);
+
+    // Moving the blocks back merges them with the first slab's segment.
+    for ptr in ptrs.iter().step_by(2) {
+        unsafe { heap.deallocate(*ptr, layout.clone()) };
+    }
+    assert_eq!(heap.balance(b, a, 100), 75);
+    assert_eq!(heap.free_blocks(a), 128);
+    assert_eq!(heap.slab(a).unwrap().segments().count(), 2);
+    assert_eq!(
+        heap.total_blocks_across_all_slabs(),
+        128 + 16 + 8 + 4 + 2 + 1
+    );
+}
+
+#[cfg(feature = "track-allocations")]
+#[test]
+fn leak_report_contains_call_sites() {
+    use alloc::string::String;
+    use core::fmt::Write;
+
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let mut heap = unsafe { Heap::new(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE) };
+    let layout = Layout::from_size_align(100, 8).unwrap();
+
+    let site_a = call_site!();
+    heap.allocate_at(layout.clone(), site_a).unwrap();
+    let site_b = call_site!();
+    heap.allocate_at(layout.clone(), site_b).unwrap();
+    let site_c = call_site!();
+    let freed = heap.allocate_at(layout.clone(), site_c).unwrap();
+    unsafe {
+        heap.deallocate(freed, layout);
+    }
+
+    let mut report = String::new();
+    heap.write_leak_report(&mut report).unwrap();
+    let mut expected = String::new();
+    for &(site, leaked) in [(site_a, true), (site_b, true), (site_c, false)].iter() {
+        expected.clear();
+        write!(expected, "{}:{}", site.file, site.line).unwrap();
+        assert_eq!(report.contains(&expected[..]), leaked);
+    }
+}
+
+#[cfg(feature = "track-allocations")]
+#[test]
+fn registry_tracks_half_its_capacity() {
+    use alloc::string::String;
+
+    let mut heap = leaked_big_heap();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut ptrs = [NonNull::dangling(); REGISTRY_CAPACITY / 2];
+    for ptr in ptrs.iter_mut() {
+        *ptr = heap.allocate_at(layout.clone(), call_site!()).unwrap();
+    }
+
+    let mut report = String::new();
+    heap.write_leak_report(&mut report).unwrap();
+    assert_eq!(report.lines().count(), REGISTRY_CAPACITY / 2);
+    assert!(!report.contains("not tracked"));
+
+    for &ptr in ptrs.iter() {
+        unsafe { heap.deallocate(ptr, layout.clone()) };
+    }
+    report.clear();
+    heap.write_leak_report(&mut report).unwrap();
+    assert!(report.is_empty());
+}
+
+#[cfg(feature = "track-allocations")]
+#[test]
+#[cfg_attr(not(feature = "tiny"), should_panic(expected = "double free"))]
+#[cfg_attr(feature = "tiny", should_panic)]
+fn double_free_is_reported() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let mut heap = unsafe { Heap::new(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE) };
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let x = heap.allocate_traced(layout.clone()).unwrap();
+    unsafe {
+        heap.deallocate(x, layout.clone());
+        heap.deallocate(x, layout);
+    }
+}
+
+#[cfg(feature = "stats")]
+struct CountingTelemetry {
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    ooms: AtomicUsize,
+    grown_bytes: AtomicUsize,
+}
+
+#[cfg(feature = "stats")]
+impl HeapTelemetry for CountingTelemetry {
+    fn on_allocate(&self, _layout: &Layout, _slab: HeapAllocator, _ptr: NonNull<u8>) {
+        self.allocations.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_deallocate(&self, _layout: &Layout, _slab: HeapAllocator, _ptr: NonNull<u8>) {
+        self.deallocations.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_oom(&self, _layout: &Layout) {
+        self.ooms.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_grow(&self, _slab: HeapAllocator, added: usize) {
+        self.grown_bytes.fetch_add(added, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn telemetry_hooks() {
+    static TELEMETRY: CountingTelemetry = CountingTelemetry {
+        allocations: AtomicUsize::new(0),
+        deallocations: AtomicUsize::new(0),
+        ooms: AtomicUsize::new(0),
+        grown_bytes: AtomicUsize::new(0),
+    };
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new_with_telemetry(start, HEAP_SIZE, &TELEMETRY) };
+
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    unsafe {
+        heap.deallocate(x, layout);
+    }
+    assert!(heap
+        .allocate(Layout::from_size_align(HEAP_SIZE, 4096).unwrap())
+        .is_err());
+    unsafe {
+        heap.grow(start + HEAP_SIZE, 4096, HeapAllocator::Slab64Bytes);
+    }
+
+    assert_eq!(TELEMETRY.allocations.load(Ordering::SeqCst), 1);
+    assert_eq!(TELEMETRY.deallocations.load(Ordering::SeqCst), 1);
+    assert_eq!(TELEMETRY.ooms.load(Ordering::SeqCst), 1);
+    assert_eq!(TELEMETRY.grown_bytes.load(Ordering::SeqCst), 4096);
+}
+
+#[test]
+fn suggest_slab_sizes_for_bimodal_workload() {
+    let histogram = [
+        (3000, 800),
+        (16, 5),
+        (96, 1000),
+        (40, 3),
+        (90, 50),
+        (200, 4),
+        (700, 2),
+        (2900, 30),
+        (1500, 3),
+        (4000, 1),
+    ];
+    let sizes = suggest_slab_sizes(&histogram, 3);
+    assert_eq!(sizes, [96, 3000, 4000, 0, 0, 0, 0]);
+
+    let sizes = suggest_slab_sizes(&histogram, 7);
+    assert!(sizes.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(sizes.contains(&96));
+    assert!(sizes.contains(&3000));
+    assert_eq!(sizes[6], 4000);
+
+    assert_eq!(suggest_slab_sizes(&[], 3), [0; 7]);
+}
+
+#[test]
+fn validate_slab_config() {
+    assert_eq!(
+        super::validate_slab_config(&[
+            (64, 64),
+            (128, 128),
+            (256, 256),
+            (512, 512),
+            (1024, 1024),
+            (2048, 2048),
+            (4096, 4096),
+        ]),
+        Ok(())
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(64, 64), (96, 32), (192, 64)]),
+        Ok(())
+    );
+    assert_eq!(super::validate_slab_config(&[(16, 8), (256, 256)]), Ok(()));
+    assert_eq!(super::validate_slab_config(&[]), Ok(()));
+
+    assert_eq!(
+        super::validate_slab_config(&[(64, 64), (128, 128), (128, 128)]),
+        Err(ConfigError::NotIncreasing { size: 128 })
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(256, 256), (64, 64)]),
+        Err(ConfigError::NotIncreasing { size: 64 })
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(4, 4), (64, 64)]),
+        Err(ConfigError::TooSmall { size: 4 })
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(64, 64), (96, 48)]),
+        Err(ConfigError::InvalidAlign {
+            size: 96,
+            align: 48
+        })
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(64, 2)]),
+        Err(ConfigError::InvalidAlign { size: 64, align: 2 })
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(64, 64), (96, 64)]),
+        Err(ConfigError::NotMultipleOfAlign {
+            size: 96,
+            align: 64
+        })
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(64, 64), (8192, 8192)]),
+        Err(ConfigError::TooLarge { size: 8192 })
+    );
+    assert_eq!(
+        super::validate_slab_config(&[(8, 8); 8]),
+        Err(ConfigError::TooManySizes)
+    );
+}
+
+#[test]
+fn grow_from_map() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let blocks_before = heap.total_blocks_across_all_slabs();
+
+    let map = [
+        // Misaligned, trimmed to two pages.
+        (start + 8 * 4096 + 100, 3 * 4096),
+        // Smaller than a page.
+        (start + 12 * 4096, 100),
+        // Overlaps the heap's initial memory.
+        (start + 2 * 4096, 4096),
+        (start + 20 * 4096, 9 * 4096),
+        // Overlaps the first region of the map.
+        (start + 9 * 4096, 4096),
+    ];
+    let summary = unsafe { heap.grow_from_map(map.iter().cloned()) }.unwrap();
+
+    assert_eq!(summary.bytes_used, 11 * 4096);
+    assert_eq!(
+        summary.bytes_skipped,
+        (4096 - 100) + 100 + 100 + 4096 + 4096
+    );
+    assert_eq!(
+        summary.slab_bytes,
+        [2 * 4096, 2 * 4096, 2 * 4096, 2 * 4096, 4096, 4096, 4096,]
+    );
+    assert!(heap.total_blocks_across_all_slabs() > blocks_before);
+    // One page from `new` and one from the map.
+    let layout = Layout::from_size_align(4096, 4096).unwrap();
+    for _ in 0..2 {
+        heap.allocate(layout.clone()).unwrap();
+    }
+    assert!(heap.allocate(layout).is_err());
+}
+
+#[cfg(feature = "debug-checks")]
+#[test]
+fn is_block_free() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, BIG_HEAP_SIZE) };
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    let y = heap.allocate(layout.clone()).unwrap();
+    assert!(!heap.is_block_free(x));
+    unsafe {
+        heap.deallocate(x, layout);
+    }
+    assert!(heap.is_block_free(x));
+    assert!(!heap.is_block_free(y));
+
+    let large = Layout::from_size_align(4097, 8).unwrap();
+    let z = heap.allocate(large.clone()).unwrap();
+    unsafe {
+        heap.deallocate(z, large);
+    }
+    assert!(!heap.is_block_free(z));
+}
+
+#[cfg(all(feature = "std", not(feature = "tiny")))]
+#[test]
+fn counters_are_read_without_lock() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    let locked_heap = Arc::new(LockedHeap::empty());
+    *locked_heap.lock() = Some(Heap::new_boxed(BIG_HEAP_SIZE));
+    locked_heap.refresh_counters();
+    let free_blocks = locked_heap
+        .counters()
+        .free_blocks(HeapAllocator::Slab64Bytes);
+    assert!(free_blocks > 0);
+
+    // A reader finishes even while the lock is held.
+    {
+        let _guard = locked_heap.lock();
+        let locked_heap = locked_heap.clone();
+        let reader = thread::spawn(move || locked_heap.counters().alloc_count());
+        assert_eq!(reader.join().unwrap(), 0);
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let locked_heap = locked_heap.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let counters = locked_heap.counters();
+            let (mut last_allocs, mut last_deallocs) = (0, 0);
+            while !done.load(Ordering::SeqCst) {
+                let deallocs = counters.dealloc_count();
+                let allocs = counters.alloc_count();
+                assert!(allocs >= last_allocs && deallocs >= last_deallocs);
+                assert!(allocs >= deallocs);
+                last_allocs = allocs;
+                last_deallocs = deallocs;
+            }
+        })
+    };
+
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    for _ in 0..1000 {
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&*locked_heap, layout.clone());
+            GlobalAlloc::dealloc(&*locked_heap, ptr, layout.clone());
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    reader.join().unwrap();
+
+    // Blocks kept in the small object caches count as allocated.
+    locked_heap.flush_caches();
+    let counters = locked_heap.counters();
+    assert_eq!(counters.alloc_count(), 1000);
+    assert_eq!(counters.dealloc_count(), 1000);
+    assert_eq!(
+        counters.free_blocks(HeapAllocator::Slab64Bytes),
+        free_blocks
+    );
+}
+
+#[test]
+fn try_lock() {
+    let locked_heap = leaked_locked_heap();
+    let free_blocks = locked_heap
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_blocks(HeapAllocator::Slab64Bytes);
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    unsafe {
+        let ptr = GlobalAlloc::alloc(&locked_heap, layout.clone());
+        GlobalAlloc::dealloc(&locked_heap, ptr, layout);
+    }
+
+    {
+        let guard = locked_heap.try_lock().unwrap();
+        assert!(locked_heap.is_locked());
+        assert!(locked_heap.try_lock().is_none());
+        // Cached blocks were returned to the heap.
+        assert_eq!(
+            guard
+                .as_ref()
+                .unwrap()
+                .free_blocks(HeapAllocator::Slab64Bytes),
+            free_blocks
+        );
+    }
+    assert!(!locked_heap.is_locked());
+
+    core::mem::forget(locked_heap.lock());
+    assert!(locked_heap.is_locked());
+    unsafe { locked_heap.force_unlock() };
+    assert!(locked_heap.try_lock().is_some());
+}
+
+#[test]
+fn grow_with_holes() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let hole_start = start + 20 * 4096 + 100;
+    let hole_end = hole_start + 2 * 4096;
+
+    let summary = unsafe {
+        heap.grow_with_holes(
+            start + HEAP_SIZE,
+            BIG_HEAP_SIZE - HEAP_SIZE,
+            &[(hole_start, hole_end - hole_start)],
+        )
+    }
+    .unwrap();
+    // The pages the hole starts and ends in are dropped.
+    assert_eq!(summary.bytes_used, BIG_HEAP_SIZE - HEAP_SIZE - 3 * 4096);
+    assert_eq!(summary.bytes_skipped, 4096);
+    assert!(!heap.owns(hole_start));
+    assert!(!heap.owns(hole_end - 1));
+    assert!(heap.owns(start + 19 * 4096));
+    assert!(heap.owns(start + 23 * 4096));
+
+    let mut size = 64;
+    while size <= 4096 {
+        let layout = Layout::from_size_align(size, size).unwrap();
+        while let Ok(ptr) = heap.allocate(layout.clone()) {
+            let addr = ptr.as_ptr() as usize;
+            assert!(addr + size <= hole_start || addr >= hole_end);
+        }
+        size *= 2;
+    }
+}
+
+#[test]
+fn free_list_order() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let slab_size = HEAP_SIZE / NUM_OF_SLABS;
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    for i in 0..4 {
+        let ptr = heap.allocate(layout.clone()).unwrap();
+        assert_eq!(ptr.as_ptr() as usize, start + i * 64);
+    }
+
+    let mut heap = unsafe { Heap::new_high_first(start, HEAP_SIZE) };
+    for i in 1..5 {
+        let ptr = heap.allocate(layout.clone()).unwrap();
+        assert_eq!(ptr.as_ptr() as usize, start + slab_size - i * 64);
+    }
+}
+
+#[test]
+fn try_grow_then_allocate() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    while heap.allocate(layout.clone()).is_ok() {}
+
+    let mut requests = 0;
+    let result = unsafe {
+        heap.try_grow_then_allocate(layout.clone(), |size| {
+            requests += 1;
+            assert_eq!(size, 1024);
+            None
+        })
+    };
+    assert!(result.is_err());
+    assert_eq!(requests, 1);
+
+    // Memory which can't be added to the slab is rejected.
+    let result = unsafe {
+        heap.try_grow_then_allocate(layout.clone(), |_| Some((start + HEAP_SIZE + 8, 4096)))
+    };
+    assert!(result.is_err());
+
+    let grown = start + HEAP_SIZE;
+    let ptr = unsafe {
+        heap.try_grow_then_allocate(layout.clone(), |_| Some((grown, 4096)))
+            .unwrap()
+    };
+    assert!(ptr.as_ptr() as usize >= grown && (ptr.as_ptr() as usize) < grown + 4096);
+
+    // Succeeds without asking for memory while the grown memory lasts.
+    let ptr = unsafe {
+        heap.try_grow_then_allocate(layout, |_| panic!("memory requested"))
+            .unwrap()
+    };
+    assert!(ptr.as_ptr() as usize >= grown && (ptr.as_ptr() as usize) < grown + 4096);
+}
+
+#[test]
+fn over_aligned_layouts_use_linked_list() {
+    let layout = Layout::from_size_align(4096, 8192).unwrap();
+    assert_eq!(
+        Heap::layout_to_allocator(&layout),
+        HeapAllocator::LinkedListAllocator
+    );
+}
+
+#[test]
+fn split_slab() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut slab = unsafe { slab::Slab::new(start, 4096, 64) };
+    let mut blocks = [None; 64];
+    for block in blocks.iter_mut() {
+        *block = Some(slab.allocate(layout.clone()).unwrap());
+    }
+    for block in blocks[..20].iter() {
+        unsafe { slab.deallocate(block.unwrap()) };
+    }
+
+    // Block 20 is still allocated.
+    let slab = match slab.split_at(21) {
+        Ok(_) => panic!("split with allocated blocks in the first slab"),
+        Err(slab) => slab,
+    };
+    let slab = match slab.split_at(65) {
+        Ok(_) => panic!("split past the last block"),
+        Err(slab) => slab,
+    };
+    let (mut first, mut second) = slab.split_at(10).ok().unwrap();
+    assert_eq!(first.total_blocks(), 10);
+    assert_eq!(first.free_blocks(), 10);
+    assert_eq!(second.total_blocks(), 54);
+    assert_eq!(second.free_blocks(), 10);
+    for _ in 0..10 {
+        let addr = first.allocate(layout.clone()).unwrap().as_ptr() as usize;
+        assert!(addr < start + 10 * 64);
+        let addr = second.allocate(layout.clone()).unwrap().as_ptr() as usize;
+        assert!(addr >= start + 10 * 64 && addr < start + 20 * 64);
+    }
+    assert!(first.allocate(layout.clone()).is_err());
+    assert!(second.allocate(layout).is_err());
+    assert!(!first.owns(start + 10 * 64));
+    assert!(second.owns(start + 10 * 64));
+}
+
+#[test]
+fn grow_by_many_disjoint_segments() {
+    let test_heap = Box::leak(Box::new(TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { Slab::new(start, 4096, 64) };
+    for i in 0..40 {
+        unsafe { slab.grow(start + 2 * 4096 + i * 256, 128) };
+    }
+    // Segments past the second are recorded three to a block.
+    assert_eq!(slab.segments().count(), 41);
+    assert_eq!(slab.total_blocks(), 64 + 80 - 13);
+    assert_eq!(slab.free_blocks(), slab.total_blocks());
+
+    // Blocks holding records are moved out of the segments being removed.
+    for i in 0..40 {
+        assert!(slab.remove_segment(start + 2 * 4096 + i * 256));
+    }
+    assert_eq!(slab.segments().count(), 1);
+    assert_eq!(slab.total_blocks(), 64);
+    assert_eq!(slab.free_blocks(), 64);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    for _ in 0..64 {
+        let addr = slab.allocate(layout.clone()).unwrap().as_ptr() as usize;
+        assert!(addr >= start && addr < start + 4096);
+    }
+}
+
+#[test]
+fn merge_slabs() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let mut slab = unsafe { slab::Slab::new(start, 4096, 256) };
+    let mut other = unsafe { slab::Slab::new(start + 4096, 4096, 256) };
+    let ptr = other.allocate(layout.clone()).unwrap();
+
+    slab.merge(other);
+    assert_eq!(slab.total_blocks(), 32);
+    assert_eq!(slab.free_blocks(), 31);
+    assert_eq!(slab.segments().count(), 1);
+    assert!(slab.owns(start + 4096));
+
+    unsafe { slab.deallocate(ptr) };
+    assert_eq!(slab.free_blocks(), 32);
+    for _ in 0..32 {
+        slab.allocate(layout.clone()).unwrap();
+    }
+    assert!(slab.allocate(layout).is_err());
+}
+
+#[test]
+fn rt_heap() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let locked_heap = LockedHeap::empty();
+    unsafe {
+        locked_heap.init(start, HEAP_SIZE);
+    }
+    let rt_heap = RtHeap::new(&locked_heap, 1024);
+
+    assert!(rt_heap
+        .allocate(Layout::from_size_align(5000, 8).unwrap())
+        .is_err());
+    assert!(rt_heap
+        .allocate(Layout::from_size_align(2048, 8).unwrap())
+        .is_err());
+    assert!(rt_heap
+        .allocate(Layout::from_size_align(8, 2048).unwrap())
+        .is_err());
+    assert!(rt_heap.allocate_sized::<[u8; 1025]>().is_err());
+    locked_heap.flush_caches();
+    {
+        let heap = locked_heap.lock();
+        let heap = heap.as_ref().unwrap();
+        assert_eq!(
+            heap.total_free_blocks(),
+            heap.total_blocks_across_all_slabs()
+        );
+    }
+
+    // Small allocations land where they would on a plain heap over the same layout.
+    let other_start = start + 2 * HEAP_SIZE;
+    let mut heap = unsafe { Heap::new(other_start, HEAP_SIZE) };
+    for &size in [8, 64, 100, 1000].iter() {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = rt_heap.allocate(layout.clone()).unwrap();
+        let expected = heap.allocate(layout.clone()).unwrap();
+        assert_eq!(
+            ptr.as_ptr() as usize - start,
+            expected.as_ptr() as usize - other_start
+        );
+        unsafe {
+            rt_heap.deallocate(ptr, layout.clone());
+            heap.deallocate(expected, layout);
+        }
+    }
+    let ptr = rt_heap.allocate_sized::<u64>().unwrap();
+    assert_eq!(ptr.as_ptr() as usize, start);
+}
+
+#[cfg(feature = "custom-routing")]
+#[test]
+fn rt_heap_rejects_linked_list_routes() {
+    fn route(layout: &Layout) -> HeapAllocator {
+        if layout.size() <= 64 {
+            HeapAllocator::LinkedListAllocator
+        } else {
+            Heap::layout_to_allocator(layout)
+        }
+    }
+    let locked_heap = leaked_locked_heap();
+    let rt_heap = RtHeap::new(&locked_heap, 1024);
+    let small = Layout::from_size_align(32, 8).unwrap();
+    let medium = Layout::from_size_align(512, 8).unwrap();
+
+    locked_heap.set_bypass(true);
+    assert!(rt_heap.allocate(small.clone()).is_err());
+    locked_heap.set_bypass(false);
+    locked_heap.lock().as_mut().unwrap().set_routing(route);
+    assert!(rt_heap.allocate(small).is_err());
+    let ptr = rt_heap.allocate(medium.clone()).unwrap();
+    unsafe { rt_heap.deallocate(ptr, medium) };
+    let heap = locked_heap.lock();
+    assert_eq!(heap.as_ref().unwrap().load_factor(), 0);
+}
+
+#[cfg(not(feature = "tiny"))]
+#[test]
+fn fragmentation_map() {
+    static mut MAP: [u64; 9] = [0; 9];
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let slab = HeapAllocator::Slab64Bytes;
+    assert_eq!(heap.fragmentation_map(slab), &[] as &[u64]);
+    assert_eq!(heap.fragmentation_map_words(slab), 1);
+    assert!(!heap.enable_fragmentation_map(HeapAllocator::LinkedListAllocator, unsafe { &mut MAP }));
+    assert!(heap.enable_fragmentation_map(slab, unsafe { &mut MAP }));
+    assert_eq!(heap.fragmentation_map(slab), &[!0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    let y = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(heap.fragmentation_map(slab)[0], !0 << 2);
+    unsafe {
+        heap.deallocate(x, layout.clone());
+    }
+    assert_eq!(heap.fragmentation_map(slab)[0], !0 << 2 | 1);
+
+    // Bits are indexed by address, so the gap up to the new segment stays clear.
+    unsafe {
+        heap.grow(start + HEAP_SIZE, 4096, slab);
+    }
+    assert_eq!(heap.fragmentation_map_words(slab), 9);
+    assert_eq!(
+        heap.fragmentation_map(slab),
+        &[!0 << 2 | 1, 0, 0, 0, 0, 0, 0, 0, !0]
+    );
+    unsafe {
+        heap.deallocate(y, layout);
+    }
+    assert_eq!(heap.fragmentation_map(slab), &[!0, 0, 0, 0, 0, 0, 0, 0, !0]);
+}
+
+#[cfg(feature = "custom-routing")]
+#[test]
+fn custom_routing() {
+    fn route(layout: &Layout) -> HeapAllocator {
+        match layout.size() {
+            33..=128 => HeapAllocator::Slab128Bytes,
+            // Too small for the layout, so the default routing is used.
+            2049..=4096 => HeapAllocator::Slab64Bytes,
+            _ => Heap::layout_to_allocator(layout),
+        }
+    }
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let mut heap = unsafe { Heap::new(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE) };
+    heap.set_routing(route);
+
+    let small = Layout::from_size_align(40, 8).unwrap();
+    let medium = Layout::from_size_align(96, 8).unwrap();
+    let large = Layout::from_size_align(3000, 8).unwrap();
+    let x = heap.allocate(small.clone()).unwrap();
+    let y = heap.allocate(medium.clone()).unwrap();
+    let z = heap.allocate(large.clone()).unwrap();
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 64);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 30);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab4096Bytes), 0);
+    unsafe {
+        heap.deallocate(x, small);
+        heap.deallocate(y, medium);
+        heap.deallocate(z, large);
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 32);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab4096Bytes), 1);
+}
+
+#[test]
+fn estimate_oom_risk() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let mut heap = unsafe { Heap::new(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE) };
+    assert_eq!(heap.estimate_oom_risk(), OomRisk::Low);
+
+    // 64 blocks of 64 bytes.
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    for _ in 0..33 {
+        heap.allocate(layout.clone()).unwrap();
+    }
+    assert_eq!(heap.estimate_oom_risk(), OomRisk::Medium);
+    for _ in 0..25 {
+        heap.allocate(layout.clone()).unwrap();
+    }
+    assert_eq!(heap.estimate_oom_risk(), OomRisk::High);
+    while heap.allocate(layout.clone()).is_ok() {}
+    assert_eq!(heap.estimate_oom_risk(), OomRisk::Critical);
+    assert!(OomRisk::Critical > OomRisk::High);
+}
+
+#[test]
+fn allocate_scattered() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let mut heap = unsafe {
+        Heap::new(
+            &test_heap.heap_space[0] as *const u8 as usize,
+            BIG_HEAP_SIZE,
+        )
+    };
+    let free_blocks = heap.free_blocks(HeapAllocator::Slab4096Bytes);
+
+    let list = heap.allocate_scattered(6000).unwrap();
+    assert_eq!(list.chunks().len(), 2);
+    assert_eq!(list.chunks()[0].1, 4096);
+    assert_eq!(list.chunks()[1].1, 6000 - 4096);
+    assert_eq!(list.len(), 6000);
+    for &(addr, _) in list.chunks() {
+        assert_eq!(addr % 4096, 0);
+    }
+    assert_eq!(
+        heap.free_blocks(HeapAllocator::Slab4096Bytes),
+        free_blocks - 2
+    );
+    unsafe {
+        heap.deallocate_scattered(list);
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab4096Bytes), free_blocks);
+
+    // Nothing stays allocated if there are not enough blocks.
+    assert!(heap.allocate_scattered((free_blocks + 1) * 4096).is_none());
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab4096Bytes), free_blocks);
+    assert!(heap.allocate_scattered(17 * 4096).is_none());
+    assert!(heap.allocate_scattered(usize::max_value()).is_none());
+}
+
+#[test]
+fn classify_waste() {
+    let heap = leaked_heap();
+    let layout = Layout::from_size_align(600, 8).unwrap();
+    assert_eq!(heap.classify_waste(&layout), (424, 0));
+    // 600 bytes need a 1024 byte block anyway, so 1024 byte alignment costs nothing extra.
+    let layout = Layout::from_size_align(600, 1024).unwrap();
+    assert_eq!(heap.classify_waste(&layout), (424, 0));
+    let layout = Layout::from_size_align(600, 2048).unwrap();
+    assert_eq!(heap.classify_waste(&layout), (424, 1024));
+    let layout = Layout::from_size_align(100, 1024).unwrap();
+    assert_eq!(heap.classify_waste(&layout), (28, 896));
+    let layout = Layout::from_size_align(5000, 8).unwrap();
+    assert_eq!(heap.classify_waste(&layout), (0, 0));
+}
+
+#[test]
+fn coalesce_check() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    let y = heap.allocate(layout.clone()).unwrap();
+    let z = heap.allocate(layout.clone()).unwrap();
+    assert!(heap.coalesce_check(x, y));
+    assert!(heap.coalesce_check(z, y));
+    assert!(!heap.coalesce_check(x, z));
+    assert!(!heap.coalesce_check(x, x));
+
+    // The last 64 byte block and the first 128 byte block are in different slabs.
+    let last_64 = NonNull::new((start + 4096 - 64) as *mut u8).unwrap();
+    assert!(!heap.coalesce_check(last_64, x));
+    let inside = NonNull::new((x.as_ptr() as usize + 64) as *mut u8).unwrap();
+    assert!(!heap.coalesce_check(inside, y));
+}
+
+#[test]
+fn max_allocation_size() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let mut heap = unsafe {
+        Heap::new(
+            &test_heap.heap_space[0] as *const u8 as usize,
+            BIG_HEAP_SIZE,
+        )
+    };
+    // The linked list region is 10 pages, some bytes may be lost to its bookkeeping.
+    let max = heap.max_allocation_size(8);
+    assert!(max > 9 * 4096 && max <= 10 * 4096);
+
+    let layout = Layout::from_size_align(2 * 4096, 8).unwrap();
+    let a = heap.allocate(layout.clone()).unwrap();
+    let b = heap.allocate(layout.clone()).unwrap();
+    let _c = heap.allocate(layout.clone()).unwrap();
+    unsafe {
+        heap.deallocate(b, layout.clone());
+    }
+    // Holes of 2 pages where b was and 4 pages at the end.
+    let max = heap.max_allocation_size(8);
+    assert!(max > 3 * 4096 && max <= 4 * 4096);
+    heap.allocate(Layout::from_size_align(max, 8).unwrap())
+        .unwrap();
+    let max = heap.max_allocation_size(8);
+    assert!(max > 4096 && max <= 2 * 4096);
+    heap.allocate(Layout::from_size_align(max, 8).unwrap())
+        .unwrap();
+    unsafe {
+        heap.deallocate(a, layout);
+    }
+    let max = heap.max_allocation_size(8);
+    assert!(max > 4096 && max <= 2 * 4096);
+    heap.allocate(Layout::from_size_align(max, 8).unwrap())
+        .unwrap();
+
+    // Only the slabs are left.
+    assert_eq!(heap.max_allocation_size(8), 4096);
+    assert_eq!(heap.max_allocation_size(8192), 0);
+}
+
+#[test]
+fn max_allocation_size_of_colored_heap() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new_with_color_offset(start, HEAP_SIZE, 4) };
+    // Fill the linked list region, so only the slabs are left.
+    heap.allocate(Layout::from_size_align(4096, 4096).unwrap())
+        .unwrap();
+
+    // The colored 2048 byte slab only delivers 16 byte alignment, and no slab delivers 128.
+    assert_eq!(heap.max_allocation_size(16), 2048);
+    assert_eq!(heap.max_allocation_size(128), 0);
+    for &align in [8, 16, 32, 64].iter() {
+        let max = heap.max_allocation_size(align);
+        assert!(max > 0);
+        heap.allocate(Layout::from_size_align(max, align).unwrap())
+            .unwrap();
+    }
+}
+
+#[test]
+fn new_from_raw_parts() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    // The 64 byte slab gets 4 pages, the others 1 page each, with the linked list region at
+    // the end of the buffer.
+    let mut heap = unsafe {
+        Heap::new_from_raw_parts(
+            [
+                Slab::new(start, 4 * 4096, 64),
+                Slab::new(start + 4 * 4096, 4096, 128),
+                Slab::new(start + 5 * 4096, 4096, 256),
+                Slab::new(start + 6 * 4096, 4096, 512),
+                Slab::new(start + 7 * 4096, 4096, 1024),
+                Slab::new(start + 8 * 4096, 4096, 2048),
+                Slab::new(start + 9 * 4096, 4096, 4096),
+            ],
+            linked_list_allocator::Heap::new(start + BIG_HEAP_SIZE - 4 * 4096, 4 * 4096),
+        )
+    };
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 4 * 4096 / 64);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 4096 / 128);
+    let layout = Layout::from_size_align(8192, 8).unwrap();
+    let addr = heap.allocate(layout.clone()).unwrap().as_ptr() as usize;
+    assert!(addr >= start + BIG_HEAP_SIZE - 4 * 4096);
+    let layout = Layout::from_size_align(4096, 4096).unwrap();
+    assert_eq!(
+        heap.allocate(layout).unwrap().as_ptr() as usize,
+        start + 9 * 4096
+    );
+}
+
+#[test]
+fn new_reduced() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new_reduced(start, 6 * REDUCED_SLAB_SIZE) };
+    // The 16 byte slab gets the part left over.
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 2 * 1024 / 16);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab1024Bytes), 1024 / 256);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab4096Bytes), 0);
+    assert_eq!(heap.total_blocks_across_all_slabs(), 128 + 32 + 16 + 8 + 4);
+    assert_eq!(heap.linked_list_region_size(), 0);
+
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(ptr.as_ptr() as usize, start);
+    assert_eq!(heap.usable_size(&layout), (16, 16));
+    let aligned = Layout::from_size_align(16, 32).unwrap();
+    let aligned_ptr = heap.allocate(aligned.clone()).unwrap();
+    assert_eq!(aligned_ptr.as_ptr() as usize, start + 2 * 1024);
+    let largest = Layout::from_size_align(256, 8).unwrap();
+    let largest_ptr = heap.allocate(largest.clone()).unwrap();
+    assert_eq!(largest_ptr.as_ptr() as usize, start + 5 * 1024);
+    // There is no linked list region for larger chunks.
+    assert!(heap
+        .allocate(Layout::from_size_align(257, 8).unwrap())
+        .is_err());
+
+    unsafe {
+        heap.deallocate(ptr, layout);
+        heap.deallocate(aligned_ptr, aligned);
+        heap.deallocate(largest_ptr, largest);
+    }
+    assert_eq!(heap.total_free_blocks(), 128 + 32 + 16 + 8 + 4);
+}
+
+#[test]
+fn new_from_raw_parts_with_custom_classes() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let classes = [
+        (64, 64),
+        (96, 32),
+        (192, 64),
+        (512, 512),
+        (1024, 1024),
+        (2048, 2048),
+        (4096, 4096),
+    ];
+    assert_eq!(super::validate_slab_config(&classes), Ok(()));
+    let slab = |i: usize| unsafe {
+        let (block_size, block_align) = classes[i];
+        Slab::new_with_align(start + i * 4096, 4096, block_size, block_align)
+    };
+    let mut heap = unsafe {
+        Heap::new_from_raw_parts(
+            [
+                slab(0),
+                slab(1),
+                slab(2),
+                slab(3),
+                slab(4),
+                slab(5),
+                slab(6),
+            ],
+            linked_list_allocator::Heap::new(start + 7 * 4096, 4096),
+        )
+    };
+    // The 96 byte class takes the place of the 128 byte slab.
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 42);
+    let layout = Layout::from_size_align(96, 8).unwrap();
+    assert_eq!(heap.usable_size(&layout), (96, 96));
+    let blocks: Vec<_> = (0..42)
+        .map(|_| heap.allocate(layout.clone()).unwrap())
+        .collect();
+    for (i, block) in blocks.iter().enumerate() {
+        let addr = block.as_ptr() as usize;
+        assert_eq!(addr, start + 4096 + i * 96);
+        assert_eq!(addr % 32, 0);
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 0);
+
+    // Size and alignment are checked separately against each class.
+    let in_slab = |ptr: NonNull<u8>, i: usize| {
+        let addr = ptr.as_ptr() as usize;
+        addr >= start + i * 4096 && addr < start + (i + 1) * 4096
+    };
+    let larger = Layout::from_size_align(100, 8).unwrap();
+    let ptr = heap.allocate(larger.clone()).unwrap();
+    assert!(in_slab(ptr, 2));
+    unsafe { heap.deallocate(ptr, larger) };
+    let aligned = Layout::from_size_align(64, 64).unwrap();
+    assert!(in_slab(heap.allocate(aligned).unwrap(), 0));
+    let aligned = Layout::from_size_align(96, 64).unwrap();
+    assert!(in_slab(heap.allocate(aligned).unwrap(), 2));
+    let aligned = Layout::from_size_align(192, 128).unwrap();
+    assert!(in_slab(heap.allocate(aligned).unwrap(), 3));
+
+    for &block in blocks.iter() {
+        unsafe { heap.deallocate(block, layout.clone()) };
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 42);
+}
+
+#[test]
+#[cfg_attr(
+    not(feature = "tiny"),
+    should_panic(expected = "Slabs should be ordered by block size of at most 4096 bytes")
+)]
+#[cfg_attr(feature = "tiny", should_panic)]
+fn new_from_raw_parts_with_unordered_slabs() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    unsafe {
+        Heap::new_from_raw_parts(
+            [
+                Slab::new(start + 4096, 4096, 128),
+                Slab::new(start, 4096, 64),
+                Slab::new(start + 2 * 4096, 4096, 256),
+                Slab::new(start + 3 * 4096, 4096, 512),
+                Slab::new(start + 4 * 4096, 4096, 1024),
+                Slab::new(start + 5 * 4096, 4096, 2048),
+                Slab::new(start + 6 * 4096, 4096, 4096),
+            ],
+            linked_list_allocator::Heap::new(start + 7 * 4096, 4096),
+        );
+    }
+}
+
+// The small object caches are not used with `alloc-fill`, `generations`, `track-allocations`,
+// `valgrind` or `waste-tracking`.
+#[cfg(all(
+    feature = "std",
+    not(feature = "alloc-fill"),
+    not(feature = "generations"),
+    not(feature = "track-allocations"),
+    not(feature = "valgrind"),
+    not(feature = "waste-tracking")
+))]
+#[test]
+fn small_allocations_do_not_wait_for_heap_lock() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let locked_heap = Arc::new(LockedHeap::empty());
+    *locked_heap.lock() = Some(Heap::new_boxed(BIG_HEAP_SIZE));
+    let small = Layout::from_size_align(32, 8).unwrap();
+    // Fills the cache of the 64 byte slab.
+    unsafe {
+        let ptr = locked_heap.alloc(small.clone());
+        locked_heap.dealloc(ptr, small.clone());
+    }
+
+    // A thread doing large allocations holds the heap lock until the small allocations are
+    // done, or gives up after a while. It is taken like the allocations do, as `lock()` turns
+    // the caches off.
+    let progress = Arc::new(AtomicUsize::new(0));
+    let (locked, is_locked) = mpsc::channel();
+    let large_thread = {
+        let locked_heap = locked_heap.clone();
+        let progress = progress.clone();
+        thread::spawn(move || {
+            let mut guard = locked_heap.lock_heap();
+            locked.send(()).unwrap();
+            let heap = guard.as_mut().unwrap();
+            let large = Layout::from_size_align(8192, 8).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while progress.load(Ordering::SeqCst) < 1000 && Instant::now() < deadline {
+                let ptr = heap.allocate(large.clone()).unwrap();
+                unsafe { heap.deallocate(ptr, large.clone()) };
+            }
+            progress.load(Ordering::SeqCst)
+        })
+    };
+    is_locked.recv().unwrap();
+    for _ in 0..1000 {
+        unsafe {
+            let ptr = locked_heap.alloc(small.clone());
+            assert!(!ptr.is_null());
+            locked_heap.dealloc(ptr, small.clone());
+        }
+        progress.fetch_add(1, Ordering::SeqCst);
+    }
+    assert_eq!(large_thread.join().unwrap(), 1000);
+}
+
+#[test]
+fn allocate_up_to() {
+    let mut heap = leaked_big_heap();
+    let exhaust = |heap: &mut Heap, size: usize| {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        while heap.allocate(layout.clone()).is_ok() {}
+    };
+    exhaust(&mut heap, 4097);
+    exhaust(&mut heap, 4096);
+    exhaust(&mut heap, 2048);
+
+    let min = Layout::from_size_align(300, 8).unwrap();
+    let free_blocks = heap.free_blocks(HeapAllocator::Slab1024Bytes);
+    let (ptr, size) = heap.allocate_up_to(min.clone(), 8192).unwrap();
+    assert_eq!(size, 1024);
+    assert_eq!(
+        heap.free_blocks(HeapAllocator::Slab1024Bytes),
+        free_blocks - 1
+    );
+    unsafe {
+        heap.deallocate(ptr, Layout::from_size_align(size, min.align()).unwrap());
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab1024Bytes), free_blocks);
+
+    exhaust(&mut heap, 1024);
+    let (_, size) = heap.allocate_up_to(min.clone(), 8192).unwrap();
+    assert_eq!(size, 512);
+    exhaust(&mut heap, 512);
+    // The smaller slabs still have blocks, but never below `min`.
+    assert!(heap.allocate_up_to(min.clone(), 8192).is_err());
+    assert!(heap.free_blocks(HeapAllocator::Slab256Bytes) > 0);
+
+    // Classes whose blocks are not aligned enough are skipped.
+    let min = Layout::from_size_align(64, 256).unwrap();
+    let (ptr, size) = heap.allocate_up_to(min, 8192).unwrap();
+    assert_eq!(size, 256);
+    assert_eq!(ptr.as_ptr() as usize % 256, 0);
+}
+
+#[test]
+fn linked_list_allocate_aligned() {
+    let mut heap = leaked_big_heap();
+    let start = heap.linked_list_region_start();
+    let end = start + heap.linked_list_region_size();
+    let max_size = heap.max_allocation_size(8);
+
+    // The hole after this chunk doesn't start at a page boundary.
+    let unaligned = Layout::from_size_align(4100, 8).unwrap();
+    let first = heap.allocate(unaligned.clone()).unwrap();
+    let ptr = heap.linked_list_allocate_aligned(8192, 8192).unwrap();
+    let addr = ptr.as_ptr() as usize;
+    assert_eq!(addr % 8192, 0);
+    assert!(addr >= start && addr + 8192 <= end);
+
+    // Small layouts are served by the linked list allocator too.
+    let page = heap.linked_list_allocate_aligned(512, 4096).unwrap();
+    let page_addr = page.as_ptr() as usize;
+    assert_eq!(page_addr % 4096, 0);
+    assert!(page_addr >= start && page_addr < end);
+
+    unsafe {
+        heap.linked_list_deallocate(page, 512, 4096);
+        heap.linked_list_deallocate(ptr, 8192, 8192);
+        heap.deallocate(first, unaligned);
+    }
+    assert_eq!(heap.max_allocation_size(8), max_size);
+}
+
+#[test]
+fn load_factor() {
+    let mut heap = leaked_big_heap();
+    assert_eq!(heap.load_factor(), 0);
+
+    // Half of the bytes, from slabs with very different block counts.
+    let slab_size = BIG_HEAP_SIZE / NUM_OF_SLABS;
+    let mut allocations = Vec::new();
+    for &block_size in [512, 1024, 2048, 4096].iter() {
+        let layout = Layout::from_size_align(block_size, 8).unwrap();
+        for _ in 0..slab_size / block_size {
+            allocations.push((heap.allocate(layout.clone()).unwrap(), layout.clone()));
+        }
+    }
+    assert_eq!(heap.capacity(), BIG_HEAP_SIZE);
+    assert_eq!(heap.load_factor(), 50);
+
+    // 8192 of 327680 bytes are 2.5%.
+    let layout = Layout::from_size_align(8192, 8).unwrap();
+    allocations.push((heap.allocate(layout.clone()).unwrap(), layout));
+    assert_eq!(heap.load_factor(), 52);
+
+    for (ptr, layout) in allocations {
+        unsafe { heap.deallocate(ptr, layout) };
+    }
+    assert_eq!(heap.load_factor(), 0);
+}
+
+#[test]
+fn restore_free_list() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { Slab::new(start, 4096, 256) };
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let a = slab.allocate(layout.clone()).unwrap();
+    let b = slab.allocate(layout.clone()).unwrap();
+    unsafe { slab.deallocate(a) };
+    let (head, len) = slab.free_list_raw_parts();
+    assert_eq!(head, a.as_ptr() as usize);
+    assert_eq!(len, 15);
+
+    // Save the slab's memory, use up the slab, then put the memory back as after hibernation.
+    let mut saved = Box::new([0u8; 4096]);
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, saved.as_mut_ptr(), 4096);
+    }
+    while slab.allocate(layout.clone()).is_ok() {}
+    assert_eq!(slab.free_list_raw_parts(), (0, 0));
+    unsafe {
+        core::ptr::copy_nonoverlapping(saved.as_ptr(), start as *mut u8, 4096);
+        slab.restore_free_list(head, len);
+    }
+    assert_eq!(slab.free_blocks(), 15);
+    assert_eq!(slab.allocate(layout.clone()).unwrap(), a);
+    assert_eq!(
+        slab.allocate(layout.clone()).unwrap().as_ptr() as usize,
+        b.as_ptr() as usize + 256
+    );
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[cfg_attr(
+    not(feature = "tiny"),
+    should_panic(expected = "Free list is longer than its length")
+)]
+#[cfg_attr(feature = "tiny", should_panic)]
+fn restore_free_list_with_wrong_length() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { Slab::new(start, 4096, 256) };
+    let (head, len) = slab.free_list_raw_parts();
+    unsafe { slab.restore_free_list(head, len - 1) };
+}
+
+#[test]
+fn grow_checks_alignment() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { Slab::new(start, 4096, 512) };
+    unsafe {
+        assert_eq!(
+            slab.try_grow(start + 4096 + 8, 2048),
+            Err(GrowError::Misaligned)
+        );
+        assert_eq!(
+            slab.try_grow(start + 4096 + 512, 256),
+            Err(GrowError::TooSmall)
+        );
+        assert_eq!(slab.try_grow(start + 4096 + 512, 2048), Ok(()));
+    }
+    assert_eq!(slab.total_blocks(), 12);
+    let layout = Layout::from_size_align(512, 512).unwrap();
+    while let Ok(ptr) = slab.allocate(layout.clone()) {
+        assert_eq!(ptr.as_ptr() as usize % 512, 0);
+    }
+}
+
+#[test]
+fn grow_linked_list_checks_alignment() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let layout = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+    unsafe {
+        heap.grow(start + HEAP_SIZE, 100, HeapAllocator::LinkedListAllocator);
+        // Adjacent to the linked list region, but not page aligned.
+        let unaligned = start + HEAP_SIZE + 100;
+        assert!(heap
+            .try_grow_then_allocate(layout.clone(), |_| Some((unaligned, HEAP_SIZE)))
+            .is_err());
+    }
+    assert_eq!(heap.linked_list_region_size(), 4096 + 100);
+}
+
+#[test]
+#[cfg_attr(
+    not(feature = "tiny"),
+    should_panic(expected = "Slab should be grown with memory aligned to its block size")
+)]
+#[cfg_attr(feature = "tiny", should_panic)]
+fn grow_misaligned_slab() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { Slab::new(start, 4096, 512) };
+    unsafe { slab.grow(start + 4096 + 8, 2048) };
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn fine_locked_heap_stress() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let test_heap = Box::leak(Box::new(TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let heap = Arc::new(unsafe { FineLockedHeap::new(start, BIG_HEAP_SIZE) });
+    let free_blocks = heap.total_free_blocks();
+
+    // Each thread churns its own size class and checks no other thread wrote to its memory.
+    let threads: Vec<_> = [48, 100, 2000, 6000]
+        .iter()
+        .enumerate()
+        .map(|(id, &size)| {
+            let heap = heap.clone();
+            thread::spawn(move || {
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                let mut live = Vec::new();
+                for round in 0..2000 {
+                    let ptr = heap.allocate(layout.clone()).unwrap();
+                    unsafe { core::ptr::write_bytes(ptr.as_ptr(), id as u8, size) };
+                    live.push(ptr);
+                    if live.len() == 4 || round == 1999 {
+                        for ptr in live.drain(..) {
+                            let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), size) };
+                            assert!(bytes.iter().all(|&byte| byte == id as u8));
+                            unsafe { heap.deallocate(ptr, layout.clone()) };
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(heap.total_free_blocks(), free_blocks);
+    assert_eq!(heap.region(), Some((start, BIG_HEAP_SIZE)));
+}
+
+#[test]
+#[cfg_attr(
+    not(feature = "tiny"),
+    should_panic(expected = "Heap should only be initialized once")
+)]
+#[cfg_attr(feature = "tiny", should_panic)]
+fn fine_locked_heap_init_twice() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let heap = unsafe { FineLockedHeap::new(start, HEAP_SIZE) };
+    unsafe { heap.init(start, HEAP_SIZE) };
+}
+
+#[cfg(not(feature = "tiny"))]
+#[test]
+fn block_usage_heatmap() {
+    let mut heap = leaked_big_heap();
+    // The 1024 byte slab has 10 pages of 4 blocks.
+    assert_eq!(
+        heap.block_usage_heatmap_len(HeapAllocator::Slab1024Bytes),
+        10
+    );
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let blocks: Vec<_> = (0..6)
+        .map(|_| heap.allocate(layout.clone()).unwrap())
+        .collect();
+    unsafe { heap.deallocate(blocks[1], layout.clone()) };
+
+    let mut out = [0xFFu8; 12];
+    assert_eq!(
+        heap.block_usage_heatmap(HeapAllocator::Slab1024Bytes, &mut out),
+        10
+    );
+    assert_eq!(&out[..3], &[191, 127, 0]);
+    assert_eq!(&out[10..], &[0xFF, 0xFF]);
+    assert_eq!(
+        heap.block_usage_heatmap(HeapAllocator::LinkedListAllocator, &mut out),
+        0
+    );
+}
+
+#[cfg(not(feature = "tiny"))]
+#[test]
+fn block_usage_heatmap_small_and_straddling_blocks() {
+    let space: &'static mut TestHeap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &space.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    let mut out = [0xFFu8; 3];
+
+    // 512 blocks per page, more than a byte counts.
+    let mut small = unsafe { slab::Slab::new(start, 2 * 4096, 8) };
+    assert_eq!(small.block_usage_heatmap(&mut out), 2);
+    assert_eq!(&out[..2], &[0, 0]);
+    for _ in 0..512 {
+        small.allocate(layout.clone()).unwrap();
+    }
+    assert_eq!(small.block_usage_heatmap(&mut out), 2);
+    assert_eq!(&out[..2], &[255, 0]);
+
+    // The second block lies in the first two pages, the third one in the last two.
+    let mut straddling = unsafe { slab::Slab::new(start + 2 * 4096, 3 * 4096, 3072) };
+    straddling.allocate(layout.clone()).unwrap();
+    assert_eq!(straddling.block_usage_heatmap(&mut out), 3);
+    assert_eq!(out, [127, 0, 0]);
+    straddling.allocate(layout.clone()).unwrap();
+    straddling.block_usage_heatmap(&mut out);
+    assert_eq!(out, [255, 127, 0]);
+}
+
+#[test]
+fn occupancy() {
+    let mut heap = leaked_heap();
+    assert_eq!(heap.occupancy(), [0; NUM_OF_SLABS - 1]);
+    // 24 of the 32 blocks of the 128 byte slab.
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    for _ in 0..24 {
+        heap.allocate(layout.clone()).unwrap();
+    }
+    heap.allocate(Layout::from_size_align(4096, 8).unwrap())
+        .unwrap();
+    assert_eq!(heap.occupancy(), [0, 75, 0, 0, 0, 0, 100]);
+}
+
+#[test]
+#[cfg(not(feature = "tiny"))]
+fn for_each_free_page_range() {
+    static mut MAPS: [[u64; 10]; NUM_OF_SLABS - 1] = [[0; 10]; NUM_OF_SLABS - 1];
+    let mut heap = leaked_big_heap();
+    for (map, &slab) in unsafe { MAPS.iter_mut() }.zip(SLAB_ALLOCATORS.iter()) {
+        assert!(heap.enable_fragmentation_map(slab, map));
+    }
+    // Keep the first 64 byte block and the sixth 1024 byte block, which lie in the first page
+    // of the 64 byte slab and the second page of the 1024 byte slab.
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(1024, 8).unwrap();
+    let small_blocks: Vec<_> = (0..128)
+        .map(|_| heap.allocate(small.clone()).unwrap())
+        .collect();
+    let large_blocks: Vec<_> = (0..12)
+        .map(|_| heap.allocate(large.clone()).unwrap())
+        .collect();
+    for &block in &small_blocks[1..] {
+        unsafe { heap.deallocate(block, small.clone()) };
+    }
+    for (i, &block) in large_blocks.iter().enumerate() {
+        if i != 5 {
+            unsafe { heap.deallocate(block, large.clone()) };
+        }
+    }
+    let live = [
+        (small_blocks[0].as_ptr() as usize, 64),
+        (large_blocks[5].as_ptr() as usize, 1024),
+    ];
+
+    let mut ranges = Vec::new();
+    heap.for_each_free_page_range(|start, size| ranges.push((start, size)));
+    // The slabs lie next to each other, so the ranges run across them.
+    assert_eq!(ranges.len(), 2);
+    for &(start, size) in &ranges {
+        assert_eq!(start % 4096, 0);
+        assert_eq!(size % 4096, 0);
+        for &(addr, len) in &live {
+            assert!(addr + len <= start || addr >= start + size);
+        }
+    }
+    // All 70 pages of the slabs but the two holding the live blocks.
+    let covered: usize = ranges.iter().map(|&(_, size)| size).sum();
+    assert_eq!(covered, 68 * 4096);
+
+    // Lose the content of the reported pages, then recover the free lists.
+    let free_blocks = heap.total_free_blocks();
+    for &(start, size) in &ranges {
+        unsafe { core::ptr::write_bytes(start as *mut u8, 0, size) };
+    }
+    unsafe { heap.rebuild_free_lists() };
+    assert_eq!(heap.total_free_blocks(), free_blocks);
+    assert_eq!(heap.allocate(small.clone()).unwrap(), small_blocks[1]);
+    for _ in 1..heap.free_blocks(HeapAllocator::Slab1024Bytes) + 1 {
+        heap.allocate(large.clone()).unwrap();
+    }
+    assert!(heap.allocate(large.clone()).is_err());
+}
+
+fn panicking_oom_handler(layout: Layout) -> ! {
+    panic!("out of memory allocating {} bytes", layout.size())
+}
+
+#[test]
+#[should_panic(expected = "out of memory allocating 40000 bytes")]
+fn oom_handler() {
+    let mut heap = leaked_heap();
+    heap.set_oom_handler(panicking_oom_handler);
+    // Allocations which fall back on failure don't call the handler.
+    let min = Layout::from_size_align(64, 8).unwrap();
+    assert!(heap.allocate_up_to(min, 40000).is_ok());
+    assert!(heap.allocate_scattered(2 * 4096).is_none());
+    let _ = heap.allocate(Layout::from_size_align(40000, 8).unwrap());
+}
+
+#[test]
+fn allocate_filled() {
+    let mut heap = leaked_big_heap();
+    for &size in &[100, 4096, 5000] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = heap.allocate_filled(layout.clone(), 0xFF).unwrap();
+        for i in 0..size {
+            assert_eq!(unsafe { *ptr.as_ptr().add(i) }, 0xFF);
+        }
+        unsafe { heap.deallocate(ptr, layout) };
+    }
+}
+
+#[test]
+fn allocate_with_hint() {
+    // Interleaves short-lived and long-lived chunks of the 40960 byte linked list region, then
+    // frees the short-lived ones.
+    let layout = Layout::from_size_align(8000, 8).unwrap();
+    let large = Layout::from_size_align(16384, 8).unwrap();
+    for &lifetime in &[Lifetime::Short, Lifetime::Long] {
+        let mut heap = leaked_big_heap();
+        let a = heap.allocate(layout.clone()).unwrap();
+        let b = heap.allocate_with_hint(layout.clone(), lifetime).unwrap();
+        let c = heap.allocate(layout.clone()).unwrap();
+        let d = heap.allocate_with_hint(layout.clone(), lifetime).unwrap();
+        unsafe {
+            heap.deallocate(a, layout.clone());
+            heap.deallocate(c, layout.clone());
+        }
+        match lifetime {
+            // Without the hint the long-lived chunks split the free memory into 8000 byte holes.
+            Lifetime::Short => assert!(heap.allocate(large.clone()).is_err()),
+            Lifetime::Long => {
+                assert!(b > d && d > c);
+                assert!(heap.allocate(large.clone()).is_ok());
+            }
+        }
+    }
+    // Slab allocations ignore the hint.
+    let mut heap = leaked_heap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let first = heap.allocate(small.clone()).unwrap();
+    let second = heap.allocate_with_hint(small, Lifetime::Long).unwrap();
+    assert_eq!(second.as_ptr() as usize, first.as_ptr() as usize + 64);
+}
+
+#[test]
+fn footprint_of() {
+    assert_eq!(Heap::footprint_of::<u32>(20), 128);
+    assert_eq!(Heap::footprint_of::<u64>(512), 4096);
+    assert_eq!(Heap::footprint_of::<u64>(513), 4104);
+    assert_eq!(Heap::footprint_of::<u32>(0), 0);
+    assert_eq!(Heap::footprint_of::<u64>(usize::max_value()), 0);
+}
+
+#[test]
+#[cfg(feature = "stats")]
+fn event_ring() {
+    static mut EVENTS: [HeapEvent; 4] = [HeapEvent::EMPTY; 4];
+    let mut heap = leaked_heap();
+    assert_eq!(
+        heap.recent_events(),
+        (&[] as &[HeapEvent], &[] as &[HeapEvent])
+    );
+    heap.enable_event_ring(unsafe { &mut EVENTS });
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    let (older, newer) = heap.recent_events();
+    assert_eq!(
+        older,
+        &[HeapEvent {
+            seq: 0,
+            kind: HeapEventKind::Allocate,
+            addr: x.as_ptr() as usize,
+            size: 100,
+            class: HeapAllocator::Slab128Bytes,
+        }]
+    );
+    assert!(newer.is_empty());
+    // Six events wrap around the ring of four.
+    unsafe { heap.deallocate(x, layout.clone()) };
+    for _ in 0..2 {
+        let y = heap.allocate(layout.clone()).unwrap();
+        unsafe { heap.deallocate(y, layout.clone()) };
+    }
+    let (older, newer) = heap.recent_events();
+    let seqs: Vec<_> = older.iter().chain(newer).map(|event| event.seq).collect();
+    assert_eq!(seqs, [2, 3, 4, 5]);
+    assert_eq!(newer.last().unwrap().kind, HeapEventKind::Deallocate);
+}
+
+#[test]
+#[cfg(all(feature = "std", feature = "track-allocations", feature = "stats"))]
+fn double_free_dumps_recent_events() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::string::String;
+
+    static mut EVENTS: [HeapEvent; 8] = [HeapEvent::EMPTY; 8];
+    let mut heap = leaked_heap();
+    heap.enable_event_ring(unsafe { &mut EVENTS });
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let x = heap.allocate(layout.clone()).unwrap();
+    unsafe { heap.deallocate(x, layout.clone()) };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+        heap.deallocate(x, layout.clone())
+    }));
+    let message = result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("double free"));
+    assert!(message.contains("recent heap events"));
+
+    let addr = x.as_ptr() as usize;
+    let (events, _) = heap.recent_events();
+    let kinds: Vec<_> = events
+        .iter()
+        .filter(|event| event.addr == addr)
+        .map(|event| event.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            HeapEventKind::Allocate,
+            HeapEventKind::Deallocate,
+            HeapEventKind::Deallocate
+        ]
+    );
+}
+
+#[test]
+fn allocate_from_specific_region() {
+    let mut heap = leaked_big_heap();
+    let local = heap
+        .slab(HeapAllocator::Slab64Bytes)
+        .unwrap()
+        .segments()
+        .next()
+        .unwrap()
+        .start_addr;
+    let remote = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let remote = &remote.heap_space[0] as *const u8 as usize;
+    unsafe { heap.grow(remote, 4096, HeapAllocator::Slab64Bytes) };
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = heap
+        .allocate_from_specific_region(remote, layout.clone())
+        .unwrap();
+    assert!(ptr.as_ptr() as usize >= remote && (ptr.as_ptr() as usize) < remote + 4096);
+    let ptr = heap
+        .allocate_from_specific_region(local, layout.clone())
+        .unwrap();
+    assert!(ptr.as_ptr() as usize >= local && (ptr.as_ptr() as usize) < local + 4096);
+    assert!(heap
+        .allocate_from_specific_region(local + 64, layout.clone())
+        .is_err());
+
+    // Empty the remote region, which leaves the local one untouched.
+    for _ in 1..64 {
+        heap.allocate_from_specific_region(remote, layout.clone())
+            .unwrap();
+    }
+    assert!(heap
+        .allocate_from_specific_region(remote, layout.clone())
+        .is_err());
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 639);
+
+    let ll_start = heap.linked_list_region_start();
+    let large = Layout::from_size_align(4097, 8).unwrap();
+    assert!(heap
+        .allocate_from_specific_region(ll_start, large.clone())
+        .is_ok());
+    assert!(heap.allocate_from_specific_region(local, large).is_err());
+}
+
+#[test]
+fn block_count_for_region() {
+    assert_eq!(
+        Heap::block_count_for_region(0x10000, 8192),
+        [128, 64, 32, 16, 8, 4, 2]
+    );
+    // The first 64 bytes are skipped by all but the 64 byte blocks.
+    assert_eq!(
+        Heap::block_count_for_region(0x10040, 4096),
+        [64, 31, 15, 7, 3, 1, 0]
+    );
+    assert_eq!(
+        Heap::block_count_for_region(0x10000, 100),
+        [1, 0, 0, 0, 0, 0, 0]
+    );
+}
+
+#[test]
+#[cfg(not(feature = "tiny"))]
+fn reset_stats() {
+    let locked_heap = leaked_locked_heap();
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let ptrs: Vec<_> = (0..3)
+        .map(|_| unsafe { GlobalAlloc::alloc(&locked_heap, layout.clone()) })
+        .collect();
+    unsafe { GlobalAlloc::dealloc(&locked_heap, ptrs[0], layout.clone()) };
+    assert_eq!(locked_heap.counters().alloc_count(), 3);
+    assert_eq!(locked_heap.counters().dealloc_count(), 1);
+    let load_factor = locked_heap.lock().as_ref().unwrap().load_factor();
+
+    locked_heap.reset_stats();
+    let counters = locked_heap.counters();
+    assert_eq!(counters.alloc_count(), 0);
+    assert_eq!(counters.dealloc_count(), 0);
+    assert_eq!(counters.free_blocks(HeapAllocator::Slab1024Bytes), 2);
+    assert_eq!(
+        locked_heap.lock().as_ref().unwrap().load_factor(),
+        load_factor
+    );
+    #[cfg(feature = "stats")]
+    assert_eq!(locked_heap.rate_since_last(), [(0, 0); NUM_OF_SLABS]);
+}
+
+#[test]
+#[cfg(all(feature = "stats", feature = "custom-routing"))]
+fn heap_reset_stats() {
+    fn route(layout: &Layout) -> HeapAllocator {
+        match Heap::layout_to_allocator(layout) {
+            HeapAllocator::Slab256Bytes => HeapAllocator::Slab512Bytes,
+            slab => slab,
+        }
+    }
+    let mut heap = leaked_heap();
+    heap.set_routing(route);
+    let layout = Layout::from_size_align(200, 8).unwrap();
+    heap.allocate(layout.clone()).unwrap();
+    let other = Layout::from_size_align(2000, 8).unwrap();
+    heap.allocate(other).unwrap();
+    assert_eq!(heap.fallback_counts()[3], 1);
+    let load_factor = heap.load_factor();
+    let free_blocks = heap.free_blocks(HeapAllocator::Slab512Bytes);
+
+    heap.reset_stats();
+    assert_eq!(heap.fallback_counts(), [0; NUM_OF_SLABS]);
+    assert_eq!(heap.rate_since_last(), [(0, 0); NUM_OF_SLABS]);
+    assert_eq!(heap.load_factor(), load_factor);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab512Bytes), free_blocks);
+
+    // Counting goes on from zero.
+    heap.allocate(layout).unwrap();
+    let mut expected = [0; NUM_OF_SLABS];
+    expected[3] = 1;
+    assert_eq!(heap.fallback_counts(), expected);
+}
+
+#[test]
+#[cfg(all(feature = "std", not(feature = "tiny")))]
+fn panic_dump() {
+    use std::string::String;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    let locked_heap = Arc::new(leaked_locked_heap());
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    unsafe { GlobalAlloc::alloc(&*locked_heap, layout) };
+
+    let mut dump = String::new();
+    locked_heap.panic_dump(&mut dump).unwrap();
+    assert!(dump.contains("allocations: 1, deallocations: 0"));
+    assert!(dump.contains("Slab1024Bytes: 3 of 4 blocks free"));
+    assert!(!dump.contains("approximate"));
+
+    // Another thread holds the lock until the dump is written.
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let holder = {
+        let locked_heap = locked_heap.clone();
+        thread::spawn(move || {
+            let _guard = locked_heap.lock();
+            locked_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+        })
+    };
+    locked_rx.recv().unwrap();
+    let mut dump = String::new();
+    locked_heap.panic_dump(&mut dump).unwrap();
+    done_tx.send(()).unwrap();
+    holder.join().unwrap();
+    assert!(dump.contains("allocations: 1, deallocations: 0"));
+    assert!(dump.contains("approximate"));
+    assert!(dump.contains("Slab1024Bytes: ~3 blocks free"));
+}
+
+#[test]
+#[cfg(feature = "generations")]
+fn allocate_with_generation() {
+    let mut heap = leaked_heap();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let (ptr, generation) = heap.allocate_with_generation(layout.clone()).unwrap();
+    assert_eq!(generation, Some(1));
+    unsafe { heap.deallocate(ptr, layout.clone()) };
+    assert_eq!(heap.generation(ptr), Some(1));
+    // Plain allocations count too.
+    let again = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(again, ptr);
+    unsafe { heap.deallocate(again, layout.clone()) };
+    let (again, generation) = heap.allocate_with_generation(layout).unwrap();
+    assert_eq!(again, ptr);
+    assert_eq!(generation, Some(3));
+}
+
+#[test]
+#[cfg(feature = "generations")]
+fn untracked_generations() {
+    let mut heap = leaked_big_heap();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut ptrs = Vec::new();
+    for _ in 0..GENERATION_CAPACITY {
+        let (ptr, generation) = heap.allocate_with_generation(layout.clone()).unwrap();
+        assert_eq!(generation, Some(1));
+        ptrs.push(ptr);
+    }
+    let (ptr, generation) = heap.allocate_with_generation(layout.clone()).unwrap();
+    assert_eq!(generation, None);
+    assert_eq!(heap.generation(ptr), None);
+    // Tracked addresses keep counting.
+    unsafe { heap.deallocate(ptrs[0], layout.clone()) };
+    let (again, generation) = heap.allocate_with_generation(layout).unwrap();
+    assert_eq!(again, ptrs[0]);
+    assert_eq!(generation, Some(2));
+}
+
+#[test]
+#[cfg(feature = "track-allocations")]
+fn deallocate_all_tagged() {
+    let mut heap = leaked_big_heap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    let free_blocks = heap.total_free_blocks();
+    for _ in 0..3 {
+        heap.allocate_tagged(small.clone(), 1).unwrap();
+    }
+    heap.allocate_tagged(large.clone(), 1).unwrap();
+    let kept = heap.allocate_tagged(small.clone(), 2).unwrap();
+    let kept_large = heap.allocate_tagged(large.clone(), 2).unwrap();
+    let untagged = heap.allocate(small.clone()).unwrap();
+    unsafe {
+        *kept.as_ptr() = 0x42;
+        *kept_large.as_ptr() = 0x43;
+    }
+
+    assert_eq!(heap.deallocate_all_tagged(1), 4);
+    assert_eq!(heap.deallocate_all_tagged(1), 0);
+    assert_eq!(heap.total_free_blocks(), free_blocks - 2);
+    unsafe {
+        assert_eq!(*kept.as_ptr(), 0x42);
+        assert_eq!(*kept_large.as_ptr(), 0x43);
+    }
+
+    assert_eq!(heap.deallocate_all_tagged(2), 2);
+    unsafe { heap.deallocate(untagged, small) };
+    assert_eq!(heap.total_free_blocks(), free_blocks);
+    assert_eq!(heap.load_factor(), 0);
+}
+
+#[test]
+fn allocate_if_address() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { Slab::new(start, 4096, 256) };
+    let addr = start + 5 * 256;
+    assert_eq!(
+        slab.allocate_if_address(addr).unwrap().as_ptr() as usize,
+        addr
+    );
+    assert_eq!(slab.free_blocks(), 15);
+    // The block is taken now.
+    assert!(slab.allocate_if_address(addr).is_err());
+    // Not the start of a block, or outside of the slab.
+    assert!(slab.allocate_if_address(addr + 8).is_err());
+    assert!(slab.allocate_if_address(start + 4096).is_err());
+
+    // The other blocks are still handed out in order.
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    assert_eq!(slab.allocate(layout).unwrap().as_ptr() as usize, start);
+    unsafe { slab.deallocate(NonNull::new_unchecked(addr as *mut u8)) };
+    assert!(slab.allocate_if_address(addr).is_ok());
+}
+
+#[test]
+fn new_with_color_offset() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new_with_color_offset(start, HEAP_SIZE, 4) };
+    let slab_size = HEAP_SIZE / NUM_OF_SLABS;
+    // The 4096 byte slab is left out, as the offset costs it its only block.
+    for i in 0..NUM_OF_SLABS - 2 {
+        let block_size = 64 << i;
+        let layout = Layout::from_size_align(block_size, 8).unwrap();
+        let ptr = heap.allocate(layout).unwrap();
+        assert_eq!(
+            ptr.as_ptr() as usize,
+            start + i * slab_size + (i * 16) % block_size
+        );
+    }
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab4096Bytes), 0);
+    assert_eq!(heap.slabs()[0].block_align(), 64);
+    assert_eq!(heap.slabs()[1].block_align(), 16);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 30);
+
+    // A layout needing more alignment than the offset gives goes to the linked list allocator.
+    let layout = Layout::from_size_align(128, 128).unwrap();
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(ptr.as_ptr() as usize % 128, 0);
+    assert!(ptr.as_ptr() as usize >= start + 7 * slab_size);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 30);
+
+    // Once the offset segment is gone the layout would be routed to the 128 byte slab, but the
+    // chunk is still freed to the allocator holding it.
+    let block = unsafe { NonNull::new_unchecked((start + slab_size + 16) as *mut u8) };
+    unsafe { heap.deallocate(block, Layout::from_size_align(128, 8).unwrap()) };
+    assert!(heap
+        .slab_mut(HeapAllocator::Slab128Bytes)
+        .unwrap()
+        .remove_segment(start + slab_size + 16));
+    assert_eq!(heap.slabs()[1].block_align(), 128);
+    unsafe { heap.deallocate(ptr, layout) };
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 0);
+}
+
+#[test]
+fn new_with_color_offset_keeps_links_aligned() {
+    for color_bits in 0..3 {
+        let test_heap = Box::leak(Box::new(TestHeap {
+            heap_space: [0u8; HEAP_SIZE],
+        }));
+        let start = &test_heap.heap_space[0] as *const u8 as usize;
+        let mut heap = unsafe { Heap::new_with_color_offset(start, HEAP_SIZE, color_bits) };
+        for (i, slab) in heap.slabs().iter().enumerate() {
+            let offset = slab.segments().next().unwrap().start_addr - start - i * HEAP_SIZE / 8;
+            assert_eq!(offset % align_of::<usize>(), 0);
+            assert!(offset >= (i << color_bits) % (64 << i));
+        }
+
+        // Every block of the 128 byte slab can be handed out and freed again.
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let total = heap.free_blocks(HeapAllocator::Slab128Bytes);
+        let mut blocks = [NonNull::dangling(); 32];
+        for block in blocks[..total].iter_mut() {
+            *block = heap.allocate(layout.clone()).unwrap();
+            assert_eq!(block.as_ptr() as usize % align_of::<usize>(), 0);
+        }
+        assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), 0);
+        for &block in blocks[..total].iter() {
+            unsafe { heap.deallocate(block, layout.clone()) };
+        }
+        assert_eq!(heap.free_blocks(HeapAllocator::Slab128Bytes), total);
+    }
+}
+
+#[test]
+fn slab_for_align() {
+    let heap = leaked_heap();
+    assert_eq!(heap.slab_for_align(8), Some(HeapAllocator::Slab64Bytes));
+    assert_eq!(heap.slab_for_align(512), Some(HeapAllocator::Slab512Bytes));
+    assert_eq!(
+        heap.slab_for_align(4096),
+        Some(HeapAllocator::Slab4096Bytes)
+    );
+    assert_eq!(heap.slab_for_align(8192), None);
+
+    // Colored slabs deliver less than their block size, at most 64 bytes with 4 color bits.
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let heap = unsafe { Heap::new_with_color_offset(start, HEAP_SIZE, 4) };
+    assert_eq!(heap.slab_for_align(64), Some(HeapAllocator::Slab64Bytes));
+    assert_eq!(heap.slab_for_align(128), None);
+}
+
+#[test]
+fn blocks_in_page() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let slab = unsafe { Slab::new(start, 3 * 4096, 1024) };
+    assert_eq!(slab.blocks_in_page(start), 4);
+    assert_eq!(slab.blocks_in_page(start + 2 * 4096), 4);
+    assert_eq!(slab.blocks_in_page(start + 3 * 4096), 0);
+    // Only the blocks starting inside the page and the slab count.
+    assert_eq!(slab.blocks_in_page(start + 100), 4);
+    assert_eq!(slab.blocks_in_page(start + 2 * 4096 + 100), 3);
+
+    // The last block of a segment which is not a multiple of the block size is cut off.
+    let slab = unsafe { Slab::new(start, 4096 + 3000, 2048) };
+    assert_eq!(slab.blocks_in_page(start), 2);
+    assert_eq!(slab.blocks_in_page(start + 4096), 1);
+    assert_eq!(slab.total_blocks(), 3);
+    let slab = unsafe { Slab::new(start, 2 * 4096, 64) };
+    assert_eq!(slab.blocks_in_page(start + 4096), 64);
+}
+
+#[test]
+fn with_capacities() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let capacities = [(64, 100), (4096, 5), (64, 10)];
+    let mut heap = unsafe { Heap::with_capacities(start, HEAP_SIZE, &capacities).unwrap() };
+    for &(size, count) in [(64, 110), (4096, 5), (128, 0)].iter() {
+        let layout = Layout::from_size_align(size, size).unwrap();
+        for _ in 0..count {
+            let ptr = heap.allocate(layout.clone()).unwrap();
+            assert_eq!(ptr.as_ptr() as usize % size, 0);
+        }
+        assert!(heap.allocate(layout).is_err());
+    }
+    assert_eq!(
+        heap.linked_list_region_size(),
+        HEAP_SIZE - 110 * 64 - 5 * 4096
+    );
+
+    unsafe {
+        assert_eq!(
+            Heap::with_capacities(start, HEAP_SIZE, &[(4096, 9)]).err(),
+            Some(HeapInitError::TooSmall)
+        );
+        assert_eq!(
+            Heap::with_capacities(start, HEAP_SIZE, &[(100, 1)]).err(),
+            Some(HeapInitError::InvalidBlockSize)
+        );
+        assert_eq!(
+            Heap::with_capacities(start + 64, HEAP_SIZE, &[]).err(),
+            Some(HeapInitError::Misaligned)
+        );
+    }
+}
+
+// Only meaningful under Valgrind, such as with
+// `valgrind target/debug/deps/slab_allocator-<hash> --ignored valgrind`.
+#[cfg(feature = "valgrind")]
+#[test]
+#[ignore]
+fn valgrind_reports_read_of_freed_block() {
+    let mut heap = leaked_heap();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    unsafe {
+        ptr.as_ptr().write_bytes(7, 64);
+        heap.deallocate(ptr, layout);
+    }
+    let errors = valgrind::count_errors();
+    let value = unsafe { core::ptr::read_volatile(ptr.as_ptr().offset(32)) };
+    assert_eq!(valgrind::count_errors(), errors + 1, "read {}", value);
+}
+
+#[test]
+fn new_verifying() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    assert!(unsafe { Heap::new_verifying(start, HEAP_SIZE) }.is_ok());
+
+    let slab = unsafe { Slab::new(start, 4096, 64) };
+    assert_eq!(slab.verify_new_free_list(64), Ok(()));
+    // A link which does not read back as written, as with a stuck bit.
+    let addr = start + 10 * 64;
+    unsafe { *(addr as *mut usize) ^= 1 << 20 };
+    assert_eq!(slab.verify_new_free_list(64), Err(addr));
+    // Sampling every 16th block misses it.
+    assert_eq!(slab.verify_new_free_list(4), Ok(()));
+    unsafe { *(addr as *mut usize) ^= 1 << 20 };
+}
+
+#[test]
+fn heap_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Heap>();
+}
+
+#[test]
+fn large_alloc_health() {
+    let mut heap = leaked_big_heap();
+    let health = heap.large_alloc_health();
+    // Apart from a few bytes the allocator may split off, the region is one extent.
+    assert!(health.largest_free > health.total_free - 64);
+    assert!(health.total_free > BIG_HEAP_SIZE / NUM_OF_SLABS - 64);
+
+    let layout = Layout::from_size_align(8000, 8).unwrap();
+    let chunks: Vec<_> = (0..4)
+        .map(|_| heap.allocate(layout.clone()).unwrap())
+        .collect();
+    unsafe {
+        heap.deallocate(chunks[0], layout.clone());
+        heap.deallocate(chunks[2], layout.clone());
+    }
+    let fragmented = heap.large_alloc_health();
+    assert!(fragmented.free_block_count >= 3);
+    assert!(fragmented.largest_free < fragmented.total_free - 8000);
+    assert!(fragmented.largest_free < 10000);
+    // Probing leaves the heap as it was.
+    assert_eq!(heap.large_alloc_health(), fragmented);
+    let big = Layout::from_size_align(16000, 8).unwrap();
+    assert!(heap.allocate(big).is_err());
+
+    unsafe {
+        heap.deallocate(chunks[1], layout.clone());
+        heap.deallocate(chunks[3], layout);
+    }
+    assert_eq!(heap.large_alloc_health(), health);
+}
+
+#[cfg(not(feature = "tiny"))]
+#[test]
+fn heap_descriptor() {
+    let descriptor = &SLAB_ALLOCATOR_DESCRIPTOR;
+    assert_eq!(descriptor.version, HEAP_DESCRIPTOR_VERSION);
+    // The offsets are computed by hand, so check them against the addresses of the fields.
+    let unregistered = leaked_heap();
+    let heap_addr = &unregistered as *const Heap as usize;
+    let slabs = [
+        &unregistered.slab_64_bytes,
+        &unregistered.slab_128_bytes,
+        &unregistered.slab_256_bytes,
+        &unregistered.slab_512_bytes,
+        &unregistered.slab_1024_bytes,
+        &unregistered.slab_2048_bytes,
+        &unregistered.slab_4096_bytes,
+    ];
+    for (slab, &offset) in slabs.iter().zip(descriptor.slab_offsets.iter()) {
+        assert_eq!(offset, *slab as *const Slab as usize - heap_addr);
+    }
+    assert_eq!(
+        [
+            ("block_size", descriptor.slab_block_size_offset),
+            ("total_blocks", descriptor.slab_total_blocks_offset),
+            ("segments", descriptor.slab_segments_offset),
+            ("segment_records", descriptor.slab_segment_records_offset),
+            ("free_list_head", descriptor.slab_free_list_head_offset),
+            ("free_list_len", descriptor.slab_free_list_len_offset),
+            (
+                "next_uninitialized",
+                descriptor.slab_next_uninitialized_offset
+            ),
+            (
+                "uninitialized_end",
+                descriptor.slab_uninitialized_end_offset
+            ),
+            ("segment_start_addr", descriptor.segment_start_addr_offset),
+            ("segment_size", descriptor.segment_size_offset),
+            ("records_next", descriptor.records_next_offset),
+            ("records_len", descriptor.records_len_offset),
+            ("records", descriptor.records_offset),
+            ("free_block_next", descriptor.free_block_next_offset),
+        ],
+        slab::field_offsets()
+    );
+    assert_eq!(descriptor.segment_size, size_of::<Segment>());
+
+    let heap: &'static LockedHeap = Box::leak(Box::new(leaked_locked_heap()));
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let ptr = unsafe { Alloc::alloc(&mut &*heap, layout) }.unwrap();
+    Heap::register_for_debug(heap);
+
+    // Read the heap like a debugger would, from raw memory.
+    let base = SLAB_ALLOCATOR_HEAP.load(Ordering::SeqCst);
+    let guard = heap.lock();
+    assert_eq!(base, guard.as_ref().unwrap() as *const Heap as usize);
+    let read = |addr: usize| unsafe { *(addr as *const usize) };
+    let slab = base + descriptor.slab_offsets[2];
+    assert_eq!(read(slab + descriptor.slab_block_size_offset), 256);
+    let slab_256 = guard
+        .as_ref()
+        .unwrap()
+        .slab(HeapAllocator::Slab256Bytes)
+        .unwrap();
+    assert_eq!(
+        (
+            read(slab + descriptor.slab_free_list_head_offset),
+            read(slab + descriptor.slab_free_list_len_offset)
+        ),
+        slab_256.free_list_raw_parts()
+    );
+    assert_eq!(read(slab + descriptor.slab_segment_records_offset), 0);
+    let segment = slab + descriptor.slab_segments_offset;
+    assert_eq!(
+        read(segment + descriptor.segment_start_addr_offset),
+        ptr.as_ptr() as usize
+    );
+    let head = read(slab + descriptor.slab_free_list_head_offset);
+    assert_eq!(read(head + descriptor.free_block_next_offset), head + 256);
+}
+
+// A telemetry hook allocating from the heap it observes, which would deadlock.
+#[cfg(all(feature = "std", debug_assertions, feature = "stats"))]
+#[test]
+#[should_panic(expected = "allocator re-entered")]
+fn reentrant_allocation_panics() {
+    static HEAP: LockedHeap = LockedHeap::empty();
+
+    struct AllocatingTelemetry;
+
+    impl HeapTelemetry for AllocatingTelemetry {
+        fn on_allocate(&self, layout: &Layout, _slab: HeapAllocator, _ptr: NonNull<u8>) {
+            let _ = unsafe { Alloc::alloc(&mut &HEAP, layout.clone()) };
+        }
+    }
+
+    static TELEMETRY: AllocatingTelemetry = AllocatingTelemetry;
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    *HEAP.lock() = Some(unsafe { Heap::new_with_telemetry(start, HEAP_SIZE, &TELEMETRY) });
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let _ = unsafe { Alloc::alloc(&mut &HEAP, layout) };
+}
+
+// A `Debug` impl allocating while its caller holds the heap through `lock()`.
+#[cfg(all(feature = "std", debug_assertions))]
+#[test]
+#[should_panic(expected = "allocator re-entered")]
+fn allocation_while_locked_panics() {
+    use std::fmt::Write;
+
+    struct Allocating<'a>(&'a LockedHeap);
+
+    impl<'a> fmt::Debug for Allocating<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            let _ = unsafe { Alloc::alloc(&mut &*self.0, layout) };
+            write!(f, "Allocating")
+        }
+    }
+
+    let locked_heap = leaked_locked_heap();
+    let heap = locked_heap.lock();
+    let mut out = std::string::String::new();
+    let _ = write!(out, "{:?} {}", Allocating(&locked_heap), heap.is_some());
+}
+
+#[cfg(feature = "waste-tracking")]
+#[test]
+fn total_wasted_bytes() {
+    let mut heap = leaked_big_heap();
+    assert_eq!(heap.total_wasted_bytes(), 0);
+    let sizes = [100, 65, 4000, 5000];
+    let ptrs: Vec<_> = sizes
+        .iter()
+        .map(|&size| {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            (heap.allocate(layout.clone()).unwrap(), layout)
+        })
+        .collect();
+    // The 5000 byte allocation goes to the linked list allocator and wastes nothing.
+    assert_eq!(heap.total_wasted_bytes(), 28 + 63 + 96);
+    unsafe { heap.deallocate(ptrs[1].0, ptrs[1].1.clone()) };
+    assert_eq!(heap.total_wasted_bytes(), 28 + 96);
+    for (i, &(ptr, ref layout)) in ptrs.iter().enumerate() {
+        if i != 1 {
+            unsafe { heap.deallocate(ptr, layout.clone()) };
+        }
+    }
+    assert_eq!(heap.total_wasted_bytes(), 0);
+}
+
+#[test]
+fn prefault() {
+    let mut heap = leaked_big_heap();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    unsafe { ptr.as_ptr().write(42) };
+    assert_eq!(heap.prefault(), BIG_HEAP_SIZE / 4096);
+    // Touching leaves the contents, and with them the free lists, alone.
+    assert_eq!(unsafe { ptr.as_ptr().read() }, 42);
+    unsafe { heap.deallocate(ptr, layout) };
+    assert_eq!(
+        heap.total_free_blocks(),
+        heap.total_blocks_across_all_slabs()
+    );
+
+    // Grown memory is touched too.
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    unsafe { heap.grow(start, 2 * 4096, HeapAllocator::Slab128Bytes) };
+    assert_eq!(heap.prefault(), BIG_HEAP_SIZE / 4096 + 2);
+}
+
+#[test]
+fn allocate_zeroed_batch() {
+    let mut heap = leaked_heap();
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    // Leave garbage behind in the blocks the batch will get.
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    unsafe {
+        ptr.as_ptr().write_bytes(0xAB, 1024);
+        heap.deallocate(ptr, layout.clone());
+    }
+    let mut out = [NonNull::dangling(); 4];
+    assert_eq!(heap.allocate_zeroed_batch(layout.clone(), 3, &mut out), 3);
+    for ptr in out[..3].iter() {
+        let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 1024) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+    // The 1024 byte slab of the small heap has only four blocks, so the batch stops early.
+    assert_eq!(heap.allocate_zeroed_batch(layout, 4, &mut out), 1);
+}
+
+#[test]
+fn allocate_zeroed_batch_skips_known_zero_blocks() {
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new_lazy_zeroed(start, HEAP_SIZE) };
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    // One block is used and freed, so it is on the free list and must be zeroed again.
+    let used = heap.allocate(layout.clone()).unwrap();
+    unsafe {
+        used.as_ptr().write_bytes(0xAB, 1024);
+        heap.deallocate(used, layout.clone());
+    }
+    let slab = heap.slab(HeapAllocator::Slab1024Bytes).unwrap();
+    let known_zero = if cfg!(feature = "poison") { 0 } else { 3 };
+    assert_eq!(slab.known_zero_blocks(), known_zero);
+
+    // Mark the last block, which the slab never touched, to see whether it gets zeroed.
+    let last = start + 4 * 4096 + 3 * 1024;
+    let skipped = !cfg!(any(
+        feature = "poison",
+        feature = "alloc-fill",
+        feature = "valgrind"
+    ));
+    if skipped {
+        unsafe { *((last + 8) as *mut u8) = 1 };
+    }
+
+    let mut out = [NonNull::dangling(); 4];
+    assert_eq!(heap.allocate_zeroed_batch(layout, 4, &mut out), 4);
+    assert_eq!(out[0], used);
+    assert_eq!(out[3].as_ptr() as usize, last);
+    for ptr in out[..3].iter() {
+        let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 1024) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+    // The batch relied on the memory being zero instead of zeroing it.
+    let marker = unsafe { *((last + 8) as *const u8) };
+    assert_eq!(marker, skipped as u8);
+}
+
+#[test]
+fn lazy_slab() {
+    let test_heap = TestHeap {
+        heap_space: [0xEE; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(256, 8).unwrap();
+
+    let mut lazy = unsafe { Slab::new_lazy(start, 4096, 256) };
+    // Nothing is written until blocks are handed out.
+    assert!(test_heap.heap_space[..4096]
+        .iter()
+        .all(|&byte| byte == 0xEE));
+    assert_eq!(lazy.uninitialized_blocks(), 16);
+    assert_eq!(lazy.free_blocks(), 16);
+
+    let first = lazy.allocate(layout.clone()).unwrap();
+    let second = lazy.allocate(layout.clone()).unwrap();
+    assert_eq!(first.as_ptr() as usize, start);
+    assert_eq!(second.as_ptr() as usize, start + 256);
+    assert!(test_heap.heap_space[512..4096]
+        .iter()
+        .all(|&byte| byte == 0xEE));
+    // Freed blocks are reused before uninitialized ones.
+    unsafe { lazy.deallocate(first) };
+    assert_eq!(lazy.free_blocks(), 15);
+    assert_eq!(lazy.allocate(layout.clone()).unwrap(), first);
+    unsafe {
+        lazy.deallocate(first);
+        lazy.deallocate(second);
+    }
+
+    let mut lazy_count = 0;
+    while lazy.allocate(layout.clone()).is_ok() {
+        lazy_count += 1;
+    }
+    let mut eager = unsafe { Slab::new(start + 4096, 4096, 256) };
+    let mut eager_count = 0;
+    while eager.allocate(layout.clone()).is_ok() {
+        eager_count += 1;
+    }
+    assert_eq!(lazy_count, eager_count);
+    assert_eq!(lazy.uninitialized_blocks(), 0);
+}
+
+#[test]
+fn lazy_slab_initialize_remaining() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(256, 8).unwrap();
+
+    let mut slab = unsafe { Slab::new_lazy(start, 4096, 256) };
+    let first = slab.allocate(layout.clone()).unwrap();
+    let _second = slab.allocate(layout.clone()).unwrap();
+    unsafe { slab.deallocate(first) };
+    slab.initialize_remaining();
+    assert_eq!(slab.uninitialized_blocks(), 0);
+    assert_eq!(slab.free_blocks(), 15);
+    // The freed block still comes first, then the rest in ascending order.
+    assert_eq!(slab.allocate(layout.clone()).unwrap(), first);
+    for i in 2..16 {
+        let ptr = slab.allocate(layout.clone()).unwrap();
+        assert_eq!(ptr.as_ptr() as usize, start + i * 256);
+    }
+    assert!(slab.allocate(layout).is_err());
+}
+
+#[test]
+fn lazy_heap() {
+    let test_heap = TestHeap {
+        heap_space: [0xEE; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let slab_size = HEAP_SIZE / NUM_OF_SLABS;
+    let mut heap = unsafe { Heap::new_lazy(start, HEAP_SIZE) };
+    assert!(test_heap.heap_space[..7 * slab_size]
+        .iter()
+        .all(|&byte| byte == 0xEE));
+    assert_eq!(
+        heap.total_free_blocks(),
+        heap.total_blocks_across_all_slabs()
+    );
+
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(ptr.as_ptr() as usize, start + slab_size);
+    #[cfg(all(feature = "poison", not(feature = "alloc-fill")))]
+    assert_eq!(unsafe { *ptr.as_ptr().offset(127) }, INIT_POISON);
+    unsafe { heap.deallocate(ptr, layout) };
+
+    heap.prefault();
+    for &slab in SLAB_ALLOCATORS.iter() {
+        assert_eq!(heap.slab(slab).unwrap().uninitialized_blocks(), 0);
+    }
+    assert_eq!(
+        heap.total_free_blocks(),
+        heap.total_blocks_across_all_slabs()
+    );
+}
+
+#[cfg(feature = "alloc-fill")]
+#[test]
+fn alloc_fill() {
+    let mut heap = leaked_big_heap();
+    // Slab blocks are filled up to the block size.
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 128) };
+    assert!(bytes.iter().all(|&byte| byte == ALLOC_FILL));
+    unsafe {
+        ptr.as_ptr().write_bytes(0, 128);
+        heap.deallocate(ptr, layout.clone());
+    }
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    assert_eq!(unsafe { *ptr.as_ptr().offset(127) }, ALLOC_FILL);
+
+    // Blocks picked by address are filled too.
+    let ptr = heap.allocate_color(layout.clone(), 1).unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 128) };
+    assert!(bytes.iter().all(|&byte| byte == ALLOC_FILL));
+    unsafe { heap.deallocate(ptr, layout.clone()) };
+
+    // Linked list chunks are filled up to the size the allocator rounds them up to.
+    let large = Layout::from_size_align(4099, 8).unwrap();
+    let ptr = heap.allocate(large.clone()).unwrap();
+    let usable = (4099 + align_of::<usize>() - 1) & !(align_of::<usize>() - 1);
+    let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), usable) };
+    assert!(bytes.iter().all(|&byte| byte == ALLOC_FILL));
+    unsafe { heap.deallocate(ptr, large.clone()) };
+    let ptr = heap.linked_list_allocate_aligned(5000, 4096).unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 5000) };
+    assert!(bytes.iter().all(|&byte| byte == ALLOC_FILL));
+    unsafe { heap.linked_list_deallocate(ptr, 5000, 4096) };
+
+    // Zeroed allocations are exempt.
+    for layout in [layout, large].iter() {
+        let ptr = unsafe { Alloc::alloc_zeroed(&mut heap, layout.clone()).unwrap() };
+        let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+}
+
+#[test]
+fn as_region_descriptor() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let slab_size = HEAP_SIZE / NUM_OF_SLABS;
+    let heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let regions = heap.as_region_descriptor();
+    for i in 0..NUM_OF_SLABS {
+        assert_eq!(regions[i].start, start + i * slab_size);
+        assert_eq!(regions[i].end, start + (i + 1) * slab_size);
+    }
+    assert_eq!(regions[0].allocator_type, "slab_64_bytes");
+    assert_eq!(regions[6].allocator_type, "slab_4096_bytes");
+    assert_eq!(regions[7].allocator_type, "linked_list");
+    assert_eq!(regions[8].allocator_type, "reserved_linked_list");
+    assert_eq!(regions[8].start, regions[8].end);
+
+    let heap = unsafe { Heap::new_with_reserved_ll_region(start, HEAP_SIZE, 1024) };
+    let regions = heap.as_region_descriptor();
+    assert_eq!(regions[8].start, start + 7 * slab_size);
+    assert_eq!(regions[8].end, regions[7].start);
+    assert_eq!(regions[7].end, start + HEAP_SIZE);
+}
+
+#[test]
+fn for_each_region() {
+    let test_heap = Box::leak(Box::new(TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let slab_size = HEAP_SIZE / NUM_OF_SLABS;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    // Leaves a gap between the 512 byte slab's two segments.
+    let grown = start + HEAP_SIZE + 4096;
+    unsafe { heap.grow(grown, 4096, HeapAllocator::Slab512Bytes) };
+    let mut regions = Vec::new();
+    heap.for_each_region(|region| regions.push(region));
+    assert_eq!(regions.len(), NUM_OF_SLABS + 2);
+    assert_eq!(
+        regions[3],
+        RegionDescriptor {
+            start: start + 3 * slab_size,
+            end: start + 4 * slab_size,
+            allocator_type: "slab_512_bytes",
+        }
+    );
+    assert_eq!(
+        regions[4],
+        RegionDescriptor {
+            start: grown,
+            end: grown + 4096,
+            allocator_type: "slab_512_bytes",
+        }
+    );
+    assert_eq!(regions[5].allocator_type, "slab_1024_bytes");
+    assert_eq!(regions[8].allocator_type, "linked_list");
+    assert_eq!(regions[9].allocator_type, "reserved_linked_list");
+
+    // The array reports the slab by its first segment only.
+    let array = heap.as_region_descriptor();
+    assert_eq!(array[3], regions[3]);
+}
+
+#[cfg(all(feature = "stats", feature = "custom-routing"))]
+#[test]
+fn fallback_counts() {
+    // Sends the 256 byte class to the 512 byte slab, as a router would once the 256 byte slab
+    // runs out.
+    fn route(layout: &Layout) -> HeapAllocator {
+        match Heap::layout_to_allocator(layout) {
+            HeapAllocator::Slab256Bytes => HeapAllocator::Slab512Bytes,
+            slab => slab,
+        }
+    }
+    let mut heap = leaked_heap();
+    heap.set_routing(route);
+    let layout = Layout::from_size_align(200, 8).unwrap();
+    let ptr = heap.allocate(layout.clone()).unwrap();
+    heap.allocate(Layout::from_size_align(500, 8).unwrap())
+        .unwrap();
+    let mut expected = [0; NUM_OF_SLABS];
+    expected[3] = 1;
+    assert_eq!(heap.fallback_counts(), expected);
+    unsafe { heap.deallocate(ptr, layout.clone()) };
+    heap.allocate(layout).unwrap();
+    expected[3] = 2;
+    assert_eq!(heap.fallback_counts(), expected);
+
+    // Layouts the colored 128 byte slab can't align are served by the linked list allocator.
+    let mut heap = unsafe {
+        let test_heap = Box::leak(Box::new(TestHeap {
+            heap_space: [0u8; HEAP_SIZE],
+        }));
+        Heap::new_with_color_offset(&test_heap.heap_space[0] as *const u8 as usize, HEAP_SIZE, 4)
+    };
+    heap.allocate(Layout::from_size_align(128, 128).unwrap())
+        .unwrap();
+    assert_eq!(heap.fallback_counts()[NUM_OF_SLABS - 1], 1);
+}
+
+#[test]
+fn heap_group() {
+    let first = leaked_locked_heap();
+    let second = leaked_locked_heap();
+    let heaps = [&first, &second];
+    let group = HeapGroup::new(&heaps);
+    let free_blocks = |heap: &LockedHeap| {
+        heap.lock()
+            .as_ref()
+            .unwrap()
+            .free_blocks(HeapAllocator::Slab256Bytes)
+    };
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let ptr = unsafe { Alloc::alloc(&mut &first, layout.clone()).unwrap() };
+    assert_eq!(free_blocks(&first), free_blocks(&second) - 1);
+
+    // Freed through the group, as by code holding a handle to the second heap only.
+    let owner = group.owner(ptr.as_ptr() as usize).unwrap();
+    assert!(owner as *const LockedHeap == &first as *const LockedHeap);
+    assert_eq!(unsafe { group.deallocate(ptr, layout.clone()) }, Ok(()));
+    assert_eq!(free_blocks(&first), free_blocks(&second));
+
+    // A transferred segment belongs to the heap it was transferred to only.
+    let segment_start = {
+        let heap = first.lock();
+        let slab = heap.as_ref().unwrap().slab(HeapAllocator::Slab256Bytes);
+        slab.unwrap().segments().next().unwrap().start_addr
+    };
+    assert!(first
+        .lock()
+        .as_mut()
+        .unwrap()
+        .transfer_segment(second.lock().as_mut().unwrap(), HeapAllocator::Slab256Bytes));
+    let owner = group.owner(segment_start).unwrap();
+    assert!(owner as *const LockedHeap == &second as *const LockedHeap);
+    assert_eq!(free_blocks(&first), 0);
+
+    let mut outside = 0u64;
+    let outside = NonNull::from(&mut outside).cast();
+    assert_eq!(
+        unsafe { group.deallocate(outside, layout) },
+        Err(DeallocError::NotOwned)
+    );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic]
+fn deallocate_to_wrong_heap() {
+    let mut first = leaked_heap();
+    let mut second = leaked_heap();
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let ptr = first.allocate(layout.clone()).unwrap();
+    unsafe { second.deallocate(ptr, layout) };
+}
+
+#[test]
+fn free_adjacent_pairs() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let mut slab = unsafe { Slab::new(start, 4096, 256) };
+    assert_eq!(slab.free_adjacent_pairs(), 15);
+    let blocks: Vec<_> = (0..16)
+        .map(|_| slab.allocate(layout.clone()).unwrap())
+        .collect();
+    assert_eq!(slab.free_adjacent_pairs(), 0);
+    for &i in [7, 0, 5, 2, 1, 8].iter() {
+        unsafe { slab.deallocate(blocks[i]) };
+    }
+    // Blocks 0, 1 and 2 make two pairs, 7 and 8 one more.
+    assert_eq!(slab.free_adjacent_pairs(), 3);
+
+    #[cfg(not(feature = "tiny"))]
+    {
+        let map = Box::leak(Box::new([0u64; 1]));
+        assert!(slab.enable_block_map(map));
+        assert_eq!(slab.free_adjacent_pairs(), 3);
+    }
+}
+
+#[test]
+fn with_locked() {
+    let heap = LockedHeap::empty();
+    *heap.lock() = Some(leaked_big_heap());
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut blocks = [NonNull::dangling(); 100];
+    heap.with_locked(|heap| {
+        for block in blocks.iter_mut() {
+            *block = heap.allocate(layout.clone()).unwrap();
+        }
+    });
+    let mut addrs: Vec<_> = blocks.iter().map(|ptr| ptr.as_ptr() as usize).collect();
+    addrs.sort();
+    addrs.dedup();
+    assert_eq!(addrs.len(), 100);
+    #[cfg(not(feature = "tiny"))]
+    assert_eq!(heap.counters().free_blocks(HeapAllocator::Slab64Bytes), 540);
+
+    let free = heap.with_locked(|heap| {
+        for &block in blocks.iter() {
+            unsafe { heap.deallocate(block, layout.clone()) };
+        }
+        heap.free_blocks(HeapAllocator::Slab64Bytes)
+    });
+    assert_eq!(free, 640);
+
+    assert_eq!(
+        LockedHeap::empty().try_with_locked(|heap| heap.total_free_blocks()),
+        Err(HeapNotInitialized)
+    );
+}
+
+#[test]
+fn large_search_limit() {
+    fn oom(_: Layout) -> ! {
+        panic!("the search limit is not running out of memory");
+    }
+    let mut heap = leaked_big_heap();
+    heap.set_oom_handler(oom);
+    // Fragment the linked list region into many holes.
+    let chunks: Vec<_> = (0..64)
+        .map(|_| heap.linked_list_allocate_aligned(128, 8).unwrap())
+        .collect();
+    for chunk in chunks.iter().step_by(2) {
+        unsafe { heap.linked_list_deallocate(*chunk, 128, 8) };
+    }
+
+    let layout = Layout::from_size_align(8192, 8).unwrap();
+    heap.set_large_search_limit(16);
+    assert_eq!(
+        heap.allocate_bounded(layout.clone()),
+        Err(BoundedAllocError::SearchLimit)
+    );
+    assert!(heap.allocate(layout.clone()).is_err());
+    assert!(heap
+        .allocate_with_hint(layout.clone(), Lifetime::Long)
+        .is_err());
+    // Small allocations are not limited.
+    assert!(heap
+        .allocate_bounded(Layout::from_size_align(128, 8).unwrap())
+        .is_ok());
+
+    heap.set_large_search_limit(usize::max_value());
+    let ptr = heap.allocate_bounded(layout.clone()).unwrap();
+    unsafe { heap.deallocate(ptr, layout.clone()) };
+    assert_eq!(
+        heap.allocate_bounded(Layout::from_size_align(BIG_HEAP_SIZE, 8).unwrap()),
+        Err(BoundedAllocError::OutOfMemory)
+    );
+
+    // Once the chunks are freed the search is short again.
+    for chunk in chunks.iter().skip(1).step_by(2) {
+        unsafe { heap.linked_list_deallocate(*chunk, 128, 8) };
+    }
+    heap.set_large_search_limit(16);
+    assert!(heap.allocate_bounded(layout.clone()).is_ok());
+
+    // Long-lived chunks are placed first fit, in a single search.
+    let first_fit = heap.allocate(layout.clone()).unwrap();
+    unsafe { heap.deallocate(first_fit, layout.clone()) };
+    let ptr = heap
+        .allocate_with_hint(layout.clone(), Lifetime::Long)
+        .unwrap();
+    assert_eq!(ptr, first_fit);
+}
+
+#[test]
+fn allocate_color() {
+    let mut heap = leaked_big_heap();
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let color_of = |addr: usize| (addr >> CACHE_COLOR_SHIFT) & 0xF;
+    let first = heap.allocate(layout.clone()).unwrap();
+    let color = (color_of(first.as_ptr() as usize) + 3) % 16;
+    let ptr = heap.allocate_color(layout.clone(), color as u8).unwrap();
+    assert_eq!(color_of(ptr.as_ptr() as usize), color);
+    // Higher bits are ignored.
+    let ptr = heap
+        .allocate_color(layout.clone(), (color + 16) as u8)
+        .unwrap();
+    assert_eq!(color_of(ptr.as_ptr() as usize), color);
+
+    // The 256 byte slab of the big heap spans 10 pages, so some colors have no blocks and any
+    // block is returned.
+    let missing = (0..16)
+        .find(|&color| (0..10).all(|page| color_of(first.as_ptr() as usize + page * 4096) != color))
+        .unwrap();
+    assert!(heap.allocate_color(layout.clone(), missing as u8).is_ok());
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab256Bytes), 160 - 4);
+
+    // Chunks over 4096 bytes are served as usual.
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    assert!(heap.allocate_color(large, 0).is_ok());
+}
+
+#[test]
+fn allocate_with_color_lazy() {
+    let space: &'static mut TestHeap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &space.heap_space[0] as *const u8 as usize;
+    let mut slab = unsafe { slab::Slab::new_lazy(start, 4 * 4096, 256) };
+    let mask = 0xF << CACHE_COLOR_SHIFT;
+    let color = (((start >> CACHE_COLOR_SHIFT) + 2) & 0xF) << CACHE_COLOR_SHIFT;
+    let ptr = slab.allocate_with_color(color, mask).unwrap();
+    assert_eq!(ptr.as_ptr() as usize, start + 2 * 4096);
+    // Only the blocks below it were linked, the rest stays uninitialized.
+    assert_eq!(slab.uninitialized_blocks(), 31);
+    assert_eq!(slab.free_blocks(), 63);
+    let color = (((start >> CACHE_COLOR_SHIFT) + 4) & 0xF) << CACHE_COLOR_SHIFT;
+    assert!(slab.allocate_with_color(color, mask).is_err());
+    assert_eq!(slab.uninitialized_blocks(), 31);
+}
+
+#[test]
+fn slab_with_align() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(96, 32).unwrap();
+    let mut slab = unsafe { Slab::new_with_align(start, 4096, 96, 32) };
+    assert_eq!(slab.total_blocks(), 42);
+    assert_eq!(slab.block_align(), 32);
+
+    let blocks: Vec<_> = (0..42)
+        .map(|_| slab.allocate(layout.clone()).unwrap())
+        .collect();
+    assert!(slab.allocate(layout.clone()).is_err());
+    for (i, block) in blocks.iter().enumerate() {
+        let addr = block.as_ptr() as usize;
+        assert_eq!(addr, start + i * 96);
+        assert_eq!(addr % 32, 0);
+    }
+    for &block in blocks.iter().rev() {
+        unsafe { slab.deallocate(block) };
+    }
+    assert_eq!(slab.free_blocks(), 42);
+    assert_eq!(slab.allocate(layout.clone()).unwrap(), blocks[0]);
+
+    // Grown memory only needs the block alignment.
+    unsafe { slab.grow(start + 4096 + 32, 960) };
+    assert_eq!(slab.total_blocks(), 52);
+    assert_eq!(slab.block_align(), 32);
+}
+
+#[test]
+#[should_panic]
+fn slab_with_align_not_dividing_block_size() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    unsafe { Slab::new_with_align(start, 4096, 96, 64) };
+}
+
+#[test]
+fn grow_and_allocate() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let mut heap = unsafe { Heap::new(start, HEAP_SIZE) };
+    let layout = Layout::from_size_align(512, 8).unwrap();
+    let ptr = unsafe { heap.grow_and_allocate(layout.clone(), start + HEAP_SIZE, 4096) }.unwrap();
+    assert_eq!(ptr.as_ptr() as usize, start + HEAP_SIZE);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab512Bytes), 8 + 7);
+
+    // Memory that can't be added is refused without touching the heap.
+    let misaligned = start + HEAP_SIZE + 4096 + 8;
+    assert!(unsafe { heap.grow_and_allocate(layout.clone(), misaligned, 4096) }.is_err());
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab512Bytes), 8 + 7);
+
+    // The linked list region of 4096 bytes is extended to fit the chunk.
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    let locked = LockedHeap::empty();
+    *locked.lock() = Some(unsafe { Heap::new(start + 2 * HEAP_SIZE, HEAP_SIZE) });
+    assert!(unsafe { locked.grow_and_allocate(large, start + 3 * HEAP_SIZE, 8192) }.is_ok());
+    #[cfg(not(feature = "tiny"))]
+    assert_eq!(locked.counters().alloc_count(), 1);
+}
+
+#[test]
+fn max_alloc_ops() {
+    let mut heap = leaked_big_heap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    assert_eq!(heap.max_alloc_ops(&small), Some(1));
+    assert_eq!(heap.max_alloc_ops(&large), Some(1));
+
+    // 32 chunks separated by 32 holes, followed by the rest of the region.
+    let chunks: Vec<_> = (0..64)
+        .map(|_| heap.linked_list_allocate_aligned(128, 8).unwrap())
+        .collect();
+    for chunk in chunks.iter().step_by(2) {
+        unsafe { heap.linked_list_deallocate(*chunk, 128, 8) };
+    }
+    assert_eq!(heap.max_alloc_ops(&small), Some(1));
+    assert_eq!(heap.max_alloc_ops(&large), Some(33));
+
+    heap.set_large_search_limit(16);
+    assert_eq!(heap.max_alloc_ops(&large), Some(1));
+}
+
+#[test]
+fn contiguity() {
+    let mut heap = leaked_heap();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let all_free = ClassContiguity {
+        longest_run: 64,
+        runs: 1,
+    };
+    assert_eq!(heap.contiguity(HeapAllocator::Slab64Bytes), all_free);
+    let blocks: Vec<_> = (0..64)
+        .map(|_| heap.allocate(layout.clone()).unwrap())
+        .collect();
+    assert_eq!(
+        heap.contiguity(HeapAllocator::Slab64Bytes),
+        ClassContiguity::default()
+    );
+
+    // Every other block is free.
+    for block in blocks.iter().step_by(2) {
+        unsafe { heap.deallocate(*block, layout.clone()) };
+    }
+    let checkerboard = ClassContiguity {
+        longest_run: 1,
+        runs: 32,
+    };
+    assert_eq!(heap.contiguity(HeapAllocator::Slab64Bytes), checkerboard);
+    #[cfg(not(feature = "tiny"))]
+    {
+        let map = Box::leak(Box::new([0u64; 1]));
+        assert!(heap.enable_fragmentation_map(HeapAllocator::Slab64Bytes, map));
+        assert_eq!(heap.contiguity(HeapAllocator::Slab64Bytes), checkerboard);
+    }
+
+    for block in blocks.iter().skip(1).step_by(2) {
+        unsafe { heap.deallocate(*block, layout.clone()) };
+    }
+    assert_eq!(heap.contiguity(HeapAllocator::Slab64Bytes), all_free);
+    assert_eq!(
+        heap.contiguity(HeapAllocator::LinkedListAllocator),
+        ClassContiguity::default()
+    );
+}
+
+#[test]
+fn move_all_to() {
+    let test_heap = TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let mut slab = unsafe { Slab::new(start, 4096, 256) };
+    let mut dest = unsafe { Slab::new(start + 4096, 4096, 256) };
+    let mut other = unsafe { Slab::new(start + 2 * 4096, 4096, 128) };
+    assert_eq!(
+        slab.move_all_to(&mut other),
+        Err(MoveError::IncompatibleBlockSize)
+    );
+    for _ in 0..3 {
+        slab.allocate(layout.clone()).unwrap();
+    }
+    let ptr = slab.allocate(layout.clone()).unwrap();
+    assert_eq!(slab.move_all_to(&mut dest), Ok(12));
+    assert_eq!(slab.free_blocks(), 0);
+    assert_eq!(slab.total_blocks(), 0);
+    assert_eq!(slab.segments().count(), 0);
+    assert_eq!(dest.free_blocks(), 16 + 12);
+    assert_eq!(dest.segments().count(), 1);
+
+    // Blocks allocated from the slab are freed to `dest`.
+    unsafe { dest.deallocate(ptr) };
+    assert_eq!(dest.free_blocks(), 16 + 13);
+    assert_eq!(dest.total_blocks(), 32);
+
+    // Scattered free blocks move with the memory around them.
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut slab = unsafe { Slab::new(start + 3 * 4096, 4096, 64) };
+    let mut dest = unsafe { Slab::new(start + 5 * 4096, 4096, 64) };
+    let blocks: Vec<_> = (0..64)
+        .map(|_| slab.allocate(layout.clone()).unwrap())
+        .collect();
+    for block in blocks.iter().step_by(2) {
+        unsafe { slab.deallocate(*block) };
+    }
+    assert_eq!(slab.move_all_to(&mut dest), Ok(32));
+    assert_eq!(slab.free_blocks(), 0);
+    assert_eq!(dest.free_blocks(), 64 + 32);
+    assert!(dest.owns(blocks[1].as_ptr() as usize));
+    assert_eq!(slab.move_all_to(&mut dest), Ok(0));
+}
+
+#[test]
+fn new_with_holes() {
+    let test_heap = TestBigHeap {
+        heap_space: [0u8; BIG_HEAP_SIZE],
+    };
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    let hole_start = start + HEAP_SIZE + 100;
+    let hole_end = hole_start + 5000;
+    let (mut heap, summary) = unsafe {
+        Heap::new_with_holes(start, BIG_HEAP_SIZE, &[(hole_start, hole_end - hole_start)])
+    }
+    .unwrap();
+    // The heap is created over the first 32K and grown by the memory after the hole.
+    assert_eq!(heap.linked_list_region_start(), start + 7 * 4096);
+    assert_eq!(summary.bytes_used, BIG_HEAP_SIZE - HEAP_SIZE - 2 * 4096);
+    assert!(!heap.owns(hole_start));
+    assert!(!heap.owns(hole_end - 1));
+    assert!(heap.owns(start + HEAP_SIZE + 2 * 4096));
+    assert!(heap.owns(start + BIG_HEAP_SIZE - 1));
+
+    let mut size = 64;
+    while size <= 8192 {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        while let Ok(ptr) = heap.allocate(layout.clone()) {
+            let addr = ptr.as_ptr() as usize;
+            assert!(addr + size <= hole_start || addr >= hole_end);
+        }
+        size *= 2;
+    }
+
+    // The first page is too small for a heap, which is created over 256K after the hole.
+    assert_eq!(
+        unsafe { Heap::new_with_holes(start, BIG_HEAP_SIZE, &[(start + 4096, HEAP_SIZE)]) }
+            .map(|(heap, _)| heap.linked_list_region_start()),
+        Ok(start + HEAP_SIZE + 4096 + 7 * (8 * HEAP_SIZE / NUM_OF_SLABS))
+    );
+    assert_eq!(
+        unsafe { Heap::new_with_holes(start, BIG_HEAP_SIZE, &[(start + 4096, BIG_HEAP_SIZE)]) }
+            .map(|_| ()),
+        Err(HeapInitError::TooSmall)
+    );
+}
+
+#[test]
+fn donate_class_to_large() {
+    let mut heap = leaked_heap();
+    let start = heap.linked_list_region_start() - 7 * 4096;
+    let buffer = Layout::from_size_align(2048, 8).unwrap();
+    let large = Layout::from_size_align(10000, 8).unwrap();
+    assert!(heap.allocate(large.clone()).is_err());
+    assert_eq!(
+        heap.donate_class_to_large(HeapAllocator::LinkedListAllocator),
+        Err(DonateError::NotASlab)
+    );
+
+    let ptr = heap.allocate(buffer.clone()).unwrap();
+    assert_eq!(
+        heap.donate_class_to_large(HeapAllocator::Slab2048Bytes),
+        Err(DonateError::LiveAllocations)
+    );
+    unsafe { heap.deallocate(ptr, buffer.clone()) };
+    // The 2048 byte slab lies below the 4096 byte slab, which still separates it from the linked
+    // list region.
+    assert_eq!(
+        heap.donate_class_to_large(HeapAllocator::Slab2048Bytes),
+        Err(DonateError::NotAdjacent)
+    );
+    assert_eq!(
+        heap.donate_class_to_large(HeapAllocator::Slab4096Bytes),
+        Ok(4096)
+    );
+    assert_eq!(
+        heap.donate_class_to_large(HeapAllocator::Slab2048Bytes),
+        Ok(4096)
+    );
+    assert_eq!(heap.linked_list_region_start(), start + 5 * 4096);
+    assert_eq!(heap.linked_list_region_size(), 3 * 4096);
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab2048Bytes), 0);
+    assert!(heap.allocate(buffer).is_err());
+
+    let ptr = heap.allocate(large.clone()).unwrap();
+    assert!(ptr.as_ptr() as usize >= start + 5 * 4096);
+    unsafe { heap.deallocate(ptr, large) };
+}
+
+#[test]
+fn slab_for_size_and_align() {
+    assert_eq!(
+        Heap::slab_for_size_and_align(64, 8),
+        HeapAllocator::Slab64Bytes
+    );
+    assert_eq!(
+        Heap::slab_for_size_and_align(8, 128),
+        HeapAllocator::Slab128Bytes
+    );
+    assert_eq!(
+        Heap::slab_for_size_and_align(4097, 8),
+        HeapAllocator::LinkedListAllocator
+    );
+    for size in [1, 64, 65, 100, 1000, 2048, 4096, 5000].iter() {
+        for align in [1, 8, 256, 4096, 8192].iter() {
+            let layout = Layout::from_size_align(*size, *align).unwrap();
+            assert_eq!(
+                Heap::slab_for_size_and_align(*size, *align),
+                Heap::layout_to_allocator(&layout)
+            );
+        }
+    }
+}
+
+#[test]
+fn transfer_segment() {
+    let mut from = leaked_heap();
+    let mut to = leaked_big_heap();
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let (from_capacity, to_capacity) = (from.capacity(), to.capacity());
+    assert!(!from.transfer_segment(&mut to, HeapAllocator::LinkedListAllocator));
+
+    let ptr = from.allocate(layout.clone()).unwrap();
+    assert!(!from.transfer_segment(&mut to, HeapAllocator::Slab1024Bytes));
+    unsafe { from.deallocate(ptr, layout.clone()) };
+    assert!(from.transfer_segment(&mut to, HeapAllocator::Slab1024Bytes));
+    assert_eq!(from.capacity(), from_capacity - 4096);
+    assert_eq!(to.capacity(), to_capacity + 4096);
+    assert!(!from.transfer_segment(&mut to, HeapAllocator::Slab1024Bytes));
+
+    assert!(from.allocate(layout.clone()).is_err());
+    assert_eq!(to.free_blocks(HeapAllocator::Slab1024Bytes), 40 + 4);
+    let blocks: Vec<_> = (0..44)
+        .map(|_| to.allocate(layout.clone()).unwrap())
+        .collect();
+    for block in blocks {
+        unsafe { to.deallocate(block, layout.clone()) };
+    }
+}
+
+#[cfg(feature = "custom-routing")]
+#[test]
+fn small_object_caches_follow_routing() {
+    fn route(layout: &Layout) -> HeapAllocator {
+        if layout.size() <= 64 {
+            HeapAllocator::LinkedListAllocator
+        } else {
+            Heap::layout_to_allocator(layout)
+        }
+    }
+    let mut heap = leaked_big_heap();
+    heap.set_routing(route);
+    let ll_start = heap.linked_list_region_start();
+    let locked = LockedHeap::empty();
+    *locked.lock() = Some(heap);
+
+    // A small chunk of the linked list allocator is freed to it, not to the 64 byte slab.
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    let ptr = unsafe { GlobalAlloc::alloc(&locked, layout.clone()) };
+    assert!(ptr as usize >= ll_start);
+    unsafe { GlobalAlloc::dealloc(&locked, ptr, layout) };
+    locked.flush_caches();
+    let heap = locked.lock();
+    let heap = heap.as_ref().unwrap();
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 640);
+    assert_eq!(heap.load_factor(), 0);
+}
+
+#[test]
+fn small_object_caches_are_flushed_when_heap_is_replaced() {
+    let locked = leaked_locked_heap();
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let ptr = unsafe { GlobalAlloc::alloc(&locked, layout.clone()) };
+    unsafe { GlobalAlloc::dealloc(&locked, ptr, layout.clone()) };
+
+    let old = locked.lock().take().unwrap();
+    assert_eq!(old.total_free_blocks(), old.total_blocks_across_all_slabs());
+    *locked.lock() = Some(leaked_heap());
+    let ptr = unsafe { GlobalAlloc::alloc(&locked, layout.clone()) };
+    assert!(locked.owns(ptr as usize));
+    assert!(!old.owns(ptr as usize));
+    unsafe { GlobalAlloc::dealloc(&locked, ptr, layout.clone()) };
+
+    // Likewise when the heap is initialized again.
+    let test_heap = Box::leak(Box::new(TestHeap {
+        heap_space: [0u8; HEAP_SIZE],
+    }));
+    let start = &test_heap.heap_space[0] as *const u8 as usize;
+    unsafe { locked.init(start, HEAP_SIZE) };
+    let ptr = unsafe { GlobalAlloc::alloc(&locked, layout.clone()) } as usize;
+    assert!(ptr >= start && ptr < start + HEAP_SIZE);
+}
+
+#[cfg(feature = "custom-routing")]
+#[test]
+fn set_bypass() {
+    let mut heap = leaked_big_heap();
+    let ll_start = heap.linked_list_region_start();
+    let in_ll_region = |ptr: NonNull<u8>| ptr.as_ptr() as usize >= ll_start;
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    let before = heap.allocate(small.clone()).unwrap();
+    assert!(!in_ll_region(before));
+
+    heap.set_bypass(true);
+    let during = heap.allocate(small.clone()).unwrap();
+    let during_large = heap.allocate(large.clone()).unwrap();
+    assert!(in_ll_region(during));
+    assert!(in_ll_region(during_large));
+    unsafe { heap.deallocate(before, small.clone()) };
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 640);
+    assert_eq!(unsafe { heap.try_deallocate(during_large, large) }, Ok(()));
+
+    heap.set_bypass(false);
+    let after = heap.allocate(small.clone()).unwrap();
+    assert!(!in_ll_region(after));
+    unsafe { heap.deallocate(during, small.clone()) };
+    unsafe { heap.deallocate(after, small.clone()) };
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 640);
+    assert_eq!(heap.load_factor(), 0);
+
+    // Chunks of the linked list allocator must not end up in the small object caches.
+    let locked = LockedHeap::empty();
+    *locked.lock() = Some(leaked_big_heap());
+    let ll_start = locked.lock().as_ref().unwrap().linked_list_region_start();
+    let before = unsafe { Alloc::alloc(&mut &locked, small.clone()) }.unwrap();
+    locked.set_bypass(true);
+    let during = unsafe { Alloc::alloc(&mut &locked, small.clone()) }.unwrap();
+    assert!(during.as_ptr() as usize >= ll_start);
+    unsafe { Alloc::dealloc(&mut &locked, during, small.clone()) };
+    locked.set_bypass(false);
+    unsafe { Alloc::dealloc(&mut &locked, before, small.clone()) };
+    let after = unsafe { Alloc::alloc(&mut &locked, small.clone()) }.unwrap();
+    assert!((after.as_ptr() as usize) < ll_start);
+    unsafe { Alloc::dealloc(&mut &locked, after, small) };
+    let heap = locked.lock();
+    let heap = heap.as_ref().unwrap();
+    assert_eq!(heap.free_blocks(HeapAllocator::Slab64Bytes), 640);
+    assert_eq!(heap.load_factor(), 0);
+}
+
+#[test]
+#[cfg(feature = "stats")]
+fn rate_since_last() {
+    let mut heap = leaked_big_heap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    let blocks: Vec<_> = (0..5)
+        .map(|_| heap.allocate(small.clone()).unwrap())
+        .collect();
+    let chunk = heap.allocate(large.clone()).unwrap();
+    unsafe { heap.deallocate(blocks[0], small.clone()) };
+    let mut expected = [(0, 0); NUM_OF_SLABS];
+    expected[0] = (5, 1);
+    expected[NUM_OF_SLABS - 1] = (1, 0);
+    assert_eq!(heap.rate_since_last(), expected);
+
+    for &block in blocks[1..].iter() {
+        unsafe { heap.deallocate(block, small.clone()) };
+    }
+    unsafe { heap.deallocate(chunk, large) };
+    heap.allocate(Layout::from_size_align(256, 8).unwrap())
+        .unwrap();
+    let mut expected = [(0, 0); NUM_OF_SLABS];
+    expected[0] = (0, 4);
+    expected[2] = (1, 0);
+    expected[NUM_OF_SLABS - 1] = (0, 1);
+    assert_eq!(heap.rate_since_last(), expected);
+    assert_eq!(heap.rate_since_last(), [(0, 0); NUM_OF_SLABS]);
+}
+
+#[test]
+#[cfg(feature = "stats")]
+fn locked_rate_since_last() {
+    let heap = leaked_locked_heap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let blocks: Vec<_> = (0..3)
+        .map(|_| unsafe { Alloc::alloc(&mut &heap, small.clone()) }.unwrap())
+        .collect();
+    unsafe { Alloc::dealloc(&mut &heap, blocks[0], small.clone()) };
+    unsafe { Alloc::alloc(&mut &heap, Layout::from_size_align(256, 8).unwrap()) }.unwrap();
+    // Blocks served by the small object cache are counted along with the heap's.
+    let mut expected = [(0, 0); NUM_OF_SLABS];
+    expected[0] = (3, 1);
+    expected[2] = (1, 0);
+    assert_eq!(heap.rate_since_last(), expected);
+
+    for &block in blocks[1..].iter() {
+        unsafe { Alloc::dealloc(&mut &heap, block, small.clone()) };
+    }
+    let mut expected = [(0, 0); NUM_OF_SLABS];
+    expected[0] = (0, 2);
+    assert_eq!(heap.rate_since_last(), expected);
+    assert_eq!(heap.rate_since_last(), [(0, 0); NUM_OF_SLABS]);
+}
@@ -0,0 +1,134 @@
+use core::mem::{align_of, size_of};
+
+use super::{MIN_SLAB_SIZE, NUM_OF_SLABS};
+
+/// Reasons why `validate_slab_config` rejected a set of slab classes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// There are more sizes than slabs.
+    TooManySizes,
+    /// The block can't hold the free list pointer kept in free blocks.
+    TooSmall { size: usize },
+    /// The block alignment is not a power of two of at least the pointer alignment.
+    InvalidAlign { size: usize, align: usize },
+    /// The block size is not a multiple of the block alignment, so successive blocks would not
+    /// stay aligned.
+    NotMultipleOfAlign { size: usize, align: usize },
+    /// The block size is not larger than the one before it, such as a duplicate.
+    NotIncreasing { size: usize },
+    /// The block size is larger than the allocations the slabs serve before the linked list
+    /// allocator takes over.
+    TooLarge { size: usize },
+}
+
+/// Suggests block sizes for `num_classes` slabs which keep the memory wasted by rounding
+/// allocations up to their block size low. `histogram` holds `(allocation size, count)` pairs,
+/// for example captured from a running system, in any order.
+///
+/// Classes are picked greedily: the largest size is always a class, then the size which
+/// reduces the wasted memory the most is added until there are `num_classes` classes or no
+/// sizes are left. The suggested sizes are sorted ascending and rounded up to a multiple of the
+/// pointer size, unused entries of the returned array are 0.
+pub fn suggest_slab_sizes(
+    histogram: &[(usize, u64)],
+    num_classes: usize,
+) -> [usize; NUM_OF_SLABS - 1] {
+    heap_assert!(
+        num_classes <= NUM_OF_SLABS - 1,
+        "Number of classes should not exceed the number of slabs"
+    );
+    let mut classes = [0; NUM_OF_SLABS - 1];
+    let largest = histogram
+        .iter()
+        .filter(|&&(_, count)| count > 0)
+        .map(|&(size, _)| block_size_for(size))
+        .max();
+    match largest {
+        Some(largest) if num_classes > 0 => classes[0] = largest,
+        _ => return classes,
+    }
+    let mut num_of_classes = 1;
+    while num_of_classes < num_classes {
+        let mut best: Option<(u64, usize)> = None;
+        for &(size, count) in histogram {
+            let candidate = block_size_for(size);
+            if count == 0 || classes[..num_of_classes].contains(&candidate) {
+                continue;
+            }
+            classes[num_of_classes] = candidate;
+            let waste = wasted_bytes(histogram, &classes[..num_of_classes + 1]);
+            if best.map_or(true, |(best_waste, _)| waste < best_waste) {
+                best = Some((waste, candidate));
+            }
+        }
+        match best {
+            Some((_, candidate)) => classes[num_of_classes] = candidate,
+            None => {
+                classes[num_of_classes] = 0;
+                break;
+            }
+        }
+        num_of_classes += 1;
+    }
+    classes[..num_of_classes].sort_unstable();
+    classes
+}
+
+/// Smallest block size which can hold an allocation of `size` bytes and a free list pointer.
+fn block_size_for(size: usize) -> usize {
+    let size = if size < size_of::<usize>() {
+        size_of::<usize>()
+    } else {
+        size
+    };
+    (size + size_of::<usize>() - 1) & !(size_of::<usize>() - 1)
+}
+
+/// Bytes wasted when every allocation of `histogram` is served by the smallest fitting class.
+fn wasted_bytes(histogram: &[(usize, u64)], classes: &[usize]) -> u64 {
+    histogram
+        .iter()
+        .map(|&(size, count)| {
+            let class = classes
+                .iter()
+                .filter(|&&class| class >= size)
+                .min()
+                .cloned()
+                .unwrap_or(size);
+            count * (class - size) as u64
+        })
+        .sum()
+}
+
+/// Checks a set of `(block size, block alignment)` slab classes, such as ones picked with the
+/// help of `suggest_slab_sizes`, before building slabs from them with `Slab::new_with_align`
+/// and a heap with `Heap::new_from_raw_parts`. The sizes must be at least the pointer size,
+/// strictly increasing and at most 4096 bytes, above which allocations are served by the
+/// linked list allocator. Each alignment must be a power of two of at least the pointer
+/// alignment which divides its size, such as `(96, 32)`. There can be at most one class per
+/// slab. Returns the first problem found.
+pub fn validate_slab_config(classes: &[(usize, usize)]) -> Result<(), ConfigError> {
+    if classes.len() > NUM_OF_SLABS - 1 {
+        return Err(ConfigError::TooManySizes);
+    }
+    let mut previous = 0;
+    for &(size, align) in classes {
+        if size < size_of::<usize>() {
+            return Err(ConfigError::TooSmall { size });
+        }
+        if !align.is_power_of_two() || align < align_of::<usize>() {
+            return Err(ConfigError::InvalidAlign { size, align });
+        }
+        if size % align != 0 {
+            return Err(ConfigError::NotMultipleOfAlign { size, align });
+        }
+        if size <= previous {
+            return Err(ConfigError::NotIncreasing { size });
+        }
+        if size > MIN_SLAB_SIZE {
+            return Err(ConfigError::TooLarge { size });
+        }
+        previous = size;
+    }
+    Ok(())
+}
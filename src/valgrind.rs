@@ -0,0 +1,73 @@
+//! Valgrind client requests, so memcheck tracks slab blocks individually with the `valgrind`
+//! feature. All heaps share one memory pool. Freed blocks are made inaccessible except for
+//! their first word, which holds the free list link the slab reads back on allocation. Outside
+//! of Valgrind the requests do nothing.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CREATE_MEMPOOL: usize = 0x1303;
+const MEMPOOL_ALLOC: usize = 0x1305;
+const MEMPOOL_FREE: usize = 0x1306;
+#[cfg(test)]
+const COUNT_ERRORS: usize = 0x1201;
+const MAKE_MEM_DEFINED: usize = ((b'M' as usize) << 24 | (b'C' as usize) << 16) + 2;
+
+/// Its address identifies the pool to Valgrind.
+static POOL: u8 = 0;
+static POOL_CREATED: AtomicBool = AtomicBool::new(false);
+
+fn pool() -> usize {
+    &POOL as *const u8 as usize
+}
+
+/// Creates the pool unless an earlier heap already did.
+pub fn create_pool() {
+    if !POOL_CREATED.swap(true, Ordering::SeqCst) {
+        request(CREATE_MEMPOOL, pool(), 0, 0);
+    }
+}
+
+/// Marks the `size` bytes at `addr` as allocated and not yet initialized.
+pub fn mempool_alloc(addr: usize, size: usize) {
+    request(MEMPOOL_ALLOC, pool(), addr, size);
+}
+
+/// Marks the block at `addr` as freed, keeping its free list link readable.
+pub fn mempool_free(addr: usize) {
+    request(MEMPOOL_FREE, pool(), addr, 0);
+    make_mem_defined(addr, size_of::<usize>());
+}
+
+/// Marks the `size` bytes at `addr` as accessible again, such as memory of freed blocks handed
+/// to the linked list allocator.
+pub fn make_mem_defined(addr: usize, size: usize) {
+    request(MAKE_MEM_DEFINED, addr, size, 0);
+}
+
+/// Returns the number of errors Valgrind reported so far, or 0 outside of Valgrind.
+#[cfg(test)]
+pub fn count_errors() -> usize {
+    request(COUNT_ERRORS, 0, 0, 0)
+}
+
+/// Issues a client request with the magic instruction sequence of `valgrind.h`, returning 0
+/// when not running under Valgrind.
+#[cfg(all(target_arch = "x86_64", unix))]
+fn request(code: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
+    let args: [usize; 6] = [code, arg1, arg2, arg3, 0, 0];
+    let result;
+    unsafe {
+        asm!("rolq $$3, %rdi; rolq $$13, %rdi; rolq $$61, %rdi; rolq $$51, %rdi; xchgq %rbx, %rbx"
+             : "={rdx}"(result)
+             : "{rax}"(args.as_ptr()), "{rdx}"(0usize)
+             : "cc", "memory"
+             : "volatile");
+    }
+    result
+}
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+fn request(_code: usize, _arg1: usize, _arg2: usize, _arg3: usize) -> usize {
+    0
+}
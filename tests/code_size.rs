@@ -0,0 +1,64 @@
+//! Builds the library in release mode with and without the `tiny` feature and checks that
+//! `tiny` shrinks its `.text` sections. This target has no test harness. It needs `cargo` and
+//! binutils' `size` on the host, and builds into its own target directories, as the outer
+//! build holds the lock of the default one.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Builds the library with `features` into `target/code-size/<name>` and returns the path of
+/// the rlib.
+fn build(name: &str, features: &str) -> PathBuf {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = manifest_dir.join("target").join("code-size").join(name);
+    let cargo = option_env!("CARGO").unwrap_or("cargo");
+    let status = Command::new(cargo)
+        .current_dir(manifest_dir)
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .args(&["build", "--release", "--lib", "--features", features])
+        .status()
+        .expect("failed to run cargo");
+    assert!(
+        status.success(),
+        "building with features {:?} failed",
+        features
+    );
+    target_dir.join("release").join("libslab_allocator.rlib")
+}
+
+/// Returns the total size of the `.text` sections of the objects in `rlib`.
+fn text_size(rlib: &Path) -> usize {
+    let output = Command::new("size")
+        .args(&["-A", "-d"])
+        .arg(rlib)
+        .output()
+        .expect("failed to run size");
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with(".text"))
+        .map(|line| {
+            line.split_whitespace()
+                .nth(1)
+                .and_then(|size| size.parse::<usize>().ok())
+                .expect("unexpected output of size")
+        })
+        .sum()
+}
+
+fn main() {
+    let default = text_size(&build("default", ""));
+    let tiny = text_size(&build("tiny", "tiny"));
+    assert!(default > 0, "no .text sections found");
+    assert!(
+        tiny < default,
+        "tiny should reduce the size of .text, but it is {} bytes with and {} without it",
+        tiny,
+        default
+    );
+    println!(
+        "code_size: .text is {} bytes by default and {} bytes with tiny ({:.1}% smaller)",
+        default,
+        tiny,
+        100.0 * (default - tiny) as f64 / default as f64
+    );
+}
@@ -0,0 +1,93 @@
+//! Times threads churning allocations of different size classes on `LockedHeap` and on
+//! `FineLockedHeap`, and prints both timings. The threads only contend for a lock on
+//! `LockedHeap`, so `FineLockedHeap` should be faster once there are as many cores as threads.
+//! The speedup isn't asserted, as it depends on the host. This target has no test harness, so
+//! its threads are the only ones running. Run it with `--release` for meaningful numbers.
+
+extern crate slab_allocator;
+
+use slab_allocator::{FineLockedHeap, LockedHeap};
+use std::alloc::{GlobalAlloc, Layout};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const HEAP_SIZE: usize = 64 * 8 * 4096;
+const ROUNDS: usize = 20_000;
+const BATCH: usize = 8;
+const RUNS: usize = 3;
+
+/// Each thread allocates from its own slab.
+const SIZES: [usize; 4] = [48, 100, 200, 400];
+
+#[repr(align(4096))]
+struct HeapSpace([u8; HEAP_SIZE]);
+
+static mut LOCKED_SPACE: HeapSpace = HeapSpace([0; HEAP_SIZE]);
+static mut FINE_LOCKED_SPACE: HeapSpace = HeapSpace([0; HEAP_SIZE]);
+
+static LOCKED: LockedHeap = LockedHeap::empty();
+static FINE_LOCKED: FineLockedHeap = FineLockedHeap::empty();
+
+/// Runs one thread per entry of `SIZES` on `heap` and returns how long they took.
+fn run<A: GlobalAlloc + Sync>(heap: &'static A) -> Duration {
+    let start = Instant::now();
+    let threads: Vec<_> = SIZES
+        .iter()
+        .map(|&size| {
+            thread::spawn(move || {
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                let mut live = [0 as *mut u8; BATCH];
+                for _ in 0..ROUNDS {
+                    for ptr in live.iter_mut() {
+                        *ptr = unsafe { heap.alloc(layout) };
+                        unsafe { **ptr = size as u8 };
+                    }
+                    for &ptr in live.iter() {
+                        assert_eq!(unsafe { *ptr }, size as u8);
+                        unsafe { heap.dealloc(ptr, layout) };
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+/// Returns the fastest of `RUNS` runs on `heap`.
+fn best<A: GlobalAlloc + Sync>(heap: &'static A) -> Duration {
+    (0..RUNS).map(|_| run(heap)).min().unwrap()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+fn main() {
+    unsafe {
+        LOCKED.init(LOCKED_SPACE.0.as_ptr() as usize, HEAP_SIZE);
+        FINE_LOCKED.init(FINE_LOCKED_SPACE.0.as_ptr() as usize, HEAP_SIZE);
+    }
+    let locked_free_blocks = LOCKED.lock().as_ref().unwrap().total_free_blocks();
+    let fine_locked_free_blocks = FINE_LOCKED.total_free_blocks();
+
+    let locked = best(&LOCKED);
+    let fine_locked = best(&FINE_LOCKED);
+
+    assert_eq!(
+        LOCKED.lock().as_ref().unwrap().total_free_blocks(),
+        locked_free_blocks
+    );
+    assert_eq!(FINE_LOCKED.total_free_blocks(), fine_locked_free_blocks);
+    println!(
+        "fine_locked_throughput: {} threads x {} allocations take {:.1} ms on LockedHeap and \
+         {:.1} ms on FineLockedHeap ({:.2}x)",
+        SIZES.len(),
+        ROUNDS * BATCH,
+        millis(locked),
+        millis(fine_locked),
+        millis(locked) / millis(fine_locked)
+    );
+}
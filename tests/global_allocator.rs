@@ -0,0 +1,112 @@
+//! Runs real collections on top of `LockedHeap` installed as the global allocator.
+//! This target has no test harness. The standard library already allocates before `main`
+//! runs, so the heap is initialized by the first allocation, which works on every target.
+
+extern crate slab_allocator;
+
+use slab_allocator::LockedHeap;
+use std::alloc::{GlobalAlloc, Layout};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::Once;
+
+const HEAP_SIZE: usize = 1024 * 4096;
+
+#[repr(align(4096))]
+struct HeapSpace([u8; HEAP_SIZE]);
+
+static mut HEAP_SPACE: HeapSpace = HeapSpace([0; HEAP_SIZE]);
+
+/// `LockedHeap` which initializes itself on the first allocation.
+struct LazyHeap {
+    heap: LockedHeap,
+    init: Once,
+}
+
+unsafe impl GlobalAlloc for LazyHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.init
+            .call_once(|| self.heap.init(HEAP_SPACE.0.as_ptr() as usize, HEAP_SIZE));
+        self.heap.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: LazyHeap = LazyHeap {
+    heap: LockedHeap::empty(),
+    init: Once::new(),
+};
+
+fn free_blocks() -> usize {
+    ALLOCATOR.heap.flush_caches();
+    ALLOCATOR.heap.lock().as_ref().unwrap().total_free_blocks()
+}
+
+fn vec_growth() {
+    let mut v = Vec::new();
+    for i in 0..10_000u32 {
+        v.push(i);
+    }
+    assert_eq!(v.iter().map(|&i| i as u64).sum::<u64>(), 49_995_000);
+    v.truncate(10);
+    v.shrink_to_fit();
+    assert_eq!(v, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+}
+
+fn boxes() {
+    let small = Box::new(42u8);
+    let medium = Box::new([7u64; 64]);
+    let large = Box::new([3u8; 6000]);
+    assert_eq!(*small, 42);
+    assert!(medium.iter().all(|&x| x == 7));
+    assert!(large.iter().all(|&x| x == 3));
+}
+
+fn strings() {
+    let mut s = String::new();
+    for i in 0..2000 {
+        s.push_str(&i.to_string());
+    }
+    assert!(s.len() > 4096);
+    assert!(s.starts_with("0123456789101112"));
+}
+
+fn btree_map() {
+    let mut map = BTreeMap::new();
+    for i in 0..1000 {
+        map.insert(i, vec![i as u8; i % 300]);
+    }
+    for i in (0..1000).filter(|i| i % 2 == 0) {
+        map.remove(&i);
+    }
+    assert_eq!(map.len(), 500);
+    assert_eq!(map[&999].len(), 999 % 300);
+}
+
+fn rc() {
+    let shared = Rc::new(vec![1u32; 2000]);
+    let clones: Vec<_> = (0..100).map(|_| Rc::clone(&shared)).collect();
+    assert_eq!(Rc::strong_count(&shared), 101);
+    drop(clones);
+    assert_eq!(Rc::strong_count(&shared), 1);
+}
+
+fn main() {
+    let tests: [(&str, fn()); 5] = [
+        ("vec_growth", vec_growth),
+        ("boxes", boxes),
+        ("strings", strings),
+        ("btree_map", btree_map),
+        ("rc", rc),
+    ];
+    for &(name, test) in tests.iter() {
+        let baseline = free_blocks();
+        test();
+        assert_eq!(free_blocks(), baseline, "{} leaked slab blocks", name);
+        println!("test {} ... ok", name);
+    }
+}
@@ -0,0 +1,77 @@
+//! Runs collections on top of `HostedSlabAlloc` installed as the global allocator and checks
+//! its statistics. This target has no test harness, as the harness threads would allocate
+//! concurrently and skew the counters.
+
+extern crate slab_allocator;
+
+use slab_allocator::HostedSlabAlloc;
+use std::collections::{BTreeMap, HashMap};
+
+const HEAP_SIZE: usize = 64 * 4096;
+
+#[global_allocator]
+static ALLOCATOR: HostedSlabAlloc = HostedSlabAlloc::new(HEAP_SIZE);
+
+fn free_blocks() -> usize {
+    ALLOCATOR.flush_caches();
+    ALLOCATOR.lock().as_ref().unwrap().total_free_blocks()
+}
+
+fn small_collections() {
+    let mut map = BTreeMap::new();
+    for i in 0..500 {
+        map.insert(i, i.to_string());
+    }
+    let mut counts = HashMap::new();
+    for word in "a b a c b a".split(' ') {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    assert_eq!(map[&499], "499");
+    assert_eq!(counts["a"], 3);
+}
+
+fn oversized_vec() {
+    // Does not fit the heap, so it is forwarded to the system allocator.
+    let system_allocations = ALLOCATOR.system_allocations();
+    let v = vec![7u8; 4 * HEAP_SIZE];
+    assert!(v.iter().all(|&x| x == 7));
+    assert_eq!(ALLOCATOR.system_allocations(), system_allocations + 1);
+    let (start, size) = ALLOCATOR.region().unwrap();
+    let addr = v.as_ptr() as usize;
+    assert!(addr < start || addr >= start + size);
+}
+
+fn vec_growth() {
+    let mut v = Vec::new();
+    for i in 0..20_000u32 {
+        v.push(i);
+    }
+    assert_eq!(v.iter().map(|&i| i as u64).sum::<u64>(), 199_990_000);
+}
+
+fn main() {
+    let tests: [(&str, fn()); 3] = [
+        ("small_collections", small_collections),
+        ("oversized_vec", oversized_vec),
+        ("vec_growth", vec_growth),
+    ];
+    for &(name, test) in tests.iter() {
+        let baseline = free_blocks();
+        test();
+        assert_eq!(free_blocks(), baseline, "{} leaked slab blocks", name);
+        println!("test {} ... ok", name);
+    }
+    // The counters are compiled out with the `tiny` feature.
+    #[cfg(not(feature = "tiny"))]
+    {
+        let counters = ALLOCATOR.counters();
+        assert!(counters.alloc_count() > 0);
+        assert!(counters.dealloc_count() > 0);
+        println!(
+            "{} allocations and {} deallocations served by the heap, {} forwarded",
+            counters.alloc_count(),
+            counters.dealloc_count(),
+            ALLOCATOR.system_allocations()
+        );
+    }
+}
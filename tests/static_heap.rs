@@ -0,0 +1,61 @@
+//! Runs collections on top of a `StaticSlabHeap` installed as the global allocator, without
+//! any init call, and checks that every allocation lies in the heap's own storage.
+//! This target has no test harness, like `global_allocator`.
+
+#[macro_use]
+extern crate slab_allocator;
+
+use std::collections::BTreeMap;
+
+static_slab_heap!(
+    #[global_allocator]
+    static HEAP: 1024 * 4096;
+);
+
+fn assert_in_storage<T: ?Sized>(value: &T, size: usize) {
+    let (start, heap_size) = HEAP.region();
+    let addr = value as *const T as *const u8 as usize;
+    assert!(addr >= start && addr + size <= start + heap_size);
+}
+
+fn vec_growth() {
+    let mut v = Vec::new();
+    for i in 0..10_000u32 {
+        v.push(i);
+        assert_in_storage(&v[..], v.capacity() * 4);
+    }
+    assert_eq!(v.iter().map(|&i| i as u64).sum::<u64>(), 49_995_000);
+}
+
+fn boxes() {
+    let small = Box::new(42u8);
+    let medium = Box::new([7u64; 64]);
+    let large = Box::new([3u8; 6000]);
+    assert_in_storage(&*small, 1);
+    assert_in_storage(&*medium, 512);
+    assert_in_storage(&*large, 6000);
+    assert!(large.iter().all(|&x| x == 3));
+}
+
+fn btree_map() {
+    let mut map = BTreeMap::new();
+    for i in 0..1000 {
+        map.insert(i, vec![i as u8; i % 300]);
+    }
+    for value in map.values().filter(|value| !value.is_empty()) {
+        assert_in_storage(&value[..], value.len());
+    }
+    assert_eq!(map.len(), 1000);
+}
+
+fn main() {
+    let tests: [(&str, fn()); 3] = [
+        ("vec_growth", vec_growth),
+        ("boxes", boxes),
+        ("btree_map", btree_map),
+    ];
+    for &(name, test) in tests.iter() {
+        test();
+        println!("test {} ... ok", name);
+    }
+}